@@ -1,44 +1,31 @@
-extern crate capstone;
 extern crate jazz_jit;
 
-use capstone::prelude::*;
-
 use jazz_jit::assembler::Assembler;
 use jazz_jit::assembler_x64::*;
 use jazz_jit::constants_x64::*;
-use jazz_jit::{get_executable_memory, Memory};
+use jazz_jit::disasm::Syntax;
+use jazz_jit::{get_executable_memory, JitFunction};
 
 fn main() {
     let mut asm = Assembler::new();
+    asm.set_name("two");
     emit_movl_imm_reg(&mut asm, 2, RAX);
     emit_retq(&mut asm);
 
-    let mem = get_executable_memory(&asm);
+    let callee = get_executable_memory(&asm.finalize().unwrap()).unwrap();
 
     let mut asm = Assembler::new();
+    asm.keep_listing = true;
 
-    emit_movq_imm64_reg(&mut asm, mem.ptr() as i64, RAX);
+    asm.comment("call two()");
+    emit_movq_imm64_reg(&mut asm, callee.start() as i64, RAX);
     emit_callq_reg(&mut asm, RAX);
     emit_retq(&mut asm);
 
-    let mem: Memory = get_executable_memory(&asm);
-
-    let ptr = mem.ptr();
-
-    let mut cs = Capstone::new().x86()
-                                .mode(arch::x86::ArchMode::Mode64)
-                                .syntax(arch::x86::ArchSyntax::Att)
-                                .detail(true)
-                                .build()
-                                .unwrap();
-
-    let buf = unsafe { ::std::slice::from_raw_parts(ptr, mem.size()) };
+    let f: JitFunction<extern "C" fn() -> i32> =
+        unsafe { JitFunction::new(get_executable_memory(&asm.finalize().unwrap()).unwrap()) };
 
-    let i = cs.disasm_all(buf, 0x0).unwrap();
-    for i in i.iter() {
-        println!("{}", i);
-    }
+    print!("{}", f.memory().disassemble_with(Syntax::Att));
 
-    let f: fn() -> i32 = unsafe { ::std::mem::transmute(ptr) };
-    print!("{}\n", f());
+    print!("{}\n", f.call());
 }
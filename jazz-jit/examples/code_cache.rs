@@ -0,0 +1,43 @@
+extern crate jazz_jit;
+
+use jazz_jit::assembler::Assembler;
+use jazz_jit::assembler_x64::*;
+use jazz_jit::code_cache::CodeCache;
+use jazz_jit::constants_x64::*;
+use jazz_jit::JitFunction;
+
+fn constant(cache: &std::sync::Arc<CodeCache>, value: i32) -> jazz_jit::Memory {
+    let mut asm = Assembler::new();
+    emit_movl_imm_reg(&mut asm, value, RAX);
+    emit_retq(&mut asm);
+
+    cache.emit(&asm.finalize().unwrap()).unwrap()
+}
+
+fn main() {
+    let cache = CodeCache::new(64 * 1024);
+
+    let mut functions = Vec::new();
+    for i in 0..64 {
+        functions.push(constant(&cache, i));
+    }
+
+    let f: JitFunction<extern "C" fn() -> i32> = unsafe { JitFunction::borrowed(&functions[42]) };
+    println!("f() = {}", f.call());
+    println!("{:?}", cache.stats());
+
+    // drop every other function and look at the holes left behind
+    let mut i = 0;
+    functions.retain(|_| {
+        i += 1;
+        i % 2 == 0
+    });
+    let stats = cache.stats();
+    println!("{:?}", stats);
+    println!("fragmentation: {:.3}", stats.fragmentation());
+
+    functions.clear();
+    let stats = cache.stats();
+    println!("{:?}", stats);
+    println!("fragmentation: {:.3}", stats.fragmentation());
+}
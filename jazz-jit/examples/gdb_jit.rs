@@ -0,0 +1,71 @@
+//! Registers two functions with the GDB JIT interface.
+//!
+//!     cargo build --features gdb-jit --example gdb_jit
+//!     gdb -ex run -ex bt -ex 'info functions jit_' --args target/debug/examples/gdb_jit --trap
+//!
+//! With `--trap` the program stops inside `jit_trap`, at line 2 of `trap.ex`. Passing
+//! a path after it writes the ELF object registered for `jit_answer`, which can be
+//! inspected with readelf.
+extern crate jazz_jit;
+
+use jazz_jit::assembler::Assembler;
+use jazz_jit::assembler_x64::*;
+use jazz_jit::constants_x64::*;
+use jazz_jit::gdb_jit::__jit_debug_descriptor;
+use jazz_jit::source;
+use jazz_jit::{get_executable_memory, JitFunction};
+
+fn main() {
+    let trap = std::env::args().any(|arg| arg == "--trap");
+    let path = std::env::args().skip(1).find(|arg| !arg.starts_with("--"));
+
+    let mut asm = Assembler::new();
+    asm.set_name("jit_answer");
+    emit_movl_imm_reg(&mut asm, 42, RAX);
+    emit_retq(&mut asm);
+    let answer: JitFunction<extern "C" fn() -> i32> =
+        unsafe { JitFunction::new(get_executable_memory(&asm.finalize().unwrap()).unwrap()) };
+
+    source::register_file(1, "trap.ex");
+    let mut asm = Assembler::new();
+    asm.set_name("jit_trap");
+    asm.set_source_position(1, 1, 1);
+    if trap {
+        asm.emit(0xcc);
+    }
+    asm.set_source_position(1, 2, 5);
+    emit_movq_imm64_reg(&mut asm, answer.memory().start() as i64, RAX);
+    emit_callq_reg(&mut asm, RAX);
+    emit_retq(&mut asm);
+    let caller: JitFunction<extern "C" fn() -> i32> =
+        unsafe { JitFunction::new(get_executable_memory(&asm.finalize().unwrap()).unwrap()) };
+
+    unsafe {
+        let descriptor = &*std::ptr::addr_of!(__jit_debug_descriptor);
+        let mut entry = descriptor.first_entry;
+
+        while !entry.is_null() {
+            println!(
+                "entry {:p}: symfile at {:p}, {} bytes",
+                entry,
+                (*entry).symfile_addr,
+                (*entry).symfile_size
+            );
+
+            // the first registered buffer is last in the list
+            if (*entry).next_entry.is_null() {
+                if let Some(path) = &path {
+                    let symfile = std::slice::from_raw_parts(
+                        (*entry).symfile_addr,
+                        (*entry).symfile_size as usize,
+                    );
+                    std::fs::write(path, symfile).unwrap();
+                }
+            }
+
+            entry = (*entry).next_entry;
+        }
+    }
+
+    println!("jit_trap() = {}", caller.call());
+}
@@ -1,12 +1,10 @@
-extern crate capstone;
 extern crate jazz_jit;
 
-use capstone::prelude::*;
-
 use jazz_jit::assembler::Assembler;
 use jazz_jit::assembler_x64::*;
 use jazz_jit::constants_x64::*;
-use jazz_jit::{get_executable_memory, Memory};
+use jazz_jit::disasm::Syntax;
+use jazz_jit::{get_executable_memory, JitFunction};
 
 fn main() {
     let mut asm = Assembler::new();
@@ -15,24 +13,10 @@ fn main() {
     emit_add_reg_reg(&mut asm, 1, RSI, RAX);
     emit_retq(&mut asm);
 
-    let mem: Memory = get_executable_memory(&asm);
-
-    let ptr = mem.ptr();
-
-    let mut cs = Capstone::new().x86()
-                                .mode(arch::x86::ArchMode::Mode64)
-                                .syntax(arch::x86::ArchSyntax::Att)
-                                .detail(true)
-                                .build()
-                                .unwrap();
-
-    let buf = unsafe { ::std::slice::from_raw_parts(ptr, mem.size()) };
+    let f: JitFunction<extern "C" fn(i32, i32) -> i32> =
+        unsafe { JitFunction::new(get_executable_memory(&asm.finalize().unwrap()).unwrap()) };
 
-    let i = cs.disasm_all(buf, 0x0).unwrap();
-    for i in i.iter() {
-        println!("{}", i);
-    }
+    print!("{}", f.memory().disassemble_with(Syntax::Att));
 
-    let f: fn(i32, i32) -> i32 = unsafe { ::std::mem::transmute(ptr) };
-    print!("{}\n", f(2, 2));
+    print!("{}\n", f.call(2, 2));
 }
@@ -0,0 +1,51 @@
+//! Writes a perf map and a jitdump file for two generated functions.
+//!
+//!     perf record -k mono target/debug/examples/perf
+//!     perf inject --jit -i perf.data -o perf.jit.data
+//!     perf report -i perf.jit.data
+extern crate jazz_jit;
+
+use jazz_jit::assembler::Assembler;
+use jazz_jit::assembler_x64::*;
+use jazz_jit::constants_x64::*;
+use jazz_jit::{get_executable_memory, perf, JitFunction};
+
+fn count_down() -> JitFunction<'static, extern "C" fn(i64) -> i64> {
+    let mut asm = Assembler::new();
+    asm.set_name("count_down");
+
+    let top = asm.create_label();
+    let done = asm.create_label();
+    emit_xor_reg_reg(&mut asm, 1, RAX, RAX);
+    asm.bind_label(top);
+    emit_testq_reg_reg(&mut asm, RDI, RDI);
+    emit_jcc(&mut asm, jazz_jit::CondCode::Zero, done);
+    emit_add_reg_reg(&mut asm, 1, RDI, RAX);
+    emit_subq_imm_reg(&mut asm, 1, RDI);
+    emit_jmp(&mut asm, top);
+    asm.bind_label(done);
+    emit_retq(&mut asm);
+
+    let code = asm.finalize().unwrap();
+    unsafe { JitFunction::new(get_executable_memory(&code).unwrap()) }
+}
+
+fn main() {
+    perf::enable_perf_map().unwrap();
+    let dump = perf::enable_jitdump(&std::env::current_dir().unwrap()).unwrap();
+
+    let f = count_down();
+    println!("count_down(100000000) = {}", f.call(100_000_000));
+
+    // unnamed code shows up as jit_<address>
+    let mut asm = Assembler::new();
+    emit_retq(&mut asm);
+    let _ = get_executable_memory(&asm.finalize().unwrap()).unwrap();
+
+    perf::disable();
+    println!(
+        "wrote /tmp/perf-{}.map and {}",
+        std::process::id(),
+        dump.display()
+    );
+}
@@ -31,6 +31,7 @@ enum class MachineMode
   Float32,
   Float64,
   Ptr,
+  Int16,
 };
 
 enum class Register : int32_t
@@ -188,6 +189,84 @@ struct Reg
   };
 };
 
+using Label = uintptr_t;
+
+enum class Segment
+{
+  Fs,
+  Gs,
+};
+
+struct RipTarget
+{
+  enum class Tag
+  {
+    Label,
+    DSeg,
+  };
+
+  struct Label_Body
+  {
+    Label _0;
+  };
+
+  struct DSeg_Body
+  {
+    int32_t _0;
+  };
+
+  Tag tag;
+  union {
+    Label_Body label;
+    DSeg_Body d_seg;
+  };
+};
+
+struct SegmentAddr
+{
+  enum class Tag
+  {
+    Base,
+    Index,
+    Offset,
+    Absolute,
+  };
+
+  struct Base_Body
+  {
+    Register _0;
+    int32_t _1;
+  };
+
+  struct Index_Body
+  {
+    Register _0;
+    Register _1;
+    int32_t _2;
+    int32_t _3;
+  };
+
+  struct Offset_Body
+  {
+    Register _0;
+    int32_t _1;
+    int32_t _2;
+  };
+
+  struct Absolute_Body
+  {
+    uint64_t _0;
+  };
+
+  Tag tag;
+  union {
+    Base_Body base;
+    Index_Body index;
+    Offset_Body offset;
+    Absolute_Body absolute;
+  };
+};
+
 struct Mem
 {
   enum class Tag
@@ -196,6 +275,9 @@ struct Mem
     Base,
     Index,
     Offset,
+    RipRelative,
+    Absolute,
+    Segment,
   };
 
   struct Local_Body
@@ -224,24 +306,35 @@ struct Mem
     int32_t _2;
   };
 
+  struct RipRelative_Body
+  {
+    RipTarget _0;
+  };
+
+  struct Absolute_Body
+  {
+    uint64_t _0;
+  };
+
+  struct Segment_Body
+  {
+    Segment _0;
+    SegmentAddr _1;
+  };
+
   Tag tag;
   union {
     Local_Body local;
     Base_Body base;
     Index_Body index;
     Offset_Body offset;
+    RipRelative_Body rip_relative;
+    Absolute_Body absolute;
+    Segment_Body segment;
   };
 };
 
-using Label = uintptr_t;
-
-struct Memory
-{
-  const uint8_t *start;
-  const uint8_t *end;
-  const uint8_t *pointer;
-  uintptr_t size;
-};
+struct Memory;
 
 extern "C"
 {
@@ -536,20 +629,42 @@ extern "C"
 
   bool fits_i8(int32_t imm);
 
-  Memory get_executable_memory(const Assembler *buf);
-
   void lea(Assembler *buf, Register dest, Mem src);
 
   Reg reg_gpr(Register reg);
   Reg reg_fpr(XMMRegister reg);
+  Mem mem_absolute(uint64_t addr);
+
   Mem mem_base(Register reg, int32_t off);
 
+  Mem mem_dseg(int32_t disp);
+
   Mem mem_index(Register reg, Register reg2, int32_t v1, int32_t v2);
 
+  Mem mem_label(Label lbl);
+
   Mem mem_local(int32_t off);
 
   Mem mem_offset(Register reg, int32_t v1, int32_t v2);
 
+  Mem mem_segment(Segment segment, int32_t disp);
+
+  Mem mem_segment_base(Segment segment, Register reg, int32_t off);
+
+  Mem mem_segment_index(Segment segment,
+                        Register reg,
+                        Register reg2,
+                        int32_t v1,
+                        int32_t v2);
+
+  Mem mem_segment_offset(Segment segment, Register reg, int32_t v1, int32_t v2);
+
+  Memory *memory_emit(Assembler *buf);
+
+  void memory_free(Memory *mem);
+
+  const uint8_t *memory_start(const Memory *mem);
+
   void movaps(Assembler *buf, XMMRegister dest, XMMRegister src);
 
   void movaps_load(Assembler *buf, XMMRegister dest, Mem src);
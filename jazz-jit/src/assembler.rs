@@ -1,14 +1,171 @@
+/// Encoding of a label reference.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub enum JumpKind {
+    /// A raw rel32 written by `emit_label`, never shrunk.
+    Label,
+    /// `jmp rel32` (E9), may be relaxed to `jmp rel8` (EB).
+    Jmp,
+    /// `jcc rel32` (0F 8x), may be relaxed to `jcc rel8` (7x).
+    Jcc,
+    /// An already short jump with a rel8 displacement.
+    Short,
+    /// The rel32 of a RIP-relative memory operand, followed by an immediate of the
+    /// given number of bytes. Reported at the displacement if the label is unbound.
+    Rip(u8),
+}
+
+impl JumpKind {
+    /// Number of opcode bytes in front of the displacement.
+    fn opcode_len(self) -> usize {
+        match self {
+            JumpKind::Label | JumpKind::Rip(_) => 0,
+            JumpKind::Jmp | JumpKind::Short => 1,
+            JumpKind::Jcc => 2,
+        }
+    }
+
+    /// Distance from the displacement to the end of the instruction, which the
+    /// displacement is relative to.
+    fn disp_len(self) -> usize {
+        match self {
+            JumpKind::Short => 1,
+            JumpKind::Rip(imm_len) => 4 + imm_len as usize,
+            _ => 4,
+        }
+    }
+}
+
+/// A reference to a label. Despite the name, backward references are recorded as
+/// well so that they can be re-patched when jumps are relaxed.
 #[derive(Debug, Clone)]
 #[repr(C)]
 pub struct ForwardJump {
+    /// position of the displacement
     pub at: usize,
     pub to: usize,
+    pub kind: JumpKind,
 }
+use crate::align;
 use crate::constants_x64::Register;
-use crate::dseg::DSeg;
+use crate::cpu::{CpuFeature, CpuFeatures};
+use crate::disasm::{Listing, Syntax};
+use crate::dseg::{DSeg, Placement};
+use crate::reloc::{RelocKind, RelocTarget, Relocation};
+use crate::source::SourcePosition;
+use crate::unwind::{CfiOp, Frame};
+use crate::MachineMode;
 use byteorder::{ByteOrder, LittleEndian, WriteBytesExt};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::ops::{Deref, Range};
 pub type Label = usize;
 
+/// A problem found while assembling. Positions are offsets into the code as it is
+/// after jumps were resolved.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AsmDiagnostic {
+    /// A label that was referenced but never bound, with the start of every
+    /// instruction referencing it.
+    UnboundLabel {
+        label: Label,
+        references: Vec<usize>,
+    },
+    /// A label that was bound twice. The first binding is kept.
+    DoubleBoundLabel {
+        label: Label,
+        first: usize,
+        second: usize,
+    },
+    /// A displacement at `at` that does not fit into `bits` bits.
+    DisplacementOutOfRange { at: usize, disp: i64, bits: u8 },
+    /// An operand or combination of operands the instruction at `at` cannot encode.
+    InvalidOperand { at: usize, message: String },
+    /// An instruction at `at` from an extension the target does not support.
+    MissingCpuFeature { at: usize, feature: CpuFeature },
+    /// A constant the data segment cannot store.
+    InvalidConstant { message: String },
+}
+
+impl fmt::Display for AsmDiagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AsmDiagnostic::UnboundLabel { label, references } => {
+                write!(f, "label {} is never bound, referenced at", label)?;
+                for (i, at) in references.iter().enumerate() {
+                    let sep = if i == 0 { " " } else { ", " };
+                    write!(f, "{}{:#x}", sep, at)?;
+                }
+                Ok(())
+            }
+            AsmDiagnostic::DoubleBoundLabel {
+                label,
+                first,
+                second,
+            } => write!(
+                f,
+                "label {} bound at {:#x} is bound again at {:#x}",
+                label, first, second
+            ),
+            AsmDiagnostic::DisplacementOutOfRange { at, disp, bits } => write!(
+                f,
+                "displacement {} at {:#x} does not fit into {} bits",
+                disp, at, bits
+            ),
+            AsmDiagnostic::InvalidOperand { at, message } => write!(f, "{} at {:#x}", message, at),
+            AsmDiagnostic::MissingCpuFeature { at, feature } => write!(
+                f,
+                "instruction at {:#x} needs {}, which the target does not support",
+                at, feature
+            ),
+            AsmDiagnostic::InvalidConstant { message } => {
+                write!(f, "{} in the data segment", message)
+            }
+        }
+    }
+}
+
+/// Returned by `Assembler::finalize` when the code cannot be used.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AsmError {
+    pub diagnostics: Vec<AsmDiagnostic>,
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "assembling failed with {} error(s)",
+            self.diagnostics.len()
+        )?;
+        for diagnostic in &self.diagnostics {
+            write!(f, "\n  {}", diagnostic)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for AsmError {}
+
+/// Finished code returned by `Assembler::finalize`: every label is bound and every
+/// jump is resolved.
+#[derive(Clone, Debug)]
+pub struct Code(Assembler);
+
+impl Code {
+    pub fn into_assembler(self) -> Assembler {
+        self.0
+    }
+}
+
+impl Deref for Code {
+    type Target = Assembler;
+
+    fn deref(&self) -> &Assembler {
+        &self.0
+    }
+}
+
 trait Idx {
     fn index(&self) -> usize;
 }
@@ -33,6 +190,59 @@ pub enum Mem {
 
     // reg1 * val1 + val2
     Offset(Register, i32, i32),
+
+    // rip + distance to the target
+    RipRelative(RipTarget),
+
+    // val1, up to 32 bits sign-extended except with movabs
+    Absolute(u64),
+
+    // val2 relative to the base of the segment register val1, e.g. thread-local storage
+    Segment(Segment, SegmentAddr),
+}
+
+/// What a `Mem::RipRelative` operand addresses. The displacement is filled in once
+/// the position of the target is known.
+#[derive(Clone, Debug, PartialEq, Eq, Copy)]
+#[repr(C)]
+pub enum RipTarget {
+    /// A label in the code.
+    Label(Label),
+    /// The data segment entry at the displacement returned by the `DSeg::add_*`
+    /// functions.
+    DSeg(i32),
+}
+
+/// Segment registers a `Mem::Segment` operand can be relative to. x86-64 ignores the
+/// bases of the others, FS and GS point at thread-local storage.
+#[derive(Clone, Debug, PartialEq, Eq, Copy)]
+#[repr(C)]
+pub enum Segment {
+    Fs,
+    Gs,
+}
+
+/// The address of a `Mem::Segment` operand within its segment. The forms are those
+/// of `Mem` that do not imply a base register of their own.
+#[derive(Clone, Debug, PartialEq, Eq, Copy)]
+#[repr(C)]
+pub enum SegmentAddr {
+    Base(Register, i32),
+    Index(Register, Register, i32, i32),
+    Offset(Register, i32, i32),
+    Absolute(u64),
+}
+
+impl SegmentAddr {
+    /// The operand without the segment override.
+    pub fn mem(self) -> Mem {
+        match self {
+            SegmentAddr::Base(base, disp) => Mem::Base(base, disp),
+            SegmentAddr::Index(base, index, scale, disp) => Mem::Index(base, index, scale, disp),
+            SegmentAddr::Offset(index, scale, disp) => Mem::Offset(index, scale, disp),
+            SegmentAddr::Absolute(addr) => Mem::Absolute(addr),
+        }
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -42,6 +252,42 @@ pub struct Assembler {
     pub dseg: DSeg,
     pub jumps: Vec<ForwardJump>,
     pub labels: Vec<Option<usize>>,
+    /// Positions of rel32 displacements that address the data segment. Each one may
+    /// only be followed by the immediate of its instruction.
+    pub dseg_refs: Vec<usize>,
+    /// Offset of the data segment behind the code, set once jumps are resolved if it
+    /// is placed as `Placement::Island`.
+    pub island: Option<usize>,
+    /// Fields that have to be filled in or adjusted once the address of the code is
+    /// known.
+    pub relocations: Vec<Relocation>,
+    /// Call frame instructions and the positions they take effect at.
+    pub cfi_ops: Vec<(usize, CfiOp)>,
+    pub(crate) frame: Frame,
+    /// Legacy prefixes emitted for the instruction being encoded. The segment override
+    /// of a memory operand goes in front of them.
+    pub(crate) prefixes: Range<usize>,
+    /// Source positions and the positions they start at.
+    pub source_positions: Vec<(usize, SourcePosition)>,
+    /// Shrink jumps to their rel8 form in `fix_forward_jumps` and `finalize`. On by
+    /// default. Relaxing moves code, so positions obtained from `pos()` before that
+    /// are no longer valid afterwards; code that keeps such positions turns it off.
+    pub relax_jumps: bool,
+    /// Problems recorded while emitting, reported by `finalize`.
+    pub diagnostics: Vec<AsmDiagnostic>,
+    /// Symbol name of the code, shown by debuggers and profilers.
+    pub name: Option<String>,
+    /// Names of labels, shown in disassembly listings.
+    pub label_names: BTreeMap<Label, String>,
+    /// Comments attached to code positions, shown in disassembly listings.
+    pub comments: Vec<(usize, String)>,
+    /// Keep label names, comments and the data segment with the emitted `Memory` so
+    /// `Memory::disassemble` can annotate its listing. Off by default, the copy lives
+    /// as long as the code.
+    pub keep_listing: bool,
+    /// Extensions the code may use. Encoders of instructions from other extensions
+    /// record an error.
+    pub features: CpuFeatures,
 }
 
 impl Assembler {
@@ -57,8 +303,67 @@ impl Assembler {
             dseg: DSeg::new(),
             jumps: Vec::new(),
             labels: Vec::new(),
+            dseg_refs: Vec::new(),
+            island: None,
+            relocations: Vec::new(),
+            cfi_ops: Vec::new(),
+            frame: Frame::new(),
+            prefixes: 0..0,
+            source_positions: Vec::new(),
+            relax_jumps: true,
+            diagnostics: Vec::new(),
+            name: None,
+            label_names: BTreeMap::new(),
+            comments: Vec::new(),
+            keep_listing: false,
+            features: CpuFeatures::all(),
+        }
+    }
+
+    /// An assembler for code that runs on this machine, targeting
+    /// `CpuFeatures::current()`. `new` targets every extension, use this when the
+    /// code is run right away or queries `has_feature`.
+    pub fn for_host() -> Assembler {
+        Assembler::with_features(CpuFeatures::current())
+    }
+
+    /// An assembler for code that runs on a CPU with `features`.
+    pub fn with_features(features: CpuFeatures) -> Assembler {
+        let mut asm = Assembler::new();
+        asm.features = features;
+        asm
+    }
+
+    pub fn has_feature(&self, feature: CpuFeature) -> bool {
+        self.features.has(feature)
+    }
+
+    /// Records an error if the code may not use `feature`. Encoders call this before
+    /// emitting an instruction that needs it.
+    pub fn require(&mut self, feature: CpuFeature) {
+        if self.features.has(feature) {
+            return;
+        }
+
+        let missing = AsmDiagnostic::MissingCpuFeature {
+            at: self.data.len(),
+            feature,
+        };
+
+        // encoders and the prefix emitters they use may both ask for a feature
+        if self.diagnostics.last() != Some(&missing) {
+            self.diagnostics.push(missing);
         }
     }
+
+    pub fn set_name(&mut self, name: impl Into<String>) {
+        self.name = Some(name.into());
+    }
+
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
     #[no_mangle]
     pub extern "C" fn create_label(&mut self) -> usize {
         let idx = self.labels.len();
@@ -66,50 +371,460 @@ impl Assembler {
         self.labels.push(None);
         idx
     }
+    pub fn create_named_label(&mut self, name: impl Into<String>) -> Label {
+        let lbl = self.create_label();
+        self.name_label(lbl, name);
+        lbl
+    }
+
+    pub fn name_label(&mut self, lbl: Label, name: impl Into<String>) {
+        self.label_names.insert(lbl, name.into());
+    }
+
+    /// Attaches a comment to the instruction emitted next.
+    pub fn comment(&mut self, text: impl Into<String>) {
+        let pos = self.data.len();
+        self.comments.push((pos, text.into()));
+    }
+
+    /// Disassembles the code emitted so far. Positions are offsets into the code.
+    pub fn disassemble(&self, syntax: Syntax) -> String {
+        Listing::new(self).render(&self.data, 0, syntax)
+    }
+
     #[no_mangle]
     pub extern "C" fn data<'r>(&'r self) -> &'r Vec<u8> {
         &self.data
     }
     #[no_mangle]
     pub extern "C" fn bind_label(&mut self, lbl: usize) {
-        let lbl_idx = lbl;
+        let pos = self.data.len();
 
-        assert!(self.labels[lbl_idx].is_none());
-        self.labels[lbl_idx] = Some(self.data.len());
+        match self.labels.get(lbl.index()) {
+            Some(&Some(first)) => self.diagnostics.push(AsmDiagnostic::DoubleBoundLabel {
+                label: lbl,
+                first,
+                second: pos,
+            }),
+            Some(&None) => {
+                self.labels[lbl.index()] = Some(pos);
+
+                for idx in 0..self.jumps.len() {
+                    if self.jumps[idx].to == lbl {
+                        self.patch_jump(idx);
+                    }
+                }
+            }
+            None => self.unknown_label(lbl),
+        }
     }
     #[no_mangle]
     pub extern "C" fn emit_label(&mut self, lbl: Label) {
-        let value = self.labels[lbl.index()];
+        self.emit_label_ref(lbl, JumpKind::Label);
+    }
+
+    /// Returns the rel8 displacement of a `len` byte jump to `lbl` emitted at the
+    /// current position, if the label is already bound and close enough.
+    pub fn short_disp(&self, lbl: Label, len: usize) -> Option<i8> {
+        let target = (*self.labels.get(lbl.index())?)? as isize;
+        let diff = target - (self.data.len() + len) as isize;
+
+        if diff == diff as i8 as isize {
+            Some(diff as i8)
+        } else {
+            None
+        }
+    }
+
+    /// Emits the displacement of a label reference of the given kind. The opcode must
+    /// have been emitted already.
+    pub fn emit_label_ref(&mut self, lbl: Label, kind: JumpKind) {
+        let pos = self.data.len();
+
+        // the displacement is written as soon as the label is bound, its range is
+        // checked by `fix_forward_jumps` and `finalize`
+        match kind {
+            JumpKind::Short => self.emit(0),
+            _ => self.emit32(0),
+        }
+
+        if lbl.index() >= self.labels.len() {
+            self.unknown_label(lbl);
+            return;
+        }
+
+        self.jumps.push(ForwardJump {
+            at: pos,
+            to: lbl,
+            kind,
+        });
+        self.patch_jump(self.jumps.len() - 1);
+    }
 
-        match value {
-            // backward jumps already know their target
-            Some(idx) => {
-                let current = self.data.len() + 4;
-                let target = idx;
+    /// Writes the displacement of `self.jumps[idx]` if its label is bound and the
+    /// target is in range. Out of range displacements are reported when the jumps are
+    /// resolved.
+    fn patch_jump(&mut self, idx: usize) {
+        let jmp = &self.jumps[idx];
+        let target = match self.labels[jmp.to] {
+            Some(target) => target,
+            None => return,
+        };
+        let diff = target as isize - (jmp.at + jmp.kind.disp_len()) as isize;
 
-                let diff = -((current - target) as i32);
-                self.emit32(diff as u32);
+        if jmp.kind == JumpKind::Short {
+            if diff == diff as i8 as isize {
+                self.data[jmp.at] = diff as u8;
             }
+        } else if diff == diff as i32 as isize {
+            LittleEndian::write_i32(&mut self.data[jmp.at..], diff as i32);
+        }
+    }
+
+    /// Records a rel32 reference to the data segment entry `disp` that ends the
+    /// instruction just emitted.
+    pub fn record_dseg_ref(&mut self, disp: i32) {
+        let pos = self.data.len() - 4;
+        self.dseg_refs.push(pos);
+        self.record_reloc(RelocKind::Rel32, RelocTarget::DSeg(disp));
+    }
+
+    /// Records a relocation for the field of `kind` that ends at the current
+    /// position. A `Rel32` field is relative to its end.
+    pub fn record_reloc(&mut self, kind: RelocKind, target: RelocTarget) {
+        let (len, addend) = match kind {
+            RelocKind::Abs64 => (8, 0),
+            RelocKind::Rel32 => (4, -4),
+        };
+
+        self.relocations.push(Relocation {
+            at: self.data.len() - len,
+            kind,
+            target,
+            addend,
+        });
+    }
+
+    /// Makes the RIP-relative displacement ending at the current position, if there
+    /// is one, relative to the end of an immediate of `len` bytes. Encoders call this
+    /// before emitting the immediate of an instruction with a memory operand.
+    pub fn imm_follows_mem(&mut self, len: usize) {
+        let pos = self.data.len();
+
+        if let Some(jmp) = self.jumps.last_mut() {
+            if jmp.kind == JumpKind::Rip(0) && jmp.at + 4 == pos {
+                jmp.kind = JumpKind::Rip(len as u8);
+                self.patch_jump(self.jumps.len() - 1);
+                return;
+            }
+        }
 
-            // forward jumps do not know their target yet
-            // we need to do this later...
-            None => {
-                let pos = self.data.len();
-                self.emit32(0);
-                self.jumps.push(ForwardJump { at: pos, to: lbl });
+        if let Some(reloc) = self.relocations.last_mut() {
+            if reloc.kind == RelocKind::Rel32 && reloc.at + 4 == pos {
+                reloc.addend -= len as i64;
             }
         }
+
+        if pos >= 4 && self.dseg_refs.last() == Some(&(pos - 4)) {
+            let disp = LittleEndian::read_i32(&self.data[pos - 4..]);
+            LittleEndian::write_i32(&mut self.data[pos - 4..], disp - len as i32);
+        }
     }
-    #[no_mangle]
-    pub extern "C" fn fix_forward_jumps(&mut self) {
+
+    /// Records an operand the instruction being emitted cannot encode. Encoders call
+    /// this instead of panicking and keep emitting placeholder bytes, the error is
+    /// reported by `finalize`.
+    pub fn invalid_operand<T: Default>(&mut self, message: impl Into<String>) -> T {
+        self.diagnostics.push(AsmDiagnostic::InvalidOperand {
+            at: self.data.len(),
+            message: message.into(),
+        });
+
+        T::default()
+    }
+
+    /// Records a machine mode the instruction being emitted does not support.
+    pub fn invalid_mode<T: Default>(&mut self, mode: MachineMode) -> T {
+        self.invalid_operand(format!("unsupported machine mode {:?}", mode))
+    }
+
+    fn unknown_label(&mut self, lbl: Label) {
+        self.invalid_operand::<()>(format!("label {} was not created by this assembler", lbl));
+    }
+
+    pub fn has_errors(&self) -> bool {
+        !self.diagnostics.is_empty() || !self.dseg.diagnostics().is_empty()
+    }
+
+    /// Resolves all jumps and returns every problem recorded so far. The code stays
+    /// in the assembler, use `finalize` to take it.
+    pub fn fix_forward_jumps(&mut self) -> Result<(), AsmError> {
+        self.resolve_jumps();
+
+        if self.has_errors() {
+            Err(AsmError {
+                diagnostics: self.diagnostics.clone(),
+            })
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Resolves all jumps and returns the finished code, or every problem recorded
+    /// while assembling.
+    pub fn finalize(mut self) -> Result<Code, AsmError> {
+        self.resolve_jumps();
+
+        if self.has_errors() {
+            Err(AsmError {
+                diagnostics: ::std::mem::take(&mut self.diagnostics),
+            })
+        } else {
+            Ok(Code(self))
+        }
+    }
+
+    fn resolve_jumps(&mut self) {
+        // jumps may be resolved more than once, report their problems only once
+        self.diagnostics.retain(|diagnostic| {
+            !matches!(
+                diagnostic,
+                AsmDiagnostic::UnboundLabel { .. } | AsmDiagnostic::DisplacementOutOfRange { .. }
+            )
+        });
+        let constants = self.dseg.take_diagnostics();
+        self.diagnostics.extend(constants);
+
+        if self.relax_jumps {
+            self.relax();
+        }
+
+        let mut unbound: BTreeMap<Label, Vec<usize>> = BTreeMap::new();
+
         for jmp in &self.jumps {
-            let target = self.labels[jmp.to].expect("Label not defined");
-            let diff = (target - jmp.at - 4) as i32;
+            let target = match self.labels[jmp.to] {
+                Some(target) => target,
+                None => {
+                    let start = jmp.at - jmp.kind.opcode_len();
+                    unbound.entry(jmp.to).or_default().push(start);
+                    continue;
+                }
+            };
+            let diff = target as isize - (jmp.at + jmp.kind.disp_len()) as isize;
+
+            if jmp.kind == JumpKind::Short {
+                if diff != diff as i8 as isize {
+                    self.diagnostics
+                        .push(AsmDiagnostic::DisplacementOutOfRange {
+                            at: jmp.at,
+                            disp: diff as i64,
+                            bits: 8,
+                        });
+                    continue;
+                }
+                self.data[jmp.at] = diff as u8;
+            } else {
+                if diff != diff as i32 as isize {
+                    self.diagnostics
+                        .push(AsmDiagnostic::DisplacementOutOfRange {
+                            at: jmp.at,
+                            disp: diff as i64,
+                            bits: 32,
+                        });
+                    continue;
+                }
+                let mut slice = &mut self.data[jmp.at..];
+                slice.write_u32::<LittleEndian>(diff as i32 as u32).unwrap();
+            }
+        }
+
+        for reloc in &self.relocations {
+            if let RelocTarget::Label(lbl) = reloc.target {
+                if self.labels.get(lbl).copied().flatten().is_none() {
+                    unbound.entry(lbl).or_default().push(reloc.at);
+                }
+            }
+        }
+
+        for (label, references) in unbound {
+            self.diagnostics
+                .push(AsmDiagnostic::UnboundLabel { label, references });
+        }
+
+        if self.dseg.placement() == Placement::Island && self.island.is_none() {
+            self.place_island();
+        }
+    }
+
+    /// Moves the data segment behind the code. Its layout stays the same, so every
+    /// reference moves by the distance between the old and the new start.
+    fn place_island(&mut self) {
+        let offset = align(self.data.len() as i32, self.dseg.alignment());
+        let moved = offset + self.dseg.size();
 
-            let mut slice = &mut self.data[jmp.at..];
-            slice.write_u32::<LittleEndian>(diff as u32).unwrap();
+        for &at in &self.dseg_refs {
+            let disp = LittleEndian::read_i32(&self.data[at..]);
+            LittleEndian::write_i32(&mut self.data[at..], disp + moved);
+        }
+
+        self.island = Some(offset as usize);
+    }
+
+    /// Offset of the data segment from the start of the code: negative in front of
+    /// the code, the island offset behind it.
+    pub fn dseg_start(&self) -> i64 {
+        match self.island {
+            Some(offset) => offset as i64,
+            None => -(self.dseg.size() as i64),
+        }
+    }
+
+    /// Shrinks every long jump whose target is within rel8 range and moves labels,
+    /// label references and data segment references accordingly. Shrinking only
+    /// brings other jumps closer to their targets, so this iterates until no more
+    /// jumps can be shrunk.
+    fn relax(&mut self) {
+        // (position of the first removed byte, number of removed bytes), sorted
+        let mut removed: Vec<(usize, usize)> = Vec::new();
+        let mut shrunk = vec![false; self.jumps.len()];
+
+        let new_pos = |removed: &[(usize, usize)], pos: usize| -> usize {
+            pos - removed
+                .iter()
+                .take_while(|&&(at, _)| at < pos)
+                .map(|&(_, len)| len)
+                .sum::<usize>()
+        };
+
+        loop {
+            let mut changed = false;
+
+            for (i, jmp) in self.jumps.iter().enumerate() {
+                if shrunk[i] || (jmp.kind != JumpKind::Jmp && jmp.kind != JumpKind::Jcc) {
+                    continue;
+                }
+
+                let target = match self.labels[jmp.to] {
+                    Some(target) => target,
+                    None => continue,
+                };
+
+                let start = jmp.at - jmp.kind.opcode_len();
+                let saved = jmp.kind.opcode_len() + 4 - 2;
+                let mut new_target = new_pos(&removed, target);
+
+                if target > start {
+                    new_target -= saved;
+                }
+
+                let diff = new_target as isize - (new_pos(&removed, start) + 2) as isize;
+
+                if diff == diff as i8 as isize {
+                    let at = start + 2;
+                    let idx = removed
+                        .iter()
+                        .position(|&(pos, _)| pos > at)
+                        .unwrap_or(removed.len());
+                    removed.insert(idx, (at, saved));
+                    shrunk[i] = true;
+                    changed = true;
+                }
+            }
+
+            if !changed {
+                break;
+            }
+        }
+
+        if removed.is_empty() {
+            return;
+        }
+
+        let old_data = ::std::mem::take(&mut self.data);
+        self.prefixes = 0..0;
+
+        // copy the code, dropping the tail of every shrunk jump
+        let mut last = 0;
+        for &(at, len) in &removed {
+            self.data.extend_from_slice(&old_data[last..at]);
+            last = at + len;
+        }
+        self.data.extend_from_slice(&old_data[last..]);
+
+        for (i, jmp) in self.jumps.iter_mut().enumerate() {
+            if shrunk[i] {
+                let start = jmp.at - jmp.kind.opcode_len();
+                let new_start = new_pos(&removed, start);
+
+                self.data[new_start] = match jmp.kind {
+                    JumpKind::Jmp => 0xeb,
+                    JumpKind::Jcc => old_data[start + 1] - 0x10,
+                    _ => unreachable!(),
+                };
+
+                jmp.at = new_start + 1;
+                jmp.kind = JumpKind::Short;
+            } else {
+                jmp.at = new_pos(&removed, jmp.at);
+            }
+        }
+
+        self.move_positions(&|pos| new_pos(&removed, pos));
+    }
+
+    /// Moves everything that refers to positions in the code, except the jumps, after
+    /// the code was rewritten. `new_pos` maps old positions to new ones. References
+    /// to the data segment, which lies in front of the code, are adjusted by the
+    /// distance their instruction moved.
+    pub(crate) fn move_positions(&mut self, new_pos: &dyn Fn(usize) -> usize) {
+        for label in self.labels.iter_mut() {
+            if let Some(pos) = *label {
+                *label = Some(new_pos(pos));
+            }
+        }
+
+        for reloc in self.relocations.iter_mut() {
+            reloc.at = new_pos(reloc.at);
+        }
+
+        for (pos, _) in self.comments.iter_mut() {
+            *pos = new_pos(*pos);
+        }
+
+        for (pos, _) in self.cfi_ops.iter_mut() {
+            *pos = new_pos(*pos);
+        }
+
+        for (pos, _) in self.source_positions.iter_mut() {
+            *pos = new_pos(*pos);
+        }
+
+        for diagnostic in self.diagnostics.iter_mut() {
+            match diagnostic {
+                AsmDiagnostic::DoubleBoundLabel { first, second, .. } => {
+                    *first = new_pos(*first);
+                    *second = new_pos(*second);
+                }
+                AsmDiagnostic::DisplacementOutOfRange { at, .. }
+                | AsmDiagnostic::InvalidOperand { at, .. }
+                | AsmDiagnostic::MissingCpuFeature { at, .. } => *at = new_pos(*at),
+                AsmDiagnostic::UnboundLabel { .. } | AsmDiagnostic::InvalidConstant { .. } => {}
+            }
+        }
+
+        for at in self.dseg_refs.iter_mut() {
+            let end = *at + 4;
+            let new_at = new_pos(*at);
+            let moved = end as i64 - new_pos(end) as i64;
+            let disp = LittleEndian::read_i32(&self.data[new_at..]);
+
+            LittleEndian::write_i32(&mut self.data[new_at..], disp + moved as i32);
+            *at = new_at;
         }
     }
+
+    /// Current position in the code. Resolving the jumps moves the code behind every
+    /// shrunk jump, positions only stay valid if `relax_jumps` is turned off.
     #[no_mangle]
     pub extern "C" fn pos(&self) -> usize {
         self.data.len()
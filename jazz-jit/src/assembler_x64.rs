@@ -1,8 +1,10 @@
 use crate::assembler::Mem;
-use crate::assembler::{Assembler, Label};
+use crate::assembler::{Assembler, JumpKind, Label, RipTarget, Segment, SegmentAddr};
 use crate::assembler_x64 as buf;
 use crate::constants_x64::*;
+use crate::cpu::CpuFeature;
 use crate::dseg::f32x4;
+use crate::reloc::{RelocKind, RelocTarget};
 use crate::CondCode;
 use crate::MachineMode;
 #[no_mangle]
@@ -19,37 +21,41 @@ pub fn new_f32x4(v1: f32, v2: f32, v3: f32, v4: f32) -> f32x4 {
 impl Assembler {
     pub fn load_int_const(&mut self, mode: MachineMode, dest: Register, imm: i64) {
         match mode {
-            MachineMode::Int8 | MachineMode::Int32 => {
+            MachineMode::Int8 | MachineMode::Int16 | MachineMode::Int32 => {
                 buf::emit_movl_imm_reg(self, imm as i32, dest)
             }
             MachineMode::Int64 | MachineMode::Ptr => {
                 buf::emit_movq_imm64_reg(self, imm, dest);
             }
-            MachineMode::Float32 | MachineMode::Float64 => unreachable!(),
+            MachineMode::Float32 | MachineMode::Float64 => self.invalid_mode(mode),
         }
     }
 
+    /// Loads the absolute address of `target`, which is filled in when the code is
+    /// emitted.
+    pub fn load_addr(&mut self, dest: Register, target: RelocTarget) {
+        buf::emit_movq_imm64_reg(self, 0, dest);
+        self.record_reloc(RelocKind::Abs64, target);
+    }
+
     pub fn load_float4_const(&mut self, dest: XMMRegister, val: f32x4) {
-        let pos = self.pos() as i32;
         let off = self.dseg.add_f32x4(val);
-        movups_load(self, dest, Mem::Base(RIP, -(off + pos + 8)));
+        movups_load(self, dest, Mem::RipRelative(RipTarget::DSeg(off)));
     }
 
     pub fn load_float_const(&mut self, mode: MachineMode, dest: XMMRegister, imm: f64) {
-        let pos = self.pos() as i32;
-
         match mode {
             MachineMode::Float32 => {
                 let off = self.dseg.add_float(imm as f32);
-                buf::movss_load(self, dest, Mem::Base(RIP, -(off + pos + 8)));
+                buf::movss_load(self, dest, Mem::RipRelative(RipTarget::DSeg(off)));
             }
 
             MachineMode::Float64 => {
                 let off = self.dseg.add_double(imm);
-                buf::movsd_load(self, dest, Mem::Base(RIP, -(off + pos + 8)));
+                buf::movsd_load(self, dest, Mem::RipRelative(RipTarget::DSeg(off)));
             }
 
-            _ => unreachable!(),
+            _ => self.invalid_mode(mode),
         }
     }
 
@@ -65,7 +71,7 @@ impl Assembler {
         let x64 = match mode {
             MachineMode::Int32 => 0,
             MachineMode::Int64 => 1,
-            _ => unimplemented!(),
+            _ => self.invalid_mode(mode),
         };
 
         buf::emit_neg_reg(self, x64, src);
@@ -93,7 +99,7 @@ impl Assembler {
                 1
             }
 
-            _ => unimplemented!(),
+            _ => self.invalid_mode(mode),
         };
 
         if dest != src {
@@ -120,7 +126,7 @@ impl Assembler {
         match mode {
             MachineMode::Float32 => buf::addss(self, lhs, rhs),
             MachineMode::Float64 => buf::addsd(self, lhs, rhs),
-            _ => unimplemented!(),
+            _ => self.invalid_mode(mode),
         }
 
         if dest != lhs {
@@ -138,7 +144,7 @@ impl Assembler {
         match mode {
             MachineMode::Float32 => buf::subss(self, lhs, rhs),
             MachineMode::Float64 => buf::subsd(self, lhs, rhs),
-            _ => unimplemented!(),
+            _ => self.invalid_mode(mode),
         }
 
         if dest != lhs {
@@ -156,7 +162,7 @@ impl Assembler {
         match mode {
             MachineMode::Float32 => buf::mulss(self, lhs, rhs),
             MachineMode::Float64 => buf::mulsd(self, lhs, rhs),
-            _ => unimplemented!(),
+            _ => self.invalid_mode(mode),
         }
 
         if dest != lhs {
@@ -174,7 +180,7 @@ impl Assembler {
         match mode {
             MachineMode::Float32 => buf::divss(self, lhs, rhs),
             MachineMode::Float64 => buf::divsd(self, lhs, rhs),
-            _ => unimplemented!(),
+            _ => self.invalid_mode(mode),
         }
 
         if dest != lhs {
@@ -183,34 +189,19 @@ impl Assembler {
     }
 
     pub fn float_neg(&mut self, mode: MachineMode, dest: XMMRegister, src: XMMRegister) {
-        let (fst, snd) = if mode == MachineMode::Float32 {
-            (1i32 << 31, 0)
-        } else {
-            (0, 1i32 << 31)
-        };
-
-        // align MMX data to 16 bytes
-        self.dseg.align(16);
-        self.dseg.add_int(0);
-        self.dseg.add_int(0);
-        self.dseg.add_int(snd);
-        let disp = self.dseg.add_int(fst);
+        // sign bit of the lowest lane, 16 byte aligned for xorps/xorpd
+        let mut mask = [0u8; 16];
+        mask[mode.size() - 1] = 0x80;
 
-        let pos = self.pos() as i32;
-        let mem = Mem::Base(RIP, 0);
+        let disp = self.dseg.add_v128(mask);
+        let mem = Mem::RipRelative(RipTarget::DSeg(disp));
 
         match mode {
             MachineMode::Float32 => buf::xorps(self, src, mem),
             MachineMode::Float64 => buf::xorpd(self, src, mem),
-            _ => unimplemented!(),
+            _ => self.invalid_mode(mode),
         }
 
-        let after = self.pos() as i32;
-        let len = after - pos;
-
-        let offset = -(disp + pos + len);
-        self.emit_u32_at(after - 4, offset as u32);
-
         if dest != src {
             self.copy_freg(mode, dest, src);
         }
@@ -220,6 +211,7 @@ impl Assembler {
         match mem {
             Mem::Local(offset) => match mode {
                 MachineMode::Int8 => buf::emit_movzbl_memq_reg(self, RBP, offset, dest.reg()),
+                MachineMode::Int16 => buf::movzx_mem(self, mode, mem, dest.reg()),
                 MachineMode::Int32 => buf::emit_movl_memq_reg(self, RBP, offset, dest.reg()),
                 MachineMode::Int64 | MachineMode::Ptr => {
                     buf::emit_movq_memq_reg(self, RBP, offset, dest.reg())
@@ -230,6 +222,7 @@ impl Assembler {
 
             Mem::Base(base, disp) => match mode {
                 MachineMode::Int8 => buf::emit_movzbl_memq_reg(self, base, disp, dest.reg()),
+                MachineMode::Int16 => buf::movzx_mem(self, mode, mem, dest.reg()),
                 MachineMode::Int32 => buf::emit_movl_memq_reg(self, base, disp, dest.reg()),
                 MachineMode::Int64 | MachineMode::Ptr => {
                     buf::emit_movq_memq_reg(self, base, disp, dest.reg())
@@ -240,10 +233,14 @@ impl Assembler {
 
             Mem::Index(base, index, scale, disp) => match mode {
                 MachineMode::Int8 => {
-                    assert!(scale == 1);
+                    if scale != 1 {
+                        self.invalid_operand::<()>("byte loads only support a scale of 1");
+                    }
                     buf::emit_movzx_memindex_byte_reg(self, 0, base, index, disp, dest.reg())
                 }
 
+                MachineMode::Int16 => buf::movzx_mem(self, mode, mem, dest.reg()),
+
                 MachineMode::Int32 | MachineMode::Int64 | MachineMode::Ptr => {
                     buf::emit_mov_memindex_reg(self, mode, base, index, scale, disp, dest.reg())
                 }
@@ -252,7 +249,40 @@ impl Assembler {
                 MachineMode::Float64 => buf::movsd_load(self, dest.freg(), mem),
             },
 
-            Mem::Offset(_, _, _) => unimplemented!(),
+            Mem::RipRelative(_) | Mem::Absolute(_) | Mem::Segment(_, _) => {
+                let mem = self.near_mem(mem);
+
+                match mode {
+                    MachineMode::Int8 | MachineMode::Int16 => {
+                        buf::movzx_mem(self, mode, mem, dest.reg())
+                    }
+                    MachineMode::Int32 | MachineMode::Int64 | MachineMode::Ptr => {
+                        buf::mov_mem_reg(self, mode, mem, dest.reg())
+                    }
+                    MachineMode::Float32 => buf::movss_load(self, dest.freg(), mem),
+                    MachineMode::Float64 => buf::movsd_load(self, dest.freg(), mem),
+                }
+            }
+
+            Mem::Offset(_, _, _) => self.invalid_operand("load_mem does not support Mem::Offset"),
+        }
+    }
+
+    /// `mem`, with an absolute address beyond 32 bits loaded into R11 first.
+    /// Instructions other than movabs cannot encode such an address.
+    fn near_mem(&mut self, mem: Mem) -> Mem {
+        match mem {
+            Mem::Absolute(addr) if !fits_i32(addr as i64) => {
+                self.load_int_const(MachineMode::Ptr, R11, addr as i64);
+                Mem::Base(R11, 0)
+            }
+
+            Mem::Segment(segment, SegmentAddr::Absolute(addr)) if !fits_i32(addr as i64) => {
+                self.load_int_const(MachineMode::Ptr, R11, addr as i64);
+                Mem::Segment(segment, SegmentAddr::Base(R11, 0))
+            }
+
+            _ => mem,
         }
     }
 
@@ -260,15 +290,15 @@ impl Assembler {
         match mode {
             MachineMode::Float32 => buf::sqrtss(self, dest, src),
             MachineMode::Float64 => buf::sqrtsd(self, dest, src),
-            _ => unreachable!(),
+            _ => self.invalid_mode(mode),
         }
     }
 
     pub fn copy_reg(&mut self, mode: MachineMode, dest: Register, src: Register) {
         let x64 = match mode {
-            MachineMode::Int8 | MachineMode::Int32 => 0,
+            MachineMode::Int8 | MachineMode::Int16 | MachineMode::Int32 => 0,
             MachineMode::Int64 | MachineMode::Ptr => 1,
-            MachineMode::Float32 | MachineMode::Float64 => unreachable!(),
+            MachineMode::Float32 | MachineMode::Float64 => self.invalid_mode(mode),
         };
 
         buf::emit_mov_reg_reg(self, x64, src, dest);
@@ -300,6 +330,7 @@ impl Assembler {
         match mem {
             Mem::Local(offset) => match mode {
                 MachineMode::Int8 => buf::emit_movb_reg_memq(self, src.reg(), RBP, offset),
+                MachineMode::Int16 => buf::mov_reg_mem(self, mode, src.reg(), mem),
                 MachineMode::Int32 => buf::emit_movl_reg_memq(self, src.reg(), RBP, offset),
                 MachineMode::Int64 | MachineMode::Ptr => {
                     buf::emit_movq_reg_memq(self, src.reg(), RBP, offset)
@@ -310,6 +341,7 @@ impl Assembler {
 
             Mem::Base(base, disp) => match mode {
                 MachineMode::Int8 => buf::emit_movb_reg_memq(self, src.reg(), base, disp),
+                MachineMode::Int16 => buf::mov_reg_mem(self, mode, src.reg(), mem),
                 MachineMode::Int32 => buf::emit_movl_reg_memq(self, src.reg(), base, disp),
                 MachineMode::Int64 | MachineMode::Ptr => {
                     buf::emit_movq_reg_memq(self, src.reg(), base, disp)
@@ -319,7 +351,11 @@ impl Assembler {
             },
 
             Mem::Index(base, index, scale, disp) => match mode {
-                MachineMode::Int8 | MachineMode::Int32 | MachineMode::Int64 | MachineMode::Ptr => {
+                MachineMode::Int8
+                | MachineMode::Int16
+                | MachineMode::Int32
+                | MachineMode::Int64
+                | MachineMode::Ptr => {
                     buf::emit_mov_reg_memindex(self, mode, src.reg(), base, index, scale, disp)
                 }
 
@@ -327,7 +363,17 @@ impl Assembler {
                 MachineMode::Float64 => buf::movsd_store(self, mem, src.freg()),
             },
 
-            Mem::Offset(_, _, _) => unimplemented!(),
+            Mem::RipRelative(_) | Mem::Absolute(_) | Mem::Segment(_, _) => {
+                let mem = self.near_mem(mem);
+
+                match mode {
+                    MachineMode::Float32 => buf::movss_store(self, mem, src.freg()),
+                    MachineMode::Float64 => buf::movsd_store(self, mem, src.freg()),
+                    _ => buf::mov_reg_mem(self, mode, src.reg(), mem),
+                }
+            }
+
+            Mem::Offset(_, _, _) => self.invalid_operand("store_mem does not support Mem::Offset"),
         }
     }
 
@@ -343,7 +389,7 @@ impl Assembler {
         match mode {
             MachineMode::Float32 => buf::movss(self, dest, src),
             MachineMode::Float64 => buf::movsd(self, dest, src),
-            _ => unreachable!(),
+            _ => self.invalid_mode(mode),
         }
     }
 
@@ -355,7 +401,7 @@ impl Assembler {
         let x64 = match mode {
             MachineMode::Int32 => 0,
             MachineMode::Int64 => 1,
-            _ => unimplemented!(),
+            _ => self.invalid_mode(mode),
         };
 
         buf::emit_movzx_byte(self, x64, src, dest);
@@ -373,27 +419,30 @@ impl Assembler {
             Mem::Index(base, index, scale, disp) => {
                 buf::emit_cmp_memindex_reg(self, mode, base, index, scale, disp, rhs)
             }
-            Mem::Offset(_, _, _) => unimplemented!(),
+            Mem::RipRelative(_) | Mem::Absolute(_) | Mem::Segment(_, _) => {
+                let mem = self.near_mem(mem);
+                buf::alu_reg_mem(self, mode, AluOp::Cmp, rhs, mem)
+            }
+            Mem::Offset(_, _, _) => self.invalid_operand("cmp_mem does not support Mem::Offset"),
         }
     }
 
     pub fn cmp_mem_imm(&mut self, mode: MachineMode, mem: Mem, imm: i32) {
         match mem {
-            Mem::Local(_) => unimplemented!(),
+            Mem::Local(offset) => buf::emit_cmp_mem_imm(self, mode, RBP, offset, imm),
             Mem::Base(base, disp) => buf::emit_cmp_mem_imm(self, mode, base, disp, imm),
-            Mem::Index(_, _, _, _) => unimplemented!(),
-            Mem::Offset(_, _, _) => unimplemented!(),
+            Mem::RipRelative(_) | Mem::Absolute(_) | Mem::Segment(_, _) => {
+                let mem = self.near_mem(mem);
+                buf::alu_imm_mem(self, mode, AluOp::Cmp, imm, mem)
+            }
+            Mem::Index(_, _, _, _) | Mem::Offset(_, _, _) => {
+                self.invalid_operand("cmp_mem_imm does not support Mem::Index and Mem::Offset")
+            }
         }
     }
 
     pub fn cmp_reg(&mut self, mode: MachineMode, lhs: Register, rhs: Register) {
-        let x64 = match mode {
-            MachineMode::Int8 | MachineMode::Int32 => 0,
-            MachineMode::Int64 | MachineMode::Ptr => 1,
-            MachineMode::Float32 | MachineMode::Float64 => unreachable!(),
-        };
-
-        buf::emit_cmp_reg_reg(self, x64, rhs, lhs);
+        buf::alu_reg_reg(self, mode, AluOp::Cmp, rhs, lhs);
     }
 
     pub fn cmp_reg_imm(&mut self, mode: MachineMode, lhs: Register, imm: i32) {
@@ -420,7 +469,7 @@ impl Assembler {
                 match mode {
                     MachineMode::Float32 => buf::ucomiss(self, lhs, rhs),
                     MachineMode::Float64 => buf::ucomisd(self, lhs, rhs),
-                    _ => unreachable!(),
+                    _ => self.invalid_mode(mode),
                 }
 
                 let parity = if cond == CondCode::Equal { false } else { true };
@@ -435,7 +484,7 @@ impl Assembler {
                 match mode {
                     MachineMode::Float32 => buf::ucomiss(self, lhs, rhs),
                     MachineMode::Float64 => buf::ucomisd(self, lhs, rhs),
-                    _ => unreachable!(),
+                    _ => self.invalid_mode(mode),
                 }
 
                 let cond = match cond {
@@ -453,7 +502,7 @@ impl Assembler {
                 match mode {
                     MachineMode::Float32 => buf::ucomiss(self, rhs, lhs),
                     MachineMode::Float64 => buf::ucomisd(self, rhs, lhs),
-                    _ => unreachable!(),
+                    _ => self.invalid_mode(mode),
                 }
 
                 let cond = match cond {
@@ -465,7 +514,7 @@ impl Assembler {
                 buf::emit_setb_reg(self, cond, dest);
             }
 
-            _ => unreachable!(),
+            _ => self.invalid_operand(format!("float_cmp does not support {:?}", cond)),
         }
     }
 
@@ -475,7 +524,7 @@ impl Assembler {
         match mode {
             MachineMode::Float32 => buf::ucomiss(self, src, src),
             MachineMode::Float64 => buf::ucomisd(self, src, src),
-            _ => unreachable!(),
+            _ => self.invalid_mode(mode),
         }
 
         buf::emit_setb_reg_parity(self, dest, true);
@@ -486,7 +535,9 @@ impl Assembler {
     }
 
     pub fn test_and_jump_if(&mut self, cond: CondCode, reg: Register, lbl: Label) {
-        assert!(cond == CondCode::Zero || cond == CondCode::NonZero);
+        if cond != CondCode::Zero && cond != CondCode::NonZero {
+            self.invalid_operand::<()>(format!("test_and_jump_if does not support {:?}", cond));
+        }
 
         buf::emit_testl_reg_reg(self, reg, reg);
         self.jump_if(cond, lbl);
@@ -504,6 +555,20 @@ impl Assembler {
         buf::emit_jmp_reg(self, reg);
     }
 
+    /// Calls `target` with a rel32 displacement, so the target has to end up within
+    /// 2GB of the code.
+    pub fn call_rel(&mut self, target: RelocTarget) {
+        buf::emit_op(self, 0xe8);
+        buf::emit32(self, 0);
+        self.record_reloc(RelocKind::Rel32, target);
+    }
+
+    /// Calls `target` through R11, which reaches any address.
+    pub fn call_abs(&mut self, target: RelocTarget) {
+        self.load_addr(R11, target);
+        buf::emit_callq_reg(self, R11);
+    }
+
     pub fn int_div(&mut self, mode: MachineMode, dest: Register, lhs: Register, rhs: Register) {
         self.div_common(mode, dest, lhs, rhs, RAX);
     }
@@ -523,11 +588,13 @@ impl Assembler {
         let x64 = match mode {
             MachineMode::Int32 => 0,
             MachineMode::Int64 => 1,
-            _ => unimplemented!(),
+            _ => self.invalid_mode(mode),
         };
 
         if lhs != RAX {
-            assert!(rhs != RAX);
+            if rhs == RAX {
+                self.invalid_operand::<()>("divisor must not be RAX when the dividend is not");
+            }
             buf::emit_mov_reg_reg(self, x64, lhs, RAX);
         }
 
@@ -548,7 +615,7 @@ impl Assembler {
         let x64 = match mode {
             MachineMode::Int32 => 0,
             MachineMode::Int64 => 1,
-            _ => unimplemented!(),
+            _ => self.invalid_mode(mode),
         };
 
         buf::emit_imul_reg_reg(self, x64, rhs, lhs);
@@ -559,22 +626,14 @@ impl Assembler {
     }
 
     pub fn int_add(&mut self, mode: MachineMode, dest: Register, lhs: Register, rhs: Register) {
-        let x64 = match mode {
-            MachineMode::Int32 => 0,
-            MachineMode::Int64 | MachineMode::Ptr => 1,
-            _ => unimplemented!(),
-        };
-
-        buf::emit_add_reg_reg(self, x64, rhs, lhs);
-
-        if dest != lhs {
-            buf::emit_mov_reg_reg(self, x64, lhs, dest);
-        }
+        self.int_alu(mode, AluOp::Add, dest, lhs, rhs);
     }
 
     pub fn int_add_imm(&mut self, mode: MachineMode, dest: Register, lhs: Register, value: i64) {
         if !fits_i32(value) {
-            assert!(mode == MachineMode::Int64 || mode == MachineMode::Ptr);
+            if mode != MachineMode::Int64 && mode != MachineMode::Ptr {
+                self.invalid_operand::<()>("immediate does not fit into 32 bits");
+            }
             let reg_size = R11;
             self.load_int_const(MachineMode::Ptr, reg_size, value);
             self.int_add(mode, dest, lhs, reg_size);
@@ -583,7 +642,7 @@ impl Assembler {
 
         let x64 = match mode {
             MachineMode::Int64 | MachineMode::Ptr => 1,
-            _ => unimplemented!(),
+            _ => self.invalid_mode(mode),
         };
 
         buf::emit_addq_imm_reg(self, value as i32, lhs);
@@ -594,134 +653,118 @@ impl Assembler {
     }
 
     pub fn int_sub(&mut self, mode: MachineMode, dest: Register, lhs: Register, rhs: Register) {
-        let x64 = match mode {
-            MachineMode::Int32 => 0,
-            MachineMode::Int64 => 1,
-            _ => unimplemented!(),
-        };
+        self.int_alu(mode, AluOp::Sub, dest, lhs, rhs);
+    }
 
-        buf::emit_sub_reg_reg(self, x64, rhs, lhs);
+    /// `dest = lhs op rhs` on integers of size `mode`.
+    fn int_alu(
+        &mut self,
+        mode: MachineMode,
+        op: AluOp,
+        dest: Register,
+        lhs: Register,
+        rhs: Register,
+    ) {
+        buf::alu_reg_reg(self, mode, op, rhs, lhs);
 
         if dest != lhs {
-            buf::emit_mov_reg_reg(self, x64, lhs, dest);
+            self.copy_reg(mode, dest, lhs);
         }
     }
 
-    pub fn int_shl(&mut self, mode: MachineMode, dest: Register, lhs: Register, rhs: Register) {
-        let x64 = match mode {
-            MachineMode::Int32 => 0,
-            MachineMode::Int64 => 1,
-            _ => unimplemented!(),
-        };
-
+    /// Shifts `lhs` of size `mode` by `rhs` into `dest`. The count has to be in CL.
+    fn int_shift(
+        &mut self,
+        mode: MachineMode,
+        op: ShiftOp,
+        dest: Register,
+        lhs: Register,
+        rhs: Register,
+    ) {
         if rhs != RCX {
-            assert!(lhs != RCX);
-            buf::emit_mov_reg_reg(self, x64, rhs, RCX);
+            if lhs == RCX {
+                self.invalid_operand::<()>("shifted register must not be RCX");
+            }
+            buf::emit_mov_reg_reg(self, 0, rhs, RCX);
         }
 
-        buf::emit_shl_reg_cl(self, x64, lhs);
+        buf::shift_reg_cl(self, mode, op, lhs);
 
         if dest != lhs {
-            buf::emit_mov_reg_reg(self, x64, lhs, dest);
+            self.copy_reg(mode, dest, lhs);
         }
     }
 
-    pub fn int_shr(&mut self, mode: MachineMode, dest: Register, lhs: Register, rhs: Register) {
-        let x64 = match mode {
-            MachineMode::Int32 => 0,
-            MachineMode::Int64 => 1,
-            _ => unimplemented!(),
-        };
-
-        if rhs != RCX {
-            assert!(lhs != RCX);
-            buf::emit_mov_reg_reg(self, x64, rhs, RCX);
-        }
-
-        buf::emit_shr_reg_cl(self, x64, lhs);
+    pub fn int_shl(&mut self, mode: MachineMode, dest: Register, lhs: Register, rhs: Register) {
+        self.int_shift(mode, ShiftOp::Shl, dest, lhs, rhs);
+    }
 
-        if dest != lhs {
-            buf::emit_mov_reg_reg(self, x64, lhs, dest);
-        }
+    pub fn int_shr(&mut self, mode: MachineMode, dest: Register, lhs: Register, rhs: Register) {
+        self.int_shift(mode, ShiftOp::Shr, dest, lhs, rhs);
     }
 
     pub fn int_sar(&mut self, mode: MachineMode, dest: Register, lhs: Register, rhs: Register) {
-        let x64 = match mode {
-            MachineMode::Int32 => 0,
-            MachineMode::Int64 => 1,
-            _ => unimplemented!(),
-        };
-
-        if rhs != RCX {
-            assert!(lhs != RCX);
-            buf::emit_mov_reg_reg(self, x64, rhs, RCX);
-        }
-
-        buf::emit_sar_reg_cl(self, x64, lhs);
-
-        if dest != lhs {
-            buf::emit_mov_reg_reg(self, x64, lhs, dest);
-        }
+        self.int_shift(mode, ShiftOp::Sar, dest, lhs, rhs);
     }
     pub fn int_sal(&mut self, mode: MachineMode, dest: Register, lhs: Register, rhs: Register) {
-        let x64 = match mode {
-            MachineMode::Int32 => 0,
-            MachineMode::Int64 => 1,
-            _ => unimplemented!(),
-        };
-
-        if rhs != RCX {
-            assert!(lhs != RCX);
-            buf::emit_mov_reg_reg(self, x64, rhs, RCX);
-        }
-
-        buf::emit_sal_reg_cl(self, x64, lhs);
-
-        if dest != lhs {
-            buf::emit_mov_reg_reg(self, x64, lhs, dest);
-        }
+        self.int_shift(mode, ShiftOp::Shl, dest, lhs, rhs);
     }
 
     pub fn int_or(&mut self, mode: MachineMode, dest: Register, lhs: Register, rhs: Register) {
-        let x64 = match mode {
-            MachineMode::Int32 => 0,
-            MachineMode::Int64 => 1,
-            _ => unimplemented!(),
-        };
+        self.int_alu(mode, AluOp::Or, dest, lhs, rhs);
+    }
 
-        buf::emit_or_reg_reg(self, x64, rhs, lhs);
+    pub fn int_and(&mut self, mode: MachineMode, dest: Register, lhs: Register, rhs: Register) {
+        self.int_alu(mode, AluOp::And, dest, lhs, rhs);
+    }
 
-        if dest != lhs {
-            buf::emit_mov_reg_reg(self, x64, lhs, dest);
-        }
+    pub fn int_xor(&mut self, mode: MachineMode, dest: Register, lhs: Register, rhs: Register) {
+        self.int_alu(mode, AluOp::Xor, dest, lhs, rhs);
     }
 
-    pub fn int_and(&mut self, mode: MachineMode, dest: Register, lhs: Register, rhs: Register) {
-        let x64 = match mode {
-            MachineMode::Int32 => 0,
-            MachineMode::Int64 => 1,
-            _ => unimplemented!(),
+    /// Number of leading zero bits of `src`, the width of `mode` if it is zero. Uses
+    /// LZCNT if the target has it and BSR otherwise.
+    pub fn int_clz(&mut self, mode: MachineMode, dest: Register, src: Register) {
+        let (x64, bits) = match mode {
+            MachineMode::Int32 => (0, 32),
+            MachineMode::Int64 => (1, 64),
+            _ => self.invalid_mode(mode),
         };
 
-        buf::emit_and_reg_reg(self, x64, rhs, lhs);
-
-        if dest != lhs {
-            buf::emit_mov_reg_reg(self, x64, lhs, dest);
+        if self.has_feature(CpuFeature::Lzcnt) {
+            buf::lzcnt(self, x64, src, dest);
+            return;
         }
+
+        // BSR gives the index of the highest set bit, which is `bits - 1 - clz`.
+        // A zero source becomes `2 * bits - 1` so that the xor gives `bits`.
+        let nonzero = self.create_label();
+        buf::bsr(self, x64, src, dest);
+        self.jump_if(CondCode::NonZero, nonzero);
+        buf::emit_movl_imm_reg(self, 2 * bits - 1, dest);
+        self.bind_label(nonzero);
+        buf::emit_xorb_imm_reg(self, bits as u8 - 1, dest);
     }
 
-    pub fn int_xor(&mut self, mode: MachineMode, dest: Register, lhs: Register, rhs: Register) {
-        let x64 = match mode {
-            MachineMode::Int32 => 0,
-            MachineMode::Int64 => 1,
-            _ => unimplemented!(),
+    /// Number of trailing zero bits of `src`, the width of `mode` if it is zero. Uses
+    /// TZCNT if the target has it and BSF otherwise.
+    pub fn int_ctz(&mut self, mode: MachineMode, dest: Register, src: Register) {
+        let (x64, bits) = match mode {
+            MachineMode::Int32 => (0, 32),
+            MachineMode::Int64 => (1, 64),
+            _ => self.invalid_mode(mode),
         };
 
-        buf::emit_xor_reg_reg(self, x64, rhs, lhs);
-
-        if dest != lhs {
-            buf::emit_mov_reg_reg(self, x64, lhs, dest);
+        if self.has_feature(CpuFeature::Bmi1) {
+            buf::tzcnt(self, x64, src, dest);
+            return;
         }
+
+        let nonzero = self.create_label();
+        buf::bsf(self, x64, src, dest);
+        self.jump_if(CondCode::NonZero, nonzero);
+        buf::emit_movl_imm_reg(self, bits, dest);
+        self.bind_label(nonzero);
     }
 
     pub fn int_to_float(
@@ -736,13 +779,13 @@ impl Assembler {
         let x64 = match src_mode {
             MachineMode::Int32 => 0,
             MachineMode::Int64 => 1,
-            _ => unreachable!(),
+            _ => self.invalid_mode(src_mode),
         };
 
         match dest_mode {
             MachineMode::Float32 => buf::cvtsi2ss(self, dest, x64, src),
             MachineMode::Float64 => buf::cvtsi2sd(self, dest, x64, src),
-            _ => unreachable!(),
+            _ => self.invalid_mode(dest_mode),
         }
     }
 
@@ -756,13 +799,13 @@ impl Assembler {
         let x64 = match dest_mode {
             MachineMode::Int32 => 0,
             MachineMode::Int64 => 1,
-            _ => unreachable!(),
+            _ => self.invalid_mode(dest_mode),
         };
 
         match src_mode {
             MachineMode::Float32 => buf::cvttss2si(self, x64, dest, src),
             MachineMode::Float64 => buf::cvttsd2si(self, x64, dest, src),
-            _ => unreachable!(),
+            _ => self.invalid_mode(src_mode),
         }
     }
 
@@ -773,6 +816,34 @@ impl Assembler {
     pub fn double_to_float(&mut self, dest: XMMRegister, src: XMMRegister) {
         buf::cvtsd2ss(self, dest, src);
     }
+
+    /// Atomically stores `src` into `mem` if `mem` equals RAX, otherwise loads
+    /// `mem` into RAX. Sets ZF if the store happened.
+    pub fn atomic_cmpxchg(&mut self, mode: MachineMode, mem: Mem, src: Register) {
+        buf::lock(self);
+        buf::cmpxchg_mem(self, mode, src, mem);
+    }
+
+    /// Atomically adds `src` to `mem` and loads the previous value into `src`.
+    pub fn atomic_fetch_add(&mut self, mode: MachineMode, mem: Mem, src: Register) {
+        buf::lock(self);
+        buf::xadd_mem(self, mode, src, mem);
+    }
+
+    /// Atomically stores `src` into `mem` and loads the previous value into `src`.
+    pub fn atomic_swap(&mut self, mode: MachineMode, mem: Mem, src: Register) {
+        buf::xchg_mem(self, mode, src, mem);
+    }
+
+    /// Keeps loads and stores from being reordered across the fence.
+    pub fn fence(&mut self) {
+        buf::mfence(self);
+    }
+
+    /// Hint for the body of a spin loop waiting for another thread.
+    pub fn spin_hint(&mut self) {
+        buf::pause(self);
+    }
 }
 #[no_mangle]
 pub fn emit_or_reg_reg(buf: &mut Assembler, x64: u8, src: Register, dest: Register) {
@@ -787,6 +858,494 @@ pub fn xchg(buf: &mut Assembler, x64: u8, src: Register, dest: Register) {
     emit_alu_reg_reg(buf, x64, 0x87, src, dest);
 }
 
+/// Emits the LOCK prefix, which makes the following read-modify-write instruction
+/// with a memory destination atomic. `xchg` with memory is always atomic.
+#[no_mangle]
+pub fn lock(buf: &mut Assembler) {
+    emit_legacy_prefix(buf, 0xF0);
+}
+
+/// Emits an operand size, LOCK, REP or mandatory prefix. A segment override of the
+/// memory operand of the instruction is put in front of these.
+pub(crate) fn emit_legacy_prefix(buf: &mut Assembler, prefix: u8) {
+    let pos = buf.pos();
+
+    if buf.prefixes.end != pos {
+        buf.prefixes = pos..pos;
+    }
+
+    emit_op(buf, prefix);
+    buf.prefixes.end = buf.pos();
+}
+
+/// Operand size and opcode of an integer instruction whose byte form is `byte_op`
+/// and whose other forms are `byte_op + 1`. 16-bit forms are the 32-bit ones after
+/// an operand size prefix, which has to precede REX and is emitted here.
+fn int_op(buf: &mut Assembler, mode: MachineMode, byte_op: u8) -> (u8, u8) {
+    match mode {
+        MachineMode::Int8 => (0, byte_op),
+        MachineMode::Int16 => {
+            emit_legacy_prefix(buf, 0x66);
+            (0, byte_op + 1)
+        }
+        MachineMode::Int32 => (0, byte_op + 1),
+        MachineMode::Int64 | MachineMode::Ptr => (1, byte_op + 1),
+        MachineMode::Float32 | MachineMode::Float64 => buf.invalid_mode(mode),
+    }
+}
+
+/// `op` with `src` in ModRM.reg and `dest` in ModRM.rm, both of size `mode`. Two
+/// byte opcodes are passed with their 0x0F escape in `escape`.
+fn emit_mode_reg_reg(
+    buf: &mut Assembler,
+    mode: MachineMode,
+    escape: bool,
+    byte_op: u8,
+    src: Register,
+    dest: Register,
+) {
+    let (x64, opcode) = int_op(buf, mode, byte_op);
+    let byte_reg = mode == MachineMode::Int8 && (!src.is_basic_reg() || !dest.is_basic_reg());
+
+    if x64 != 0 || src.msb() != 0 || dest.msb() != 0 || byte_reg {
+        emit_rex(buf, x64, src.msb(), 0, dest.msb());
+    }
+
+    if escape {
+        emit_op(buf, 0x0F);
+    }
+
+    emit_op(buf, opcode);
+    emit_modrm(buf, 0b11, src.and7(), dest.and7());
+}
+
+/// Like `emit_mode_reg_reg`, with a memory operand in ModRM.rm.
+fn emit_mode_reg_mem(
+    buf: &mut Assembler,
+    mode: MachineMode,
+    escape: bool,
+    byte_op: u8,
+    src: Register,
+    dest: Mem,
+) {
+    emit_segment_prefix(buf, &dest);
+    let (x64, opcode) = int_op(buf, mode, byte_op);
+    let (base_msb, index_msb) = emit_mem_prefix(buf, &dest);
+    let byte_reg = mode == MachineMode::Int8 && !src.is_basic_reg();
+
+    if x64 != 0 || src.msb() != 0 || index_msb != 0 || base_msb != 0 || byte_reg {
+        emit_rex(buf, x64, src.msb(), index_msb, base_msb);
+    }
+
+    if escape {
+        emit_op(buf, 0x0F);
+    }
+
+    emit_op(buf, opcode);
+    emit_mem(buf, src, &dest);
+}
+
+/// `op` with the opcode extension `ext` in ModRM.reg and `dest`, of size `mode`, in
+/// ModRM.rm. `ext` is passed as the register with that number.
+fn emit_mode_ext_reg(
+    buf: &mut Assembler,
+    mode: MachineMode,
+    byte_op: u8,
+    ext: Register,
+    dest: Register,
+) {
+    let (x64, opcode) = int_op(buf, mode, byte_op);
+    let byte_reg = mode == MachineMode::Int8 && !dest.is_basic_reg();
+
+    if x64 != 0 || dest.msb() != 0 || byte_reg {
+        emit_rex(buf, x64, 0, 0, dest.msb());
+    }
+
+    emit_op(buf, opcode);
+    emit_modrm(buf, 0b11, ext.and7(), dest.and7());
+}
+
+/// Like `emit_mode_ext_reg`, with a memory operand in ModRM.rm.
+fn emit_mode_ext_mem(
+    buf: &mut Assembler,
+    mode: MachineMode,
+    byte_op: u8,
+    ext: Register,
+    dest: Mem,
+) {
+    emit_segment_prefix(buf, &dest);
+    let (x64, opcode) = int_op(buf, mode, byte_op);
+    emit_rex_mem(buf, x64, ext, &dest);
+    emit_op(buf, opcode);
+    emit_mem(buf, ext, &dest);
+}
+
+/// Size of the immediate `emit_mode_imm` emits for `mode`.
+fn mode_imm_len(mode: MachineMode) -> usize {
+    match mode {
+        MachineMode::Int8 => 1,
+        MachineMode::Int16 => 2,
+        _ => 4,
+    }
+}
+
+/// Emits `imm` as an immediate of size `mode`. 64-bit instructions take a 32-bit
+/// immediate and sign-extend it.
+fn emit_mode_imm(buf: &mut Assembler, mode: MachineMode, imm: i32) {
+    match mode {
+        MachineMode::Int8 => {
+            if !(-0x80..=0xFF).contains(&imm) {
+                buf.invalid_operand::<()>("immediate does not fit into 8 bits");
+            }
+            emit(buf, imm as u8);
+        }
+
+        MachineMode::Int16 => {
+            if !(-0x8000..=0xFFFF).contains(&imm) {
+                buf.invalid_operand::<()>("immediate does not fit into 16 bits");
+            }
+            buf.emit16(imm as u16);
+        }
+
+        _ => emit32(buf, imm as u32),
+    }
+}
+
+/// The arithmetic and logic instructions that share the opcode layout of ADD: a
+/// row of six opcodes starting at `byte_op`, and the group 1 immediate forms
+/// (0x80, 0x81 and 0x83) with the operation in ModRM.reg.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum AluOp {
+    Add,
+    Or,
+    And,
+    Sub,
+    Xor,
+    Cmp,
+}
+
+impl AluOp {
+    /// Opcode of the byte form with a register source and ModRM.rm destination.
+    fn byte_op(self) -> u8 {
+        match self {
+            AluOp::Add => 0x00,
+            AluOp::Or => 0x08,
+            AluOp::And => 0x20,
+            AluOp::Sub => 0x28,
+            AluOp::Xor => 0x30,
+            AluOp::Cmp => 0x38,
+        }
+    }
+
+    /// The opcode extension of the immediate forms.
+    fn ext(self) -> Register {
+        match self {
+            AluOp::Add => RAX,
+            AluOp::Or => RCX,
+            AluOp::And => RSP,
+            AluOp::Sub => RBP,
+            AluOp::Xor => RSI,
+            AluOp::Cmp => RDI,
+        }
+    }
+}
+
+/// `dest = dest op src` on operands of size `mode`. `Cmp` only sets the flags.
+#[no_mangle]
+pub fn alu_reg_reg(
+    buf: &mut Assembler,
+    mode: MachineMode,
+    op: AluOp,
+    src: Register,
+    dest: Register,
+) {
+    emit_mode_reg_reg(buf, mode, false, op.byte_op(), src, dest);
+}
+
+#[no_mangle]
+pub fn alu_reg_mem(buf: &mut Assembler, mode: MachineMode, op: AluOp, src: Register, dest: Mem) {
+    emit_mode_reg_mem(buf, mode, false, op.byte_op(), src, dest);
+}
+
+#[no_mangle]
+pub fn alu_mem_reg(buf: &mut Assembler, mode: MachineMode, op: AluOp, src: Mem, dest: Register) {
+    emit_mode_reg_mem(buf, mode, false, op.byte_op() + 2, dest, src);
+}
+
+/// `dest = dest op imm`. Byte and 16-bit immediates may be given signed or
+/// unsigned, wider operands sign-extend the 32-bit `imm`.
+#[no_mangle]
+pub fn alu_imm_reg(buf: &mut Assembler, mode: MachineMode, op: AluOp, imm: i32, dest: Register) {
+    if mode != MachineMode::Int8 && fits_i8(imm) {
+        // 0x83 sign-extends an 8-bit immediate
+        emit_mode_ext_reg(buf, mode, 0x82, op.ext(), dest);
+        emit(buf, imm as u8);
+    } else if dest == RAX {
+        // the accumulator forms have no ModRM byte
+        let (x64, opcode) = int_op(buf, mode, op.byte_op() + 4);
+
+        if x64 != 0 {
+            emit_rex(buf, x64, 0, 0, 0);
+        }
+
+        emit_op(buf, opcode);
+        emit_mode_imm(buf, mode, imm);
+    } else {
+        emit_mode_ext_reg(buf, mode, 0x80, op.ext(), dest);
+        emit_mode_imm(buf, mode, imm);
+    }
+
+    if dest == RSP && (mode == MachineMode::Int64 || mode == MachineMode::Ptr) {
+        match op {
+            AluOp::Add => buf.cfi_adjust_sp(imm.wrapping_neg()),
+            AluOp::Sub => buf.cfi_adjust_sp(imm),
+            _ => {}
+        }
+    }
+}
+
+#[no_mangle]
+pub fn alu_imm_mem(buf: &mut Assembler, mode: MachineMode, op: AluOp, imm: i32, dest: Mem) {
+    if mode != MachineMode::Int8 && fits_i8(imm) {
+        emit_mode_ext_mem(buf, mode, 0x82, op.ext(), dest);
+        buf.imm_follows_mem(1);
+        emit(buf, imm as u8);
+    } else {
+        emit_mode_ext_mem(buf, mode, 0x80, op.ext(), dest);
+        buf.imm_follows_mem(mode_imm_len(mode));
+        emit_mode_imm(buf, mode, imm);
+    }
+}
+
+/// Copies `src` into `dest`. Byte and 16-bit moves leave the rest of `dest`
+/// unchanged, 32-bit ones clear its upper half.
+#[no_mangle]
+pub fn mov_reg_reg(buf: &mut Assembler, mode: MachineMode, src: Register, dest: Register) {
+    emit_mode_reg_reg(buf, mode, false, 0x88, src, dest);
+
+    if mode == MachineMode::Int64 || mode == MachineMode::Ptr {
+        buf.cfi_mov(src, dest);
+    }
+}
+
+#[no_mangle]
+pub fn mov_reg_mem(buf: &mut Assembler, mode: MachineMode, src: Register, dest: Mem) {
+    emit_mode_reg_mem(buf, mode, false, 0x88, src, dest);
+}
+
+#[no_mangle]
+pub fn mov_mem_reg(buf: &mut Assembler, mode: MachineMode, src: Mem, dest: Register) {
+    emit_mode_reg_mem(buf, mode, false, 0x8A, dest, src);
+}
+
+/// Loads `imm` into `dest`, sign-extended for 64-bit operands. `load_int_const`
+/// also handles 64-bit immediates.
+#[no_mangle]
+pub fn mov_imm_reg(buf: &mut Assembler, mode: MachineMode, imm: i32, dest: Register) {
+    emit_mode_ext_reg(buf, mode, 0xC6, RAX, dest);
+    emit_mode_imm(buf, mode, imm);
+}
+
+#[no_mangle]
+pub fn mov_imm_mem(buf: &mut Assembler, mode: MachineMode, imm: i32, dest: Mem) {
+    emit_mode_ext_mem(buf, mode, 0xC6, RAX, dest);
+    buf.imm_follows_mem(mode_imm_len(mode));
+    emit_mode_imm(buf, mode, imm);
+}
+
+/// Opcode of the MOVZX or MOVSX form reading a byte or 16-bit `mode` source.
+fn extend_op(buf: &mut Assembler, mode: MachineMode, byte_op: u8) -> u8 {
+    match mode {
+        MachineMode::Int8 => byte_op,
+        MachineMode::Int16 => byte_op + 1,
+        _ => buf.invalid_mode(mode),
+    }
+}
+
+fn emit_extend_reg(
+    buf: &mut Assembler,
+    x64: u8,
+    mode: MachineMode,
+    byte_op: u8,
+    src: Register,
+    dest: Register,
+) {
+    let opcode = extend_op(buf, mode, byte_op);
+    let byte_reg = mode == MachineMode::Int8 && !src.is_basic_reg();
+
+    if x64 != 0 || dest.msb() != 0 || src.msb() != 0 || byte_reg {
+        emit_rex(buf, x64, dest.msb(), 0, src.msb());
+    }
+
+    emit_op(buf, 0x0F);
+    emit_op(buf, opcode);
+    emit_modrm(buf, 0b11, dest.and7(), src.and7());
+}
+
+/// Zero-extends the byte or 16-bit `src` into the 32-bit `dest`, which clears the
+/// upper half of the 64-bit register as well.
+#[no_mangle]
+pub fn movzx(buf: &mut Assembler, mode: MachineMode, src: Register, dest: Register) {
+    emit_extend_reg(buf, 0, mode, 0xB6, src, dest);
+}
+
+#[no_mangle]
+pub fn movzx_mem(buf: &mut Assembler, mode: MachineMode, src: Mem, dest: Register) {
+    let opcode = extend_op(buf, mode, 0xB6);
+    emit_0f_reg_mem(buf, 0, opcode, dest, src);
+}
+
+/// Sign-extends the byte or 16-bit `src` into the 32-bit or, if `x64` is set,
+/// 64-bit `dest`.
+#[no_mangle]
+pub fn movsx(buf: &mut Assembler, x64: u8, mode: MachineMode, src: Register, dest: Register) {
+    emit_extend_reg(buf, x64, mode, 0xBE, src, dest);
+}
+
+#[no_mangle]
+pub fn movsx_mem(buf: &mut Assembler, x64: u8, mode: MachineMode, src: Mem, dest: Register) {
+    let opcode = extend_op(buf, mode, 0xBE);
+    emit_0f_reg_mem(buf, x64, opcode, dest, src);
+}
+
+/// The shifts and rotates, which share their opcodes and differ in ModRM.reg.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ShiftOp {
+    Rol,
+    Ror,
+    Shl,
+    Shr,
+    Sar,
+}
+
+impl ShiftOp {
+    fn ext(self) -> Register {
+        match self {
+            ShiftOp::Rol => RAX,
+            ShiftOp::Ror => RCX,
+            ShiftOp::Shl => RSP,
+            ShiftOp::Shr => RBP,
+            ShiftOp::Sar => RDI,
+        }
+    }
+}
+
+/// Shifts or rotates `dest` by CL, masked to 5 bits or 6 bits for 64-bit operands.
+#[no_mangle]
+pub fn shift_reg_cl(buf: &mut Assembler, mode: MachineMode, op: ShiftOp, dest: Register) {
+    emit_mode_ext_reg(buf, mode, 0xD2, op.ext(), dest);
+}
+
+#[no_mangle]
+pub fn shift_reg_imm(buf: &mut Assembler, mode: MachineMode, op: ShiftOp, dest: Register, imm: u8) {
+    if imm == 1 {
+        emit_mode_ext_reg(buf, mode, 0xD0, op.ext(), dest);
+    } else {
+        emit_mode_ext_reg(buf, mode, 0xC0, op.ext(), dest);
+        emit(buf, imm);
+    }
+}
+
+/// Loads the accumulator (AL, AX, EAX or RAX) from a full 64-bit address. `dest`
+/// must be RAX, other registers need the address in a register.
+#[no_mangle]
+pub fn movabs_load(buf: &mut Assembler, mode: MachineMode, addr: u64, dest: Register) {
+    emit_movabs(buf, mode, 0xA0, dest, addr);
+}
+
+#[no_mangle]
+pub fn movabs_store(buf: &mut Assembler, mode: MachineMode, src: Register, addr: u64) {
+    emit_movabs(buf, mode, 0xA2, src, addr);
+}
+
+fn emit_movabs(buf: &mut Assembler, mode: MachineMode, byte_op: u8, reg: Register, addr: u64) {
+    if reg != RAX {
+        buf.invalid_operand::<()>("movabs only supports the accumulator");
+    }
+
+    let (x64, opcode) = int_op(buf, mode, byte_op);
+
+    if x64 != 0 {
+        emit_rex(buf, x64, 0, 0, 0);
+    }
+
+    emit_op(buf, opcode);
+    buf.emit64(addr);
+}
+
+/// Swaps `src` with `dest`. Always atomic, with or without a LOCK prefix.
+#[no_mangle]
+pub fn xchg_mem(buf: &mut Assembler, mode: MachineMode, src: Register, dest: Mem) {
+    emit_mode_reg_mem(buf, mode, false, 0x86, src, dest);
+}
+
+/// Compares the accumulator (AL, EAX or RAX) with `dest`. If they are equal ZF is
+/// set and `src` is stored into `dest`, otherwise ZF is cleared and `dest` is
+/// loaded into the accumulator.
+#[no_mangle]
+pub fn cmpxchg(buf: &mut Assembler, mode: MachineMode, src: Register, dest: Register) {
+    emit_mode_reg_reg(buf, mode, true, 0xB0, src, dest);
+}
+
+#[no_mangle]
+pub fn cmpxchg_mem(buf: &mut Assembler, mode: MachineMode, src: Register, dest: Mem) {
+    emit_mode_reg_mem(buf, mode, true, 0xB0, src, dest);
+}
+
+/// Compares RDX:RAX with the 16 bytes at `dest`, which must be 16-byte aligned. If
+/// they are equal ZF is set and RCX:RBX is stored into `dest`, otherwise ZF is
+/// cleared and `dest` is loaded into RDX:RAX.
+#[no_mangle]
+pub fn cmpxchg16b(buf: &mut Assembler, dest: Mem) {
+    emit_rex_mem(buf, 1, RAX, &dest);
+    emit_op(buf, 0x0F);
+    emit_op(buf, 0xC7);
+    emit_mem(buf, RCX, &dest);
+}
+
+/// Stores `src + dest` into `dest` and the previous value of `dest` into `src`.
+#[no_mangle]
+pub fn xadd(buf: &mut Assembler, mode: MachineMode, src: Register, dest: Register) {
+    emit_mode_reg_reg(buf, mode, true, 0xC0, src, dest);
+}
+
+#[no_mangle]
+pub fn xadd_mem(buf: &mut Assembler, mode: MachineMode, src: Register, dest: Mem) {
+    emit_mode_reg_mem(buf, mode, true, 0xC0, src, dest);
+}
+
+/// Orders all loads and stores before the fence before those after it.
+#[no_mangle]
+pub fn mfence(buf: &mut Assembler) {
+    emit_op(buf, 0x0F);
+    emit_op(buf, 0xAE);
+    emit_op(buf, 0xF0);
+}
+
+/// Orders loads and keeps later instructions from starting before earlier ones
+/// completed.
+#[no_mangle]
+pub fn lfence(buf: &mut Assembler) {
+    emit_op(buf, 0x0F);
+    emit_op(buf, 0xAE);
+    emit_op(buf, 0xE8);
+}
+
+/// Orders stores, including non-temporal ones.
+#[no_mangle]
+pub fn sfence(buf: &mut Assembler) {
+    emit_op(buf, 0x0F);
+    emit_op(buf, 0xAE);
+    emit_op(buf, 0xF8);
+}
+
+/// Spin loop hint.
+#[no_mangle]
+pub fn pause(buf: &mut Assembler) {
+    emit_op(buf, 0xF3);
+    emit_op(buf, 0x90);
+}
+
 #[no_mangle]
 pub fn emit_xor_reg_reg(buf: &mut Assembler, x64: u8, src: Register, dest: Register) {
     emit_alu_reg_reg(buf, x64, 0x31, src, dest);
@@ -803,7 +1362,7 @@ fn emit_alu_reg_reg(buf: &mut Assembler, x64: u8, opcode: u8, src: Register, des
 #[no_mangle]
 pub fn emit_movl_imm_reg(buf: &mut Assembler, imm: i32, reg: Register) {
     if reg.msb() != 0 {
-        emit_rex(buf, 0, 0, 0, 1);
+        emit_rex(buf, 0, 0, 0, reg.msb());
     }
 
     emit_op(buf, (0xB8 as u8) + reg.and7());
@@ -885,7 +1444,7 @@ pub fn emit_movb_reg_memq(buf: &mut Assembler, src: Register, dest: Register, di
     let dest_msb = if dest == RIP { 0 } else { dest.msb() };
 
     if dest_msb != 0 || src.msb() != 0 || (src != RAX && src != RBX && src != RCX && src != RDX) {
-        emit_rex(buf, 0, src.msb(), 0, dest.msb());
+        emit_rex(buf, 0, src.msb(), 0, dest_msb);
     }
 
     emit_op(buf, 0x88);
@@ -896,7 +1455,7 @@ pub fn emit_movb_imm_memq(buf: &mut Assembler, imm: u8, dest: Register, disp: i3
     let dest_msb = if dest == RIP { 0 } else { dest.msb() };
 
     if dest_msb != 0 {
-        emit_rex(buf, 0, 0, 0, dest.msb());
+        emit_rex(buf, 0, 0, 0, dest_msb);
     }
 
     emit_op(buf, 0xC6);
@@ -916,8 +1475,7 @@ pub fn emit_movb_imm_memscaleq(
     }
 
     emit_op(buf, 0xC6);
-    emit_modrm(buf, 0b00, 0b000, 0b100);
-    emit_sib(buf, scale, index.and7(), base.and7());
+    emit_membase_with_index_and_scale(buf, base, index, 1 << scale, 0, RAX);
     emit(buf, imm);
 }
 #[no_mangle]
@@ -970,22 +1528,12 @@ fn emit_mov_ar(
     scale: u8,
     dest: Register,
 ) {
-    assert!(scale == 8 || scale == 4 || scale == 2 || scale == 1);
-
     if x64 != 0 || dest.msb() != 0 || index.msb() != 0 || base.msb() != 0 {
         emit_rex(buf, x64, dest.msb(), index.msb(), base.msb());
     }
 
-    let scale = match scale {
-        8 => 3,
-        4 => 2,
-        2 => 1,
-        _ => 0,
-    };
-
     emit_op(buf, opcode);
-    emit_modrm(buf, 0b00, dest.and7(), 0b100);
-    emit_sib(buf, scale, index.and7(), base.and7());
+    emit_membase_with_index_and_scale(buf, base, index, scale as i32, 0, dest);
 }
 
 fn emit_mov_reg_memq(
@@ -1007,14 +1555,29 @@ fn emit_mov_reg_memq(
 }
 
 fn emit_membase(buf: &mut Assembler, base: Register, disp: i32, dest: Register) {
+    emit_membase_disp8n(buf, base, disp, dest, 1);
+}
+
+/// The disp8 encoding of `disp` when the disp8 is scaled by `n`, as in the
+/// compressed displacements of EVEX instructions.
+fn disp8(disp: i32, n: i32) -> Option<u8> {
+    if disp % n == 0 && fits_i8(disp / n) {
+        Some((disp / n) as u8)
+    } else {
+        // plain `None` is `SIMDPrefix::None` in this module
+        Option::None
+    }
+}
+
+fn emit_membase_disp8n(buf: &mut Assembler, base: Register, disp: i32, dest: Register, n: i32) {
     if base == RSP || base == R12 {
         if disp == 0 {
             emit_modrm(buf, 0, dest.and7(), RSP.and7());
             emit_sib(buf, 0, RSP.and7(), RSP.and7());
-        } else if fits_i8(disp) {
+        } else if let Some(disp8) = disp8(disp, n) {
             emit_modrm(buf, 1, dest.and7(), RSP.and7());
             emit_sib(buf, 0, RSP.and7(), RSP.and7());
-            emit(buf, disp as u8);
+            emit(buf, disp8);
         } else {
             emit_modrm(buf, 2, dest.and7(), RSP.and7());
             emit_sib(buf, 0, RSP.and7(), RSP.and7());
@@ -1025,9 +1588,9 @@ fn emit_membase(buf: &mut Assembler, base: Register, disp: i32, dest: Register)
     } else if base == RIP {
         emit_modrm(buf, 0, dest.and7(), RBP.and7());
         emit32(buf, disp as u32);
-    } else if fits_i8(disp) {
+    } else if let Some(disp8) = disp8(disp, n) {
         emit_modrm(buf, 1, dest.and7(), base.and7());
-        emit(buf, disp as u8);
+        emit(buf, disp8);
     } else {
         emit_modrm(buf, 2, dest.and7(), base.and7());
         emit32(buf, disp as u32);
@@ -1035,22 +1598,23 @@ fn emit_membase(buf: &mut Assembler, base: Register, disp: i32, dest: Register)
 }
 #[no_mangle]
 pub fn emit_cmp_imm_reg(buf: &mut Assembler, mode: MachineMode, imm: i32, reg: Register) {
-    let x64 = match mode {
-        MachineMode::Int8 | MachineMode::Int32 => 0,
-        MachineMode::Int64 => unimplemented!(),
-        MachineMode::Float32 | MachineMode::Float64 => unreachable!(),
-        MachineMode::Ptr => 1,
-    };
-
-    emit_aluq_imm_reg(buf, x64, imm, reg, 0x3d, 0b111);
+    alu_imm_reg(buf, mode, AluOp::Cmp, imm, reg);
 }
 #[no_mangle]
 pub fn emit_subq_imm_reg(buf: &mut Assembler, imm: i32, reg: Register) {
     emit_aluq_imm_reg(buf, 1, imm, reg, 0x2d, 0b101);
+
+    if reg == RSP {
+        buf.cfi_adjust_sp(imm);
+    }
 }
 #[no_mangle]
 pub fn emit_addq_imm_reg(buf: &mut Assembler, imm: i32, reg: Register) {
     emit_aluq_imm_reg(buf, 1, imm, reg, 0x05, 0);
+
+    if reg == RSP {
+        buf.cfi_adjust_sp(imm.wrapping_neg());
+    }
 }
 #[no_mangle]
 pub fn emit_andq_imm_reg(buf: &mut Assembler, imm: i32, reg: Register) {
@@ -1065,8 +1629,6 @@ fn emit_aluq_imm_reg(
     rax_opcode: u8,
     modrm_reg: u8,
 ) {
-    assert!(x64 == 0 || x64 == 1);
-
     if x64 != 0 || reg.msb() != 0 {
         emit_rex(buf, x64, 0, 0, reg.msb());
     }
@@ -1092,6 +1654,10 @@ pub fn emit_mov_reg_reg(buf: &mut Assembler, x64: u8, src: Register, dest: Regis
 
     emit_op(buf, 0x89);
     emit_modrm(buf, 0b11, src.and7(), dest.and7());
+
+    if x64 != 0 {
+        buf.cfi_mov(src, dest);
+    }
 }
 #[no_mangle]
 pub fn emit_neg_reg(buf: &mut Assembler, x64: u8, reg: Register) {
@@ -1109,7 +1675,13 @@ pub fn cpuid(buf: &mut Assembler) {
 }
 #[no_mangle]
 pub fn emit_not_reg_byte(buf: &mut Assembler, reg: Register) {
-    emit_alul_reg(buf, 0xf6, 0b10, 0, reg);
+    // spl, bpl, sil and dil need a REX prefix, without one they encode ah..bh
+    if reg.msb() != 0 || !reg.is_basic_reg() {
+        emit_rex(buf, 0, 0, 0, reg.msb());
+    }
+
+    emit_op(buf, 0xf6);
+    emit_modrm(buf, 0b11, 0b10, reg.and7());
 }
 
 fn emit_alul_reg(buf: &mut Assembler, opcode: u8, modrm_reg: u8, x64: u8, reg: Register) {
@@ -1152,37 +1724,25 @@ fn emit_alub_imm_reg(
 }
 #[no_mangle]
 pub fn emit_sub_imm_mem(buf: &mut Assembler, mode: MachineMode, base: Register, imm: u8) {
-    let (x64, opcode) = match mode {
-        MachineMode::Ptr => (1, 0x83),
-        MachineMode::Int32 => (0, 0x83),
-        MachineMode::Int64 => unimplemented!(),
-        MachineMode::Float32 | MachineMode::Float64 => unreachable!(),
-        MachineMode::Int8 => (0, 0x80),
-    };
-
-    if x64 != 0 || base.msb() != 0 {
-        emit_rex(buf, x64, 0, 0, base.msb());
-    }
-
-    emit_op(buf, opcode);
-    emit_modrm(buf, 0b00, 0b101, base.and7());
-    emit(buf, imm);
+    alu_imm_mem(buf, mode, AluOp::Sub, imm as i8 as i32, Mem::Base(base, 0));
 }
 #[no_mangle]
 pub fn emit_pushq_reg(buf: &mut Assembler, reg: Register) {
     if reg.msb() != 0 {
-        emit_rex(buf, 0, 0, 0, 1);
+        emit_rex(buf, 0, 0, 0, reg.msb());
     }
 
     emit_op(buf, 0x50 + reg.and7());
+    buf.cfi_push(reg);
 }
 #[no_mangle]
 pub fn emit_popq_reg(buf: &mut Assembler, reg: Register) {
     if reg.msb() != 0 {
-        emit_rex(buf, 0, 0, 0, 1);
+        emit_rex(buf, 0, 0, 0, reg.msb());
     }
 
     emit_op(buf, 0x58 + reg.and7());
+    buf.cfi_pop(reg);
 }
 #[no_mangle]
 pub fn emit_retq(buf: &mut Assembler) {
@@ -1219,28 +1779,27 @@ pub fn emit_op(buf: &mut Assembler, opcode: u8) {
 }
 #[no_mangle]
 pub fn emit_rex(buf: &mut Assembler, w: u8, r: u8, x: u8, b: u8) {
-    assert!(w == 0 || w == 1);
-    assert!(r == 0 || r == 1);
-    assert!(x == 0 || x == 1);
-    assert!(b == 0 || b == 1);
+    if w > 1 || r > 1 || x > 1 || b > 1 {
+        buf.invalid_operand::<()>("register cannot be encoded in a REX prefix");
+    }
 
-    buf.emit(0x4 << 4 | w << 3 | r << 2 | x << 1 | b);
+    buf.emit(0x4 << 4 | (w & 1) << 3 | (r & 1) << 2 | (x & 1) << 1 | (b & 1));
 }
 #[no_mangle]
 pub fn emit_modrm(buf: &mut Assembler, mode: u8, reg: u8, rm: u8) {
-    assert!(mode < 4);
-    assert!(reg < 8);
-    assert!(rm < 8);
+    if mode >= 4 || reg >= 8 || rm >= 8 {
+        buf.invalid_operand::<()>("invalid ModRM fields");
+    }
 
-    buf.emit(mode << 6 | reg << 3 | rm);
+    buf.emit((mode & 3) << 6 | (reg & 7) << 3 | (rm & 7));
 }
 #[no_mangle]
 pub fn emit_sib(buf: &mut Assembler, scale: u8, index: u8, base: u8) {
-    assert!(scale < 4);
-    assert!(index < 8);
-    assert!(base < 8);
+    if scale >= 4 || index >= 8 || base >= 8 {
+        buf.invalid_operand::<()>("invalid SIB fields");
+    }
 
-    buf.emit(scale << 6 | index << 3 | base);
+    buf.emit((scale & 3) << 6 | (index & 7) << 3 | (base & 7));
 }
 #[no_mangle]
 pub fn fits_i8(imm: i32) -> bool {
@@ -1261,9 +1820,15 @@ pub fn emit_jcc(buf: &mut Assembler, cond: CondCode, lbl: Label) {
         CondCode::UnsignedLessEq => 0x86,    // below or equal
     };
 
+    if buf.short_disp(lbl, 2).is_some() {
+        emit_op(buf, opcode - 0x10);
+        buf.emit_label_ref(lbl, JumpKind::Short);
+        return;
+    }
+
     emit_op(buf, 0x0f);
     emit_op(buf, opcode);
-    buf.emit_label(lbl);
+    buf.emit_label_ref(lbl, JumpKind::Jcc);
 }
 #[no_mangle]
 pub fn emit_movsx(buf: &mut Assembler, src: Register, dest: Register) {
@@ -1274,8 +1839,14 @@ pub fn emit_movsx(buf: &mut Assembler, src: Register, dest: Register) {
 }
 #[no_mangle]
 pub fn emit_jmp(buf: &mut Assembler, lbl: Label) {
+    if buf.short_disp(lbl, 2).is_some() {
+        emit_op(buf, 0xeb);
+        buf.emit_label_ref(lbl, JumpKind::Short);
+        return;
+    }
+
     emit_op(buf, 0xe9);
-    buf.emit_label(lbl);
+    buf.emit_label_ref(lbl, JumpKind::Jmp);
 }
 #[no_mangle]
 pub fn emit_jmp_reg(buf: &mut Assembler, reg: Register) {
@@ -1306,11 +1877,42 @@ pub fn lea(buf: &mut Assembler, dest: Register, src: Mem) {
     emit_op(buf, 0x8D);
     emit_mem(buf, dest, &src);
 }
-#[no_mangle]
-pub fn emit_rex_mem(buf: &mut Assembler, x64: u8, dest: Register, src: &Mem) {
-    assert!(x64 == 0 || x64 == 1);
+/// Emits the segment override prefix of a memory operand, if it has one. Encoders
+/// with a mandatory or operand size prefix call this first, since decoders expect
+/// those right in front of REX and the opcode.
+pub(crate) fn emit_segment_prefix(buf: &mut Assembler, src: &Mem) {
+    if let &Mem::Segment(segment, _) = src {
+        emit_legacy_prefix(buf, segment_prefix(segment));
+    }
+}
+
+fn segment_prefix(segment: Segment) -> u8 {
+    match segment {
+        Segment::Fs => 0x64,
+        Segment::Gs => 0x65,
+    }
+}
+
+/// Emits the segment override prefix of a memory operand unless the prefixes right
+/// in front already contain it, and returns its REX.B and REX.X. Encoders call this
+/// in place of emitting REX, VEX or EVEX, which have to follow the legacy prefixes.
+pub(crate) fn emit_mem_prefix(buf: &mut Assembler, src: &Mem) -> (u8, u8) {
+    if let &Mem::Segment(segment, _) = src {
+        let prefix = segment_prefix(segment);
+        let emitted = buf.prefixes.end == buf.pos()
+            && buf.data[buf.prefixes.clone()].contains(&prefix);
 
-    let (base_msb, index_msb) = match src {
+        if !emitted {
+            emit_legacy_prefix(buf, prefix);
+        }
+    }
+
+    mem_msbs(src)
+}
+
+/// REX.B and REX.X of a memory operand.
+fn mem_msbs(src: &Mem) -> (u8, u8) {
+    match src {
         &Mem::Local(_) => (RBP.msb(), 0),
         &Mem::Base(base, _) => {
             let base_msb = if base == RIP { 0 } else { base.msb() };
@@ -1320,7 +1922,13 @@ pub fn emit_rex_mem(buf: &mut Assembler, x64: u8, dest: Register, src: &Mem) {
 
         &Mem::Index(base, index, _, _) => (base.msb(), index.msb()),
         &Mem::Offset(index, _, _) => (0, index.msb()),
-    };
+        &Mem::RipRelative(_) | &Mem::Absolute(_) => (0, 0),
+        &Mem::Segment(_, addr) => mem_msbs(&addr.mem()),
+    }
+}
+#[no_mangle]
+pub fn emit_rex_mem(buf: &mut Assembler, x64: u8, dest: Register, src: &Mem) {
+    let (base_msb, index_msb) = emit_mem_prefix(buf, src);
 
     if dest.msb() != 0 || index_msb != 0 || base_msb != 0 || x64 != 0 {
         emit_rex(buf, x64, dest.msb(), index_msb, base_msb);
@@ -1328,22 +1936,59 @@ pub fn emit_rex_mem(buf: &mut Assembler, x64: u8, dest: Register, src: &Mem) {
 }
 #[no_mangle]
 pub fn emit_mem(buf: &mut Assembler, dest: Register, src: &Mem) {
+    emit_mem_disp8n(buf, dest, src, 1);
+}
+
+/// Like `emit_mem`, but a one byte displacement is multiplied by `n`. EVEX
+/// instructions scale it by the size of the memory operand.
+pub fn emit_mem_disp8n(buf: &mut Assembler, dest: Register, src: &Mem, n: i32) {
     match src {
         &Mem::Local(offset) => {
-            emit_membase(buf, RBP, offset, dest);
+            emit_membase_disp8n(buf, RBP, offset, dest, n);
         }
 
         &Mem::Base(base, disp) => {
-            emit_membase(buf, base, disp, dest);
+            emit_membase_disp8n(buf, base, disp, dest, n);
         }
 
         &Mem::Index(base, index, scale, disp) => {
-            emit_membase_with_index_and_scale(buf, base, index, scale, disp, dest);
+            emit_membase_with_index_and_scale_disp8n(buf, base, index, scale, disp, dest, n);
         }
 
         &Mem::Offset(index, scale, disp) => {
             emit_membase_without_base(buf, index, scale, disp, dest);
         }
+
+        &Mem::RipRelative(target) => {
+            emit_modrm(buf, 0, dest.and7(), RBP.and7());
+
+            match target {
+                RipTarget::Label(lbl) => buf.emit_label_ref(lbl, JumpKind::Rip(0)),
+                RipTarget::DSeg(disp) => {
+                    // the data segment lies in front of the code
+                    let end = buf.pos() as i32 + 4;
+                    emit32(buf, -(disp + end) as u32);
+                    buf.record_dseg_ref(disp);
+                }
+            }
+        }
+
+        &Mem::Absolute(addr) => {
+            if !fits_i32(addr as i64) {
+                buf.invalid_operand::<()>("absolute address does not fit into 32 bits");
+            }
+
+            // a SIB byte without base and index stands for a disp32 on its own, the
+            // ModRM form without SIB is RIP-relative
+            emit_modrm(buf, 0, dest.and7(), RSP.and7());
+            emit_sib(buf, 0, RSP.and7(), RBP.and7());
+            emit32(buf, addr as u32);
+        }
+
+        // the prefix was emitted in front of the instruction
+        &Mem::Segment(_, addr) => {
+            emit_mem_disp8n(buf, dest, &addr.mem(), n);
+        }
     }
 }
 #[no_mangle]
@@ -1402,22 +2047,7 @@ pub fn emit_cmp_mem_reg(
     disp: i32,
     dest: Register,
 ) {
-    let base_msb = if base == RIP { 0 } else { base.msb() };
-
-    let (x64, opcode) = match mode {
-        MachineMode::Int8 => (0, 0x38),
-        MachineMode::Int32 => (0, 0x39),
-        MachineMode::Int64 => unimplemented!(),
-        MachineMode::Float32 | MachineMode::Float64 => unreachable!(),
-        MachineMode::Ptr => (1, 0x39),
-    };
-
-    if x64 != 0 || dest.msb() != 0 || base_msb != 0 {
-        emit_rex(buf, x64, dest.msb(), 0, base_msb);
-    }
-
-    emit_op(buf, opcode);
-    emit_membase(buf, base, disp, dest);
+    alu_reg_mem(buf, mode, AluOp::Cmp, dest, Mem::Base(base, disp));
 }
 #[no_mangle]
 pub fn emit_mov_memindex_reg(
@@ -1429,22 +2059,11 @@ pub fn emit_mov_memindex_reg(
     disp: i32,
     dest: Register,
 ) {
-    assert!(scale == 8 || scale == 4 || scale == 2 || scale == 1);
-    assert!(mode.size() as i32 == scale);
-
-    let (x64, opcode) = match mode {
-        MachineMode::Int8 => (0, 0x8a),
-        MachineMode::Int32 => (0, 0x8b),
-        MachineMode::Int64 | MachineMode::Ptr => (1, 0x8b),
-        MachineMode::Float32 | MachineMode::Float64 => unreachable!(),
-    };
-
-    if x64 != 0 || dest.msb() != 0 || index.msb() != 0 || base.msb() != 0 {
-        emit_rex(buf, x64, dest.msb(), index.msb(), base.msb());
+    if mode.size() as i32 != scale {
+        buf.invalid_operand::<()>("scale must match the size of the loaded value");
     }
 
-    emit_op(buf, opcode);
-    emit_membase_with_index_and_scale(buf, base, index, scale, disp, dest);
+    mov_mem_reg(buf, mode, Mem::Index(base, index, scale, disp), dest);
 }
 #[no_mangle]
 pub fn emit_movzx_memindex_byte_reg(
@@ -1473,21 +2092,7 @@ pub fn emit_mov_reg_memindex(
     scale: i32,
     disp: i32,
 ) {
-    assert!(scale == 8 || scale == 4 || scale == 2 || scale == 1);
-
-    let (x64, opcode) = match mode {
-        MachineMode::Int8 => (0, 0x88),
-        MachineMode::Int32 => (0, 0x89),
-        MachineMode::Int64 | MachineMode::Ptr => (1, 0x89),
-        MachineMode::Float32 | MachineMode::Float64 => unreachable!(),
-    };
-
-    if x64 != 0 || src.msb() != 0 || index.msb() != 0 || base.msb() != 0 {
-        emit_rex(buf, x64, src.msb(), index.msb(), base.msb());
-    }
-
-    emit_op(buf, opcode);
-    emit_membase_with_index_and_scale(buf, base, index, scale, disp, src);
+    mov_reg_mem(buf, mode, src, Mem::Index(base, index, scale, disp));
 }
 #[no_mangle]
 pub fn emit_cmp_mem_imm(
@@ -1497,34 +2102,7 @@ pub fn emit_cmp_mem_imm(
     disp: i32,
     imm: i32,
 ) {
-    let base_msb = if base == RIP { 0 } else { base.msb() };
-
-    let opcode = if fits_i8(imm) { 0x83 } else { 0x81 };
-
-    let (x64, opcode) = match mode {
-        MachineMode::Int8 => (0, 0x80),
-        MachineMode::Int32 => (0, opcode),
-        MachineMode::Int64 => unimplemented!(),
-        MachineMode::Ptr => (1, opcode),
-        MachineMode::Float32 | MachineMode::Float64 => unreachable!(),
-    };
-
-    if x64 != 0 || base_msb != 0 {
-        emit_rex(buf, x64, 0, 0, base_msb);
-    }
-
-    emit_op(buf, opcode);
-    emit_membase(buf, base, disp, RDI);
-
-    if fits_i8(imm) {
-        emit(buf, imm as u8);
-    } else {
-        if mode == MachineMode::Int8 {
-            panic!("Int8 does not support 32 bit values");
-        }
-
-        emit32(buf, imm as u32);
-    }
+    alu_imm_mem(buf, mode, AluOp::Cmp, imm, Mem::Base(base, disp));
 }
 #[no_mangle]
 pub fn emit_cmp_memindex_reg(
@@ -1536,22 +2114,29 @@ pub fn emit_cmp_memindex_reg(
     disp: i32,
     dest: Register,
 ) {
-    assert!(scale == 8 || scale == 4 || scale == 2 || scale == 1);
-
-    let (x64, opcode) = match mode {
-        MachineMode::Int8 => (0, 0x38),
-        MachineMode::Int32 => (0, 0x39),
-        MachineMode::Int64 => unimplemented!(),
-        MachineMode::Ptr => (1, 0x39),
-        MachineMode::Float32 | MachineMode::Float64 => unreachable!(),
-    };
+    alu_reg_mem(
+        buf,
+        mode,
+        AluOp::Cmp,
+        dest,
+        Mem::Index(base, index, scale, disp),
+    );
+}
 
-    if x64 != 0 || dest.msb() != 0 || index.msb() != 0 || base.msb() != 0 {
-        emit_rex(buf, x64, dest.msb(), index.msb(), base.msb());
+fn scale_bits(buf: &mut Assembler, scale: i32) -> u8 {
+    match scale {
+        8 => 3,
+        4 => 2,
+        2 => 1,
+        1 => 0,
+        _ => buf.invalid_operand(format!("invalid scale {}", scale)),
     }
+}
 
-    emit_op(buf, opcode);
-    emit_membase_with_index_and_scale(buf, base, index, scale, disp, dest);
+fn check_index(buf: &mut Assembler, index: Register) {
+    if index == RSP {
+        buf.invalid_operand::<()>("RSP cannot be used as an index register");
+    }
 }
 
 fn emit_membase_without_base(
@@ -1561,14 +2146,8 @@ fn emit_membase_without_base(
     disp: i32,
     dest: Register,
 ) {
-    assert!(scale == 8 || scale == 4 || scale == 2 || scale == 1);
-
-    let scale = match scale {
-        8 => 3,
-        4 => 2,
-        2 => 1,
-        _ => 0,
-    };
+    check_index(buf, index);
+    let scale = scale_bits(buf, scale);
 
     emit_modrm(buf, 0, dest.and7(), 4);
     emit_sib(buf, scale, index.and7(), 5);
@@ -1583,22 +2162,30 @@ fn emit_membase_with_index_and_scale(
     disp: i32,
     dest: Register,
 ) {
-    assert!(scale == 8 || scale == 4 || scale == 2 || scale == 1);
+    emit_membase_with_index_and_scale_disp8n(buf, base, index, scale, disp, dest, 1);
+}
 
-    let scale = match scale {
-        8 => 3,
-        4 => 2,
-        2 => 1,
-        _ => 0,
-    };
+fn emit_membase_with_index_and_scale_disp8n(
+    buf: &mut Assembler,
+    base: Register,
+    index: Register,
+    scale: i32,
+    disp: i32,
+    dest: Register,
+    n: i32,
+) {
+    check_index(buf, index);
+    let scale = scale_bits(buf, scale);
 
-    if disp == 0 {
+    // [rbp + index] and [r13 + index] need an explicit displacement, mod 00 with
+    // base 101 means there is no base register
+    if disp == 0 && base.and7() != RBP.and7() {
         emit_modrm(buf, 0, dest.and7(), 4);
         emit_sib(buf, scale, index.and7(), base.and7());
-    } else if fits_i8(disp) {
+    } else if let Some(disp8) = disp8(disp, n) {
         emit_modrm(buf, 1, dest.and7(), 4);
         emit_sib(buf, scale, index.and7(), base.and7());
-        emit(buf, disp as u8);
+        emit(buf, disp8);
     } else {
         emit_modrm(buf, 2, dest.and7(), 4);
         emit_sib(buf, scale, index.and7(), base.and7());
@@ -1652,8 +2239,8 @@ pub fn emit_setb_reg_parity(buf: &mut Assembler, reg: Register, parity: bool) {
 }
 #[no_mangle]
 pub fn emit_movb_reg_reg(buf: &mut Assembler, src: Register, dest: Register) {
-    if src.msb() != 0 || dest.msb() != 0 || !src.is_basic_reg() {
-        emit_rex(buf, 0, dest.msb(), 0, src.msb());
+    if src.msb() != 0 || dest.msb() != 0 || !src.is_basic_reg() || !dest.is_basic_reg() {
+        emit_rex(buf, 0, src.msb(), 0, dest.msb());
     }
 
     emit_op(buf, 0x88);
@@ -1784,8 +2371,334 @@ pub fn emit_sar_reg_cl(buf: &mut Assembler, x64: u8, dest: Register) {
     emit_modrm(buf, 0b11, 0b111, dest.and7());
 }
 
+// rotates share the encodings of the shifts, ModRM.reg selects the operation
+
+fn emit_shift_cl(buf: &mut Assembler, x64: u8, ext: u8, dest: Register) {
+    if dest.msb() != 0 || x64 != 0 {
+        emit_rex(buf, x64, 0, 0, dest.msb());
+    }
+
+    emit_op(buf, 0xD3);
+    emit_modrm(buf, 0b11, ext, dest.and7());
+}
+
+fn emit_shift_imm(buf: &mut Assembler, x64: u8, ext: u8, dest: Register, imm: u8) {
+    if dest.msb() != 0 || x64 != 0 {
+        emit_rex(buf, x64, 0, 0, dest.msb());
+    }
+
+    emit_op(buf, if imm == 1 { 0xD1 } else { 0xC1 });
+    emit_modrm(buf, 0b11, ext, dest.and7());
+
+    if imm != 1 {
+        emit(buf, imm);
+    }
+}
+#[no_mangle]
+pub fn emit_rol_reg_cl(buf: &mut Assembler, x64: u8, dest: Register) {
+    emit_shift_cl(buf, x64, 0b000, dest);
+}
+#[no_mangle]
+pub fn emit_ror_reg_cl(buf: &mut Assembler, x64: u8, dest: Register) {
+    emit_shift_cl(buf, x64, 0b001, dest);
+}
+#[no_mangle]
+pub fn emit_rol_reg_imm(buf: &mut Assembler, x64: u8, dest: Register, imm: u8) {
+    emit_shift_imm(buf, x64, 0b000, dest, imm);
+}
+#[no_mangle]
+pub fn emit_ror_reg_imm(buf: &mut Assembler, x64: u8, dest: Register, imm: u8) {
+    emit_shift_imm(buf, x64, 0b001, dest, imm);
+}
+
+/// `0F op` with `reg` in ModRM.reg and `rm` in ModRM.rm, preceded by `prefix` if
+/// it is not zero.
+fn emit_0f_reg_reg(buf: &mut Assembler, prefix: u8, x64: u8, op: u8, reg: Register, rm: Register) {
+    if prefix != 0 {
+        emit_op(buf, prefix);
+    }
+
+    if x64 != 0 || reg.msb() != 0 || rm.msb() != 0 {
+        emit_rex(buf, x64, reg.msb(), 0, rm.msb());
+    }
+
+    emit_op(buf, 0x0F);
+    emit_op(buf, op);
+    emit_modrm(buf, 0b11, reg.and7(), rm.and7());
+}
+
+fn emit_0f_reg_mem(buf: &mut Assembler, x64: u8, op: u8, reg: Register, rm: Mem) {
+    emit_rex_mem(buf, x64, reg, &rm);
+    emit_op(buf, 0x0F);
+    emit_op(buf, op);
+    emit_mem(buf, reg, &rm);
+}
+
+/// The bit test instructions. `$op` is the opcode of the forms with the bit number
+/// in a register, the register numbered like the ModRM.reg of the forms with an
+/// immediate is `$ext`. With a
+/// register bit number the memory forms address bits outside of the operand, the
+/// register is a signed offset into a bit string starting at the operand.
+macro_rules! bit_test {
+    ($name: ident, $op: expr, $ext: expr) => {
+        paste::item! {
+            #[no_mangle]
+            pub fn $name(buf: &mut Assembler, x64: u8, bit: Register, dest: Register) {
+                emit_0f_reg_reg(buf, 0, x64, $op, bit, dest);
+            }
+            #[no_mangle]
+            pub fn [<$name _mem>](buf: &mut Assembler, x64: u8, bit: Register, dest: Mem) {
+                emit_0f_reg_mem(buf, x64, $op, bit, dest);
+            }
+            #[no_mangle]
+            pub fn [<$name _imm>](buf: &mut Assembler, x64: u8, dest: Register, imm: u8) {
+                if dest.msb() != 0 || x64 != 0 {
+                    emit_rex(buf, x64, 0, 0, dest.msb());
+                }
+
+                emit_op(buf, 0x0F);
+                emit_op(buf, 0xBA);
+                emit_modrm(buf, 0b11, $ext.and7(), dest.and7());
+                emit(buf, imm);
+            }
+            #[no_mangle]
+            pub fn [<$name _mem_imm>](buf: &mut Assembler, x64: u8, dest: Mem, imm: u8) {
+                emit_rex_mem(buf, x64, $ext, &dest);
+                emit_op(buf, 0x0F);
+                emit_op(buf, 0xBA);
+                emit_mem(buf, $ext, &dest);
+                buf.imm_follows_mem(1);
+                emit(buf, imm);
+            }
+        }
+    };
+}
+
+bit_test!(bt, 0xA3, RSP);
+bit_test!(bts, 0xAB, RBP);
+bit_test!(btr, 0xB3, RSI);
+bit_test!(btc, 0xBB, RDI);
+
+/// Index of the lowest set bit of `src`. ZF is set and `dest` is undefined if `src`
+/// is zero.
+#[no_mangle]
+pub fn bsf(buf: &mut Assembler, x64: u8, src: Register, dest: Register) {
+    emit_0f_reg_reg(buf, 0, x64, 0xBC, dest, src);
+}
+
+/// Index of the highest set bit of `src`, undefined like `bsf` for zero.
+#[no_mangle]
+pub fn bsr(buf: &mut Assembler, x64: u8, src: Register, dest: Register) {
+    emit_0f_reg_reg(buf, 0, x64, 0xBD, dest, src);
+}
+#[no_mangle]
+pub fn popcnt(buf: &mut Assembler, x64: u8, src: Register, dest: Register) {
+    buf.require(CpuFeature::Popcnt);
+    emit_0f_reg_reg(buf, 0xF3, x64, 0xB8, dest, src);
+}
+
+/// Number of leading zero bits of `src`, the operand size for zero. Without LZCNT
+/// the encoding runs as `bsr`.
+#[no_mangle]
+pub fn lzcnt(buf: &mut Assembler, x64: u8, src: Register, dest: Register) {
+    buf.require(CpuFeature::Lzcnt);
+    emit_0f_reg_reg(buf, 0xF3, x64, 0xBD, dest, src);
+}
+
+/// Number of trailing zero bits of `src`, the operand size for zero. Without BMI1
+/// the encoding runs as `bsf`.
+#[no_mangle]
+pub fn tzcnt(buf: &mut Assembler, x64: u8, src: Register, dest: Register) {
+    buf.require(CpuFeature::Bmi1);
+    emit_0f_reg_reg(buf, 0xF3, x64, 0xBC, dest, src);
+}
+
+/// A VEX encoded BMI instruction on general purpose registers with `reg` in
+/// ModRM.reg, `vreg` in VEX.vvvv and `rm` in ModRM.rm.
+#[allow(clippy::too_many_arguments)]
+fn emit_bmi(
+    buf: &mut Assembler,
+    x64: u8,
+    pp: SIMDPrefix,
+    mm: LeadingOpcode,
+    op: u8,
+    reg: u8,
+    vreg: Register,
+    rm: Register,
+) {
+    if [reg, vreg as u8, rm as u8].iter().any(|&reg| reg > 15) {
+        buf.invalid_operand::<()>("register cannot be encoded in a VEX prefix");
+    }
+
+    let w = if x64 != 0 { VexW::W1 } else { VexW::W0 };
+    let l = VectorLength::kL128;
+    emit_vex(buf, reg >> 3 & 1, 0, rm.msb() & 1, vreg as u8, l, pp, mm, w);
+    emit_op(buf, op);
+    emit_modrm(buf, 0b11, reg & 7, rm.and7());
+}
+
+/// `dest = !src1 & src2`
+#[no_mangle]
+pub fn andn(buf: &mut Assembler, x64: u8, dest: Register, src1: Register, src2: Register) {
+    buf.require(CpuFeature::Bmi1);
+    emit_bmi(
+        buf,
+        x64,
+        SIMDPrefix::None,
+        LeadingOpcode::k0F38,
+        0xF2,
+        dest as u8,
+        src1,
+        src2,
+    );
+}
+
+/// Clears the lowest set bit: `dest = src & (src - 1)`
+#[no_mangle]
+pub fn blsr(buf: &mut Assembler, x64: u8, dest: Register, src: Register) {
+    buf.require(CpuFeature::Bmi1);
+    emit_bmi(
+        buf,
+        x64,
+        SIMDPrefix::None,
+        LeadingOpcode::k0F38,
+        0xF3,
+        1,
+        dest,
+        src,
+    );
+}
+
+/// Isolates the lowest set bit: `dest = src & -src`
+#[no_mangle]
+pub fn blsi(buf: &mut Assembler, x64: u8, dest: Register, src: Register) {
+    buf.require(CpuFeature::Bmi1);
+    emit_bmi(
+        buf,
+        x64,
+        SIMDPrefix::None,
+        LeadingOpcode::k0F38,
+        0xF3,
+        3,
+        dest,
+        src,
+    );
+}
+
+/// Extracts the bit field of `src` whose start is bits 0-7 of `control` and whose
+/// length is bits 8-15.
+#[no_mangle]
+pub fn bextr(buf: &mut Assembler, x64: u8, dest: Register, src: Register, control: Register) {
+    buf.require(CpuFeature::Bmi1);
+    emit_bmi(
+        buf,
+        x64,
+        SIMDPrefix::None,
+        LeadingOpcode::k0F38,
+        0xF7,
+        dest as u8,
+        control,
+        src,
+    );
+}
+
+/// Deposits the low bits of `src` at the positions of the set bits of `mask`.
+#[no_mangle]
+pub fn pdep(buf: &mut Assembler, x64: u8, dest: Register, src: Register, mask: Register) {
+    buf.require(CpuFeature::Bmi2);
+    emit_bmi(
+        buf,
+        x64,
+        SIMDPrefix::k0xf2,
+        LeadingOpcode::k0F38,
+        0xF5,
+        dest as u8,
+        src,
+        mask,
+    );
+}
+
+/// Gathers the bits of `src` at the positions of the set bits of `mask` into the
+/// low bits of `dest`.
+#[no_mangle]
+pub fn pext(buf: &mut Assembler, x64: u8, dest: Register, src: Register, mask: Register) {
+    buf.require(CpuFeature::Bmi2);
+    emit_bmi(
+        buf,
+        x64,
+        SIMDPrefix::k0xf3,
+        LeadingOpcode::k0F38,
+        0xF5,
+        dest as u8,
+        src,
+        mask,
+    );
+}
+
+// the BMI2 shifts take the count from any register and leave the flags alone
+
+#[no_mangle]
+pub fn shlx(buf: &mut Assembler, x64: u8, dest: Register, src: Register, count: Register) {
+    buf.require(CpuFeature::Bmi2);
+    emit_bmi(
+        buf,
+        x64,
+        SIMDPrefix::k0x66,
+        LeadingOpcode::k0F38,
+        0xF7,
+        dest as u8,
+        count,
+        src,
+    );
+}
+#[no_mangle]
+pub fn sarx(buf: &mut Assembler, x64: u8, dest: Register, src: Register, count: Register) {
+    buf.require(CpuFeature::Bmi2);
+    emit_bmi(
+        buf,
+        x64,
+        SIMDPrefix::k0xf3,
+        LeadingOpcode::k0F38,
+        0xF7,
+        dest as u8,
+        count,
+        src,
+    );
+}
+#[no_mangle]
+pub fn shrx(buf: &mut Assembler, x64: u8, dest: Register, src: Register, count: Register) {
+    buf.require(CpuFeature::Bmi2);
+    emit_bmi(
+        buf,
+        x64,
+        SIMDPrefix::k0xf2,
+        LeadingOpcode::k0F38,
+        0xF7,
+        dest as u8,
+        count,
+        src,
+    );
+}
+
+/// Rotates right by an immediate without changing the flags.
+#[no_mangle]
+pub fn rorx(buf: &mut Assembler, x64: u8, dest: Register, src: Register, imm: u8) {
+    buf.require(CpuFeature::Bmi2);
+    emit_bmi(
+        buf,
+        x64,
+        SIMDPrefix::k0xf2,
+        LeadingOpcode::k0F3A,
+        0xF0,
+        dest as u8,
+        RAX,
+        src,
+    );
+    emit(buf, imm);
+}
+
 pub fn emit_movzx_byte(buf: &mut Assembler, x64: u8, src: Register, dest: Register) {
-    if src.msb() != 0 || dest.msb() != 0 || x64 != 0 {
+    if src.msb() != 0 || dest.msb() != 0 || x64 != 0 || !src.is_basic_reg() {
         emit_rex(buf, x64, dest.msb(), 0, src.msb());
     }
 
@@ -1878,8 +2791,9 @@ pub fn sqrtps(buf: &mut Assembler, dest: XMMRegister, src: XMMRegister) {
     sse_packed_freg_freg(buf, 0x51, dest, src);
 }
 #[no_mangle]
-pub fn movlps(buf: &mut Assembler, dest: XMMRegister, src: XMMRegister) {
-    sse_packed_freg_freg(buf, 0x45, dest, src);
+pub fn movlps(buf: &mut Assembler, _dest: XMMRegister, _src: XMMRegister) {
+    // 0f 12 with a register operand is movhlps, movlps only exists with memory
+    buf.invalid_operand::<()>("movlps has no register to register form");
 }
 #[no_mangle]
 pub fn movss(buf: &mut Assembler, dest: XMMRegister, src: XMMRegister) {
@@ -1944,13 +2858,14 @@ pub fn movq_freg_reg(buf: &mut Assembler, dest: XMMRegister, src: Register) {
 
 fn sse_reg_freg(buf: &mut Assembler, op: u8, x64: u8, dest: Register, src: XMMRegister) {
     emit_op(buf, op);
+    // 0f 7e stores the xmm register in ModRM.reg and the destination in ModRM.rm
     if x64 != 0 || dest.msb() != 0 || src.msb() != 0 {
-        emit_rex(buf, x64, dest.msb(), 0, src.msb());
+        emit_rex(buf, x64, src.msb(), 0, dest.msb());
     }
 
     emit_op(buf, 0x0f);
     emit_op(buf, 0x7e);
-    emit_modrm(buf, 0b11, dest.and7(), src.and7());
+    emit_modrm(buf, 0b11, src.and7(), dest.and7());
 }
 
 fn sse_freg_reg(buf: &mut Assembler, op: u8, x64: u8, dest: XMMRegister, src: Register) {
@@ -2008,7 +2923,8 @@ pub fn sse_float_freg_freg(
 pub fn sse_float_freg_mem(buf: &mut Assembler, dbl: bool, op: u8, dest: XMMRegister, src: Mem) {
     let prefix = if dbl { 0xf2 } else { 0xf3 };
 
-    emit_op(buf, prefix);
+    emit_segment_prefix(buf, &src);
+    emit_legacy_prefix(buf, prefix);
     emit_rex_mem(buf, 0, unsafe { ::core::mem::transmute(dest) }, &src);
     emit_op(buf, 0x0f);
     emit_op(buf, op);
@@ -2023,8 +2939,10 @@ pub fn sse_packed_freg_mem(buf: &mut Assembler, op: u8, dest: XMMRegister, src:
 }
 #[no_mangle]
 pub fn sse_float_freg_mem_66(buf: &mut Assembler, dbl: bool, op: u8, dest: XMMRegister, src: Mem) {
+    emit_segment_prefix(buf, &src);
+
     if dbl {
-        emit_op(buf, 0x66);
+        emit_legacy_prefix(buf, 0x66);
     }
 
     emit_rex_mem(buf, 0, unsafe { ::core::mem::transmute(dest) }, &src);
@@ -2102,6 +3020,7 @@ pub enum RoundMode {
 
 #[no_mangle]
 pub fn roundsd(buf: &mut Assembler, dest: XMMRegister, src: XMMRegister, mode: RoundMode) {
+    buf.require(CpuFeature::Sse41);
     emit_op(buf, 0x66);
     if dest.msb() != 0 || src.msb() != 0 {
         emit_rex(buf, 0, dest.msb(), 0, src.msb());
@@ -2151,6 +3070,15 @@ fn sse_optional_rex32_fm(buf: &mut Assembler, reg: XMMRegister, base: Mem) {
     }
 }
 
+/// The extension that introduced the 0F 38 instruction `opcode`.
+fn ssse3_or_4_feature(opcode: u8) -> CpuFeature {
+    match opcode {
+        0x00..=0x0f | 0x1c..=0x1e => CpuFeature::Ssse3,
+        0x37 => CpuFeature::Sse42,
+        _ => CpuFeature::Sse41,
+    }
+}
+
 fn ssse3_or_4_instr(
     buf: &mut Assembler,
     dst: XMMRegister,
@@ -2160,6 +3088,7 @@ fn ssse3_or_4_instr(
     escape2: u8,
     opcode: u8,
 ) {
+    buf.require(ssse3_or_4_feature(opcode));
     buf.emit(prefix);
     sse_optional_rex_32_ff(buf, dst, src);
     buf.emit(escape1);
@@ -2177,7 +3106,9 @@ fn ssse3_or_4_instr_mem(
     escape2: u8,
     opcode: u8,
 ) {
-    buf.emit(prefix);
+    buf.require(ssse3_or_4_feature(opcode));
+    emit_segment_prefix(buf, &src);
+    emit_legacy_prefix(buf, prefix);
     sse_optional_rex32_fm(buf, dst, src);
     buf.emit(escape1);
     buf.emit(escape2);
@@ -2209,7 +3140,8 @@ fn sse2_instr_mem(
     escape: u8,
     opcode: u8,
 ) {
-    buf.emit(prefix);
+    emit_segment_prefix(buf, &src);
+    emit_legacy_prefix(buf, prefix);
     sse_optional_rex32_fm(buf, dst, src);
     buf.emit(escape);
     buf.emit(opcode);
@@ -1,6 +1,8 @@
-///! This file contains functions that emit's vector instructions
-///! WARNING: Still not implemented
-///!
+//! VEX encoded AVX, AVX2 and FMA instructions.
+//!
+//! Functions without a suffix take XMM registers and encode the 128-bit form, the
+//! `_ymm` variants take YMM registers and encode the 256-bit form. Memory operand
+//! forms end in `_mem`.
 
 #[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
 #[repr(i32)]
@@ -93,10 +95,13 @@ use VexPrefix::*;
 pub const VEX_W: VexPrefix = VEX_R;
 
 impl LeadingOpcode {
+    /// The map selected by the escape byte following 0x0f in the legacy encoding:
+    /// 0x38 and 0x3a select the three byte maps, any other opcode the 0F map.
     pub extern "C" fn from_v(x: u8) -> LeadingOpcode {
         match x {
-            102 => LeadingOpcode::k0F,
-            _ => unimplemented!(),
+            0x38 => LeadingOpcode::k0F38,
+            0x3a => LeadingOpcode::k0F3A,
+            _ => LeadingOpcode::k0F,
         }
     }
 }
@@ -113,31 +118,134 @@ pub enum SIMDPrefix {
 use crate::assembler::*;
 use crate::assembler_x64::*;
 use crate::constants_x64::*;
+use crate::cpu::CpuFeature;
 
-fn emit_vex3_byte1_1(asm: &mut Assembler, reg: XMMRegister, rm: XMMRegister, m: LeadingOpcode) {
-    let rxb = !((reg.high_bit() << 2) | rm.high_bit()) << 5;
-    asm.emit(rxb | m as u8);
+use std::mem;
+
+/// Vector registers that can be VEX operands. The register type selects VEX.L.
+pub trait VexRegister: Copy {
+    const LENGTH: VectorLength;
+
+    fn code(self) -> u8;
 }
 
-use std::mem;
+impl VexRegister for XMMRegister {
+    const LENGTH: VectorLength = VectorLength::kL128;
+
+    fn code(self) -> u8 { self as u8 }
+}
+
+impl VexRegister for YMMRegister {
+    const LENGTH: VectorLength = VectorLength::kL256;
+
+    fn code(self) -> u8 { self as u8 }
+}
 
-fn emit_vex3_byte1_2(asm: &mut Assembler, reg: XMMRegister, rm: Mem, m: LeadingOpcode) {
-    let rxb = emit_rex_memv(asm, 1, unsafe { mem::transmute(reg) }, &rm);
-    asm.emit(rxb | m as u8);
+/// `emit_vex` for an instruction from AVX or a later vector extension.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn emit_vex_prefix(asm: &mut Assembler,
+                              r: u8,
+                              x: u8,
+                              b: u8,
+                              vreg: u8,
+                              l: VectorLength,
+                              pp: SIMDPrefix,
+                              mm: LeadingOpcode,
+                              w: VexW) {
+    asm.require(CpuFeature::Avx);
+    emit_vex(asm, r, x, b, vreg, l, pp, mm, w);
 }
 
-fn emit_vex2_byte1(asm: &mut Assembler,
-                   reg: XMMRegister,
-                   v: XMMRegister,
-                   l: VectorLength,
-                   pp: SIMDPrefix) {
-    let rv = !((reg.high_bit() << 4) | v as u8) << 3;
-    asm.emit(rv | l as u8 | pp as u8);
+/// Emits the two byte prefix when X and B are clear, the map is 0F and W is zero
+/// and the three byte prefix otherwise. VEX stores R, X, B and `vreg` inverted.
+/// The BMI instructions on general purpose registers use it directly, they do not
+/// need AVX.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn emit_vex(asm: &mut Assembler,
+                       r: u8,
+                       x: u8,
+                       b: u8,
+                       vreg: u8,
+                       l: VectorLength,
+                       pp: SIMDPrefix,
+                       mm: LeadingOpcode,
+                       w: VexW) {
+    let vvvv = (!vreg & 0xf) << 3;
+
+    if x == 0 && b == 0 && mm == LeadingOpcode::k0F && w == VexW::W0 {
+        asm.emit(0xc5);
+        asm.emit((!r & 1) << 7 | vvvv | l as u8 | pp as u8);
+    } else {
+        asm.emit(0xc4);
+        asm.emit((!(r << 2 | x << 1 | b) & 0x7) << 5 | mm as u8);
+        asm.emit(w as u8 | vvvv | l as u8 | pp as u8);
+    }
 }
 
-fn emit_vex3_byte2(asm: &mut Assembler, w: VexW, v: XMMRegister, l: VectorLength, pp: SIMDPrefix) {
-    let v = v as u8;
-    asm.emit(w as u8 | ((!v & 0xf) << 3) | l as u8 | pp as u8);
+fn check_vex_regs(asm: &mut Assembler, regs: &[u8]) {
+    if regs.iter().any(|&reg| reg > 15) {
+        asm.invalid_operand::<()>("register cannot be encoded in a VEX prefix");
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn emit_vex_rr(asm: &mut Assembler,
+               reg: u8,
+               vreg: u8,
+               rm: u8,
+               l: VectorLength,
+               pp: SIMDPrefix,
+               mm: LeadingOpcode,
+               w: VexW) {
+    check_vex_regs(asm, &[reg, vreg, rm]);
+    emit_vex_prefix(asm, reg >> 3 & 1, 0, rm >> 3 & 1, vreg, l, pp, mm, w);
+}
+
+#[allow(clippy::too_many_arguments)]
+fn emit_vex_rm(asm: &mut Assembler,
+               reg: u8,
+               vreg: u8,
+               rm: &Mem,
+               l: VectorLength,
+               pp: SIMDPrefix,
+               mm: LeadingOpcode,
+               w: VexW) {
+    check_vex_regs(asm, &[reg, vreg]);
+    let xb = emit_rex_memv(asm, 0, RAX, rm) & 0x3;
+    emit_vex_prefix(asm, reg >> 3 & 1, xb >> 1, xb & 1, vreg, l, pp, mm, w);
+}
+
+/// Emits a VEX instruction with register operands. `reg` goes into ModRM.reg,
+/// `vreg` into VEX.vvvv and `rm` into ModRM.rm.
+#[allow(clippy::too_many_arguments)]
+fn vex_rr(asm: &mut Assembler,
+          op: u8,
+          reg: u8,
+          vreg: u8,
+          rm: u8,
+          l: VectorLength,
+          pp: SIMDPrefix,
+          mm: LeadingOpcode,
+          w: VexW) {
+    emit_vex_rr(asm, reg, vreg, rm, l, pp, mm, w);
+    asm.emit(op);
+    asm.emit(0xc0 | (reg & 7) << 3 | rm & 7);
+}
+
+/// Like `vex_rr` with a memory operand in ModRM.rm.
+#[allow(clippy::too_many_arguments)]
+fn vex_rm(asm: &mut Assembler,
+          op: u8,
+          reg: u8,
+          vreg: u8,
+          rm: Mem,
+          l: VectorLength,
+          pp: SIMDPrefix,
+          mm: LeadingOpcode,
+          w: VexW) {
+    emit_vex_rm(asm, reg, vreg, &rm, l, pp, mm, w);
+    asm.emit(op);
+    emit_mem(asm, unsafe { mem::transmute::<i32, Register>((reg & 7) as i32) }, &rm);
 }
 
 fn emit_vex_prefixf(asm: &mut Assembler,
@@ -148,25 +256,7 @@ fn emit_vex_prefixf(asm: &mut Assembler,
                     pp: SIMDPrefix,
                     mm: LeadingOpcode,
                     w: VexW) {
-    if rm.high_bit() != 0 || mm != LeadingOpcode::k0F || w != VexW::W0 {
-        emit_vex3_byte0(asm);
-        emit_vex3_byte1_1(asm, reg, rm, mm);
-        emit_vex3_byte2(asm, w, vreg, l, pp);
-    } else {
-        emit_vex2_byte0(asm);
-        emit_vex2_byte1(asm, reg, vreg, l, pp);
-    }
-}
-
-fn evex_prefix(asm: &mut Assembler,
-               vex_r: bool,
-               vex_b: bool,
-               vex_x: bool,
-               evex_r: bool,
-               enc: i32,
-               pre: SIMDPrefix,
-               op: LeadingOpcode) {
-    asm.emit(0x62);
+    emit_vex_rr(asm, reg as u8, vreg as u8, rm as u8, l, pp, mm, w);
 }
 
 fn emit_vex_prefixr(asm: &mut Assembler,
@@ -197,48 +287,23 @@ fn emit_vex_prefixfm(asm: &mut Assembler,
                      pp: SIMDPrefix,
                      mm: LeadingOpcode,
                      w: VexW) {
-    let rex = emit_rex_memv(asm, 1, unsafe { mem::transmute(reg) }, &rm);
-    if rex != 0 || mm != LeadingOpcode::k0F || w != VexW::W0 {
-        emit_vex3_byte0(asm);
-        emit_vex3_byte1_2(asm, reg, rm, mm);
-        emit_vex3_byte2(asm, w, vreg, l, pp);
-    } else {
-        emit_vex2_byte0(asm);
-        emit_vex2_byte1(asm, reg, vreg, l, pp);
-    }
+    emit_vex_rm(asm, reg as u8, vreg as u8, &rm, l, pp, mm, w);
 }
 
-fn emit_vex2_byte0(asm: &mut Assembler) { asm.emit(0xc5); }
-
-fn emit_vex3_byte0(asm: &mut Assembler) { asm.emit(0xc4); }
-
 pub(crate) fn emit_rex_memv(buf: &mut Assembler, x64: u8, dest: Register, src: &Mem) -> u8 {
-    assert!(x64 == 0 || x64 == 1);
-
-    let (base_msb, index_msb) = match src {
-        &Mem::Local(_) => (RBP.msb(), 0),
-        &Mem::Base(base, _) => {
-            let base_msb = if base == RIP { 0 } else { base.msb() };
-
-            (base_msb, 0)
-        }
-
-        &Mem::Index(base, index, _, _) => (base.msb(), index.msb()),
-        &Mem::Offset(index, _, _) => (0, index.msb()),
-    };
+    let (base_msb, index_msb) = emit_mem_prefix(buf, src);
 
     if dest.msb() != 0 || index_msb != 0 || base_msb != 0 || x64 != 0 {
         return emit_rexv(buf, x64, dest.msb(), index_msb, base_msb);
     }
     return 0;
 }
-pub(crate) fn emit_rexv(_: &mut Assembler, w: u8, r: u8, x: u8, b: u8) -> u8 {
-    assert!(w == 0 || w == 1);
-    assert!(r == 0 || r == 1);
-    assert!(x == 0 || x == 1);
-    assert!(b == 0 || b == 1);
+pub(crate) fn emit_rexv(buf: &mut Assembler, w: u8, r: u8, x: u8, b: u8) -> u8 {
+    if w > 1 || r > 1 || x > 1 || b > 1 {
+        buf.invalid_operand::<()>("register cannot be encoded in a REX prefix");
+    }
 
-    (0x4 << 4 | w << 3 | r << 2 | x << 1 | b)
+    0x4 << 4 | (w & 1) << 3 | (r & 1) << 2 | (x & 1) << 1 | (b & 1)
 }
 
 pub(crate) fn emit_sse_ff(asm: &mut Assembler, dst: XMMRegister, src: XMMRegister) {
@@ -256,6 +321,30 @@ pub(crate) fn emit_sse_mem_f(asm: &mut Assembler, dst: XMMRegister, src: Mem) {
     emit_mem(asm, unsafe { mem::transmute(dst) }, &src);
 }
 
+#[allow(clippy::too_many_arguments)]
+fn vinstr_v<R: VexRegister>(asm: &mut Assembler,
+                            op: u8,
+                            dst: R,
+                            src1: R,
+                            src2: R,
+                            pp: SIMDPrefix,
+                            m: LeadingOpcode,
+                            w: VexW) {
+    vex_rr(asm, op, dst.code(), src1.code(), src2.code(), R::LENGTH, pp, m, w);
+}
+
+#[allow(clippy::too_many_arguments)]
+fn vinstrm_v<R: VexRegister>(asm: &mut Assembler,
+                             op: u8,
+                             dst: R,
+                             src1: R,
+                             src2: Mem,
+                             pp: SIMDPrefix,
+                             m: LeadingOpcode,
+                             w: VexW) {
+    vex_rm(asm, op, dst.code(), src1.code(), src2, R::LENGTH, pp, m, w);
+}
+
 pub extern "C" fn vinstr(asm: &mut Assembler,
                          op: u8,
                          dst: XMMRegister,
@@ -264,9 +353,7 @@ pub extern "C" fn vinstr(asm: &mut Assembler,
                          pp: SIMDPrefix,
                          m: LeadingOpcode,
                          w: VexW) {
-    emit_vex_prefixf(asm, dst, src1, src2, VectorLength::kL128, pp, m, w);
-    asm.emit(op);
-    emit_sse_ff(asm, dst, src2);
+    vinstr_v(asm, op, dst, src1, src2, pp, m, w);
 }
 
 pub extern "C" fn vinstrm(asm: &mut Assembler,
@@ -277,9 +364,7 @@ pub extern "C" fn vinstrm(asm: &mut Assembler,
                           pp: SIMDPrefix,
                           m: LeadingOpcode,
                           w: VexW) {
-    emit_vex_prefixfm(asm, dst, src1, src2, VectorLength::kL128, pp, m, w);
-    asm.emit(op);
-    emit_sse_mem_f(asm, dst, src2);
+    vinstrm_v(asm, op, dst, src1, src2, pp, m, w);
 }
 
 pub extern "C" fn vps(asm: &mut Assembler,
@@ -355,6 +440,7 @@ pub extern "C" fn vfmasd(asm: &mut Assembler,
                          dst: XMMRegister,
                          src1: XMMRegister,
                          src2: XMMRegister) {
+    asm.require(CpuFeature::Fma);
     emit_vex_prefixf(asm,
                      dst,
                      src1,
@@ -372,6 +458,7 @@ pub extern "C" fn vfmasdm(asm: &mut Assembler,
                           dst: XMMRegister,
                           src1: XMMRegister,
                           src2: Mem) {
+    asm.require(CpuFeature::Fma);
     emit_vex_prefixfm(asm,
                       dst,
                       src1,
@@ -389,6 +476,7 @@ pub extern "C" fn vfmass(asm: &mut Assembler,
                          dst: XMMRegister,
                          src1: XMMRegister,
                          src2: XMMRegister) {
+    asm.require(CpuFeature::Fma);
     emit_vex_prefixf(asm,
                      dst,
                      src1,
@@ -406,6 +494,7 @@ pub extern "C" fn vfmassm(asm: &mut Assembler,
                           dst: XMMRegister,
                           src1: XMMRegister,
                           src2: Mem) {
+    asm.require(CpuFeature::Fma);
     emit_vex_prefixfm(asm,
                       dst,
                       src1,
@@ -455,7 +544,8 @@ pub extern "C" fn vmovd_reg_freg(buf: &mut Assembler, dst: Register, src: XMMReg
                      LeadingOpcode::k0F,
                      VexW::W0);
     buf.emit(0x7e);
-    emit_sse_rf(buf, dst, src);
+    // the xmm register goes into ModRM.reg like in the prefix
+    emit_sse_fr(buf, src, dst);
 }
 
 pub extern "C" fn vmovq_freg_reg(buf: &mut Assembler, dst: XMMRegister, src: Register) {
@@ -495,7 +585,8 @@ pub extern "C" fn vmovq_reg_freg(buf: &mut Assembler, dst: Register, src: XMMReg
                      LeadingOpcode::k0F,
                      VexW::W1);
     buf.emit(0x7e);
-    emit_sse_rf(buf, dst, src);
+    // the xmm register goes into ModRM.reg like in the prefix
+    emit_sse_fr(buf, src, dst);
 }
 
 pub extern "C" fn vaddpd(buf: &mut Assembler,
@@ -512,16 +603,22 @@ pub extern "C" fn vaddpd(buf: &mut Assembler,
            VexW::W1);
 }
 
+/// `$feature` is the extension of the instruction, AVX if left out.
 macro_rules! avx_instr {
     ($name: ident, $op: expr, $prefix: expr,$escape: expr,$vex: expr) => {
+        avx_instr!($name, $op, $prefix, $escape, $vex, Avx);
+    };
+    ($name: ident, $op: expr, $prefix: expr,$escape: expr,$vex: expr, $feature: ident) => {
         #[no_mangle]
         pub extern "C" fn $name(buf: &mut Assembler, dst: XMMRegister, src1: XMMRegister, src2: XMMRegister) {
+            buf.require(CpuFeature::$feature);
             vinstr(buf, $op, dst, src1, src2, $prefix, $escape, $vex);
         }
 
         paste::item! {
             #[no_mangle]
             pub extern "C" fn [<$name _mem>] (buf: &mut Assembler, dst: XMMRegister, src1: XMMRegister, src2: Mem) {
+                buf.require(CpuFeature::$feature);
                 vinstrm(buf,$op,dst,src1,src2,$prefix,$escape,$vex);
             }
         }
@@ -536,31 +633,360 @@ macro_rules! avx_mem_instr {
     };
 }
 
+/// `$feature` is the extension of the 256-bit form, AVX if left out. Most integer
+/// instructions were extended to YMM registers by AVX2.
+macro_rules! avx_ymm_instr {
+    ($name: ident, $op: expr, $prefix: expr,$escape: expr,$vex: expr) => {
+        avx_ymm_instr!($name, $op, $prefix, $escape, $vex, Avx);
+    };
+    ($name: ident, $op: expr, $prefix: expr,$escape: expr,$vex: expr, $feature: ident) => {
+        paste::item! {
+            #[no_mangle]
+            pub extern "C" fn [<$name _ymm>](buf: &mut Assembler, dst: YMMRegister, src1: YMMRegister, src2: YMMRegister) {
+                buf.require(CpuFeature::$feature);
+                vinstr_v(buf, $op, dst, src1, src2, $prefix, $escape, $vex);
+            }
+
+            #[no_mangle]
+            pub extern "C" fn [<$name _ymm_mem>](buf: &mut Assembler, dst: YMMRegister, src1: YMMRegister, src2: Mem) {
+                buf.require(CpuFeature::$feature);
+                vinstrm_v(buf, $op, dst, src1, src2, $prefix, $escape, $vex);
+            }
+        }
+    };
+}
+
+/// `$xmm` and `$ymm` are the extensions of the 128 and 256-bit forms, AVX if left
+/// out. A single one applies to the 256-bit forms.
+macro_rules! avx_packed_instr {
+    ($name: ident, $op: expr, $prefix: expr,$escape: expr,$vex: expr) => {
+        avx_packed_instr!($name, $op, $prefix, $escape, $vex, Avx, Avx);
+    };
+    ($name: ident, $op: expr, $prefix: expr,$escape: expr,$vex: expr, $ymm: ident) => {
+        avx_packed_instr!($name, $op, $prefix, $escape, $vex, Avx, $ymm);
+    };
+    ($name: ident, $op: expr, $prefix: expr,$escape: expr,$vex: expr, $xmm: ident, $ymm: ident) => {
+        avx_instr!($name, $op, $prefix, $escape, $vex, $xmm);
+        avx_ymm_instr!($name, $op, $prefix, $escape, $vex, $ymm);
+    };
+}
+
+/// Instructions with a single source, VEX.vvvv is unused.
 macro_rules! avx_instr_2op {
-    ($name: ident,$op: expr,$prefix: expr,$escape: expr,$vex: expr,$vlen: expr) => {
-        pub extern "C" fn $name(asm: &mut Assembler, dst: XMMRegister, src: XMMRegister) {
-            emit_vex_prefixf(asm, dst, XMM0, src, $vlen, $prefix, $escape, $vex);
-            asm.emit($op);
-            emit_sse_ff(asm, dst, src);
-            asm.data.pop();
+    ($name: ident, $op: expr, $prefix: expr,$escape: expr,$vex: expr) => {
+        avx_instr_2op!($name, $op, $prefix, $escape, $vex, Avx, Avx);
+    };
+    ($name: ident, $op: expr, $prefix: expr,$escape: expr,$vex: expr, $ymm: ident) => {
+        avx_instr_2op!($name, $op, $prefix, $escape, $vex, Avx, $ymm);
+    };
+    ($name: ident, $op: expr, $prefix: expr,$escape: expr,$vex: expr, $xmm: ident, $ymm: ident) => {
+        paste::item! {
+            #[no_mangle]
+            pub extern "C" fn $name(buf: &mut Assembler, dst: XMMRegister, src: XMMRegister) {
+                buf.require(CpuFeature::$xmm);
+                vinstr_v(buf, $op, dst, XMM0, src, $prefix, $escape, $vex);
+            }
+
+            #[no_mangle]
+            pub extern "C" fn [<$name _mem>](buf: &mut Assembler, dst: XMMRegister, src: Mem) {
+                buf.require(CpuFeature::$xmm);
+                vinstrm_v(buf, $op, dst, XMM0, src, $prefix, $escape, $vex);
+            }
+
+            #[no_mangle]
+            pub extern "C" fn [<$name _ymm>](buf: &mut Assembler, dst: YMMRegister, src: YMMRegister) {
+                buf.require(CpuFeature::$ymm);
+                vinstr_v(buf, $op, dst, YMM0, src, $prefix, $escape, $vex);
+            }
+
+            #[no_mangle]
+            pub extern "C" fn [<$name _ymm_mem>](buf: &mut Assembler, dst: YMMRegister, src: Mem) {
+                buf.require(CpuFeature::$ymm);
+                vinstrm_v(buf, $op, dst, YMM0, src, $prefix, $escape, $vex);
+            }
         }
-        paste::item!(
-        pub extern "C" fn [<$name _mem>](asm: &mut Assembler, dst: XMMRegister, src: Mem) {
-            emit_vex_prefixfm(asm, dst, XMM0, src, $vlen, $prefix, $escape, $vex);
-            asm.emit($op);
-            emit_sse_mem_f(asm, dst, src);
-            asm.data.pop();
+    };
+}
+
+/// Two sources followed by an immediate byte.
+macro_rules! avx_instr_imm {
+    ($name: ident, $op: expr, $prefix: expr,$escape: expr,$vex: expr) => {
+        avx_instr_imm!($name, $op, $prefix, $escape, $vex, Avx, Avx);
+    };
+    ($name: ident, $op: expr, $prefix: expr,$escape: expr,$vex: expr, $ymm: ident) => {
+        avx_instr_imm!($name, $op, $prefix, $escape, $vex, Avx, $ymm);
+    };
+    ($name: ident, $op: expr, $prefix: expr,$escape: expr,$vex: expr, $xmm: ident, $ymm: ident) => {
+        paste::item! {
+            #[no_mangle]
+            pub extern "C" fn $name(buf: &mut Assembler, dst: XMMRegister, src1: XMMRegister, src2: XMMRegister, imm: u8) {
+                buf.require(CpuFeature::$xmm);
+                vinstr_v(buf, $op, dst, src1, src2, $prefix, $escape, $vex);
+                buf.emit(imm);
+            }
+
+            #[no_mangle]
+            pub extern "C" fn [<$name _mem>](buf: &mut Assembler, dst: XMMRegister, src1: XMMRegister, src2: Mem, imm: u8) {
+                buf.require(CpuFeature::$xmm);
+                vinstrm_v(buf, $op, dst, src1, src2, $prefix, $escape, $vex);
+                buf.imm_follows_mem(1);
+                buf.emit(imm);
+            }
+
+            #[no_mangle]
+            pub extern "C" fn [<$name _ymm>](buf: &mut Assembler, dst: YMMRegister, src1: YMMRegister, src2: YMMRegister, imm: u8) {
+                buf.require(CpuFeature::$ymm);
+                vinstr_v(buf, $op, dst, src1, src2, $prefix, $escape, $vex);
+                buf.emit(imm);
+            }
+
+            #[no_mangle]
+            pub extern "C" fn [<$name _ymm_mem>](buf: &mut Assembler, dst: YMMRegister, src1: YMMRegister, src2: Mem, imm: u8) {
+                buf.require(CpuFeature::$ymm);
+                vinstrm_v(buf, $op, dst, src1, src2, $prefix, $escape, $vex);
+                buf.imm_follows_mem(1);
+                buf.emit(imm);
+            }
         }
-        );
     };
 }
-#[no_mangle]
-pub extern "C" fn vmovaps(buf: &mut Assembler, dst: XMMRegister, src: XMMRegister) {
-    vps(buf, 0x28, dst, XMM0, src);
+
+/// A single source followed by an immediate byte.
+macro_rules! avx_instr_2op_imm {
+    ($name: ident, $op: expr, $prefix: expr,$escape: expr,$vex: expr) => {
+        avx_instr_2op_imm!($name, $op, $prefix, $escape, $vex, Avx, Avx);
+    };
+    ($name: ident, $op: expr, $prefix: expr,$escape: expr,$vex: expr, $ymm: ident) => {
+        avx_instr_2op_imm!($name, $op, $prefix, $escape, $vex, Avx, $ymm);
+    };
+    ($name: ident, $op: expr, $prefix: expr,$escape: expr,$vex: expr, $xmm: ident, $ymm: ident) => {
+        paste::item! {
+            #[no_mangle]
+            pub extern "C" fn $name(buf: &mut Assembler, dst: XMMRegister, src: XMMRegister, imm: u8) {
+                buf.require(CpuFeature::$xmm);
+                vinstr_v(buf, $op, dst, XMM0, src, $prefix, $escape, $vex);
+                buf.emit(imm);
+            }
+
+            #[no_mangle]
+            pub extern "C" fn [<$name _mem>](buf: &mut Assembler, dst: XMMRegister, src: Mem, imm: u8) {
+                buf.require(CpuFeature::$xmm);
+                vinstrm_v(buf, $op, dst, XMM0, src, $prefix, $escape, $vex);
+                buf.imm_follows_mem(1);
+                buf.emit(imm);
+            }
+
+            #[no_mangle]
+            pub extern "C" fn [<$name _ymm>](buf: &mut Assembler, dst: YMMRegister, src: YMMRegister, imm: u8) {
+                buf.require(CpuFeature::$ymm);
+                vinstr_v(buf, $op, dst, YMM0, src, $prefix, $escape, $vex);
+                buf.emit(imm);
+            }
+
+            #[no_mangle]
+            pub extern "C" fn [<$name _ymm_mem>](buf: &mut Assembler, dst: YMMRegister, src: Mem, imm: u8) {
+                buf.require(CpuFeature::$ymm);
+                vinstrm_v(buf, $op, dst, YMM0, src, $prefix, $escape, $vex);
+                buf.imm_follows_mem(1);
+                buf.emit(imm);
+            }
+        }
+    };
 }
-#[no_mangle]
-pub extern "C" fn vmovaps_mem(buf: &mut Assembler, dst: XMMRegister, src: Mem) {
-    vpsm(buf, 0x28, dst, XMM0, src);
+
+/// Register moves and loads use `$load`, stores `$store`.
+macro_rules! avx_mov {
+    ($name: ident, $prefix: expr, $load: expr, $store: expr) => {
+        paste::item! {
+            #[no_mangle]
+            pub extern "C" fn $name(buf: &mut Assembler, dst: XMMRegister, src: XMMRegister) {
+                vinstr_v(buf, $load, dst, XMM0, src, $prefix, LeadingOpcode::k0F, WIG);
+            }
+
+            #[no_mangle]
+            pub extern "C" fn [<$name _mem>](buf: &mut Assembler, dst: XMMRegister, src: Mem) {
+                vinstrm_v(buf, $load, dst, XMM0, src, $prefix, LeadingOpcode::k0F, WIG);
+            }
+
+            #[no_mangle]
+            pub extern "C" fn [<$name _store>](buf: &mut Assembler, dst: Mem, src: XMMRegister) {
+                vinstrm_v(buf, $store, src, XMM0, dst, $prefix, LeadingOpcode::k0F, WIG);
+            }
+
+            #[no_mangle]
+            pub extern "C" fn [<$name _ymm>](buf: &mut Assembler, dst: YMMRegister, src: YMMRegister) {
+                vinstr_v(buf, $load, dst, YMM0, src, $prefix, LeadingOpcode::k0F, WIG);
+            }
+
+            #[no_mangle]
+            pub extern "C" fn [<$name _ymm_mem>](buf: &mut Assembler, dst: YMMRegister, src: Mem) {
+                vinstrm_v(buf, $load, dst, YMM0, src, $prefix, LeadingOpcode::k0F, WIG);
+            }
+
+            #[no_mangle]
+            pub extern "C" fn [<$name _ymm_store>](buf: &mut Assembler, dst: Mem, src: YMMRegister) {
+                vinstrm_v(buf, $store, src, YMM0, dst, $prefix, LeadingOpcode::k0F, WIG);
+            }
+        }
+    };
+}
+
+/// Shifts by the count in the low quadword of an XMM register or by an immediate.
+/// The immediate forms encode the operation in ModRM.reg and the destination in
+/// VEX.vvvv. The XMM forms by register of the word and dword shifts are defined in
+/// `assembler_x64`.
+macro_rules! avx_shift {
+    ($name: ident, $op: expr, $imm_op: expr, $ext: expr) => {
+        paste::item! {
+            #[no_mangle]
+            pub extern "C" fn [<$name _ymm>](buf: &mut Assembler, dst: YMMRegister, src: YMMRegister, count: XMMRegister) {
+                buf.require(CpuFeature::Avx2);
+                vex_rr(buf, $op, dst.code(), src.code(), count.code(), VectorLength::kL256, SIMDPrefix::k0x66, LeadingOpcode::k0F, WIG);
+            }
+
+            #[no_mangle]
+            pub extern "C" fn [<$name _ymm_mem>](buf: &mut Assembler, dst: YMMRegister, src: YMMRegister, count: Mem) {
+                buf.require(CpuFeature::Avx2);
+                vex_rm(buf, $op, dst.code(), src.code(), count, VectorLength::kL256, SIMDPrefix::k0x66, LeadingOpcode::k0F, WIG);
+            }
+
+            #[no_mangle]
+            pub extern "C" fn [<$name _imm>](buf: &mut Assembler, dst: XMMRegister, src: XMMRegister, imm: u8) {
+                vex_rr(buf, $imm_op, $ext, dst.code(), src.code(), VectorLength::kL128, SIMDPrefix::k0x66, LeadingOpcode::k0F, WIG);
+                buf.emit(imm);
+            }
+
+            #[no_mangle]
+            pub extern "C" fn [<$name _ymm_imm>](buf: &mut Assembler, dst: YMMRegister, src: YMMRegister, imm: u8) {
+                buf.require(CpuFeature::Avx2);
+                vex_rr(buf, $imm_op, $ext, dst.code(), src.code(), VectorLength::kL256, SIMDPrefix::k0x66, LeadingOpcode::k0F, WIG);
+                buf.emit(imm);
+            }
+        }
+    };
+}
+
+/// Broadcasts of the low element of an XMM register (AVX2) or of a memory operand.
+/// `$mem` is the extension of the forms with a memory source.
+macro_rules! avx_broadcast {
+    ($name: ident, $op: expr, $mem: ident) => {
+        paste::item! {
+            #[no_mangle]
+            pub extern "C" fn $name(buf: &mut Assembler, dst: XMMRegister, src: XMMRegister) {
+                buf.require(CpuFeature::Avx2);
+                vex_rr(buf, $op, dst.code(), 0, src.code(), VectorLength::kL128, SIMDPrefix::k0x66, LeadingOpcode::k0F38, VexW::W0);
+            }
+
+            #[no_mangle]
+            pub extern "C" fn [<$name _mem>](buf: &mut Assembler, dst: XMMRegister, src: Mem) {
+                buf.require(CpuFeature::$mem);
+                vex_rm(buf, $op, dst.code(), 0, src, VectorLength::kL128, SIMDPrefix::k0x66, LeadingOpcode::k0F38, VexW::W0);
+            }
+
+            #[no_mangle]
+            pub extern "C" fn [<$name _ymm>](buf: &mut Assembler, dst: YMMRegister, src: XMMRegister) {
+                buf.require(CpuFeature::Avx2);
+                vex_rr(buf, $op, dst.code(), 0, src.code(), VectorLength::kL256, SIMDPrefix::k0x66, LeadingOpcode::k0F38, VexW::W0);
+            }
+
+            #[no_mangle]
+            pub extern "C" fn [<$name _ymm_mem>](buf: &mut Assembler, dst: YMMRegister, src: Mem) {
+                buf.require(CpuFeature::$mem);
+                vex_rm(buf, $op, dst.code(), 0, src, VectorLength::kL256, SIMDPrefix::k0x66, LeadingOpcode::k0F38, VexW::W0);
+            }
+        }
+    };
+}
+
+/// The packed and scalar, single and double precision forms of an FMA instruction.
+/// `$op` is the packed opcode, the scalar one follows it.
+macro_rules! avx_fma {
+    ($name: ident, $op: expr) => {
+        paste::item! {
+            avx_packed_instr!([<$name ps>], $op, SIMDPrefix::k0x66, LeadingOpcode::k0F38, VexW::W0, Fma, Fma);
+            avx_packed_instr!([<$name pd>], $op, SIMDPrefix::k0x66, LeadingOpcode::k0F38, VexW::W1, Fma, Fma);
+            avx_instr!([<$name ss>], $op + 1, SIMDPrefix::k0x66, LeadingOpcode::k0F38, VexW::W0, Fma);
+            avx_instr!([<$name sd>], $op + 1, SIMDPrefix::k0x66, LeadingOpcode::k0F38, VexW::W1, Fma);
+        }
+    };
+}
+
+/// Gathers with the VSIB operand `[base + index * scale + disp]`, `index` holds one
+/// index per element. Elements whose sign bit in `mask` is set are loaded and the
+/// mask is cleared. `$ymm_dst` and `$ymm_index` are the register types of the
+/// 256-bit form.
+macro_rules! avx_gather {
+    ($name: ident, $op: expr, $vex: expr, $ymm_dst: ty, $ymm_index: ty) => {
+        paste::item! {
+            #[no_mangle]
+            pub extern "C" fn $name(buf: &mut Assembler, dst: XMMRegister, base: Register, index: XMMRegister, scale: i32, disp: i32, mask: XMMRegister) {
+                vgather(buf, $op, $vex, VectorLength::kL128, dst.code(), base, index.code(), scale, disp, mask.code());
+            }
+
+            #[no_mangle]
+            pub extern "C" fn [<$name _ymm>](buf: &mut Assembler, dst: $ymm_dst, base: Register, index: $ymm_index, scale: i32, disp: i32, mask: $ymm_dst) {
+                vgather(buf, $op, $vex, VectorLength::kL256, dst.code(), base, index.code(), scale, disp, mask.code());
+            }
+        }
+    };
+}
+
+#[allow(clippy::too_many_arguments)]
+fn vgather(asm: &mut Assembler,
+           op: u8,
+           w: VexW,
+           l: VectorLength,
+           dst: u8,
+           base: Register,
+           index: u8,
+           scale: i32,
+           disp: i32,
+           mask: u8) {
+    asm.require(CpuFeature::Avx2);
+
+    if dst == index || dst == mask || index == mask {
+        asm.invalid_operand::<()>("destination, index and mask of a gather must be distinct");
+    }
+
+    if base.msb() > 1 {
+        asm.invalid_operand::<()>("gathers need a general purpose base register");
+    }
+
+    check_vex_regs(asm, &[dst, index, mask]);
+
+    let scale = match scale {
+        1 => 0,
+        2 => 1,
+        4 => 2,
+        8 => 3,
+        _ => asm.invalid_operand(format!("invalid scale {}", scale)),
+    };
+
+    emit_vex_prefix(asm,
+                    dst >> 3 & 1,
+                    index >> 3 & 1,
+                    base.msb() & 1,
+                    mask,
+                    l,
+                    SIMDPrefix::k0x66,
+                    LeadingOpcode::k0F38,
+                    w);
+    asm.emit(op);
+
+    // like a SIB operand without index restrictions, [rbp + index] and
+    // [r13 + index] need an explicit displacement
+    if disp == 0 && base.and7() != RBP.and7() {
+        emit_modrm(asm, 0b00, dst & 7, 0b100);
+        emit_sib(asm, scale, index & 7, base.and7());
+    } else if fits_i8(disp) {
+        emit_modrm(asm, 0b01, dst & 7, 0b100);
+        emit_sib(asm, scale, index & 7, base.and7());
+        asm.emit(disp as u8);
+    } else {
+        emit_modrm(asm, 0b10, dst & 7, 0b100);
+        emit_sib(asm, scale, index & 7, base.and7());
+        emit32(asm, disp as u32);
+    }
 }
 
 avx_instr!(vaddps, 0x58, SIMDPrefix::None, LeadingOpcode::k0F, VexW::W0);
@@ -653,21 +1079,414 @@ avx_instr!(vsqrtsd,
            LeadingOpcode::k0F,
            VexW::W0);
 
-avx_instr_2op!(vbroadcastf128,
-               0x1a,
-               SIMDPrefix::k0x66,
-               LeadingOpcode::k0F3A,
-               VexW::W1,
-               VectorLength::kL256);
-avx_instr_2op!(vbroadcasti128,
-               0x5a,
-               SIMDPrefix::k0x66,
-               LeadingOpcode::k0F38,
-               VexW::W0,
-               VectorLength::kL128);
-avx_instr_2op!(vbroadcastsd,
-               0x19,
-               SIMDPrefix::k0x66,
-               LeadingOpcode::k0F38,
-               VexW::W1,
-               VectorLength::kL128);
+avx_instr!(vminss, 0x5d, SIMDPrefix::k0xf3, LeadingOpcode::k0F, VexW::W0);
+avx_instr!(vminsd, 0x5d, SIMDPrefix::k0xf2, LeadingOpcode::k0F, VexW::W0);
+avx_instr!(vmaxss, 0x5f, SIMDPrefix::k0xf3, LeadingOpcode::k0F, VexW::W0);
+avx_instr!(vmaxsd, 0x5f, SIMDPrefix::k0xf2, LeadingOpcode::k0F, VexW::W0);
+
+// 256-bit forms of the packed instructions above and in `assembler_x64`
+avx_ymm_instr!(vaddps, 0x58, SIMDPrefix::None, LeadingOpcode::k0F, VexW::W0);
+avx_ymm_instr!(vaddpd, 0x58, SIMDPrefix::k0x66, LeadingOpcode::k0F, VexW::W0);
+avx_ymm_instr!(vandnps, 0x55, SIMDPrefix::None, LeadingOpcode::k0F, VexW::W0);
+avx_ymm_instr!(vandnpd, 0x55, SIMDPrefix::k0x66, LeadingOpcode::k0F, VexW::W0);
+avx_ymm_instr!(vsubps, 0x5c, SIMDPrefix::None, LeadingOpcode::k0F, VexW::W0);
+avx_ymm_instr!(vsubpd, 0x5c, SIMDPrefix::k0x66, LeadingOpcode::k0F, VexW::W0);
+avx_ymm_instr!(vmulps, 0x59, SIMDPrefix::None, LeadingOpcode::k0F, VexW::W0);
+avx_ymm_instr!(vmulpd, 0x59, SIMDPrefix::k0x66, LeadingOpcode::k0F, VexW::W0);
+avx_ymm_instr!(vdivps, 0x5e, SIMDPrefix::None, LeadingOpcode::k0F, VexW::W0);
+avx_ymm_instr!(vdivpd, 0x5e, SIMDPrefix::k0x66, LeadingOpcode::k0F, VexW::W0);
+avx_ymm_instr!(vsqrtps, 0x51, SIMDPrefix::None, LeadingOpcode::k0F, VexW::W0);
+avx_ymm_instr!(vsqrtpd, 0x51, SIMDPrefix::k0x66, LeadingOpcode::k0F, VexW::W0);
+avx_ymm_instr!(vcvtps2dq, 0x5b, SIMDPrefix::k0x66, LeadingOpcode::k0F, VexW::W0);
+avx_ymm_instr!(vpunpcklbw, 0x60, SIMDPrefix::k0x66, LeadingOpcode::k0F, VexW::W0, Avx2);
+avx_ymm_instr!(vpunpcklwd, 0x61, SIMDPrefix::k0x66, LeadingOpcode::k0F, VexW::W0, Avx2);
+avx_ymm_instr!(vpunpckldq, 0x62, SIMDPrefix::k0x66, LeadingOpcode::k0F, VexW::W0, Avx2);
+avx_ymm_instr!(vpacksswb, 0x63, SIMDPrefix::k0x66, LeadingOpcode::k0F, VexW::W0, Avx2);
+avx_ymm_instr!(vpackuswb, 0x67, SIMDPrefix::k0x66, LeadingOpcode::k0F, VexW::W0, Avx2);
+avx_ymm_instr!(vpunpckhbw, 0x68, SIMDPrefix::k0x66, LeadingOpcode::k0F, VexW::W0, Avx2);
+avx_ymm_instr!(vpunpckhwd, 0x69, SIMDPrefix::k0x66, LeadingOpcode::k0F, VexW::W0, Avx2);
+avx_ymm_instr!(vpunpckhdq, 0x6a, SIMDPrefix::k0x66, LeadingOpcode::k0F, VexW::W0, Avx2);
+avx_ymm_instr!(vpackssdw, 0x6b, SIMDPrefix::k0x66, LeadingOpcode::k0F, VexW::W0, Avx2);
+avx_ymm_instr!(vpunpcklqdq, 0x6c, SIMDPrefix::k0x66, LeadingOpcode::k0F, VexW::W0, Avx2);
+avx_ymm_instr!(vpunpckhqdq, 0x6d, SIMDPrefix::k0x66, LeadingOpcode::k0F, VexW::W0, Avx2);
+avx_ymm_instr!(vpaddb, 0xfc, SIMDPrefix::k0x66, LeadingOpcode::k0F, VexW::W0, Avx2);
+avx_ymm_instr!(vpaddw, 0xfd, SIMDPrefix::k0x66, LeadingOpcode::k0F, VexW::W0, Avx2);
+avx_ymm_instr!(vpaddd, 0xfe, SIMDPrefix::k0x66, LeadingOpcode::k0F, VexW::W0, Avx2);
+avx_ymm_instr!(vpaddsb, 0xec, SIMDPrefix::k0x66, LeadingOpcode::k0F, VexW::W0, Avx2);
+avx_ymm_instr!(vpaddsw, 0xed, SIMDPrefix::k0x66, LeadingOpcode::k0F, VexW::W0, Avx2);
+avx_ymm_instr!(vpaddusb, 0xdc, SIMDPrefix::k0x66, LeadingOpcode::k0F, VexW::W0, Avx2);
+avx_ymm_instr!(vpaddusw, 0xdd, SIMDPrefix::k0x66, LeadingOpcode::k0F, VexW::W0, Avx2);
+avx_ymm_instr!(vpcmpeqb, 0x74, SIMDPrefix::k0x66, LeadingOpcode::k0F, VexW::W0, Avx2);
+avx_ymm_instr!(vpcmpeqw, 0x75, SIMDPrefix::k0x66, LeadingOpcode::k0F, VexW::W0, Avx2);
+avx_ymm_instr!(vpcmpeqd, 0x76, SIMDPrefix::k0x66, LeadingOpcode::k0F, VexW::W0, Avx2);
+avx_ymm_instr!(vpcmpgtb, 0x64, SIMDPrefix::k0x66, LeadingOpcode::k0F, VexW::W0, Avx2);
+avx_ymm_instr!(vpcmpgtw, 0x65, SIMDPrefix::k0x66, LeadingOpcode::k0F, VexW::W0, Avx2);
+avx_ymm_instr!(vpcmpgtd, 0x66, SIMDPrefix::k0x66, LeadingOpcode::k0F, VexW::W0, Avx2);
+avx_ymm_instr!(vpmaxsw, 0xee, SIMDPrefix::k0x66, LeadingOpcode::k0F, VexW::W0, Avx2);
+avx_ymm_instr!(vpmaxub, 0xde, SIMDPrefix::k0x66, LeadingOpcode::k0F, VexW::W0, Avx2);
+avx_ymm_instr!(vpminsw, 0xea, SIMDPrefix::k0x66, LeadingOpcode::k0F, VexW::W0, Avx2);
+avx_ymm_instr!(vpminub, 0xda, SIMDPrefix::k0x66, LeadingOpcode::k0F, VexW::W0, Avx2);
+avx_ymm_instr!(vpmullw, 0xd5, SIMDPrefix::k0x66, LeadingOpcode::k0F, VexW::W0, Avx2);
+avx_ymm_instr!(vpmuludq, 0xf4, SIMDPrefix::k0x66, LeadingOpcode::k0F, VexW::W0, Avx2);
+avx_ymm_instr!(vpsubb, 0xf8, SIMDPrefix::k0x66, LeadingOpcode::k0F, VexW::W0, Avx2);
+avx_ymm_instr!(vpsubw, 0xf9, SIMDPrefix::k0x66, LeadingOpcode::k0F, VexW::W0, Avx2);
+avx_ymm_instr!(vpsubd, 0xfa, SIMDPrefix::k0x66, LeadingOpcode::k0F, VexW::W0, Avx2);
+avx_ymm_instr!(vpsubsb, 0xe8, SIMDPrefix::k0x66, LeadingOpcode::k0F, VexW::W0, Avx2);
+avx_ymm_instr!(vpsubsw, 0xe9, SIMDPrefix::k0x66, LeadingOpcode::k0F, VexW::W0, Avx2);
+avx_ymm_instr!(vpsubusb, 0xd8, SIMDPrefix::k0x66, LeadingOpcode::k0F, VexW::W0, Avx2);
+avx_ymm_instr!(vpsubusw, 0xd9, SIMDPrefix::k0x66, LeadingOpcode::k0F, VexW::W0, Avx2);
+avx_ymm_instr!(vpand, 0xdb, SIMDPrefix::k0x66, LeadingOpcode::k0F, VexW::W0, Avx2);
+avx_ymm_instr!(vpor, 0xeb, SIMDPrefix::k0x66, LeadingOpcode::k0F, VexW::W0, Avx2);
+avx_ymm_instr!(vpxor, 0xef, SIMDPrefix::k0x66, LeadingOpcode::k0F, VexW::W0, Avx2);
+
+avx_packed_instr!(vandps, 0x54, SIMDPrefix::None, LeadingOpcode::k0F, VexW::W0);
+avx_packed_instr!(vandpd, 0x54, SIMDPrefix::k0x66, LeadingOpcode::k0F, VexW::W0);
+avx_packed_instr!(vorps, 0x56, SIMDPrefix::None, LeadingOpcode::k0F, VexW::W0);
+avx_packed_instr!(vorpd, 0x56, SIMDPrefix::k0x66, LeadingOpcode::k0F, VexW::W0);
+avx_packed_instr!(vxorps, 0x57, SIMDPrefix::None, LeadingOpcode::k0F, VexW::W0);
+avx_packed_instr!(vxorpd, 0x57, SIMDPrefix::k0x66, LeadingOpcode::k0F, VexW::W0);
+avx_packed_instr!(vminps, 0x5d, SIMDPrefix::None, LeadingOpcode::k0F, VexW::W0);
+avx_packed_instr!(vminpd, 0x5d, SIMDPrefix::k0x66, LeadingOpcode::k0F, VexW::W0);
+avx_packed_instr!(vmaxps, 0x5f, SIMDPrefix::None, LeadingOpcode::k0F, VexW::W0);
+avx_packed_instr!(vmaxpd, 0x5f, SIMDPrefix::k0x66, LeadingOpcode::k0F, VexW::W0);
+avx_packed_instr!(vhaddps, 0x7c, SIMDPrefix::k0xf2, LeadingOpcode::k0F, VexW::W0);
+avx_packed_instr!(vhaddpd, 0x7c, SIMDPrefix::k0x66, LeadingOpcode::k0F, VexW::W0);
+avx_packed_instr!(vunpcklps, 0x14, SIMDPrefix::None, LeadingOpcode::k0F, VexW::W0);
+avx_packed_instr!(vunpckhps, 0x15, SIMDPrefix::None, LeadingOpcode::k0F, VexW::W0);
+avx_packed_instr!(vunpcklpd, 0x14, SIMDPrefix::k0x66, LeadingOpcode::k0F, VexW::W0);
+avx_packed_instr!(vunpckhpd, 0x15, SIMDPrefix::k0x66, LeadingOpcode::k0F, VexW::W0);
+
+avx_packed_instr!(vpaddq, 0xd4, SIMDPrefix::k0x66, LeadingOpcode::k0F, VexW::W0, Avx2);
+avx_packed_instr!(vpsubq, 0xfb, SIMDPrefix::k0x66, LeadingOpcode::k0F, VexW::W0, Avx2);
+avx_packed_instr!(vpandn, 0xdf, SIMDPrefix::k0x66, LeadingOpcode::k0F, VexW::W0, Avx2);
+avx_packed_instr!(vpavgb, 0xe0, SIMDPrefix::k0x66, LeadingOpcode::k0F, VexW::W0, Avx2);
+avx_packed_instr!(vpavgw, 0xe3, SIMDPrefix::k0x66, LeadingOpcode::k0F, VexW::W0, Avx2);
+avx_packed_instr!(vpmulhuw, 0xe4, SIMDPrefix::k0x66, LeadingOpcode::k0F, VexW::W0, Avx2);
+avx_packed_instr!(vpmulhw, 0xe5, SIMDPrefix::k0x66, LeadingOpcode::k0F, VexW::W0, Avx2);
+avx_packed_instr!(vpmaddwd, 0xf5, SIMDPrefix::k0x66, LeadingOpcode::k0F, VexW::W0, Avx2);
+avx_packed_instr!(vpsadbw, 0xf6, SIMDPrefix::k0x66, LeadingOpcode::k0F, VexW::W0, Avx2);
+avx_packed_instr!(vpshufb, 0x00, SIMDPrefix::k0x66, LeadingOpcode::k0F38, VexW::W0, Avx2);
+avx_packed_instr!(vpmuldq, 0x28, SIMDPrefix::k0x66, LeadingOpcode::k0F38, VexW::W0, Avx2);
+avx_packed_instr!(vpcmpeqq, 0x29, SIMDPrefix::k0x66, LeadingOpcode::k0F38, VexW::W0, Avx2);
+avx_packed_instr!(vpackusdw, 0x2b, SIMDPrefix::k0x66, LeadingOpcode::k0F38, VexW::W0, Avx2);
+avx_packed_instr!(vpcmpgtq, 0x37, SIMDPrefix::k0x66, LeadingOpcode::k0F38, VexW::W0, Avx2);
+avx_packed_instr!(vpminsb, 0x38, SIMDPrefix::k0x66, LeadingOpcode::k0F38, VexW::W0, Avx2);
+avx_packed_instr!(vpminsd, 0x39, SIMDPrefix::k0x66, LeadingOpcode::k0F38, VexW::W0, Avx2);
+avx_packed_instr!(vpminuw, 0x3a, SIMDPrefix::k0x66, LeadingOpcode::k0F38, VexW::W0, Avx2);
+avx_packed_instr!(vpminud, 0x3b, SIMDPrefix::k0x66, LeadingOpcode::k0F38, VexW::W0, Avx2);
+avx_packed_instr!(vpmaxsb, 0x3c, SIMDPrefix::k0x66, LeadingOpcode::k0F38, VexW::W0, Avx2);
+avx_packed_instr!(vpmaxsd, 0x3d, SIMDPrefix::k0x66, LeadingOpcode::k0F38, VexW::W0, Avx2);
+avx_packed_instr!(vpmaxuw, 0x3e, SIMDPrefix::k0x66, LeadingOpcode::k0F38, VexW::W0, Avx2);
+avx_packed_instr!(vpmaxud, 0x3f, SIMDPrefix::k0x66, LeadingOpcode::k0F38, VexW::W0, Avx2);
+avx_packed_instr!(vpmulld, 0x40, SIMDPrefix::k0x66, LeadingOpcode::k0F38, VexW::W0, Avx2);
+avx_packed_instr!(vpsrlvd, 0x45, SIMDPrefix::k0x66, LeadingOpcode::k0F38, VexW::W0, Avx2, Avx2);
+avx_packed_instr!(vpsrlvq, 0x45, SIMDPrefix::k0x66, LeadingOpcode::k0F38, VexW::W1, Avx2, Avx2);
+avx_packed_instr!(vpsravd, 0x46, SIMDPrefix::k0x66, LeadingOpcode::k0F38, VexW::W0, Avx2, Avx2);
+avx_packed_instr!(vpsllvd, 0x47, SIMDPrefix::k0x66, LeadingOpcode::k0F38, VexW::W0, Avx2, Avx2);
+avx_packed_instr!(vpsllvq, 0x47, SIMDPrefix::k0x66, LeadingOpcode::k0F38, VexW::W1, Avx2, Avx2);
+avx_packed_instr!(vpermilps, 0x0c, SIMDPrefix::k0x66, LeadingOpcode::k0F38, VexW::W0);
+avx_packed_instr!(vpermilpd, 0x0d, SIMDPrefix::k0x66, LeadingOpcode::k0F38, VexW::W0);
+
+avx_instr!(vpsllq, 0xf3, SIMDPrefix::k0x66, LeadingOpcode::k0F, VexW::W0);
+avx_instr!(vpsrlq, 0xd3, SIMDPrefix::k0x66, LeadingOpcode::k0F, VexW::W0);
+
+avx_shift!(vpsllw, 0xf1, 0x71, 6);
+avx_shift!(vpslld, 0xf2, 0x72, 6);
+avx_shift!(vpsllq, 0xf3, 0x73, 6);
+avx_shift!(vpsraw, 0xe1, 0x71, 4);
+avx_shift!(vpsrad, 0xe2, 0x72, 4);
+avx_shift!(vpsrlw, 0xd1, 0x71, 2);
+avx_shift!(vpsrld, 0xd2, 0x72, 2);
+avx_shift!(vpsrlq, 0xd3, 0x73, 2);
+
+avx_instr_2op!(vcvtdq2ps, 0x5b, SIMDPrefix::None, LeadingOpcode::k0F, VexW::W0);
+avx_instr_2op!(vcvttps2dq, 0x5b, SIMDPrefix::k0xf3, LeadingOpcode::k0F, VexW::W0);
+avx_instr_2op!(vrsqrtps, 0x52, SIMDPrefix::None, LeadingOpcode::k0F, VexW::W0);
+avx_instr_2op!(vrcpps, 0x53, SIMDPrefix::None, LeadingOpcode::k0F, VexW::W0);
+avx_instr_2op!(vptest, 0x17, SIMDPrefix::k0x66, LeadingOpcode::k0F38, VexW::W0);
+avx_instr_2op!(vpabsb, 0x1c, SIMDPrefix::k0x66, LeadingOpcode::k0F38, VexW::W0, Avx2);
+avx_instr_2op!(vpabsw, 0x1d, SIMDPrefix::k0x66, LeadingOpcode::k0F38, VexW::W0, Avx2);
+avx_instr_2op!(vpabsd, 0x1e, SIMDPrefix::k0x66, LeadingOpcode::k0F38, VexW::W0, Avx2);
+
+avx_mov!(vmovups, SIMDPrefix::None, 0x10, 0x11);
+avx_mov!(vmovupd, SIMDPrefix::k0x66, 0x10, 0x11);
+avx_mov!(vmovaps, SIMDPrefix::None, 0x28, 0x29);
+avx_mov!(vmovapd, SIMDPrefix::k0x66, 0x28, 0x29);
+avx_mov!(vmovdqa, SIMDPrefix::k0x66, 0x6f, 0x7f);
+avx_mov!(vmovdqu, SIMDPrefix::k0xf3, 0x6f, 0x7f);
+
+avx_instr_imm!(vshufps, 0xc6, SIMDPrefix::None, LeadingOpcode::k0F, VexW::W0);
+avx_instr_imm!(vshufpd, 0xc6, SIMDPrefix::k0x66, LeadingOpcode::k0F, VexW::W0);
+avx_instr_imm!(vpblendd, 0x02, SIMDPrefix::k0x66, LeadingOpcode::k0F3A, VexW::W0, Avx2, Avx2);
+avx_instr_imm!(vblendps, 0x0c, SIMDPrefix::k0x66, LeadingOpcode::k0F3A, VexW::W0);
+avx_instr_imm!(vblendpd, 0x0d, SIMDPrefix::k0x66, LeadingOpcode::k0F3A, VexW::W0);
+avx_instr_imm!(vpblendw, 0x0e, SIMDPrefix::k0x66, LeadingOpcode::k0F3A, VexW::W0, Avx2);
+avx_instr_imm!(vpalignr, 0x0f, SIMDPrefix::k0x66, LeadingOpcode::k0F3A, VexW::W0, Avx2);
+
+avx_instr_2op_imm!(vpshufd, 0x70, SIMDPrefix::k0x66, LeadingOpcode::k0F, VexW::W0, Avx2);
+avx_instr_2op_imm!(vpshufhw, 0x70, SIMDPrefix::k0xf3, LeadingOpcode::k0F, VexW::W0, Avx2);
+avx_instr_2op_imm!(vpshuflw, 0x70, SIMDPrefix::k0xf2, LeadingOpcode::k0F, VexW::W0, Avx2);
+avx_instr_2op_imm!(vpermilps_imm, 0x04, SIMDPrefix::k0x66, LeadingOpcode::k0F3A, VexW::W0);
+avx_instr_2op_imm!(vpermilpd_imm, 0x05, SIMDPrefix::k0x66, LeadingOpcode::k0F3A, VexW::W0);
+avx_instr_2op_imm!(vroundps, 0x08, SIMDPrefix::k0x66, LeadingOpcode::k0F3A, VexW::W0);
+avx_instr_2op_imm!(vroundpd, 0x09, SIMDPrefix::k0x66, LeadingOpcode::k0F3A, VexW::W0);
+
+// lane crossing permutes only exist with 256-bit operands
+#[no_mangle]
+pub extern "C" fn vpermd(buf: &mut Assembler, dst: YMMRegister, idx: YMMRegister, src: YMMRegister) {
+    buf.require(CpuFeature::Avx2);
+    vinstr_v(buf, 0x36, dst, idx, src, SIMDPrefix::k0x66, LeadingOpcode::k0F38, VexW::W0);
+}
+#[no_mangle]
+pub extern "C" fn vpermd_mem(buf: &mut Assembler, dst: YMMRegister, idx: YMMRegister, src: Mem) {
+    buf.require(CpuFeature::Avx2);
+    vinstrm_v(buf, 0x36, dst, idx, src, SIMDPrefix::k0x66, LeadingOpcode::k0F38, VexW::W0);
+}
+#[no_mangle]
+pub extern "C" fn vpermps(buf: &mut Assembler, dst: YMMRegister, idx: YMMRegister, src: YMMRegister) {
+    buf.require(CpuFeature::Avx2);
+    vinstr_v(buf, 0x16, dst, idx, src, SIMDPrefix::k0x66, LeadingOpcode::k0F38, VexW::W0);
+}
+#[no_mangle]
+pub extern "C" fn vpermps_mem(buf: &mut Assembler, dst: YMMRegister, idx: YMMRegister, src: Mem) {
+    buf.require(CpuFeature::Avx2);
+    vinstrm_v(buf, 0x16, dst, idx, src, SIMDPrefix::k0x66, LeadingOpcode::k0F38, VexW::W0);
+}
+#[no_mangle]
+pub extern "C" fn vpermq(buf: &mut Assembler, dst: YMMRegister, src: YMMRegister, imm: u8) {
+    buf.require(CpuFeature::Avx2);
+    vinstr_v(buf, 0x00, dst, YMM0, src, SIMDPrefix::k0x66, LeadingOpcode::k0F3A, VexW::W1);
+    buf.emit(imm);
+}
+#[no_mangle]
+pub extern "C" fn vpermq_mem(buf: &mut Assembler, dst: YMMRegister, src: Mem, imm: u8) {
+    buf.require(CpuFeature::Avx2);
+    vinstrm_v(buf, 0x00, dst, YMM0, src, SIMDPrefix::k0x66, LeadingOpcode::k0F3A, VexW::W1);
+    buf.imm_follows_mem(1);
+    buf.emit(imm);
+}
+#[no_mangle]
+pub extern "C" fn vpermpd(buf: &mut Assembler, dst: YMMRegister, src: YMMRegister, imm: u8) {
+    buf.require(CpuFeature::Avx2);
+    vinstr_v(buf, 0x01, dst, YMM0, src, SIMDPrefix::k0x66, LeadingOpcode::k0F3A, VexW::W1);
+    buf.emit(imm);
+}
+#[no_mangle]
+pub extern "C" fn vpermpd_mem(buf: &mut Assembler, dst: YMMRegister, src: Mem, imm: u8) {
+    buf.require(CpuFeature::Avx2);
+    vinstrm_v(buf, 0x01, dst, YMM0, src, SIMDPrefix::k0x66, LeadingOpcode::k0F3A, VexW::W1);
+    buf.imm_follows_mem(1);
+    buf.emit(imm);
+}
+#[no_mangle]
+pub extern "C" fn vperm2f128(buf: &mut Assembler,
+                             dst: YMMRegister,
+                             src1: YMMRegister,
+                             src2: YMMRegister,
+                             imm: u8) {
+    vinstr_v(buf, 0x06, dst, src1, src2, SIMDPrefix::k0x66, LeadingOpcode::k0F3A, VexW::W0);
+    buf.emit(imm);
+}
+#[no_mangle]
+pub extern "C" fn vperm2f128_mem(buf: &mut Assembler,
+                                 dst: YMMRegister,
+                                 src1: YMMRegister,
+                                 src2: Mem,
+                                 imm: u8) {
+    vinstrm_v(buf, 0x06, dst, src1, src2, SIMDPrefix::k0x66, LeadingOpcode::k0F3A, VexW::W0);
+    buf.imm_follows_mem(1);
+    buf.emit(imm);
+}
+#[no_mangle]
+pub extern "C" fn vperm2i128(buf: &mut Assembler,
+                             dst: YMMRegister,
+                             src1: YMMRegister,
+                             src2: YMMRegister,
+                             imm: u8) {
+    buf.require(CpuFeature::Avx2);
+    vinstr_v(buf, 0x46, dst, src1, src2, SIMDPrefix::k0x66, LeadingOpcode::k0F3A, VexW::W0);
+    buf.emit(imm);
+}
+#[no_mangle]
+pub extern "C" fn vperm2i128_mem(buf: &mut Assembler,
+                                 dst: YMMRegister,
+                                 src1: YMMRegister,
+                                 src2: Mem,
+                                 imm: u8) {
+    buf.require(CpuFeature::Avx2);
+    vinstrm_v(buf, 0x46, dst, src1, src2, SIMDPrefix::k0x66, LeadingOpcode::k0F3A, VexW::W0);
+    buf.imm_follows_mem(1);
+    buf.emit(imm);
+}
+
+fn vinsert128(asm: &mut Assembler, op: u8, dst: YMMRegister, src1: YMMRegister, src2: XMMRegister, imm: u8) {
+    vex_rr(asm,
+           op,
+           dst.code(),
+           src1.code(),
+           src2.code(),
+           VectorLength::kL256,
+           SIMDPrefix::k0x66,
+           LeadingOpcode::k0F3A,
+           VexW::W0);
+    asm.emit(imm);
+}
+
+/// Extracts into ModRM.rm, the YMM source goes into ModRM.reg.
+fn vextract128(asm: &mut Assembler, op: u8, dst: XMMRegister, src: YMMRegister, imm: u8) {
+    vex_rr(asm,
+           op,
+           src.code(),
+           0,
+           dst.code(),
+           VectorLength::kL256,
+           SIMDPrefix::k0x66,
+           LeadingOpcode::k0F3A,
+           VexW::W0);
+    asm.emit(imm);
+}
+#[no_mangle]
+pub extern "C" fn vinsertf128(buf: &mut Assembler,
+                              dst: YMMRegister,
+                              src1: YMMRegister,
+                              src2: XMMRegister,
+                              imm: u8) {
+    vinsert128(buf, 0x18, dst, src1, src2, imm);
+}
+#[no_mangle]
+pub extern "C" fn vinsertf128_mem(buf: &mut Assembler,
+                                  dst: YMMRegister,
+                                  src1: YMMRegister,
+                                  src2: Mem,
+                                  imm: u8) {
+    vinstrm_v(buf, 0x18, dst, src1, src2, SIMDPrefix::k0x66, LeadingOpcode::k0F3A, VexW::W0);
+    buf.imm_follows_mem(1);
+    buf.emit(imm);
+}
+#[no_mangle]
+pub extern "C" fn vinserti128(buf: &mut Assembler,
+                              dst: YMMRegister,
+                              src1: YMMRegister,
+                              src2: XMMRegister,
+                              imm: u8) {
+    buf.require(CpuFeature::Avx2);
+    vinsert128(buf, 0x38, dst, src1, src2, imm);
+}
+#[no_mangle]
+pub extern "C" fn vinserti128_mem(buf: &mut Assembler,
+                                  dst: YMMRegister,
+                                  src1: YMMRegister,
+                                  src2: Mem,
+                                  imm: u8) {
+    buf.require(CpuFeature::Avx2);
+    vinstrm_v(buf, 0x38, dst, src1, src2, SIMDPrefix::k0x66, LeadingOpcode::k0F3A, VexW::W0);
+    buf.imm_follows_mem(1);
+    buf.emit(imm);
+}
+#[no_mangle]
+pub extern "C" fn vextractf128(buf: &mut Assembler, dst: XMMRegister, src: YMMRegister, imm: u8) {
+    vextract128(buf, 0x19, dst, src, imm);
+}
+#[no_mangle]
+pub extern "C" fn vextractf128_store(buf: &mut Assembler, dst: Mem, src: YMMRegister, imm: u8) {
+    vinstrm_v(buf, 0x19, src, YMM0, dst, SIMDPrefix::k0x66, LeadingOpcode::k0F3A, VexW::W0);
+    buf.imm_follows_mem(1);
+    buf.emit(imm);
+}
+#[no_mangle]
+pub extern "C" fn vextracti128(buf: &mut Assembler, dst: XMMRegister, src: YMMRegister, imm: u8) {
+    buf.require(CpuFeature::Avx2);
+    vextract128(buf, 0x39, dst, src, imm);
+}
+#[no_mangle]
+pub extern "C" fn vextracti128_store(buf: &mut Assembler, dst: Mem, src: YMMRegister, imm: u8) {
+    buf.require(CpuFeature::Avx2);
+    vinstrm_v(buf, 0x39, src, YMM0, dst, SIMDPrefix::k0x66, LeadingOpcode::k0F3A, VexW::W0);
+    buf.imm_follows_mem(1);
+    buf.emit(imm);
+}
+
+avx_broadcast!(vbroadcastss, 0x18, Avx);
+avx_broadcast!(vpbroadcastd, 0x58, Avx2);
+avx_broadcast!(vpbroadcastq, 0x59, Avx2);
+avx_broadcast!(vpbroadcastb, 0x78, Avx2);
+avx_broadcast!(vpbroadcastw, 0x79, Avx2);
+
+// the double and 128-bit broadcasts only have a 256-bit form
+#[no_mangle]
+pub extern "C" fn vbroadcastsd(buf: &mut Assembler, dst: YMMRegister, src: XMMRegister) {
+    buf.require(CpuFeature::Avx2);
+    vex_rr(buf,
+           0x19,
+           dst.code(),
+           0,
+           src.code(),
+           VectorLength::kL256,
+           SIMDPrefix::k0x66,
+           LeadingOpcode::k0F38,
+           VexW::W0);
+}
+#[no_mangle]
+pub extern "C" fn vbroadcastsd_mem(buf: &mut Assembler, dst: YMMRegister, src: Mem) {
+    vinstrm_v(buf, 0x19, dst, YMM0, src, SIMDPrefix::k0x66, LeadingOpcode::k0F38, VexW::W0);
+}
+#[no_mangle]
+pub extern "C" fn vbroadcastf128_mem(buf: &mut Assembler, dst: YMMRegister, src: Mem) {
+    vinstrm_v(buf, 0x1a, dst, YMM0, src, SIMDPrefix::k0x66, LeadingOpcode::k0F38, VexW::W0);
+}
+#[no_mangle]
+pub extern "C" fn vbroadcasti128_mem(buf: &mut Assembler, dst: YMMRegister, src: Mem) {
+    buf.require(CpuFeature::Avx2);
+    vinstrm_v(buf, 0x5a, dst, YMM0, src, SIMDPrefix::k0x66, LeadingOpcode::k0F38, VexW::W0);
+}
+
+avx_gather!(vgatherdps, 0x92, VexW::W0, YMMRegister, YMMRegister);
+avx_gather!(vgatherdpd, 0x92, VexW::W1, YMMRegister, XMMRegister);
+avx_gather!(vgatherqps, 0x93, VexW::W0, XMMRegister, YMMRegister);
+avx_gather!(vgatherqpd, 0x93, VexW::W1, YMMRegister, YMMRegister);
+avx_gather!(vpgatherdd, 0x90, VexW::W0, YMMRegister, YMMRegister);
+avx_gather!(vpgatherdq, 0x90, VexW::W1, YMMRegister, XMMRegister);
+avx_gather!(vpgatherqd, 0x91, VexW::W0, XMMRegister, YMMRegister);
+avx_gather!(vpgatherqq, 0x91, VexW::W1, YMMRegister, YMMRegister);
+
+avx_fma!(vfmadd132, 0x98);
+avx_fma!(vfmadd213, 0xa8);
+avx_fma!(vfmadd231, 0xb8);
+avx_fma!(vfmsub132, 0x9a);
+avx_fma!(vfmsub213, 0xaa);
+avx_fma!(vfmsub231, 0xba);
+avx_fma!(vfnmadd132, 0x9c);
+avx_fma!(vfnmadd213, 0xac);
+avx_fma!(vfnmadd231, 0xbc);
+avx_fma!(vfnmsub132, 0x9e);
+avx_fma!(vfnmsub213, 0xae);
+avx_fma!(vfnmsub231, 0xbe);
+
+/// Zeroes bits 128 and up of all YMM registers. Emit before calling code that
+/// uses legacy SSE encodings to avoid the transition penalty.
+#[no_mangle]
+pub extern "C" fn vzeroupper(buf: &mut Assembler) {
+    emit_vex_prefix(buf,
+                    0,
+                    0,
+                    0,
+                    0,
+                    VectorLength::kL128,
+                    SIMDPrefix::None,
+                    LeadingOpcode::k0F,
+                    VexW::W0);
+    buf.emit(0x77);
+}
+#[no_mangle]
+pub extern "C" fn vzeroall(buf: &mut Assembler) {
+    emit_vex_prefix(buf,
+                    0,
+                    0,
+                    0,
+                    0,
+                    VectorLength::kL256,
+                    SIMDPrefix::None,
+                    LeadingOpcode::k0F,
+                    VexW::W0);
+    buf.emit(0x77);
+}
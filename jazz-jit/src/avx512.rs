@@ -0,0 +1,895 @@
+//! EVEX encoded AVX-512 instructions on ZMM registers.
+//!
+//! Every instruction takes the `Masking` applied to its destination. Memory operand
+//! forms end in `_mem`, forms that broadcast one element from memory to all lanes
+//! in `_bcst` and register forms with an embedded rounding mode in `_round`. One
+//! byte displacements of memory operands are scaled by the size given by the tuple
+//! type of the instruction (disp8*N), other displacements fall back to four bytes.
+
+use crate::assembler::{Assembler, Mem};
+use crate::assembler_x64::{emit_mem_disp8n, RoundMode};
+use crate::avx::ExexPrefix::*;
+use crate::avx::{
+    emit_rex_memv, emit_vex_prefix, AvxVecSize, EvexInputSizeInBits, EvexTupleType, LeadingOpcode,
+    SIMDPrefix, VectorLength, VexW,
+};
+use crate::constants_x64::*;
+use crate::cpu::CpuFeature;
+
+use std::mem;
+
+/// Opmask applied to the destination of an EVEX instruction.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[repr(C)]
+pub enum Masking {
+    /// Every element is written.
+    Unmasked,
+    /// Elements whose mask bit is clear keep their previous value.
+    Merge(KRegister),
+    /// Elements whose mask bit is clear are zeroed.
+    Zero(KRegister),
+}
+
+impl Masking {
+    /// EVEX.aaa and EVEX.z. K0 encodes no masking and cannot be named explicitly.
+    fn encode(self, asm: &mut Assembler) -> (u8, u8) {
+        let (k, z) = match self {
+            Masking::Unmasked => return (0, 0),
+            Masking::Merge(k) => (k, 0),
+            Masking::Zero(k) => (k, 1),
+        };
+
+        if k == K0 || k as u8 > 7 {
+            asm.invalid_operand::<()>(format!("{:?} cannot be used as a write mask", k));
+        }
+
+        (k as u8 & 7, z)
+    }
+}
+
+/// The operand in ModRM.rm.
+#[derive(Copy, Clone)]
+enum Rm {
+    Reg(u8),
+    /// A register operand with the rounding mode overriding MXCSR.RC, implies
+    /// 512-bit vectors.
+    Round(u8, RoundMode),
+    Mem(Mem),
+    /// A memory operand holding a single element that is broadcast to all lanes.
+    Bcst(Mem),
+}
+
+/// Fixed encoding fields of an EVEX instruction.
+#[derive(Copy, Clone)]
+struct Evex {
+    op: u8,
+    pp: SIMDPrefix,
+    mm: LeadingOpcode,
+    w: VexW,
+    tuple: EvexTupleType,
+    input: EvexInputSizeInBits,
+}
+
+/// N of disp8*N by tuple type and vector length. Tuple types with several rows are
+/// indexed by broadcast, EVEX.W or input size as done in `disp8_scale`, a zero
+/// marks combinations that do not exist.
+const TUPLE_TABLE: [[i32; 3]; EvexTupleType::EVEX_ETUP as usize] = [
+    [16, 32, 64], // FV
+    [4, 4, 4],    // FV, broadcast
+    [16, 32, 64], // FV, W1
+    [8, 8, 8],    // FV, W1 and broadcast
+    [8, 16, 32],  // HV
+    [4, 4, 4],    // HV, broadcast
+    [16, 32, 64], // FVM
+    [1, 1, 1],    // T1S, 8 bit
+    [2, 2, 2],    // T1S, 16 bit
+    [4, 4, 4],    // T1S, 32 bit
+    [8, 8, 8],    // T1S, 64 bit
+    [4, 4, 4],    // T1F, 32 bit
+    [8, 8, 8],    // T1F, 64 bit
+    [8, 8, 8],    // T2, 32 bit
+    [0, 16, 16],  // T2, 64 bit
+    [0, 16, 16],  // T4, 32 bit
+    [0, 0, 32],   // T4, 64 bit
+    [0, 0, 32],   // T8
+    [8, 16, 32],  // HVM
+    [4, 8, 16],   // QVM
+    [2, 4, 8],    // OVM
+    [16, 16, 16], // M128
+    [8, 32, 64],  // DUP
+];
+
+fn disp8_scale(asm: &mut Assembler, enc: Evex, len: AvxVecSize, bcst: bool) -> i32 {
+    use crate::avx::EvexInputSizeInBits::*;
+    use crate::avx::EvexTupleType::*;
+
+    let row = match enc.tuple {
+        EVEX_FV => bcst as usize + 2 * (enc.w == VexW::W1) as usize,
+        EVEX_HV => bcst as usize,
+        EVEX_T1S => match enc.input {
+            EVEX_NObit => 0,
+            input => input as usize,
+        },
+        EVEX_T1F | EVEX_T2 | EVEX_T4 => (enc.input == EVEX_64bit) as usize,
+        _ => 0,
+    };
+
+    if bcst && enc.tuple != EVEX_FV && enc.tuple != EVEX_HV {
+        asm.invalid_operand::<()>("instruction does not support embedded broadcasts");
+    }
+
+    match TUPLE_TABLE[enc.tuple as usize + row][len as usize] {
+        0 => asm.invalid_operand("tuple type does not exist for the vector length"),
+        n => n,
+    }
+}
+
+fn check_evex_regs(asm: &mut Assembler, regs: &[u8]) {
+    if regs.iter().any(|&reg| reg > 31) {
+        asm.invalid_operand::<()>("register cannot be encoded in an EVEX prefix");
+    }
+}
+
+/// Emits an EVEX instruction. `reg` goes into ModRM.reg, `vreg` into EVEX.vvvv
+/// and EVEX.V'. R, X, B, R', vvvv and V' are stored inverted.
+fn evex(
+    asm: &mut Assembler,
+    enc: Evex,
+    reg: u8,
+    vreg: u8,
+    rm: Rm,
+    len: AvxVecSize,
+    masking: Masking,
+) {
+    asm.require(CpuFeature::Avx512F);
+    let (aaa, z) = masking.encode(asm);
+    check_evex_regs(asm, &[reg, vreg]);
+
+    // X and B of the operand in ModRM.rm, EVEX.b and EVEX.L'L
+    let (x, b, extended, ll) = match rm {
+        Rm::Reg(rm) => {
+            check_evex_regs(asm, &[rm]);
+            (rm >> 4 & 1, rm >> 3 & 1, false, len as u8)
+        }
+        Rm::Round(rm, mode) => {
+            check_evex_regs(asm, &[rm]);
+            (rm >> 4 & 1, rm >> 3 & 1, true, mode as u8)
+        }
+        Rm::Mem(ref mem) | Rm::Bcst(ref mem) => {
+            let xb = emit_rex_memv(asm, 0, RAX, mem) & 3;
+            let bcst = matches!(rm, Rm::Bcst(_));
+            (xb >> 1, xb & 1, bcst, len as u8)
+        }
+    };
+
+    let mut p0 = enc.mm as u8 & 3;
+    if reg >> 3 & 1 == 0 {
+        p0 |= 0x80;
+    }
+    if x == 0 {
+        p0 |= EVEX_X as u8;
+    }
+    if b == 0 {
+        p0 |= 0x20;
+    }
+    if reg >> 4 & 1 == 0 {
+        p0 |= EVEX_RB as u8;
+    }
+
+    let p1 = enc.w as u8 | (!vreg & 0xf) << 3 | EVEX_F as u8 | enc.pp as u8;
+
+    let mut p2 = (ll & 3) << 5 | aaa;
+    if z != 0 {
+        p2 |= EVEX_Z as u8;
+    }
+    if extended {
+        p2 |= EVEX_RB as u8;
+    }
+    if vreg >> 4 & 1 == 0 {
+        p2 |= EVEX_V as u8;
+    }
+
+    asm.emit(0x62);
+    asm.emit(p0);
+    asm.emit(p1);
+    asm.emit(p2);
+    asm.emit(enc.op);
+
+    match rm {
+        Rm::Reg(rm) | Rm::Round(rm, _) => asm.emit(0xc0 | (reg & 7) << 3 | rm & 7),
+        Rm::Mem(mem) | Rm::Bcst(mem) => {
+            let n = disp8_scale(asm, enc, len, extended);
+            let reg = unsafe { mem::transmute::<i32, Register>((reg & 7) as i32) };
+            emit_mem_disp8n(asm, reg, &mem, n);
+        }
+    }
+}
+
+/// Memory destinations only support merge masking.
+fn check_store_masking(asm: &mut Assembler, masking: Masking) {
+    if let Masking::Zero(_) = masking {
+        asm.invalid_operand::<()>("zero masking is not allowed with a memory destination");
+    }
+}
+
+/// Element size of instructions whose EVEX.W selects between dwords and qwords.
+const fn input_size(w: VexW) -> EvexInputSizeInBits {
+    match w {
+        VexW::W0 => EvexInputSizeInBits::EVEX_32bit,
+        VexW::W1 => EvexInputSizeInBits::EVEX_64bit,
+    }
+}
+
+/// Full vector instructions with register, memory and broadcast forms.
+macro_rules! evex_instr {
+    ($name: ident, $op: expr, $pp: ident, $mm: ident, $w: ident) => {
+        paste::item! {
+            const [<$name _evex>]: Evex = Evex {
+                op: $op,
+                pp: SIMDPrefix::$pp,
+                mm: LeadingOpcode::$mm,
+                w: VexW::$w,
+                tuple: EvexTupleType::EVEX_FV,
+                input: input_size(VexW::$w),
+            };
+
+            #[no_mangle]
+            pub extern "C" fn [<$name _zmm>](
+                buf: &mut Assembler,
+                dst: ZMMRegister,
+                src1: ZMMRegister,
+                src2: ZMMRegister,
+                masking: Masking,
+            ) {
+                let rm = Rm::Reg(src2 as u8);
+                evex(buf, [<$name _evex>], dst as u8, src1 as u8, rm, AvxVecSize::AVX_512bit, masking);
+            }
+
+            #[no_mangle]
+            pub extern "C" fn [<$name _zmm_mem>](
+                buf: &mut Assembler,
+                dst: ZMMRegister,
+                src1: ZMMRegister,
+                src2: Mem,
+                masking: Masking,
+            ) {
+                let rm = Rm::Mem(src2);
+                evex(buf, [<$name _evex>], dst as u8, src1 as u8, rm, AvxVecSize::AVX_512bit, masking);
+            }
+
+            #[no_mangle]
+            pub extern "C" fn [<$name _zmm_bcst>](
+                buf: &mut Assembler,
+                dst: ZMMRegister,
+                src1: ZMMRegister,
+                src2: Mem,
+                masking: Masking,
+            ) {
+                let rm = Rm::Bcst(src2);
+                evex(buf, [<$name _evex>], dst as u8, src1 as u8, rm, AvxVecSize::AVX_512bit, masking);
+            }
+        }
+    };
+}
+
+/// Like `evex_instr!` with an additional form taking the rounding mode.
+macro_rules! evex_instr_round {
+    ($name: ident, $op: expr, $pp: ident, $mm: ident, $w: ident) => {
+        evex_instr!($name, $op, $pp, $mm, $w);
+
+        paste::item! {
+            #[no_mangle]
+            pub extern "C" fn [<$name _zmm_round>](
+                buf: &mut Assembler,
+                dst: ZMMRegister,
+                src1: ZMMRegister,
+                src2: ZMMRegister,
+                mode: RoundMode,
+                masking: Masking,
+            ) {
+                let rm = Rm::Round(src2 as u8, mode);
+                evex(buf, [<$name _evex>], dst as u8, src1 as u8, rm, AvxVecSize::AVX_512bit, masking);
+            }
+        }
+    };
+}
+
+/// Byte and word instructions (AVX512BW), which have no broadcast forms.
+macro_rules! evex_instr_bw {
+    ($name: ident, $op: expr, $mm: ident) => {
+        paste::item! {
+            const [<$name _evex>]: Evex = Evex {
+                op: $op,
+                pp: SIMDPrefix::k0x66,
+                mm: LeadingOpcode::$mm,
+                w: VexW::W0,
+                tuple: EvexTupleType::EVEX_FVM,
+                input: EvexInputSizeInBits::EVEX_NObit,
+            };
+
+            #[no_mangle]
+            pub extern "C" fn [<$name _zmm>](
+                buf: &mut Assembler,
+                dst: ZMMRegister,
+                src1: ZMMRegister,
+                src2: ZMMRegister,
+                masking: Masking,
+            ) {
+                buf.require(CpuFeature::Avx512Bw);
+                let rm = Rm::Reg(src2 as u8);
+                evex(buf, [<$name _evex>], dst as u8, src1 as u8, rm, AvxVecSize::AVX_512bit, masking);
+            }
+
+            #[no_mangle]
+            pub extern "C" fn [<$name _zmm_mem>](
+                buf: &mut Assembler,
+                dst: ZMMRegister,
+                src1: ZMMRegister,
+                src2: Mem,
+                masking: Masking,
+            ) {
+                buf.require(CpuFeature::Avx512Bw);
+                let rm = Rm::Mem(src2);
+                evex(buf, [<$name _evex>], dst as u8, src1 as u8, rm, AvxVecSize::AVX_512bit, masking);
+            }
+        }
+    };
+}
+
+/// Instructions with a single source, EVEX.vvvv is unused.
+macro_rules! evex_instr_2op_round {
+    ($name: ident, $op: expr, $pp: ident, $w: ident) => {
+        paste::item! {
+            const [<$name _evex>]: Evex = Evex {
+                op: $op,
+                pp: SIMDPrefix::$pp,
+                mm: LeadingOpcode::k0F,
+                w: VexW::$w,
+                tuple: EvexTupleType::EVEX_FV,
+                input: input_size(VexW::$w),
+            };
+
+            #[no_mangle]
+            pub extern "C" fn [<$name _zmm>](buf: &mut Assembler, dst: ZMMRegister, src: ZMMRegister, masking: Masking) {
+                let rm = Rm::Reg(src as u8);
+                evex(buf, [<$name _evex>], dst as u8, 0, rm, AvxVecSize::AVX_512bit, masking);
+            }
+
+            #[no_mangle]
+            pub extern "C" fn [<$name _zmm_mem>](buf: &mut Assembler, dst: ZMMRegister, src: Mem, masking: Masking) {
+                let rm = Rm::Mem(src);
+                evex(buf, [<$name _evex>], dst as u8, 0, rm, AvxVecSize::AVX_512bit, masking);
+            }
+
+            #[no_mangle]
+            pub extern "C" fn [<$name _zmm_bcst>](buf: &mut Assembler, dst: ZMMRegister, src: Mem, masking: Masking) {
+                let rm = Rm::Bcst(src);
+                evex(buf, [<$name _evex>], dst as u8, 0, rm, AvxVecSize::AVX_512bit, masking);
+            }
+
+            #[no_mangle]
+            pub extern "C" fn [<$name _zmm_round>](
+                buf: &mut Assembler,
+                dst: ZMMRegister,
+                src: ZMMRegister,
+                mode: RoundMode,
+                masking: Masking,
+            ) {
+                let rm = Rm::Round(src as u8, mode);
+                evex(buf, [<$name _evex>], dst as u8, 0, rm, AvxVecSize::AVX_512bit, masking);
+            }
+        }
+    };
+}
+
+/// Full vector instructions followed by an immediate byte.
+macro_rules! evex_instr_imm {
+    ($name: ident, $op: expr, $pp: ident, $mm: ident, $w: ident) => {
+        paste::item! {
+            const [<$name _evex>]: Evex = Evex {
+                op: $op,
+                pp: SIMDPrefix::$pp,
+                mm: LeadingOpcode::$mm,
+                w: VexW::$w,
+                tuple: EvexTupleType::EVEX_FV,
+                input: input_size(VexW::$w),
+            };
+
+            #[no_mangle]
+            pub extern "C" fn [<$name _zmm>](
+                buf: &mut Assembler,
+                dst: ZMMRegister,
+                src1: ZMMRegister,
+                src2: ZMMRegister,
+                imm: u8,
+                masking: Masking,
+            ) {
+                let rm = Rm::Reg(src2 as u8);
+                evex(buf, [<$name _evex>], dst as u8, src1 as u8, rm, AvxVecSize::AVX_512bit, masking);
+                buf.emit(imm);
+            }
+
+            #[no_mangle]
+            pub extern "C" fn [<$name _zmm_mem>](
+                buf: &mut Assembler,
+                dst: ZMMRegister,
+                src1: ZMMRegister,
+                src2: Mem,
+                imm: u8,
+                masking: Masking,
+            ) {
+                let rm = Rm::Mem(src2);
+                evex(buf, [<$name _evex>], dst as u8, src1 as u8, rm, AvxVecSize::AVX_512bit, masking);
+                buf.emit(imm);
+            }
+
+            #[no_mangle]
+            pub extern "C" fn [<$name _zmm_bcst>](
+                buf: &mut Assembler,
+                dst: ZMMRegister,
+                src1: ZMMRegister,
+                src2: Mem,
+                imm: u8,
+                masking: Masking,
+            ) {
+                let rm = Rm::Bcst(src2);
+                evex(buf, [<$name _evex>], dst as u8, src1 as u8, rm, AvxVecSize::AVX_512bit, masking);
+                buf.emit(imm);
+            }
+        }
+    };
+}
+
+/// Comparisons writing one bit per element into an opmask register, optionally
+/// followed by the predicate. `masking` selects the elements that are compared,
+/// zero masking is not allowed.
+macro_rules! evex_cmp {
+    ($name: ident, $op: expr, $pp: ident, $mm: ident, $w: ident $(, $imm: ident)*) => {
+        paste::item! {
+            const [<$name _evex>]: Evex = Evex {
+                op: $op,
+                pp: SIMDPrefix::$pp,
+                mm: LeadingOpcode::$mm,
+                w: VexW::$w,
+                tuple: EvexTupleType::EVEX_FV,
+                input: input_size(VexW::$w),
+            };
+
+            #[no_mangle]
+            pub extern "C" fn [<$name _zmm>](
+                buf: &mut Assembler,
+                dst: KRegister,
+                src1: ZMMRegister,
+                src2: ZMMRegister,
+                $($imm: u8,)*
+                masking: Masking,
+            ) {
+                check_store_masking(buf, masking);
+                let rm = Rm::Reg(src2 as u8);
+                evex(buf, [<$name _evex>], dst as u8, src1 as u8, rm, AvxVecSize::AVX_512bit, masking);
+                $(buf.emit($imm);)*
+            }
+
+            #[no_mangle]
+            pub extern "C" fn [<$name _zmm_mem>](
+                buf: &mut Assembler,
+                dst: KRegister,
+                src1: ZMMRegister,
+                src2: Mem,
+                $($imm: u8,)*
+                masking: Masking,
+            ) {
+                check_store_masking(buf, masking);
+                let rm = Rm::Mem(src2);
+                evex(buf, [<$name _evex>], dst as u8, src1 as u8, rm, AvxVecSize::AVX_512bit, masking);
+                $(buf.emit($imm);)*
+            }
+
+            #[no_mangle]
+            pub extern "C" fn [<$name _zmm_bcst>](
+                buf: &mut Assembler,
+                dst: KRegister,
+                src1: ZMMRegister,
+                src2: Mem,
+                $($imm: u8,)*
+                masking: Masking,
+            ) {
+                check_store_masking(buf, masking);
+                let rm = Rm::Bcst(src2);
+                evex(buf, [<$name _evex>], dst as u8, src1 as u8, rm, AvxVecSize::AVX_512bit, masking);
+                $(buf.emit($imm);)*
+            }
+        }
+    };
+}
+
+/// Full register moves: `_zmm` between registers, `_zmm_mem` loads and
+/// `_zmm_store` stores.
+macro_rules! evex_mov {
+    ($name: ident, $pp: ident, $w: ident, $load: expr, $store: expr) => {
+        paste::item! {
+            const [<$name _load_evex>]: Evex = Evex {
+                op: $load,
+                pp: SIMDPrefix::$pp,
+                mm: LeadingOpcode::k0F,
+                w: VexW::$w,
+                tuple: EvexTupleType::EVEX_FVM,
+                input: EvexInputSizeInBits::EVEX_NObit,
+            };
+            const [<$name _store_evex>]: Evex = Evex {
+                op: $store,
+                ..[<$name _load_evex>]
+            };
+
+            #[no_mangle]
+            pub extern "C" fn [<$name _zmm>](buf: &mut Assembler, dst: ZMMRegister, src: ZMMRegister, masking: Masking) {
+                let rm = Rm::Reg(src as u8);
+                evex(buf, [<$name _load_evex>], dst as u8, 0, rm, AvxVecSize::AVX_512bit, masking);
+            }
+
+            #[no_mangle]
+            pub extern "C" fn [<$name _zmm_mem>](buf: &mut Assembler, dst: ZMMRegister, src: Mem, masking: Masking) {
+                let rm = Rm::Mem(src);
+                evex(buf, [<$name _load_evex>], dst as u8, 0, rm, AvxVecSize::AVX_512bit, masking);
+            }
+
+            #[no_mangle]
+            pub extern "C" fn [<$name _zmm_store>](buf: &mut Assembler, dst: Mem, src: ZMMRegister, masking: Masking) {
+                check_store_masking(buf, masking);
+                let rm = Rm::Mem(dst);
+                evex(buf, [<$name _store_evex>], src as u8, 0, rm, AvxVecSize::AVX_512bit, masking);
+            }
+        }
+    };
+}
+
+/// Broadcasts of the low element of an XMM register or of a memory operand.
+macro_rules! evex_broadcast {
+    ($name: ident, $op: expr, $w: ident) => {
+        paste::item! {
+            const [<$name _evex>]: Evex = Evex {
+                op: $op,
+                pp: SIMDPrefix::k0x66,
+                mm: LeadingOpcode::k0F38,
+                w: VexW::$w,
+                tuple: EvexTupleType::EVEX_T1S,
+                input: input_size(VexW::$w),
+            };
+
+            #[no_mangle]
+            pub extern "C" fn [<$name _zmm>](buf: &mut Assembler, dst: ZMMRegister, src: XMMRegister, masking: Masking) {
+                let rm = Rm::Reg(src as u8);
+                evex(buf, [<$name _evex>], dst as u8, 0, rm, AvxVecSize::AVX_512bit, masking);
+            }
+
+            #[no_mangle]
+            pub extern "C" fn [<$name _zmm_mem>](buf: &mut Assembler, dst: ZMMRegister, src: Mem, masking: Masking) {
+                let rm = Rm::Mem(src);
+                evex(buf, [<$name _evex>], dst as u8, 0, rm, AvxVecSize::AVX_512bit, masking);
+            }
+        }
+    };
+}
+
+/// The packed single and double precision forms of an FMA instruction.
+macro_rules! evex_fma {
+    ($name: ident, $op: expr) => {
+        paste::item! {
+            evex_instr_round!([<$name ps>], $op, k0x66, k0F38, W0);
+            evex_instr_round!([<$name pd>], $op, k0x66, k0F38, W1);
+        }
+    };
+}
+
+evex_instr_round!(vaddps, 0x58, None, k0F, W0);
+evex_instr_round!(vaddpd, 0x58, k0x66, k0F, W1);
+evex_instr_round!(vsubps, 0x5c, None, k0F, W0);
+evex_instr_round!(vsubpd, 0x5c, k0x66, k0F, W1);
+evex_instr_round!(vmulps, 0x59, None, k0F, W0);
+evex_instr_round!(vmulpd, 0x59, k0x66, k0F, W1);
+evex_instr_round!(vdivps, 0x5e, None, k0F, W0);
+evex_instr_round!(vdivpd, 0x5e, k0x66, k0F, W1);
+evex_instr!(vminps, 0x5d, None, k0F, W0);
+evex_instr!(vminpd, 0x5d, k0x66, k0F, W1);
+evex_instr!(vmaxps, 0x5f, None, k0F, W0);
+evex_instr!(vmaxpd, 0x5f, k0x66, k0F, W1);
+evex_instr!(vunpcklps, 0x14, None, k0F, W0);
+evex_instr!(vunpcklpd, 0x14, k0x66, k0F, W1);
+evex_instr!(vunpckhps, 0x15, None, k0F, W0);
+evex_instr!(vunpckhpd, 0x15, k0x66, k0F, W1);
+evex_instr_2op_round!(vsqrtps, 0x51, None, W0);
+evex_instr_2op_round!(vsqrtpd, 0x51, k0x66, W1);
+
+evex_instr!(vpaddd, 0xfe, k0x66, k0F, W0);
+evex_instr!(vpaddq, 0xd4, k0x66, k0F, W1);
+evex_instr!(vpsubd, 0xfa, k0x66, k0F, W0);
+evex_instr!(vpsubq, 0xfb, k0x66, k0F, W1);
+evex_instr!(vpmulld, 0x40, k0x66, k0F38, W0);
+evex_instr!(vpmuludq, 0xf4, k0x66, k0F, W1);
+evex_instr!(vpmuldq, 0x28, k0x66, k0F38, W1);
+evex_instr!(vpandd, 0xdb, k0x66, k0F, W0);
+evex_instr!(vpandq, 0xdb, k0x66, k0F, W1);
+evex_instr!(vpandnd, 0xdf, k0x66, k0F, W0);
+evex_instr!(vpandnq, 0xdf, k0x66, k0F, W1);
+evex_instr!(vpord, 0xeb, k0x66, k0F, W0);
+evex_instr!(vporq, 0xeb, k0x66, k0F, W1);
+evex_instr!(vpxord, 0xef, k0x66, k0F, W0);
+evex_instr!(vpxorq, 0xef, k0x66, k0F, W1);
+evex_instr!(vpminsd, 0x39, k0x66, k0F38, W0);
+evex_instr!(vpminsq, 0x39, k0x66, k0F38, W1);
+evex_instr!(vpminud, 0x3b, k0x66, k0F38, W0);
+evex_instr!(vpminuq, 0x3b, k0x66, k0F38, W1);
+evex_instr!(vpmaxsd, 0x3d, k0x66, k0F38, W0);
+evex_instr!(vpmaxsq, 0x3d, k0x66, k0F38, W1);
+evex_instr!(vpmaxud, 0x3f, k0x66, k0F38, W0);
+evex_instr!(vpmaxuq, 0x3f, k0x66, k0F38, W1);
+evex_instr!(vpsrlvd, 0x45, k0x66, k0F38, W0);
+evex_instr!(vpsrlvq, 0x45, k0x66, k0F38, W1);
+evex_instr!(vpsravd, 0x46, k0x66, k0F38, W0);
+evex_instr!(vpsravq, 0x46, k0x66, k0F38, W1);
+evex_instr!(vpsllvd, 0x47, k0x66, k0F38, W0);
+evex_instr!(vpsllvq, 0x47, k0x66, k0F38, W1);
+evex_instr!(vpermd, 0x36, k0x66, k0F38, W0);
+evex_instr!(vpermq, 0x36, k0x66, k0F38, W1);
+evex_instr!(vpermps, 0x16, k0x66, k0F38, W0);
+evex_instr!(vpermpd, 0x16, k0x66, k0F38, W1);
+
+evex_instr_bw!(vpaddb, 0xfc, k0F);
+evex_instr_bw!(vpaddw, 0xfd, k0F);
+evex_instr_bw!(vpsubb, 0xf8, k0F);
+evex_instr_bw!(vpsubw, 0xf9, k0F);
+evex_instr_bw!(vpmullw, 0xd5, k0F);
+evex_instr_bw!(vpminub, 0xda, k0F);
+evex_instr_bw!(vpmaxub, 0xde, k0F);
+evex_instr_bw!(vpshufb, 0x00, k0F38);
+
+evex_instr_imm!(vpternlogd, 0x25, k0x66, k0F3A, W0);
+evex_instr_imm!(vpternlogq, 0x25, k0x66, k0F3A, W1);
+evex_instr_imm!(vshufps, 0xc6, None, k0F, W0);
+evex_instr_imm!(vshufpd, 0xc6, k0x66, k0F, W1);
+
+evex_fma!(vfmadd132, 0x98);
+evex_fma!(vfmadd213, 0xa8);
+evex_fma!(vfmadd231, 0xb8);
+evex_fma!(vfmsub132, 0x9a);
+evex_fma!(vfmsub213, 0xaa);
+evex_fma!(vfmsub231, 0xba);
+evex_fma!(vfnmadd132, 0x9c);
+evex_fma!(vfnmadd213, 0xac);
+evex_fma!(vfnmadd231, 0xbc);
+evex_fma!(vfnmsub132, 0x9e);
+evex_fma!(vfnmsub213, 0xae);
+evex_fma!(vfnmsub231, 0xbe);
+
+evex_cmp!(vcmpps, 0xc2, None, k0F, W0, predicate);
+evex_cmp!(vcmppd, 0xc2, k0x66, k0F, W1, predicate);
+evex_cmp!(vpcmpd, 0x1f, k0x66, k0F3A, W0, predicate);
+evex_cmp!(vpcmpq, 0x1f, k0x66, k0F3A, W1, predicate);
+evex_cmp!(vpcmpud, 0x1e, k0x66, k0F3A, W0, predicate);
+evex_cmp!(vpcmpuq, 0x1e, k0x66, k0F3A, W1, predicate);
+evex_cmp!(vpcmpeqd, 0x76, k0x66, k0F, W0);
+evex_cmp!(vpcmpeqq, 0x29, k0x66, k0F38, W1);
+evex_cmp!(vpcmpgtd, 0x66, k0x66, k0F, W0);
+evex_cmp!(vpcmpgtq, 0x37, k0x66, k0F38, W1);
+
+evex_mov!(vmovups, None, W0, 0x10, 0x11);
+evex_mov!(vmovupd, k0x66, W1, 0x10, 0x11);
+evex_mov!(vmovaps, None, W0, 0x28, 0x29);
+evex_mov!(vmovapd, k0x66, W1, 0x28, 0x29);
+evex_mov!(vmovdqa32, k0x66, W0, 0x6f, 0x7f);
+evex_mov!(vmovdqa64, k0x66, W1, 0x6f, 0x7f);
+evex_mov!(vmovdqu32, k0xf3, W0, 0x6f, 0x7f);
+evex_mov!(vmovdqu64, k0xf3, W1, 0x6f, 0x7f);
+
+evex_broadcast!(vbroadcastss, 0x18, W0);
+evex_broadcast!(vbroadcastsd, 0x19, W1);
+evex_broadcast!(vpbroadcastd, 0x58, W0);
+evex_broadcast!(vpbroadcastq, 0x59, W1);
+
+const VPBROADCAST_GPR: Evex = Evex {
+    op: 0x7c,
+    pp: SIMDPrefix::k0x66,
+    mm: LeadingOpcode::k0F38,
+    w: VexW::W0,
+    tuple: EvexTupleType::EVEX_T1S,
+    input: EvexInputSizeInBits::EVEX_32bit,
+};
+
+/// Broadcasts the low dword of `src` to all lanes.
+#[no_mangle]
+pub extern "C" fn vpbroadcastd_zmm_reg(
+    buf: &mut Assembler,
+    dst: ZMMRegister,
+    src: Register,
+    masking: Masking,
+) {
+    let rm = Rm::Reg(src as u8);
+    evex(
+        buf,
+        VPBROADCAST_GPR,
+        dst as u8,
+        0,
+        rm,
+        AvxVecSize::AVX_512bit,
+        masking,
+    );
+}
+
+/// Broadcasts `src` to all lanes.
+#[no_mangle]
+pub extern "C" fn vpbroadcastq_zmm_reg(
+    buf: &mut Assembler,
+    dst: ZMMRegister,
+    src: Register,
+    masking: Masking,
+) {
+    let enc = Evex {
+        w: VexW::W1,
+        input: EvexInputSizeInBits::EVEX_64bit,
+        ..VPBROADCAST_GPR
+    };
+    evex(
+        buf,
+        enc,
+        dst as u8,
+        0,
+        Rm::Reg(src as u8),
+        AvxVecSize::AVX_512bit,
+        masking,
+    );
+}
+
+// Opmask instructions are VEX encoded, `w` selects between word and quadword masks.
+// Quadword masks were added by AVX512BW.
+
+fn kinstr(asm: &mut Assembler, op: u8, w: VexW, pp: SIMDPrefix, reg: u8, vreg: u8, rm: u8) {
+    asm.require(match w {
+        VexW::W0 => CpuFeature::Avx512F,
+        VexW::W1 => CpuFeature::Avx512Bw,
+    });
+    let l = VectorLength::kL128;
+    emit_vex_prefix(
+        asm,
+        reg >> 3 & 1,
+        0,
+        rm >> 3 & 1,
+        vreg,
+        l,
+        pp,
+        LeadingOpcode::k0F,
+        w,
+    );
+    asm.emit(op);
+    asm.emit(0xc0 | (reg & 7) << 3 | rm & 7);
+}
+
+#[no_mangle]
+pub extern "C" fn kmovw_k_reg(buf: &mut Assembler, dst: KRegister, src: Register) {
+    kinstr(
+        buf,
+        0x92,
+        VexW::W0,
+        SIMDPrefix::None,
+        dst as u8,
+        0,
+        src as u8,
+    );
+}
+
+#[no_mangle]
+pub extern "C" fn kmovw_reg_k(buf: &mut Assembler, dst: Register, src: KRegister) {
+    kinstr(
+        buf,
+        0x93,
+        VexW::W0,
+        SIMDPrefix::None,
+        dst as u8,
+        0,
+        src as u8,
+    );
+}
+
+#[no_mangle]
+pub extern "C" fn kmovq_k_reg(buf: &mut Assembler, dst: KRegister, src: Register) {
+    kinstr(
+        buf,
+        0x92,
+        VexW::W1,
+        SIMDPrefix::k0xf2,
+        dst as u8,
+        0,
+        src as u8,
+    );
+}
+
+#[no_mangle]
+pub extern "C" fn kmovq_reg_k(buf: &mut Assembler, dst: Register, src: KRegister) {
+    kinstr(
+        buf,
+        0x93,
+        VexW::W1,
+        SIMDPrefix::k0xf2,
+        dst as u8,
+        0,
+        src as u8,
+    );
+}
+
+#[no_mangle]
+pub extern "C" fn kmovw(buf: &mut Assembler, dst: KRegister, src: KRegister) {
+    kinstr(
+        buf,
+        0x90,
+        VexW::W0,
+        SIMDPrefix::None,
+        dst as u8,
+        0,
+        src as u8,
+    );
+}
+
+#[no_mangle]
+pub extern "C" fn knotw(buf: &mut Assembler, dst: KRegister, src: KRegister) {
+    kinstr(
+        buf,
+        0x44,
+        VexW::W0,
+        SIMDPrefix::None,
+        dst as u8,
+        0,
+        src as u8,
+    );
+}
+
+/// Sets ZF if `src1 | src2` is zero and CF if it is all ones.
+#[no_mangle]
+pub extern "C" fn kortestw(buf: &mut Assembler, src1: KRegister, src2: KRegister) {
+    kinstr(
+        buf,
+        0x98,
+        VexW::W0,
+        SIMDPrefix::None,
+        src1 as u8,
+        0,
+        src2 as u8,
+    );
+}
+
+#[no_mangle]
+pub extern "C" fn kortestq(buf: &mut Assembler, src1: KRegister, src2: KRegister) {
+    kinstr(
+        buf,
+        0x98,
+        VexW::W1,
+        SIMDPrefix::None,
+        src1 as u8,
+        0,
+        src2 as u8,
+    );
+}
+
+/// The two operand mask logic instructions use VEX.L1.
+fn klogic(asm: &mut Assembler, op: u8, dst: KRegister, src1: KRegister, src2: KRegister) {
+    asm.require(CpuFeature::Avx512F);
+    let (dst, src2) = (dst as u8, src2 as u8);
+    let l = VectorLength::kL256;
+    let (pp, mm, w) = (SIMDPrefix::None, LeadingOpcode::k0F, VexW::W0);
+    emit_vex_prefix(asm, 0, 0, 0, src1 as u8, l, pp, mm, w);
+    asm.emit(op);
+    asm.emit(0xc0 | (dst & 7) << 3 | src2 & 7);
+}
+
+#[no_mangle]
+pub extern "C" fn kandw(buf: &mut Assembler, dst: KRegister, src1: KRegister, src2: KRegister) {
+    klogic(buf, 0x41, dst, src1, src2);
+}
+
+#[no_mangle]
+pub extern "C" fn korw(buf: &mut Assembler, dst: KRegister, src1: KRegister, src2: KRegister) {
+    klogic(buf, 0x45, dst, src1, src2);
+}
+
+#[no_mangle]
+pub extern "C" fn kxorw(buf: &mut Assembler, dst: KRegister, src1: KRegister, src2: KRegister) {
+    klogic(buf, 0x47, dst, src1, src2);
+}
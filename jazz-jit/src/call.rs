@@ -0,0 +1,379 @@
+//! Calls from generated code into native functions that follow the System V AMD64
+//! calling convention.
+//!
+//! A `CallBuilder` collects the arguments from wherever they are and emits the whole
+//! call sequence: RSP is aligned to 16 bytes, live caller-saved registers are saved,
+//! arguments beyond the argument registers are stored on the stack, the register
+//! arguments are moved in parallel so no source is overwritten before it is read,
+//! and the return value is moved out of RAX or XMM0. R11 is used as scratch
+//! register, it may not hold an argument and is not preserved. R10 and XMM15 break
+//! cycles of moves.
+
+use crate::assembler::{Assembler, Mem};
+use crate::assembler_x64 as buf;
+use crate::constants_x64::*;
+use crate::regalloc::{caller_saved, ARG_FLOATS, ARG_GPRS};
+use crate::reloc::RelocTarget;
+use crate::MachineMode;
+
+/// An argument and where its value comes from. Integer arguments are passed as 64
+/// bits, float arguments as doubles.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Arg {
+    Reg(Register),
+    Imm(i64),
+    Mem(Mem),
+    Xmm(XMMRegister),
+    Float(f64),
+    FloatMem(Mem),
+}
+
+impl Arg {
+    fn is_float(&self) -> bool {
+        match self {
+            Arg::Xmm(_) | Arg::Float(_) | Arg::FloatMem(_) => true,
+            Arg::Reg(_) | Arg::Imm(_) | Arg::Mem(_) => false,
+        }
+    }
+
+    fn mode(&self) -> MachineMode {
+        if self.is_float() {
+            MachineMode::Float64
+        } else {
+            MachineMode::Int64
+        }
+    }
+}
+
+impl From<Register> for Arg {
+    fn from(reg: Register) -> Arg {
+        Arg::Reg(reg)
+    }
+}
+
+impl From<XMMRegister> for Arg {
+    fn from(reg: XMMRegister) -> Arg {
+        Arg::Xmm(reg)
+    }
+}
+
+impl From<i64> for Arg {
+    fn from(imm: i64) -> Arg {
+        Arg::Imm(imm)
+    }
+}
+
+impl From<f64> for Arg {
+    fn from(imm: f64) -> Arg {
+        Arg::Float(imm)
+    }
+}
+
+/// Registers `mem` reads to compute its address.
+fn address_regs(mem: &Mem) -> Vec<Register> {
+    match *mem {
+        Mem::Local(_) => vec![RBP],
+        Mem::Base(base, _) => vec![base],
+        Mem::Index(base, index, _, _) => vec![base, index],
+        Mem::Offset(index, _, _) => vec![index],
+        Mem::RipRelative(_) | Mem::Absolute(_) => vec![],
+        Mem::Segment(_, addr) => address_regs(&addr.mem()),
+    }
+}
+
+enum Callee {
+    Target(RelocTarget),
+    Reg(Register),
+}
+
+/// Builds a call into a native function.
+#[derive(Clone, Debug, Default)]
+pub struct CallBuilder {
+    args: Vec<Arg>,
+    live: Vec<Reg>,
+    result: Option<Reg>,
+    depth: Option<u32>,
+}
+
+impl CallBuilder {
+    pub fn new() -> CallBuilder {
+        CallBuilder::default()
+    }
+
+    /// Adds the next argument.
+    pub fn arg(mut self, arg: impl Into<Arg>) -> CallBuilder {
+        self.args.push(arg.into());
+        self
+    }
+
+    /// Keeps the value of `reg` over the call. Only caller-saved registers are
+    /// actually saved, of XMM registers the low 64 bits.
+    pub fn live(mut self, reg: Reg) -> CallBuilder {
+        self.live.push(reg);
+        self
+    }
+
+    /// Moves the return value into `reg`, from RAX for a general purpose register
+    /// and from XMM0 for an XMM register.
+    pub fn result(mut self, reg: Reg) -> CallBuilder {
+        self.result = Some(reg);
+        self
+    }
+
+    /// RSP is `bytes` below a 16-byte boundary at the call site. Without it RSP is
+    /// aligned at runtime, which does not allow arguments relative to RSP and needs
+    /// a frame pointer for the unwind information.
+    pub fn stack_depth(mut self, bytes: u32) -> CallBuilder {
+        self.depth = Some(bytes);
+        self
+    }
+
+    /// Calls `target` through R11.
+    pub fn call(&self, asm: &mut Assembler, target: RelocTarget) {
+        self.emit(asm, Callee::Target(target));
+    }
+
+    /// Calls the address in `target`.
+    pub fn call_reg(&self, asm: &mut Assembler, target: Register) {
+        self.emit(asm, Callee::Reg(target));
+    }
+
+    fn emit(&self, asm: &mut Assembler, callee: Callee) {
+        let mut sources = Vec::new();
+        for arg in &self.args {
+            match arg {
+                Arg::Reg(reg) => sources.push(*reg),
+                Arg::Mem(mem) | Arg::FloatMem(mem) => sources.extend(address_regs(mem)),
+                _ => {}
+            }
+        }
+        if let Callee::Reg(reg) = callee {
+            sources.push(reg);
+        }
+
+        if sources.contains(&R11) {
+            return asm.invalid_operand("R11 is the scratch register of calls");
+        }
+
+        let rsp_relative = sources.contains(&RSP);
+        if rsp_relative && self.depth.is_none() {
+            return asm.invalid_operand("arguments relative to RSP need a known stack depth");
+        }
+
+        // the CFA cannot follow RSP once it is aligned
+        if self.depth.is_none() && !asm.cfi_frame_pointer() {
+            return asm.invalid_operand("calls without a frame pointer need a known stack depth");
+        }
+
+        // argument registers in order, the rest goes onto the stack
+        let (mut gprs, mut floats) = (ARG_GPRS.iter(), ARG_FLOATS.iter());
+        let mut in_regs = Vec::new();
+        let mut on_stack = Vec::new();
+        for &arg in &self.args {
+            let reg = if arg.is_float() {
+                floats.next().map(|&reg| Reg::Float(reg))
+            } else {
+                gprs.next().map(|&reg| Reg::Gpr(reg))
+            };
+
+            match reg {
+                Some(reg) => in_regs.push((arg, reg)),
+                None => on_stack.push(arg),
+            }
+        }
+
+        let caller_saved = caller_saved();
+        let mut saved: Vec<Reg> = Vec::new();
+        for &reg in &self.live {
+            let keep = caller_saved.contains(&reg) && reg != Reg::Gpr(R11);
+            if keep && Some(reg) != self.result && !saved.contains(&reg) {
+                saved.push(reg);
+            }
+        }
+
+        // from RSP up: stack arguments, padding, saved registers, the old RSP
+        let args_size = 8 * on_stack.len() as i32;
+        let old_rsp = if self.depth.is_none() { 8 } else { 0 };
+        let body = 8 * saved.len() as i32 + old_rsp;
+        let depth = self.depth.unwrap_or(0) as i32;
+        let padding = (16 - (depth + body + args_size) % 16) % 16;
+        let size = args_size + padding + body;
+        let slot = |idx: usize| Mem::Base(RSP, args_size + padding + 8 * idx as i32);
+
+        if self.depth.is_none() {
+            asm.copy_reg(MachineMode::Int64, R11, RSP);
+            buf::emit_andq_imm_reg(asm, -16, RSP);
+            buf::emit_subq_imm_reg(asm, size, RSP);
+            asm.store_mem(MachineMode::Int64, Mem::Base(RSP, size - 8), Reg::Gpr(R11));
+        } else if size > 0 {
+            buf::emit_subq_imm_reg(asm, size, RSP);
+        }
+
+        for (idx, &reg) in saved.iter().enumerate() {
+            asm.store_mem(mode_of(reg), slot(idx), reg);
+        }
+
+        // RSP-relative sources are read after RSP was moved down
+        let mut pushed = 0;
+        let at = |mem: Mem, pushed: i32| match mem {
+            Mem::Base(RSP, disp) => Mem::Base(RSP, disp + size + pushed),
+            Mem::Index(RSP, index, scale, disp) => {
+                Mem::Index(RSP, index, scale, disp + size + pushed)
+            }
+            mem => mem,
+        };
+
+        for (idx, &arg) in on_stack.iter().enumerate() {
+            let dest = Mem::Base(RSP, 8 * idx as i32);
+
+            match arg {
+                Arg::Reg(reg) => asm.store_mem(MachineMode::Int64, dest, Reg::Gpr(reg)),
+                Arg::Xmm(reg) => asm.store_mem(MachineMode::Float64, dest, Reg::Float(reg)),
+                Arg::Imm(imm) => {
+                    asm.load_int_const(MachineMode::Int64, R11, imm);
+                    asm.store_mem(MachineMode::Int64, dest, Reg::Gpr(R11));
+                }
+                Arg::Float(imm) => {
+                    asm.load_int_const(MachineMode::Int64, R11, imm.to_bits() as i64);
+                    asm.store_mem(MachineMode::Int64, dest, Reg::Gpr(R11));
+                }
+                Arg::Mem(mem) | Arg::FloatMem(mem) => {
+                    asm.load_mem(MachineMode::Int64, Reg::Gpr(R11), at(mem, 0));
+                    asm.store_mem(MachineMode::Int64, dest, Reg::Gpr(R11));
+                }
+            }
+        }
+
+        let mut moves: Vec<(Reg, Reg)> = Vec::new();
+        let mut dests: Vec<Reg> = in_regs.iter().map(|&(_, reg)| reg).collect();
+        for &(arg, dest) in &in_regs {
+            match arg {
+                Arg::Reg(reg) => moves.push((Reg::Gpr(reg), dest)),
+                Arg::Xmm(reg) => moves.push((Reg::Float(reg), dest)),
+                _ => {}
+            }
+        }
+        if let Callee::Reg(reg) = callee {
+            moves.push((Reg::Gpr(reg), Reg::Gpr(R11)));
+            dests.push(Reg::Gpr(R11));
+        }
+
+        // memory whose address is computed from a register that is about to be
+        // overwritten is read onto the stack first
+        let clobbered = |mem: &Mem| {
+            address_regs(mem)
+                .iter()
+                .any(|&reg| dests.contains(&Reg::Gpr(reg)))
+        };
+        let mut stacked = Vec::new();
+        for &(arg, dest) in &in_regs {
+            if let Arg::Mem(mem) | Arg::FloatMem(mem) = arg {
+                if clobbered(&mem) {
+                    asm.load_mem(MachineMode::Int64, Reg::Gpr(R11), at(mem, pushed));
+                    buf::emit_pushq_reg(asm, R11);
+                    pushed += 8;
+                    stacked.push(dest);
+                }
+            }
+        }
+
+        parallel_move(asm, moves);
+
+        for &dest in stacked.iter().rev() {
+            match dest {
+                Reg::Gpr(reg) => buf::emit_popq_reg(asm, reg),
+                Reg::Float(reg) => {
+                    asm.load_mem(MachineMode::Float64, dest, Mem::Base(RSP, 0));
+                    buf::emit_addq_imm_reg(asm, 8, RSP);
+                }
+            }
+        }
+
+        for &(arg, dest) in &in_regs {
+            match arg {
+                Arg::Imm(imm) => asm.load_int_const(MachineMode::Int64, dest.reg(), imm),
+                Arg::Float(imm) => asm.load_float_const(MachineMode::Float64, dest.freg(), imm),
+                Arg::Mem(mem) | Arg::FloatMem(mem) if !clobbered(&mem) => {
+                    asm.load_mem(arg.mode(), dest, at(mem, 0))
+                }
+                _ => {}
+            }
+        }
+
+        match callee {
+            Callee::Target(target) => asm.call_abs(target),
+            Callee::Reg(_) => buf::emit_callq_reg(asm, R11),
+        }
+
+        match self.result {
+            Some(Reg::Gpr(reg)) if reg != RAX => asm.copy_reg(MachineMode::Int64, reg, RAX),
+            Some(Reg::Float(reg)) if reg != XMM0 => asm.copy_freg(MachineMode::Float64, reg, XMM0),
+            _ => {}
+        }
+
+        for (idx, &reg) in saved.iter().enumerate() {
+            asm.load_mem(mode_of(reg), reg, slot(idx));
+        }
+
+        if self.depth.is_none() {
+            asm.load_mem(MachineMode::Int64, Reg::Gpr(RSP), Mem::Base(RSP, size - 8));
+            // the frame only saw the `sub`
+            asm.cfi_adjust_sp(-size);
+        } else if size > 0 {
+            buf::emit_addq_imm_reg(asm, size, RSP);
+        }
+    }
+}
+
+fn mode_of(reg: Reg) -> MachineMode {
+    match reg {
+        Reg::Gpr(_) => MachineMode::Int64,
+        Reg::Float(_) => MachineMode::Float64,
+    }
+}
+
+fn copy(asm: &mut Assembler, dest: Reg, src: Reg) {
+    match (dest, src) {
+        (Reg::Gpr(dest), Reg::Gpr(src)) => asm.copy_reg(MachineMode::Int64, dest, src),
+        (Reg::Float(dest), Reg::Float(src)) => asm.copy_freg(MachineMode::Float64, dest, src),
+        _ => asm.invalid_operand(format!("cannot move {:?} to {:?}", src, dest)),
+    }
+}
+
+/// Performs all `(src, dest)` moves as if at once. Every register is the
+/// destination of one move at most.
+///
+/// A move is done as soon as no other move still reads its destination. What is
+/// left are cycles, one of which is broken by moving a source out of the way into
+/// R10 or XMM15. Neither is a destination, so the cycle then unwinds before the
+/// next one has to be broken.
+pub fn parallel_move(asm: &mut Assembler, moves: Vec<(Reg, Reg)>) {
+    let mut pending: Vec<(Reg, Reg)> = moves
+        .into_iter()
+        .filter(|(src, dest)| src != dest)
+        .collect();
+
+    while !pending.is_empty() {
+        let ready = pending
+            .iter()
+            .position(|&(_, dest)| !pending.iter().any(|&(src, _)| src == dest));
+
+        match ready {
+            Some(idx) => {
+                let (src, dest) = pending.remove(idx);
+                copy(asm, dest, src);
+            }
+
+            None => {
+                let (src, _) = pending[0];
+                let tmp = match src {
+                    Reg::Gpr(_) => Reg::Gpr(R10),
+                    Reg::Float(_) => Reg::Float(XMM15),
+                };
+
+                copy(asm, tmp, src);
+                pending[0].0 = tmp;
+            }
+        }
+    }
+}
@@ -0,0 +1,462 @@
+//! Executable memory allocator.
+//!
+//! Code is carved out of large regions that are reserved up front. Every region keeps
+//! a free list ordered by offset, so freed blocks are coalesced with their neighbours
+//! and reused by later allocations. Pages that become completely unused are returned
+//! to the OS but stay reserved.
+//!
+//! Writing a block flips its pages to read-write for the duration of the copy. Blocks
+//! never share a page, so code in other blocks stays executable meanwhile and may keep
+//! running on other threads.
+
+use crate::assembler::{Assembler, Code};
+use crate::disasm::Listing;
+use crate::reloc::RelocError;
+use crate::source::LineTable;
+use crate::symbols;
+use crate::unwind::Registration;
+use crate::{discard, page_align, protect, release, reserve, Memory, ProtType, PAGE_SIZE};
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex, OnceLock};
+
+/// Size of a region reserved by the global cache.
+pub const DEFAULT_REGION_SIZE: usize = 16 * 1024 * 1024;
+
+/// Every block starts and ends at this alignment, a page, so changing the protection
+/// of one block never affects another.
+pub const CODE_ALIGNMENT: usize = PAGE_SIZE;
+
+fn block_size(size: usize) -> usize {
+    let size = if size == 0 { 1 } else { size };
+    (size + CODE_ALIGNMENT - 1) & !(CODE_ALIGNMENT - 1)
+}
+
+/// Usage report of a `CodeCache`.
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+pub struct CodeCacheStats {
+    /// Number of reserved regions.
+    pub regions: usize,
+    /// Bytes reserved by all regions.
+    pub reserved: usize,
+    /// Bytes handed out to live allocations.
+    pub used: usize,
+    /// Bytes available for allocation.
+    pub free: usize,
+    /// Number of live allocations.
+    pub allocations: usize,
+    /// Number of disjoint free blocks.
+    pub free_blocks: usize,
+    /// Size of the largest free block.
+    pub largest_free_block: usize,
+}
+
+impl CodeCacheStats {
+    /// Share of free memory that is not part of the largest free block: 0.0 when all
+    /// free memory is contiguous, approaching 1.0 as it gets split into small holes.
+    pub fn fragmentation(&self) -> f64 {
+        if self.free == 0 {
+            return 0.0;
+        }
+
+        1.0 - self.largest_free_block as f64 / self.free as f64
+    }
+}
+
+struct Region {
+    base: *mut u8,
+    size: usize,
+    used: usize,
+    allocations: usize,
+    /// offset -> length of every free block
+    free: BTreeMap<usize, usize>,
+}
+
+unsafe impl Send for Region {}
+
+impl Region {
+    fn new(size: usize) -> Region {
+        let size = page_align(size);
+        let mut free = BTreeMap::new();
+        free.insert(0, size);
+
+        Region {
+            base: reserve(size, ProtType::None),
+            size,
+            used: 0,
+            allocations: 0,
+            free,
+        }
+    }
+
+    fn contains(&self, ptr: *const u8) -> bool {
+        let ptr = ptr as usize;
+        let base = self.base as usize;
+
+        ptr >= base && ptr < base + self.size
+    }
+
+    fn allocate(&mut self, size: usize) -> Option<*mut u8> {
+        let (offset, len) = self
+            .free
+            .iter()
+            .find(|&(_, &len)| len >= size)
+            .map(|(&offset, &len)| (offset, len))?;
+
+        self.free.remove(&offset);
+
+        if len > size {
+            self.free.insert(offset + size, len - size);
+        }
+
+        self.used += size;
+        self.allocations += 1;
+
+        Some(unsafe { self.base.add(offset) })
+    }
+
+    fn free(&mut self, ptr: *const u8, size: usize) {
+        let mut offset = ptr as usize - self.base as usize;
+        let mut len = size;
+
+        self.used -= size;
+        self.allocations -= 1;
+
+        let prev = self
+            .free
+            .range(..offset)
+            .next_back()
+            .map(|(&prev, &prev_len)| (prev, prev_len));
+
+        if let Some((prev, prev_len)) = prev {
+            if prev + prev_len == offset {
+                self.free.remove(&prev);
+                offset = prev;
+                len += prev_len;
+            }
+        }
+
+        if let Some(next_len) = self.free.remove(&(offset + len)) {
+            len += next_len;
+        }
+
+        self.free.insert(offset, len);
+
+        // the pages of the block are no longer needed
+        let pages = unsafe { self.base.add(ptr as usize - self.base as usize) };
+        protect(pages, size, ProtType::None);
+        discard(pages, size);
+    }
+}
+
+/// Allocator for executable memory. `Memory` handles created by `emit` give their
+/// block back when they are dropped.
+pub struct CodeCache {
+    region_size: usize,
+    regions: Mutex<Vec<Region>>,
+}
+
+impl CodeCache {
+    pub fn new(region_size: usize) -> Arc<CodeCache> {
+        Arc::new(CodeCache {
+            region_size: page_align(region_size),
+            regions: Mutex::new(Vec::new()),
+        })
+    }
+
+    /// The cache used by `get_executable_memory`.
+    pub fn global() -> &'static Arc<CodeCache> {
+        static GLOBAL: OnceLock<Arc<CodeCache>> = OnceLock::new();
+
+        GLOBAL.get_or_init(|| CodeCache::new(DEFAULT_REGION_SIZE))
+    }
+
+    /// Allocates a block of at least `size` bytes. The block is not accessible until
+    /// its pages are made writable with `protect`.
+    pub fn allocate(&self, size: usize) -> *mut u8 {
+        let size = block_size(size);
+        let mut regions = self.regions.lock().unwrap();
+
+        for region in regions.iter_mut() {
+            if let Some(ptr) = region.allocate(size) {
+                return ptr;
+            }
+        }
+
+        let mut region = Region::new(self.region_size.max(size));
+        let ptr = region.allocate(size).unwrap();
+        regions.push(region);
+
+        ptr
+    }
+
+    /// Returns a block obtained from `allocate` with the same `size`.
+    pub fn free(&self, ptr: *const u8, size: usize) {
+        let size = block_size(size);
+        let mut regions = self.regions.lock().unwrap();
+
+        let idx = regions
+            .iter()
+            .position(|region| region.contains(ptr))
+            .expect("pointer was not allocated by this code cache");
+
+        regions[idx].free(ptr, size);
+
+        // keep one region around so the next allocation does not have to map again
+        if regions[idx].allocations == 0 && regions.len() > 1 {
+            let region = regions.remove(idx);
+            release(region.base, region.size);
+        }
+    }
+
+    /// Copies the data segment and code of finalized code into the cache, fills in
+    /// its relocations, registers its unwind information and seals it. The source
+    /// positions of the code go to the returned `Memory`. Fails if a relocation
+    /// cannot be applied, e.g. because its symbol is not registered.
+    pub fn emit(self: &Arc<Self>, buf: &Code) -> Result<Memory, RelocError> {
+        let data = buf.data();
+        let dseg = &buf.dseg;
+        let (code_offset, dseg_offset, total_size) = match buf.island {
+            Some(island) => (0, island, island + dseg.size() as usize),
+            None => {
+                let size = dseg.size() as usize;
+                (size, 0, size + data.len())
+            }
+        };
+        let ptr = self.allocate(total_size);
+
+        protect(ptr, total_size, ProtType::Writable);
+
+        let start = unsafe {
+            dseg.finish(ptr.add(dseg_offset));
+
+            let start = ptr.add(code_offset);
+            ::core::ptr::copy_nonoverlapping(data.as_ptr(), start, data.len());
+            start
+        };
+
+        let code = unsafe { ::std::slice::from_raw_parts_mut(start, data.len()) };
+        for reloc in &buf.relocations {
+            let result = reloc
+                .target_address(buf, start as u64)
+                .and_then(|target| reloc.write(code, start as u64, target));
+
+            if let Err(err) = result {
+                self.free(ptr, total_size);
+                return Err(err);
+            }
+        }
+
+        protect(ptr, total_size, ProtType::Executable);
+
+        Ok(self.loaded(Memory {
+            start,
+            end: unsafe { start.add(data.len()) },
+            pointer: ptr,
+            size: total_size,
+            cache: Some(self.clone()),
+            name: buf.name.clone(),
+            listing: if buf.keep_listing {
+                Some(Box::new(Listing::new(buf)))
+            } else {
+                None
+            },
+            relocations: buf.relocations.clone(),
+            unwind: Some(Registration::new(start, data.len(), buf.cfi_ops.clone())),
+            lines: LineTable::new(buf.source_positions.clone()),
+            #[cfg(feature = "gdb-jit")]
+            gdb: None,
+        }))
+    }
+
+    /// Copies code emitted by a `CodeCache` to a new block of this cache and adjusts
+    /// its relocations. The original stays valid until it is dropped.
+    ///
+    /// # Panics
+    /// If a rel32 field no longer reaches its target outside of the code.
+    pub fn copy(self: &Arc<Self>, memory: &Memory) -> Memory {
+        let size = memory.size;
+        let ptr = self.allocate(size);
+
+        protect(ptr, size, ProtType::Writable);
+
+        let start;
+        let code_len = memory.end as usize - memory.start as usize;
+        let code = unsafe {
+            ::core::ptr::copy_nonoverlapping(memory.pointer, ptr, size);
+            start = ptr.add(memory.start as usize - memory.pointer as usize);
+            ::std::slice::from_raw_parts_mut(start, code_len)
+        };
+
+        let delta = start as i64 - memory.start as i64;
+        for reloc in &memory.relocations {
+            if let Err(err) = reloc.moved(code, delta) {
+                self.free(ptr, size);
+                panic!("{}", err);
+            }
+        }
+
+        protect(ptr, size, ProtType::Executable);
+
+        self.loaded(Memory {
+            start,
+            end: unsafe { start.add(code_len) },
+            pointer: ptr,
+            size,
+            cache: Some(self.clone()),
+            name: memory.name.clone(),
+            listing: memory.listing.clone(),
+            relocations: memory.relocations.clone(),
+            unwind: memory
+                .unwind
+                .as_ref()
+                .map(|unwind| Registration::new(start, code_len, unwind.ops().to_vec())),
+            lines: memory.lines.clone(),
+            #[cfg(feature = "gdb-jit")]
+            gdb: None,
+        })
+    }
+
+    /// Announces new code to the symbol table, debuggers and profilers.
+    #[allow(unused_mut)]
+    fn loaded(&self, mut memory: Memory) -> Memory {
+        let start = memory.start;
+        let code_len = memory.end as usize - start as usize;
+
+        if let Some(name) = &memory.name {
+            symbols::register(start, name.as_str());
+        }
+
+        #[cfg(feature = "gdb-jit")]
+        {
+            let name = memory.symbol_name();
+            memory.gdb = Some(crate::gdb_jit::Registration::new(
+                &name,
+                start,
+                code_len,
+                &memory.lines,
+            ));
+        }
+
+        #[cfg(target_os = "linux")]
+        crate::perf::code_loaded(&memory.symbol_name(), start, code_len, &memory.lines);
+
+        memory
+    }
+
+    pub fn stats(&self) -> CodeCacheStats {
+        let regions = self.regions.lock().unwrap();
+        let mut stats = CodeCacheStats::default();
+
+        for region in regions.iter() {
+            stats.regions += 1;
+            stats.reserved += region.size;
+            stats.used += region.used;
+            stats.allocations += region.allocations;
+
+            for &len in region.free.values() {
+                stats.free += len;
+                stats.free_blocks += 1;
+                stats.largest_free_block = stats.largest_free_block.max(len);
+            }
+        }
+
+        stats
+    }
+}
+
+impl Drop for CodeCache {
+    fn drop(&mut self) {
+        for region in self.regions.get_mut().unwrap().drain(..) {
+            release(region.base, region.size);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PAGES: usize = 16;
+
+    #[test]
+    fn blocks_do_not_share_pages() {
+        let cache = CodeCache::new(PAGES * PAGE_SIZE);
+        let a = cache.allocate(1) as usize;
+        let b = cache.allocate(PAGE_SIZE + 1) as usize;
+        let c = cache.allocate(100) as usize;
+
+        assert_eq!(a % PAGE_SIZE, 0);
+        assert_eq!(b - a, PAGE_SIZE);
+        assert_eq!(c - b, 2 * PAGE_SIZE);
+        assert_eq!(cache.stats().used, 4 * PAGE_SIZE);
+    }
+
+    #[test]
+    fn freed_blocks_coalesce_and_are_reused() {
+        let cache = CodeCache::new(PAGES * PAGE_SIZE);
+        let a = cache.allocate(PAGE_SIZE);
+        let b = cache.allocate(PAGE_SIZE);
+        let c = cache.allocate(PAGE_SIZE);
+
+        let stats = cache.stats();
+        assert_eq!(stats.allocations, 3);
+        assert_eq!(stats.used, 3 * PAGE_SIZE);
+        assert_eq!(stats.free_blocks, 1);
+        assert_eq!(stats.largest_free_block, (PAGES - 3) * PAGE_SIZE);
+        assert_eq!(stats.fragmentation(), 0.0);
+
+        cache.free(b, PAGE_SIZE);
+        let stats = cache.stats();
+        assert_eq!(stats.free_blocks, 2);
+        assert_eq!(stats.free, (PAGES - 2) * PAGE_SIZE);
+        assert!(stats.fragmentation() > 0.0);
+
+        // a and b become one block, big enough for two pages
+        cache.free(a, PAGE_SIZE);
+        assert_eq!(cache.stats().free_blocks, 2);
+        assert_eq!(cache.allocate(2 * PAGE_SIZE), a);
+
+        cache.free(a, 2 * PAGE_SIZE);
+        cache.free(c, PAGE_SIZE);
+        let stats = cache.stats();
+        assert_eq!(stats.allocations, 0);
+        assert_eq!(stats.free_blocks, 1);
+        assert_eq!(stats.free, stats.reserved);
+    }
+
+    #[test]
+    fn regions_grow_and_shrink() {
+        let cache = CodeCache::new(4 * PAGE_SIZE);
+        let a = cache.allocate(3 * PAGE_SIZE);
+        let b = cache.allocate(2 * PAGE_SIZE);
+        let c = cache.allocate(10 * PAGE_SIZE);
+
+        let stats = cache.stats();
+        assert_eq!(stats.regions, 3);
+        assert_eq!(stats.reserved, (4 + 4 + 10) * PAGE_SIZE);
+
+        // empty regions are released, except for the last one
+        cache.free(b, 2 * PAGE_SIZE);
+        cache.free(c, 10 * PAGE_SIZE);
+        assert_eq!(cache.stats().regions, 1);
+        cache.free(a, 3 * PAGE_SIZE);
+        assert_eq!(cache.stats().regions, 1);
+        assert_eq!(cache.stats().used, 0);
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn writing_a_block_keeps_others_executable() {
+        use crate::assembler_x64::*;
+        use crate::tests::permissions;
+
+        let cache = CodeCache::new(PAGES * PAGE_SIZE);
+        let mut asm = Assembler::new();
+        emit_retq(&mut asm);
+        let code = asm.finalize().unwrap();
+        let (a, b) = (cache.emit(&code).unwrap(), cache.emit(&code).unwrap());
+
+        a.patch(|_| assert_eq!(permissions(b.start()), "r-x"));
+        assert_eq!(permissions(a.start()), "r-x");
+    }
+}
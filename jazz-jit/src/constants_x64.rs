@@ -1,3 +1,5 @@
+use crate::MachineMode;
+
 #[derive(Clone, Debug, PartialEq, Eq, Copy, PartialOrd, Ord, Hash)]
 #[repr(i32)]
 pub enum Register {
@@ -27,17 +29,15 @@ impl Register {
     pub extern "C" fn is_basic_reg(self) -> bool {
         self == RAX || self == RBX || self == RCX || self == RDX
     }
+    /// Bit 3 of the register number. `RIP` and `kNoRegister` give a value above 1,
+    /// which `emit_rex` reports as an invalid operand.
     #[inline]
     pub extern "C" fn msb(self) -> u8 {
-        assert!(self != RIP);
-
-        (self as u8 >> 3) & 0x01
+        self as u8 >> 3
     }
 
     #[inline]
     pub extern "C" fn and7(self) -> u8 {
-        assert!(self != RIP);
-
         self as u8 & 0x07
     }
 
@@ -50,10 +50,44 @@ impl Register {
     pub extern "C" fn low_bit(&self) -> u8 {
         self.and7()
     }
+
+    /// Name of the register as an operand of `mode`, the way disassemblers print it.
+    /// Float modes name the general purpose register of the same width.
+    pub fn name(self, mode: MachineMode) -> &'static str {
+        let names = match mode {
+            MachineMode::Int8 => &GPR8,
+            MachineMode::Int16 => &GPR16,
+            MachineMode::Int32 | MachineMode::Float32 => &GPR32,
+            _ => &GPR64,
+        };
+
+        match self {
+            RIP => "rip",
+            kNoRegister => "<none>",
+            reg => names[reg as usize],
+        }
+    }
 }
 
 pub use self::Register::*;
 
+const GPR64: [&str; 16] = [
+    "rax", "rcx", "rdx", "rbx", "rsp", "rbp", "rsi", "rdi", "r8", "r9", "r10", "r11", "r12", "r13",
+    "r14", "r15",
+];
+const GPR32: [&str; 16] = [
+    "eax", "ecx", "edx", "ebx", "esp", "ebp", "esi", "edi", "r8d", "r9d", "r10d", "r11d", "r12d",
+    "r13d", "r14d", "r15d",
+];
+const GPR16: [&str; 16] = [
+    "ax", "cx", "dx", "bx", "sp", "bp", "si", "di", "r8w", "r9w", "r10w", "r11w", "r12w", "r13w",
+    "r14w", "r15w",
+];
+const GPR8: [&str; 16] = [
+    "al", "cl", "dl", "bl", "spl", "bpl", "sil", "dil", "r8b", "r9b", "r10b", "r11b", "r12b",
+    "r13b", "r14b", "r15b",
+];
+
 #[derive(Clone, Debug, PartialEq, Eq, Copy, PartialOrd, Ord, Hash)]
 #[repr(i32)]
 pub enum XMMRegister {
@@ -80,14 +114,10 @@ pub enum XMMRegister {
 impl XMMRegister {
     #[inline]
     pub extern "C" fn msb(self) -> u8 {
-        //assert!(self != RIP);
-
-        (self as u8 >> 3) & 0x01
+        self as u8 >> 3
     }
     #[inline]
     pub extern "C" fn and7(self) -> u8 {
-        //assert!(self != RIP);
-
         self as u8 & 0x07
     }
 
@@ -108,6 +138,144 @@ impl XMMRegister {
 
 pub use self::XMMRegister::*;
 
+/// 256-bit AVX registers. `YMMn` shares its low 128 bits with `XMMn`.
+#[derive(Clone, Debug, PartialEq, Eq, Copy, PartialOrd, Ord, Hash)]
+#[repr(i32)]
+pub enum YMMRegister {
+    YMM0 = 0,
+    YMM1 = 1,
+    YMM2 = 2,
+    YMM3 = 3,
+    YMM4 = 4,
+    YMM5 = 5,
+    YMM6 = 6,
+    YMM7 = 7,
+    YMM8 = 8,
+    YMM9 = 9,
+    YMM10 = 10,
+    YMM11 = 11,
+    YMM12 = 12,
+    YMM13 = 13,
+    YMM14 = 14,
+    YMM15 = 15,
+    kNumberOfYmmRegisters = 16,
+    kNoYmmRegister = -1, // Signals an illegal register.
+}
+
+impl YMMRegister {
+    #[inline]
+    pub extern "C" fn msb(self) -> u8 {
+        self as u8 >> 3
+    }
+    #[inline]
+    pub extern "C" fn and7(self) -> u8 {
+        self as u8 & 0x07
+    }
+
+    #[inline]
+    pub extern "C" fn high_bit(&self) -> u8 {
+        self.msb()
+    }
+
+    #[inline]
+    pub extern "C" fn low_bit(&self) -> u8 {
+        self.and7()
+    }
+    /// The register holding the low 128 bits.
+    #[inline]
+    pub extern "C" fn xmm(self) -> XMMRegister {
+        unsafe { std::mem::transmute(self) }
+    }
+}
+
+impl XMMRegister {
+    /// The 256-bit register this register is the low half of.
+    #[inline]
+    pub extern "C" fn ymm(self) -> YMMRegister {
+        unsafe { std::mem::transmute(self) }
+    }
+}
+
+pub use self::YMMRegister::*;
+
+/// 512-bit AVX-512 registers. `ZMMn` shares its low 256 bits with `YMMn`, the
+/// registers from 16 up are only reachable through EVEX prefixes.
+#[derive(Clone, Debug, PartialEq, Eq, Copy, PartialOrd, Ord, Hash)]
+#[repr(i32)]
+pub enum ZMMRegister {
+    ZMM0 = 0,
+    ZMM1 = 1,
+    ZMM2 = 2,
+    ZMM3 = 3,
+    ZMM4 = 4,
+    ZMM5 = 5,
+    ZMM6 = 6,
+    ZMM7 = 7,
+    ZMM8 = 8,
+    ZMM9 = 9,
+    ZMM10 = 10,
+    ZMM11 = 11,
+    ZMM12 = 12,
+    ZMM13 = 13,
+    ZMM14 = 14,
+    ZMM15 = 15,
+    ZMM16 = 16,
+    ZMM17 = 17,
+    ZMM18 = 18,
+    ZMM19 = 19,
+    ZMM20 = 20,
+    ZMM21 = 21,
+    ZMM22 = 22,
+    ZMM23 = 23,
+    ZMM24 = 24,
+    ZMM25 = 25,
+    ZMM26 = 26,
+    ZMM27 = 27,
+    ZMM28 = 28,
+    ZMM29 = 29,
+    ZMM30 = 30,
+    ZMM31 = 31,
+    kNumberOfZmmRegisters = 32,
+    kNoZmmRegister = -1, // Signals an illegal register.
+}
+
+impl ZMMRegister {
+    #[inline]
+    pub extern "C" fn msb(self) -> u8 {
+        self as u8 >> 3 & 1
+    }
+    #[inline]
+    pub extern "C" fn and7(self) -> u8 {
+        self as u8 & 0x07
+    }
+    /// Bit 4 of the register number, encoded in EVEX.R', EVEX.X or EVEX.V'.
+    #[inline]
+    pub extern "C" fn high16(self) -> u8 {
+        self as u8 >> 4 & 1
+    }
+}
+
+pub use self::ZMMRegister::*;
+
+/// AVX-512 opmask registers. K0 cannot be used as a write mask, encoding it
+/// means no masking.
+#[derive(Clone, Debug, PartialEq, Eq, Copy, PartialOrd, Ord, Hash)]
+#[repr(i32)]
+pub enum KRegister {
+    K0 = 0,
+    K1 = 1,
+    K2 = 2,
+    K3 = 3,
+    K4 = 4,
+    K5 = 5,
+    K6 = 6,
+    K7 = 7,
+    kNumberOfKRegisters = 8,
+    kNoKRegister = -1, // Signals an illegal register.
+}
+
+pub use self::KRegister::*;
+
 pub type FpuRegister = XMMRegister;
 pub const FpuTMP: FpuRegister = XMM0;
 
@@ -140,17 +308,21 @@ pub enum Reg {
 }
 
 impl Reg {
+    /// # Panics
+    /// If this is a float register.
     pub extern "C" fn reg(&self) -> Register {
         match self {
             Reg::Gpr(reg) => *reg,
-            _ => panic!(""),
+            _ => panic!("{:?} is not a general purpose register", self),
         }
     }
 
+    /// # Panics
+    /// If this is a general purpose register.
     pub extern "C" fn freg(&self) -> XMMRegister {
         match self {
             Reg::Float(float) => *float,
-            _ => panic!(""),
+            _ => panic!("{:?} is not a float register", self),
         }
     }
 }
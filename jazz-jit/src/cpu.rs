@@ -0,0 +1,238 @@
+//! Instruction set extensions of the CPU generated code runs on.
+//!
+//! `CpuFeatures::host()` is detected once per process. `Assembler::new` targets
+//! every extension, `Assembler::for_host` the features of `CpuFeatures::current()`
+//! and `Assembler::with_features` any other set: encoders of instructions from an
+//! extension the target lacks record an error, and code generators query
+//! `Assembler::has_feature` to pick an implementation the target supports.
+
+use std::cell::Cell;
+use std::fmt;
+use std::marker::PhantomData;
+use std::sync::OnceLock;
+
+/// An instruction set extension beyond the 8086 to x86-64 base instructions.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+#[repr(C)]
+pub enum CpuFeature {
+    Sse2,
+    Sse3,
+    Ssse3,
+    Sse41,
+    Sse42,
+    Popcnt,
+    Lzcnt,
+    Bmi1,
+    Bmi2,
+    Avx,
+    Avx2,
+    Fma,
+    Avx512F,
+    Avx512Cd,
+    Avx512Bw,
+    Avx512Dq,
+    Avx512Vl,
+}
+
+impl CpuFeature {
+    pub const ALL: [CpuFeature; 17] = [
+        CpuFeature::Sse2,
+        CpuFeature::Sse3,
+        CpuFeature::Ssse3,
+        CpuFeature::Sse41,
+        CpuFeature::Sse42,
+        CpuFeature::Popcnt,
+        CpuFeature::Lzcnt,
+        CpuFeature::Bmi1,
+        CpuFeature::Bmi2,
+        CpuFeature::Avx,
+        CpuFeature::Avx2,
+        CpuFeature::Fma,
+        CpuFeature::Avx512F,
+        CpuFeature::Avx512Cd,
+        CpuFeature::Avx512Bw,
+        CpuFeature::Avx512Dq,
+        CpuFeature::Avx512Vl,
+    ];
+
+    /// The name used by `is_x86_feature_detected!`.
+    pub fn name(self) -> &'static str {
+        match self {
+            CpuFeature::Sse2 => "sse2",
+            CpuFeature::Sse3 => "sse3",
+            CpuFeature::Ssse3 => "ssse3",
+            CpuFeature::Sse41 => "sse4.1",
+            CpuFeature::Sse42 => "sse4.2",
+            CpuFeature::Popcnt => "popcnt",
+            CpuFeature::Lzcnt => "lzcnt",
+            CpuFeature::Bmi1 => "bmi1",
+            CpuFeature::Bmi2 => "bmi2",
+            CpuFeature::Avx => "avx",
+            CpuFeature::Avx2 => "avx2",
+            CpuFeature::Fma => "fma",
+            CpuFeature::Avx512F => "avx512f",
+            CpuFeature::Avx512Cd => "avx512cd",
+            CpuFeature::Avx512Bw => "avx512bw",
+            CpuFeature::Avx512Dq => "avx512dq",
+            CpuFeature::Avx512Vl => "avx512vl",
+        }
+    }
+
+    fn bit(self) -> u32 {
+        1 << self as u32
+    }
+}
+
+impl fmt::Display for CpuFeature {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// A set of `CpuFeature`s.
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Default)]
+#[repr(C)]
+pub struct CpuFeatures {
+    bits: u32,
+}
+
+thread_local! {
+    static OVERRIDE: Cell<Option<CpuFeatures>> = const { Cell::new(None) };
+}
+
+impl CpuFeatures {
+    pub const fn none() -> CpuFeatures {
+        CpuFeatures { bits: 0 }
+    }
+
+    pub fn all() -> CpuFeatures {
+        CpuFeature::ALL
+            .iter()
+            .fold(CpuFeatures::none(), |features, &feature| {
+                features.with(feature)
+            })
+    }
+
+    /// The features of the machine the process runs on, detected on first use.
+    pub fn host() -> CpuFeatures {
+        static HOST: OnceLock<CpuFeatures> = OnceLock::new();
+        *HOST.get_or_init(detect)
+    }
+
+    /// The features set by `override_for_testing` on this thread, or the host's.
+    pub fn current() -> CpuFeatures {
+        OVERRIDE
+            .with(|features| features.get())
+            .unwrap_or_else(CpuFeatures::host)
+    }
+
+    /// Makes `current` return `features` on this thread until the returned guard is
+    /// dropped. Assemblers created by `Assembler::for_host` meanwhile target
+    /// `features`, which lets tests run the fallback paths of code generators on a
+    /// machine that has every extension.
+    pub fn override_for_testing(features: CpuFeatures) -> FeatureOverride {
+        let previous = OVERRIDE.with(|current| current.replace(Some(features)));
+
+        FeatureOverride {
+            previous,
+            _thread: PhantomData,
+        }
+    }
+
+    pub fn has(self, feature: CpuFeature) -> bool {
+        self.bits & feature.bit() != 0
+    }
+
+    pub fn with(self, feature: CpuFeature) -> CpuFeatures {
+        CpuFeatures {
+            bits: self.bits | feature.bit(),
+        }
+    }
+
+    pub fn without(self, feature: CpuFeature) -> CpuFeatures {
+        CpuFeatures {
+            bits: self.bits & !feature.bit(),
+        }
+    }
+
+    pub fn iter(self) -> impl Iterator<Item = CpuFeature> {
+        CpuFeature::ALL
+            .iter()
+            .cloned()
+            .filter(move |&feature| self.has(feature))
+    }
+}
+
+impl fmt::Debug for CpuFeatures {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_set().entries(self.iter()).finish()
+    }
+}
+
+impl fmt::Display for CpuFeatures {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, feature) in self.iter().enumerate() {
+            let sep = if i == 0 { "" } else { " " };
+            write!(f, "{}{}", sep, feature)?;
+        }
+        Ok(())
+    }
+}
+
+/// Restores the previous features of `CpuFeatures::current` when dropped. Bound to
+/// the thread that created it.
+#[must_use]
+pub struct FeatureOverride {
+    previous: Option<CpuFeatures>,
+    _thread: PhantomData<*const ()>,
+}
+
+impl Drop for FeatureOverride {
+    fn drop(&mut self) {
+        OVERRIDE.with(|current| current.set(self.previous));
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+fn detect() -> CpuFeatures {
+    let mut features = CpuFeatures::none();
+
+    // `is_x86_feature_detected!` also checks that the OS saves the AVX and AVX-512
+    // register state, CPUID alone is not enough
+    macro_rules! detect {
+        ($($feature:ident: $name:tt),*) => {
+            $(
+                if is_x86_feature_detected!($name) {
+                    features = features.with(CpuFeature::$feature);
+                }
+            )*
+        };
+    }
+
+    detect!(
+        Sse2: "sse2",
+        Sse3: "sse3",
+        Ssse3: "ssse3",
+        Sse41: "sse4.1",
+        Sse42: "sse4.2",
+        Popcnt: "popcnt",
+        Lzcnt: "lzcnt",
+        Bmi1: "bmi1",
+        Bmi2: "bmi2",
+        Avx: "avx",
+        Avx2: "avx2",
+        Fma: "fma",
+        Avx512F: "avx512f",
+        Avx512Cd: "avx512cd",
+        Avx512Bw: "avx512bw",
+        Avx512Dq: "avx512dq",
+        Avx512Vl: "avx512vl"
+    );
+
+    features
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+fn detect() -> CpuFeatures {
+    CpuFeatures::none()
+}
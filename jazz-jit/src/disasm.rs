@@ -0,0 +1,317 @@
+//! Annotated disassembly listings.
+//!
+//! Besides the instructions a listing shows bound labels, comments attached with
+//! `Assembler::comment`, the data segment constants loaded by an instruction and the
+//! names of call targets registered in `symbols`.
+
+use crate::assembler::Assembler;
+use crate::dseg::{DSeg, Value};
+use crate::symbols;
+use capstone::prelude::*;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Syntax {
+    Intel,
+    Att,
+}
+
+/// Everything besides the code bytes needed to render a listing. `Memory` keeps one
+/// if the assembler was told to, so that finalized code can be listed as well.
+#[derive(Debug, Clone)]
+pub struct Listing {
+    labels: BTreeMap<usize, Vec<String>>,
+    comments: BTreeMap<usize, Vec<String>>,
+    dseg_refs: Vec<usize>,
+    dseg: DSeg,
+    dseg_start: i64,
+}
+
+impl Listing {
+    pub fn new(asm: &Assembler) -> Listing {
+        let mut labels: BTreeMap<usize, Vec<String>> = BTreeMap::new();
+        let mut comments: BTreeMap<usize, Vec<String>> = BTreeMap::new();
+
+        for (lbl, pos) in asm.labels.iter().enumerate() {
+            if let Some(pos) = *pos {
+                let name = match asm.label_names.get(&lbl) {
+                    Some(name) => name.clone(),
+                    None => format!("L{}", lbl),
+                };
+                labels.entry(pos).or_default().push(name);
+            }
+        }
+
+        for (pos, text) in &asm.comments {
+            comments.entry(*pos).or_default().push(text.clone());
+        }
+
+        Listing {
+            labels,
+            comments,
+            dseg_refs: asm.dseg_refs.clone(),
+            dseg: asm.dseg.clone(),
+            dseg_start: asm.dseg_start(),
+        }
+    }
+
+    pub fn empty() -> Listing {
+        Listing {
+            labels: BTreeMap::new(),
+            comments: BTreeMap::new(),
+            dseg_refs: Vec::new(),
+            dseg: DSeg::new(),
+            dseg_start: 0,
+        }
+    }
+
+    /// Renders `code`, whose first byte is at address `base`.
+    pub fn render(&self, code: &[u8], base: u64, syntax: Syntax) -> String {
+        let syntax = match syntax {
+            Syntax::Intel => arch::x86::ArchSyntax::Intel,
+            Syntax::Att => arch::x86::ArchSyntax::Att,
+        };
+        let cs = Capstone::new()
+            .x86()
+            .mode(arch::x86::ArchMode::Mode64)
+            .syntax(syntax)
+            .build();
+        let mut out = String::new();
+
+        let insns = match cs.as_ref().map(|cs| cs.disasm_all(code, base)) {
+            Ok(Ok(insns)) => insns,
+            Ok(Err(err)) => {
+                let _ = writeln!(out, "<disassembly failed: {}>", err);
+                return out;
+            }
+            Err(err) => {
+                let _ = writeln!(out, "<disassembly failed: {}>", err);
+                return out;
+            }
+        };
+
+        let mut end = 0;
+
+        for insn in insns.iter() {
+            let offset = (insn.address() - base) as usize;
+            let len = insn.bytes().len();
+            self.header(&mut out, offset);
+
+            let mnemonic = insn.mnemonic().unwrap_or("");
+            let op_str = insn.op_str().unwrap_or("");
+            let text = format!("{} {}", mnemonic, op_str);
+            let bytes = insn
+                .bytes()
+                .iter()
+                .map(|byte| format!("{:02x}", byte))
+                .collect::<Vec<_>>()
+                .join(" ");
+
+            let _ = write!(
+                out,
+                "  {:>#8x}:  {:<30} {}",
+                insn.address(),
+                bytes,
+                text.trim()
+            );
+
+            if let Some(note) = self.annotation(
+                mnemonic,
+                op_str,
+                offset,
+                len,
+                insn.bytes(),
+                base,
+                code.len(),
+            ) {
+                let _ = write!(out, "  ; {}", note);
+            }
+            out.push('\n');
+
+            end = offset + len;
+        }
+
+        if end < code.len() {
+            let _ = writeln!(
+                out,
+                "  {:>#8x}:  <{} bytes not decoded>",
+                base + end as u64,
+                code.len() - end
+            );
+        }
+
+        // labels bound at the very end of the code
+        self.header(&mut out, code.len());
+        out
+    }
+
+    fn header(&self, out: &mut String, offset: usize) {
+        if let Some(names) = self.labels.get(&offset) {
+            for name in names {
+                let _ = writeln!(out, "{}:", name);
+            }
+        }
+
+        if let Some(comments) = self.comments.get(&offset) {
+            for comment in comments {
+                let _ = writeln!(out, "  ; {}", comment);
+            }
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn annotation(
+        &self,
+        mnemonic: &str,
+        op_str: &str,
+        offset: usize,
+        len: usize,
+        bytes: &[u8],
+        base: u64,
+        code_len: usize,
+    ) -> Option<String> {
+        // a data segment load ends with its displacement
+        if len >= 4 && self.dseg_refs.contains(&(offset + len - 4)) {
+            let disp = i32::from_le_bytes([
+                bytes[len - 4],
+                bytes[len - 3],
+                bytes[len - 2],
+                bytes[len - 1],
+            ]);
+            let target = (offset + len) as i64 + disp as i64 - self.dseg_start;
+
+            return match self.dseg.value_at(target as i32) {
+                Some(value) => Some(format!("= {}", format_value(value))),
+                None => Some(format!("dseg+{:#x}", target)),
+            };
+        }
+
+        let is_branch = mnemonic.starts_with('j') || mnemonic.starts_with("call");
+        if !is_branch && !mnemonic.starts_with("mov") {
+            return None;
+        }
+
+        let target = op_str.split(',').filter_map(parse_imm).next()?;
+
+        if is_branch && target >= base && target <= base + code_len as u64 {
+            if let Some(names) = self.labels.get(&((target - base) as usize)) {
+                return Some(format!("-> {}", names.join(", ")));
+            }
+        }
+
+        symbols::lookup(target as *const u8).map(|name| format!("-> {}", name))
+    }
+}
+
+fn parse_imm(operand: &str) -> Option<u64> {
+    let operand = operand.trim().trim_start_matches('$');
+
+    match operand.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => operand.parse().ok(),
+    }
+}
+
+fn format_value(value: &Value) -> String {
+    match value {
+        Value::Ptr(ptr) => match symbols::lookup(*ptr) {
+            Some(name) => format!("{:p} ({})", ptr, name),
+            None => format!("{:p}", ptr),
+        },
+        Value::Float(v) => format!("{:?} (f32)", v),
+        Value::Double(v) => format!("{:?} (f64)", v),
+        Value::Int(v) => format!("{} (i32)", v),
+        Value::F4(v) => format!("{:?}", v),
+        Value::V128(v) => format!("{:02x?}", v),
+        Value::V256(v) => format!("{:02x?}", v),
+        Value::Blob(bytes, _) => format!("{} bytes", bytes.len()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler_x64::*;
+    use crate::code_cache::CodeCache;
+    use crate::constants_x64::*;
+    use crate::{CondCode, MachineMode};
+
+    /// A loop counting RDI down, loading a constant and an address registered as
+    /// `disasm_callee`, then jumping to an unnamed label at the very end.
+    fn function(callee: *const u8) -> Assembler {
+        let mut asm = Assembler::new();
+        let top = asm.create_label();
+        let end = asm.create_label();
+        asm.name_label(top, "top");
+
+        asm.bind_label(top);
+        asm.comment("count down");
+        emit_subq_imm_reg(&mut asm, 1, RDI);
+        asm.jump_if(CondCode::NotEqual, top);
+        asm.load_float_const(MachineMode::Float64, XMM0, 1.5);
+        asm.load_int_const(MachineMode::Int64, RAX, callee as i64);
+        asm.comment("done");
+        asm.comment("really");
+        asm.jump(end);
+        asm.bind_label(end);
+
+        asm
+    }
+
+    #[test]
+    fn listings_are_annotated() {
+        let callee = 0x1234_5678 as *const u8;
+        symbols::register(callee, "disasm_callee");
+        let listing = function(callee).disassemble(Syntax::Intel);
+        symbols::unregister(callee);
+        let lines: Vec<&str> = listing.lines().collect();
+
+        assert_eq!(lines[0], "top:");
+        assert_eq!(lines[1], "  ; count down");
+        assert!(lines[2].ends_with("sub rdi, 1"));
+        assert!(lines[3].ends_with("jne 0  ; -> top"));
+        assert!(lines[4].ends_with("  ; = 1.5 (f64)"));
+        assert!(lines[5].ends_with("movabs rax, 0x12345678  ; -> disasm_callee"));
+        assert_eq!(&lines[6..8], ["  ; done", "  ; really"]);
+        assert!(lines[8].ends_with("  ; -> L1"));
+        assert_eq!(lines[9], "L1:");
+        assert_eq!(lines.len(), 10);
+    }
+
+    #[test]
+    fn finalized_code_keeps_its_listing() {
+        let mut asm = function(std::ptr::null());
+        asm.keep_listing = true;
+        let code = asm.finalize().unwrap();
+        let memory = CodeCache::new(1 << 16).emit(&code).unwrap();
+        let listing = memory.disassemble_with(Syntax::Att);
+
+        assert!(listing.starts_with("top:\n  ; count down\n"));
+        assert!(listing.contains(&format!("{:#x}:", memory.start() as usize)));
+        assert!(listing.contains("subq $1, %rdi"));
+        assert!(listing.contains("; = 1.5 (f64)"));
+        assert!(listing.ends_with("  ; -> L1\nL1:\n"));
+    }
+
+    #[test]
+    fn listings_are_kept_on_request() {
+        let code = function(std::ptr::null()).finalize().unwrap();
+        let memory = CodeCache::new(1 << 16).emit(&code).unwrap();
+        let listing = memory.disassemble();
+
+        assert!(listing.contains("sub rdi, 1"));
+        assert!(!listing.contains("top:"));
+        assert!(!listing.contains("; count down"));
+    }
+
+    #[test]
+    fn unknown_data_segment_offsets_are_shown_raw() {
+        let asm = function(std::ptr::null());
+        let mut listing = Listing::new(&asm);
+        listing.dseg = DSeg::new();
+
+        let text = listing.render(asm.data(), 0, Syntax::Intel);
+        assert!(text.contains("[rip - 0x16]  ; dseg+0x"));
+    }
+}
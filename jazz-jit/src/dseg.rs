@@ -1,12 +1,32 @@
-///! TODO: Don't use Data segment at all
+//! Constant pool of an `Assembler`.
+//!
+//! Constants are addressed RIP-relative. By default the pool lies in front of the
+//! code and every entry is identified by its distance `disp` from the start of the
+//! code. With `Placement::Island` the same layout is placed behind the code instead
+//! and `Assembler::finalize` points every reference at it.
+//!
+//! Equal constants are stored once. The start of the code (or the island) is aligned
+//! to the widest entry, so vector constants can be loaded with aligned moves.
+//!
+//! Constants that cannot be stored are recorded like the errors of the instruction
+//! encoders and reported by `Assembler::finalize`.
 use crate::align;
+use crate::assembler::AsmDiagnostic;
+use crate::code_cache::CODE_ALIGNMENT;
 use core::mem::size_of;
+use std::collections::HashMap;
 
 #[derive(Debug, Clone)]
 #[repr(C)]
 pub struct DSeg {
     entries: Vec<Entry>,
     size: i32,
+    alignment: i32,
+    reused: usize,
+    placement: Placement,
+    /// Displacement of the entry holding each stored constant and alignment.
+    lookup: HashMap<(Vec<u8>, i32), i32>,
+    diagnostics: Vec<AsmDiagnostic>,
 }
 
 #[derive(Debug, Clone)]
@@ -16,6 +36,29 @@ pub struct Entry {
     value: Value,
 }
 
+/// Where the data segment is placed relative to the code.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[repr(C)]
+pub enum Placement {
+    /// In front of the code, addressed with negative displacements.
+    Front,
+    /// Behind the code, at the first offset aligned to the widest entry.
+    Island,
+}
+
+/// Usage report of a `DSeg`.
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+pub struct DSegStats {
+    /// Number of stored constants.
+    pub entries: usize,
+    /// Size of the data segment including padding.
+    pub size: usize,
+    /// Bytes lost to alignment.
+    pub padding: usize,
+    /// Number of constants that were added again and reused an existing entry.
+    pub reused: usize,
+}
+
 #[derive(Copy, Clone, Debug, PartialEq)]
 #[repr(C)]
 pub struct f32x4(pub f32, pub f32, pub f32, pub f32);
@@ -28,16 +71,49 @@ pub enum Value {
     Double(f64),
     Int(i32),
     F4(f32x4),
+    /// 128-bit integer vector, 16 byte aligned.
+    V128([u8; 16]),
+    /// 256-bit integer vector, 32 byte aligned.
+    V256([u8; 32]),
+    /// Raw bytes and their alignment.
+    Blob(Vec<u8>, i32),
 }
 
 impl Value {
     pub extern "C" fn size(&self) -> i32 {
-        match self {
-            &Value::Ptr(_) => size_of::<*const u8>() as i32,
-            &Value::Int(_) => size_of::<i32>() as i32,
-            &Value::Float(_) => size_of::<f32>() as i32,
-            &Value::Double(_) => size_of::<f64>() as i32,
-            &Value::F4(_) => size_of::<f32x4>() as i32,
+        match *self {
+            Value::Ptr(_) => size_of::<*const u8>() as i32,
+            Value::Int(_) => size_of::<i32>() as i32,
+            Value::Float(_) => size_of::<f32>() as i32,
+            Value::Double(_) => size_of::<f64>() as i32,
+            Value::F4(_) => size_of::<f32x4>() as i32,
+            Value::V128(_) => 16,
+            Value::V256(_) => 32,
+            Value::Blob(ref bytes, _) => bytes.len() as i32,
+        }
+    }
+
+    pub fn alignment(&self) -> i32 {
+        match *self {
+            Value::Blob(_, alignment) => alignment,
+            _ => self.size(),
+        }
+    }
+
+    /// The bytes stored in the data segment. Entries are compared by these, so
+    /// `-0.0` and `0.0` are different constants and a NaN equals itself.
+    pub fn bytes(&self) -> Vec<u8> {
+        match *self {
+            Value::Ptr(v) => (v as usize).to_le_bytes().to_vec(),
+            Value::Float(v) => v.to_le_bytes().to_vec(),
+            Value::Double(v) => v.to_le_bytes().to_vec(),
+            Value::Int(v) => v.to_le_bytes().to_vec(),
+            Value::F4(v) => [v.0, v.1, v.2, v.3].iter()
+                                                .flat_map(|lane| lane.to_le_bytes().to_vec())
+                                                .collect(),
+            Value::V128(ref v) => v.to_vec(),
+            Value::V256(ref v) => v.to_vec(),
+            Value::Blob(ref bytes, _) => bytes.clone(),
         }
     }
 }
@@ -45,58 +121,96 @@ impl Value {
 impl DSeg {
     pub extern "C" fn new() -> DSeg {
         DSeg { entries: Vec::new(),
-               size: 0 }
+               size: 0,
+               alignment: 1,
+               reused: 0,
+               placement: Placement::Front,
+               lookup: HashMap::new(),
+               diagnostics: Vec::new() }
     }
 
-    pub extern "C" fn size(&self) -> i32 { self.size }
+    /// Size of the data segment, padded so that the code behind it (or, for an
+    /// island, the end of the island) is aligned to the widest entry.
+    pub extern "C" fn size(&self) -> i32 { align(self.size, self.alignment) }
+
+    /// The widest alignment of any entry, the start of the code has to be aligned
+    /// to it.
+    pub fn alignment(&self) -> i32 { self.alignment }
+
+    pub fn placement(&self) -> Placement { self.placement }
+
+    pub fn set_placement(&mut self, placement: Placement) { self.placement = placement; }
+
+    pub fn is_empty(&self) -> bool { self.entries.is_empty() }
+
+    /// Problems with the constants added so far.
+    pub fn diagnostics(&self) -> &[AsmDiagnostic] { &self.diagnostics }
+
+    pub(crate) fn take_diagnostics(&mut self) -> Vec<AsmDiagnostic> {
+        ::std::mem::take(&mut self.diagnostics)
+    }
+
+    /// Records an invalid constant. The returned displacement addresses no entry, the
+    /// code cannot be finalized anyway.
+    fn invalid_constant(&mut self, message: &str) -> i32 {
+        self.diagnostics
+            .push(AsmDiagnostic::InvalidConstant { message: message.to_owned() });
+        0
+    }
+
+    /// Checks that `alignment` is a power of two the start of a code block satisfies.
+    fn check_alignment(&mut self, alignment: i32) -> bool {
+        if alignment <= 0 || alignment & (alignment - 1) != 0 {
+            self.invalid_constant("alignment must be a power of two");
+            return false;
+        }
+
+        if alignment as usize > CODE_ALIGNMENT {
+            self.invalid_constant("alignment must not exceed the alignment of code blocks");
+            return false;
+        }
+
+        true
+    }
 
     fn add_value(&mut self, v: Value) -> i32 {
-        let size = v.size();
-        self.size = align(self.size() + size, size);
-        let entry = Entry { disp: self.size(),
+        let alignment = v.alignment();
+        if !self.check_alignment(alignment) {
+            return 0;
+        }
+
+        let key = (v.bytes(), alignment);
+        if let Some(&disp) = self.lookup.get(&key) {
+            self.reused += 1;
+            return disp;
+        }
+
+        self.size = align(self.size + v.size(), alignment);
+        self.alignment = self.alignment.max(alignment);
+        let entry = Entry { disp: self.size,
                             value: v };
 
         self.entries.push(entry);
+        self.lookup.insert(key, self.size);
         self.size
     }
 
+    /// Writes every entry to the data segment starting at `ptr`.
     pub extern "C" fn finish(&self, ptr: *const u8) {
+        let size = self.size();
+
         for entry in &self.entries {
-            let offset = self.size - entry.disp;
+            let offset = size - entry.disp;
+            let bytes = entry.value.bytes();
 
             unsafe {
-                let entry_ptr = ptr.offset(offset as isize);
-
-                match entry.value {
-                    Value::Ptr(v) => *(entry_ptr as *mut (*const u8)) = v,
-                    Value::Float(v) => {
-                        *(entry_ptr as *mut f32) = v;
-                    }
-
-                    Value::Double(v) => {
-                        *(entry_ptr as *mut f64) = v;
-                    }
-
-                    Value::Int(v) => {
-                        *(entry_ptr as *mut i32) = v;
-                    }
-                    Value::F4(v) => {
-                        *(entry_ptr as *mut f32x4) = v;
-                    }
-                }
+                let entry_ptr = ptr.offset(offset as isize) as *mut u8;
+                ::core::ptr::copy_nonoverlapping(bytes.as_ptr(), entry_ptr, bytes.len());
             }
         }
     }
 
-    pub extern "C" fn add_addr_reuse(&mut self, ptr: *const u8) -> i32 {
-        for entry in &self.entries {
-            if entry.value == Value::Ptr(ptr) {
-                return entry.disp;
-            }
-        }
-
-        self.add_addr(ptr)
-    }
+    pub extern "C" fn add_addr_reuse(&mut self, ptr: *const u8) -> i32 { self.add_addr(ptr) }
     pub extern "C" fn add_f32x4(&mut self, value: f32x4) -> i32 { self.add_value(Value::F4(value)) }
     pub extern "C" fn add_int(&mut self, value: i32) -> i32 { self.add_value(Value::Int(value)) }
 
@@ -112,10 +226,50 @@ impl DSeg {
         self.add_value(Value::Float(value))
     }
 
+    pub fn add_v128(&mut self, value: [u8; 16]) -> i32 { self.add_value(Value::V128(value)) }
+
+    pub fn add_v256(&mut self, value: [u8; 32]) -> i32 { self.add_value(Value::V256(value)) }
+
+    /// Adds `bytes` at an address aligned to `alignment`, a power of two of at most
+    /// `CODE_ALIGNMENT`.
+    pub fn add_blob(&mut self, bytes: &[u8], alignment: i32) -> i32 {
+        if bytes.is_empty() {
+            return self.invalid_constant("constants must not be empty");
+        }
+
+        self.add_value(Value::Blob(bytes.to_vec(), alignment))
+    }
+
+    /// The value stored at `offset` bytes from the start of the data segment.
+    pub fn value_at(&self, offset: i32) -> Option<&Value> {
+        let size = self.size();
+
+        self.entries
+            .iter()
+            .find(|entry| {
+                      let start = size - entry.disp;
+                      offset >= start && offset < start + entry.value.size()
+                  })
+            .map(|entry| &entry.value)
+    }
+
     pub extern "C" fn align(&mut self, size: i32) -> i32 {
-        assert!(size > 0);
+        if !self.check_alignment(size) {
+            return self.size;
+        }
+
         self.size = align(self.size, size);
+        self.alignment = self.alignment.max(size);
 
         self.size
     }
+
+    pub fn stats(&self) -> DSegStats {
+        let used: i32 = self.entries.iter().map(|entry| entry.value.size()).sum();
+
+        DSegStats { entries: self.entries.len(),
+                    size: self.size() as usize,
+                    padding: (self.size() - used) as usize,
+                    reused: self.reused }
+    }
 }
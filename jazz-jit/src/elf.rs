@@ -0,0 +1,260 @@
+//! Minimal ELF64 object writer.
+//!
+//! Only what is needed to describe generated code to other tools: sections, a
+//! symbol table, the string tables that go with them and relocation sections.
+
+use byteorder::{LittleEndian, WriteBytesExt};
+
+pub const ET_REL: u16 = 1;
+pub const EM_X86_64: u16 = 62;
+
+pub const SHT_NULL: u32 = 0;
+pub const SHT_PROGBITS: u32 = 1;
+pub const SHT_SYMTAB: u32 = 2;
+pub const SHT_STRTAB: u32 = 3;
+pub const SHT_RELA: u32 = 4;
+pub const SHT_NOBITS: u32 = 8;
+
+pub const SHF_WRITE: u64 = 0x1;
+pub const SHF_ALLOC: u64 = 0x2;
+pub const SHF_EXECINSTR: u64 = 0x4;
+pub const SHF_INFO_LINK: u64 = 0x40;
+
+pub const STB_LOCAL: u8 = 0;
+pub const STB_GLOBAL: u8 = 1;
+
+pub const STT_NOTYPE: u8 = 0;
+pub const STT_OBJECT: u8 = 1;
+pub const STT_FUNC: u8 = 2;
+pub const STT_SECTION: u8 = 3;
+pub const STT_FILE: u8 = 4;
+
+pub const SHN_UNDEF: u16 = 0;
+pub const SHN_ABS: u16 = 0xfff1;
+
+pub const R_X86_64_64: u32 = 1;
+pub const R_X86_64_PC32: u32 = 2;
+pub const R_X86_64_PLT32: u32 = 4;
+
+const EHDR_SIZE: usize = 64;
+pub(crate) const SHDR_SIZE: usize = 64;
+pub(crate) const SYM_SIZE: usize = 24;
+pub const RELA_SIZE: usize = 24;
+
+#[derive(Clone, Debug)]
+pub struct Section {
+    pub name: String,
+    pub kind: u32,
+    pub flags: u64,
+    pub addr: u64,
+    pub data: Vec<u8>,
+    /// Size of a `SHT_NOBITS` section, which has no data in the file.
+    pub nobits_size: u64,
+    pub link: u32,
+    pub info: u32,
+    pub align: u64,
+    pub entsize: u64,
+}
+
+impl Section {
+    pub fn new(name: &str, kind: u32, flags: u64) -> Section {
+        Section {
+            name: name.to_owned(),
+            kind,
+            flags,
+            addr: 0,
+            data: Vec::new(),
+            nobits_size: 0,
+            link: 0,
+            info: 0,
+            align: 1,
+            entsize: 0,
+        }
+    }
+
+    /// A `.text` section that only describes code already in memory at `addr`.
+    pub fn text_at(addr: u64, size: u64) -> Section {
+        let mut section = Section::new(".text", SHT_NOBITS, SHF_ALLOC | SHF_EXECINSTR);
+        section.addr = addr;
+        section.nobits_size = size;
+        section.align = 16;
+        section
+    }
+
+    fn size(&self) -> u64 {
+        if self.kind == SHT_NOBITS {
+            self.nobits_size
+        } else {
+            self.data.len() as u64
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Symbol {
+    pub name: String,
+    pub value: u64,
+    pub size: u64,
+    pub bind: u8,
+    pub kind: u8,
+    pub section: u16,
+}
+
+/// Collects sections and symbols and lays them out as an ELF64 object. The symbol
+/// and string tables are added by `finish`.
+#[derive(Clone, Debug)]
+pub struct ElfWriter {
+    pub kind: u16,
+    sections: Vec<Section>,
+    symbols: Vec<Symbol>,
+}
+
+impl ElfWriter {
+    pub fn new(kind: u16) -> ElfWriter {
+        let mut null = Section::new("", SHT_NULL, 0);
+        null.align = 0;
+
+        ElfWriter {
+            kind,
+            sections: vec![null],
+            symbols: Vec::new(),
+        }
+    }
+
+    /// Adds a section and returns its index.
+    pub fn add_section(&mut self, section: Section) -> u16 {
+        self.sections.push(section);
+        (self.sections.len() - 1) as u16
+    }
+
+    pub fn section_mut(&mut self, idx: u16) -> &mut Section {
+        &mut self.sections[idx as usize]
+    }
+
+    pub fn add_symbol(&mut self, symbol: Symbol) {
+        self.symbols.push(symbol);
+    }
+
+    pub fn finish(mut self) -> Vec<u8> {
+        // local symbols have to come before global ones
+        self.symbols.sort_by_key(|sym| sym.bind != STB_LOCAL);
+
+        let mut strtab = Section::new(".strtab", SHT_STRTAB, 0);
+        strtab.data.push(0);
+
+        let mut symtab = Section::new(".symtab", SHT_SYMTAB, 0);
+        symtab.align = 8;
+        symtab.entsize = SYM_SIZE as u64;
+        symtab.data.extend_from_slice(&[0; SYM_SIZE]);
+        symtab.info = 1 + self
+            .symbols
+            .iter()
+            .filter(|sym| sym.bind == STB_LOCAL)
+            .count() as u32;
+
+        for sym in &self.symbols {
+            let name = add_string(&mut strtab.data, &sym.name);
+            let data = &mut symtab.data;
+
+            data.write_u32::<LittleEndian>(name).unwrap();
+            data.write_u8(sym.bind << 4 | sym.kind).unwrap();
+            data.write_u8(0).unwrap();
+            data.write_u16::<LittleEndian>(sym.section).unwrap();
+            data.write_u64::<LittleEndian>(sym.value).unwrap();
+            data.write_u64::<LittleEndian>(sym.size).unwrap();
+        }
+
+        symtab.link = self.sections.len() as u32 + 1;
+        self.sections.push(symtab);
+        self.sections.push(strtab);
+
+        let mut shstrtab = Section::new(".shstrtab", SHT_STRTAB, 0);
+        shstrtab.data.push(0);
+        let shstrndx = self.sections.len() as u16;
+        let mut names = Vec::new();
+
+        for section in &self.sections {
+            names.push(add_string(&mut shstrtab.data, &section.name));
+        }
+        names.push(add_string(&mut shstrtab.data, ".shstrtab"));
+        self.sections.push(shstrtab);
+
+        // section contents follow the header, the section headers come last
+        let mut offsets = Vec::new();
+        let mut pos = EHDR_SIZE as u64;
+
+        for section in &self.sections {
+            if section.kind == SHT_NOBITS || section.kind == SHT_NULL {
+                offsets.push(pos);
+                continue;
+            }
+
+            pos = align(pos, section.align.max(1));
+            offsets.push(pos);
+            pos += section.data.len() as u64;
+        }
+
+        let shoff = align(pos, 8);
+        let mut out = Vec::with_capacity(shoff as usize + SHDR_SIZE * self.sections.len());
+
+        out.extend_from_slice(&[0x7f, b'E', b'L', b'F', 2, 1, 1, 0]);
+        out.extend_from_slice(&[0; 8]);
+        out.write_u16::<LittleEndian>(self.kind).unwrap();
+        out.write_u16::<LittleEndian>(EM_X86_64).unwrap();
+        out.write_u32::<LittleEndian>(1).unwrap();
+        out.write_u64::<LittleEndian>(0).unwrap(); // e_entry
+        out.write_u64::<LittleEndian>(0).unwrap(); // e_phoff
+        out.write_u64::<LittleEndian>(shoff).unwrap();
+        out.write_u32::<LittleEndian>(0).unwrap(); // e_flags
+        out.write_u16::<LittleEndian>(EHDR_SIZE as u16).unwrap();
+        out.write_u16::<LittleEndian>(0).unwrap(); // e_phentsize
+        out.write_u16::<LittleEndian>(0).unwrap(); // e_phnum
+        out.write_u16::<LittleEndian>(SHDR_SIZE as u16).unwrap();
+        out.write_u16::<LittleEndian>(self.sections.len() as u16)
+            .unwrap();
+        out.write_u16::<LittleEndian>(shstrndx).unwrap();
+
+        for (section, &offset) in self.sections.iter().zip(&offsets) {
+            if section.kind == SHT_NOBITS || section.kind == SHT_NULL {
+                continue;
+            }
+
+            out.resize(offset as usize, 0);
+            out.extend_from_slice(&section.data);
+        }
+
+        out.resize(shoff as usize, 0);
+
+        for ((section, &offset), &name) in self.sections.iter().zip(&offsets).zip(&names) {
+            let offset = if section.kind == SHT_NULL { 0 } else { offset };
+
+            out.write_u32::<LittleEndian>(name).unwrap();
+            out.write_u32::<LittleEndian>(section.kind).unwrap();
+            out.write_u64::<LittleEndian>(section.flags).unwrap();
+            out.write_u64::<LittleEndian>(section.addr).unwrap();
+            out.write_u64::<LittleEndian>(offset).unwrap();
+            out.write_u64::<LittleEndian>(section.size()).unwrap();
+            out.write_u32::<LittleEndian>(section.link).unwrap();
+            out.write_u32::<LittleEndian>(section.info).unwrap();
+            out.write_u64::<LittleEndian>(section.align).unwrap();
+            out.write_u64::<LittleEndian>(section.entsize).unwrap();
+        }
+
+        out
+    }
+}
+
+fn add_string(table: &mut Vec<u8>, s: &str) -> u32 {
+    if s.is_empty() {
+        return 0;
+    }
+
+    let offset = table.len() as u32;
+    table.extend_from_slice(s.as_bytes());
+    table.push(0);
+    offset
+}
+
+fn align(pos: u64, align: u64) -> u64 {
+    (pos + align - 1) & !(align - 1)
+}
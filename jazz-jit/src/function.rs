@@ -0,0 +1,176 @@
+//! Typed handles to generated functions.
+//!
+//! A `JitFunction` pairs a function pointer type such as
+//! `extern "C" fn(i64, f64) -> i64` with the `Memory` holding the code, either by
+//! owning it or by borrowing it, so the function cannot be called after the code was
+//! freed. Calls always enter at `Memory::start()`, behind the data segment.
+
+use crate::Memory;
+
+/// Function pointer types a `JitFunction` can be created for.
+///
+/// # Safety
+/// Implementors must be function pointers.
+pub unsafe trait FnPtr: Copy {
+    /// Reinterprets `ptr` as a function pointer of this type.
+    ///
+    /// # Safety
+    /// `ptr` must point to a function with this signature.
+    unsafe fn from_ptr(ptr: *const u8) -> Self;
+}
+
+enum Code<'a> {
+    Owned(Memory),
+    Borrowed(&'a Memory),
+}
+
+/// A generated function of type `F` that keeps its code alive.
+pub struct JitFunction<'a, F: FnPtr> {
+    code: Code<'a>,
+    function: F,
+}
+
+impl<F: FnPtr> JitFunction<'static, F> {
+    /// Takes ownership of `memory`, the code is freed when the function is dropped.
+    ///
+    /// # Safety
+    /// The code at `memory.start()` must follow the calling convention and signature
+    /// of `F`.
+    pub unsafe fn new(memory: Memory) -> JitFunction<'static, F> {
+        let function = F::from_ptr(memory.start());
+
+        JitFunction {
+            code: Code::Owned(memory),
+            function,
+        }
+    }
+}
+
+impl<'a, F: FnPtr> JitFunction<'a, F> {
+    /// Borrows `memory`, which has to outlive the function.
+    ///
+    /// # Safety
+    /// The code at `memory.start()` must follow the calling convention and signature
+    /// of `F`.
+    pub unsafe fn borrowed(memory: &'a Memory) -> JitFunction<'a, F> {
+        JitFunction {
+            code: Code::Borrowed(memory),
+            function: F::from_ptr(memory.start()),
+        }
+    }
+
+    pub fn memory(&self) -> &Memory {
+        match &self.code {
+            Code::Owned(memory) => memory,
+            Code::Borrowed(memory) => memory,
+        }
+    }
+
+    /// Gives back the code of an owning function, `None` for borrowed ones.
+    pub fn into_memory(self) -> Option<Memory> {
+        match self.code {
+            Code::Owned(memory) => Some(memory),
+            Code::Borrowed(_) => None,
+        }
+    }
+
+    /// The raw function pointer.
+    ///
+    /// # Safety
+    /// The pointer must not be called after this handle is dropped.
+    pub unsafe fn as_raw(&self) -> F {
+        self.function
+    }
+}
+
+macro_rules! fn_ptr {
+    ($($arg:ident),*) => {
+        fn_ptr!("C" $($arg),*);
+        // lets panics of runtime functions called by the code unwind through it
+        fn_ptr!("C-unwind" $($arg),*);
+    };
+    ($abi:literal $($arg:ident),*) => {
+        unsafe impl<R, $($arg),*> FnPtr for extern $abi fn($($arg),*) -> R {
+            unsafe fn from_ptr(ptr: *const u8) -> Self {
+                ::std::mem::transmute::<*const u8, Self>(ptr)
+            }
+        }
+
+        impl<'a, R, $($arg),*> JitFunction<'a, extern $abi fn($($arg),*) -> R> {
+            #[allow(non_snake_case, clippy::too_many_arguments)]
+            pub fn call(&self, $($arg: $arg),*) -> R {
+                (self.function)($($arg),*)
+            }
+        }
+    };
+}
+
+fn_ptr!();
+fn_ptr!(A);
+fn_ptr!(A, B);
+fn_ptr!(A, B, C);
+fn_ptr!(A, B, C, D);
+fn_ptr!(A, B, C, D, E);
+fn_ptr!(A, B, C, D, E, F);
+fn_ptr!(A, B, C, D, E, F, G);
+fn_ptr!(A, B, C, D, E, F, G, H);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::Assembler;
+    use crate::assembler_x64::*;
+    use crate::code_cache::CodeCache;
+    use crate::constants_x64::*;
+
+    /// `fn(a, b) -> a - b`.
+    fn sub(cache: &std::sync::Arc<CodeCache>) -> Memory {
+        let mut asm = Assembler::new();
+        emit_mov_reg_reg(&mut asm, 1, RDI, RAX);
+        emit_sub_reg_reg(&mut asm, 1, RSI, RAX);
+        emit_retq(&mut asm);
+
+        cache.emit(&asm.finalize().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn owned_functions() {
+        let cache = CodeCache::new(1 << 16);
+        let memory = sub(&cache);
+        let start = memory.start();
+
+        let fun: JitFunction<extern "C" fn(i64, i64) -> i64> = unsafe { JitFunction::new(memory) };
+        assert_eq!(fun.call(7, 10), -3);
+        assert_eq!(fun.memory().start(), start);
+        assert_eq!(unsafe { fun.as_raw() }(1, 1), 0);
+
+        let memory = fun.into_memory().unwrap();
+        let fun: JitFunction<extern "C-unwind" fn(i64, i64) -> i64> =
+            unsafe { JitFunction::new(memory) };
+        assert_eq!(fun.call(10, 7), 3);
+
+        drop(fun);
+        assert_eq!(cache.stats().allocations, 0);
+    }
+
+    #[test]
+    fn borrowed_functions() {
+        let cache = CodeCache::new(1 << 16);
+        let memory = sub(&cache);
+
+        let fun: JitFunction<extern "C" fn(i64, i64) -> i64> =
+            unsafe { JitFunction::borrowed(&memory) };
+        let unwind: JitFunction<extern "C-unwind" fn(u64, u64) -> u64> =
+            unsafe { JitFunction::borrowed(&memory) };
+        assert_eq!(fun.call(i64::MIN, 1), i64::MAX);
+        assert_eq!(unwind.call(5, 3), 2);
+
+        // the code stays with its owner
+        assert!(fun.into_memory().is_none());
+        assert!(unwind.into_memory().is_none());
+        assert_eq!(cache.stats().allocations, 1);
+        let fun: JitFunction<extern "C" fn(i64, i64) -> i64> =
+            unsafe { JitFunction::borrowed(&memory) };
+        assert_eq!(fun.call(1, 2), -1);
+    }
+}
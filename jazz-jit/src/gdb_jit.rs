@@ -0,0 +1,282 @@
+//! GDB JIT interface.
+//!
+//! Every finalized buffer is described by a small in-memory ELF object holding a
+//! `.text` section at the address of the code and a symbol for it, plus DWARF line
+//! information if the code has source positions. The objects are
+//! linked into `__jit_debug_descriptor` and announced by calling
+//! `__jit_debug_register_code`, on which gdb (and lldb) keep a breakpoint.
+
+use crate::elf::*;
+use crate::source::{self, LineTable};
+use std::ptr;
+use std::sync::Mutex;
+
+const JIT_NOACTION: u32 = 0;
+const JIT_REGISTER_FN: u32 = 1;
+const JIT_UNREGISTER_FN: u32 = 2;
+
+#[repr(C)]
+pub struct JitCodeEntry {
+    pub next_entry: *mut JitCodeEntry,
+    pub prev_entry: *mut JitCodeEntry,
+    pub symfile_addr: *const u8,
+    pub symfile_size: u64,
+}
+
+#[repr(C)]
+pub struct JitDescriptor {
+    pub version: u32,
+    pub action_flag: u32,
+    pub relevant_entry: *mut JitCodeEntry,
+    pub first_entry: *mut JitCodeEntry,
+}
+
+#[no_mangle]
+pub static mut __jit_debug_descriptor: JitDescriptor = JitDescriptor {
+    version: 1,
+    action_flag: JIT_NOACTION,
+    relevant_entry: ptr::null_mut(),
+    first_entry: ptr::null_mut(),
+};
+
+/// The debugger sets a breakpoint here and reads `__jit_debug_descriptor` when it
+/// is hit.
+#[no_mangle]
+#[inline(never)]
+pub extern "C" fn __jit_debug_register_code() {
+    // keep the call from being optimized away
+    unsafe { std::arch::asm!("", options(nostack, preserves_flags)) };
+}
+
+/// Serializes changes to the descriptor.
+static LOCK: Mutex<()> = Mutex::new(());
+
+/// Builds the ELF object describing `size` bytes of code at `start`.
+pub fn symfile(name: &str, start: *const u8, size: usize, lines: &LineTable) -> Vec<u8> {
+    let mut elf = ElfWriter::new(ET_REL);
+    let text = elf.add_section(Section::text_at(start as u64, size as u64));
+
+    if !lines.is_empty() {
+        let unit = source::file_name(lines.rows()[0].1.file);
+        let (abbrev, info) = source::compile_unit(&unit, start as u64, size);
+
+        for (name, data) in [
+            (".debug_abbrev", abbrev),
+            (".debug_info", info),
+            (".debug_line", lines.debug_line(start as u64, size)),
+        ] {
+            let mut section = Section::new(name, SHT_PROGBITS, 0);
+            section.data = data;
+            elf.add_section(section);
+        }
+    }
+
+    elf.add_symbol(Symbol {
+        name: "jazz-jit".to_owned(),
+        value: 0,
+        size: 0,
+        bind: STB_LOCAL,
+        kind: STT_FILE,
+        section: SHN_ABS,
+    });
+    elf.add_symbol(Symbol {
+        name: name.to_owned(),
+        value: 0,
+        size: size as u64,
+        bind: STB_GLOBAL,
+        kind: STT_FUNC,
+        section: text,
+    });
+
+    elf.finish()
+}
+
+/// A buffer registered with the debugger, unregistered on drop.
+pub struct Registration {
+    entry: *mut JitCodeEntry,
+    symfile: Box<[u8]>,
+}
+
+unsafe impl Send for Registration {}
+unsafe impl Sync for Registration {}
+
+impl Registration {
+    pub fn new(name: &str, start: *const u8, size: usize, lines: &LineTable) -> Registration {
+        let symfile = symfile(name, start, size, lines).into_boxed_slice();
+        let entry = Box::into_raw(Box::new(JitCodeEntry {
+            next_entry: ptr::null_mut(),
+            prev_entry: ptr::null_mut(),
+            symfile_addr: symfile.as_ptr(),
+            symfile_size: symfile.len() as u64,
+        }));
+
+        let _lock = LOCK.lock().unwrap();
+
+        unsafe {
+            let descriptor = &mut *ptr::addr_of_mut!(__jit_debug_descriptor);
+            let first = descriptor.first_entry;
+
+            (*entry).next_entry = first;
+            if !first.is_null() {
+                (*first).prev_entry = entry;
+            }
+
+            descriptor.first_entry = entry;
+            descriptor.relevant_entry = entry;
+            descriptor.action_flag = JIT_REGISTER_FN;
+            __jit_debug_register_code();
+        }
+
+        Registration { entry, symfile }
+    }
+
+    /// The ELF object handed to the debugger.
+    pub fn symfile(&self) -> &[u8] {
+        &self.symfile
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        let _lock = LOCK.lock().unwrap();
+
+        unsafe {
+            let descriptor = &mut *ptr::addr_of_mut!(__jit_debug_descriptor);
+            let entry = self.entry;
+            let prev = (*entry).prev_entry;
+            let next = (*entry).next_entry;
+
+            if prev.is_null() {
+                descriptor.first_entry = next;
+            } else {
+                (*prev).next_entry = next;
+            }
+
+            if !next.is_null() {
+                (*next).prev_entry = prev;
+            }
+
+            descriptor.relevant_entry = entry;
+            descriptor.action_flag = JIT_UNREGISTER_FN;
+            __jit_debug_register_code();
+
+            descriptor.relevant_entry = ptr::null_mut();
+            descriptor.action_flag = JIT_NOACTION;
+            drop(Box::from_raw(entry));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::Assembler;
+    use crate::assembler_x64::*;
+    use crate::code_cache::CodeCache;
+    use byteorder::{ByteOrder, LittleEndian};
+
+    /// Symbol files of the entries linked into the descriptor.
+    fn registered() -> Vec<*const u8> {
+        let _lock = LOCK.lock().unwrap();
+        let mut symfiles = Vec::new();
+
+        unsafe {
+            let mut entry = (*ptr::addr_of!(__jit_debug_descriptor)).first_entry;
+
+            while !entry.is_null() {
+                symfiles.push((*entry).symfile_addr);
+                entry = (*entry).next_entry;
+            }
+        }
+
+        symfiles
+    }
+
+    /// Header of the section called `name`: address, file offset, size and link.
+    fn section(elf: &[u8], name: &str) -> Option<(u64, usize, usize, u32)> {
+        let shoff = LittleEndian::read_u64(&elf[40..]) as usize;
+        let shnum = LittleEndian::read_u16(&elf[60..]) as usize;
+        let shstrndx = LittleEndian::read_u16(&elf[62..]) as usize;
+        let header = |idx: usize| &elf[shoff + idx * SHDR_SIZE..];
+        let names = LittleEndian::read_u64(&header(shstrndx)[24..]) as usize;
+
+        (0..shnum).map(header).find_map(|shdr| {
+            let start = names + LittleEndian::read_u32(shdr) as usize;
+            let len = elf[start..].iter().position(|&b| b == 0).unwrap();
+
+            if &elf[start..start + len] != name.as_bytes() {
+                return None;
+            }
+
+            Some((
+                LittleEndian::read_u64(&shdr[16..]),
+                LittleEndian::read_u64(&shdr[24..]) as usize,
+                LittleEndian::read_u64(&shdr[32..]) as usize,
+                LittleEndian::read_u32(&shdr[40..]),
+            ))
+        })
+    }
+
+    /// Name, kind and size of every symbol.
+    fn symbols(elf: &[u8]) -> Vec<(String, u8, u64)> {
+        let (_, offset, size, _) = section(elf, ".symtab").unwrap();
+        let (_, strings, _, _) = section(elf, ".strtab").unwrap();
+
+        elf[offset..offset + size]
+            .chunks(SYM_SIZE)
+            .skip(1)
+            .map(|sym| {
+                let start = strings + LittleEndian::read_u32(sym) as usize;
+                let len = elf[start..].iter().position(|&b| b == 0).unwrap();
+                let name = String::from_utf8(elf[start..start + len].to_vec()).unwrap();
+
+                (name, sym[4] & 0xf, LittleEndian::read_u64(&sym[16..]))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn code_is_registered_until_dropped() {
+        let cache = CodeCache::new(1 << 16);
+        let mut asm = Assembler::new();
+        asm.set_name("gdb_jit_registered");
+        emit_nop(&mut asm);
+        emit_retq(&mut asm);
+
+        let memory = cache.emit(&asm.finalize().unwrap()).unwrap();
+        let symfile = memory.gdb.as_ref().unwrap().symfile();
+        let addr = symfile.as_ptr();
+        assert!(registered().contains(&addr));
+
+        assert_eq!(&symfile[..4], b"\x7fELF");
+        assert_eq!(LittleEndian::read_u16(&symfile[16..]), ET_REL);
+        assert_eq!(LittleEndian::read_u16(&symfile[18..]), EM_X86_64);
+
+        let (text, _, _, _) = section(symfile, ".text").unwrap();
+        assert_eq!(text, memory.start() as u64);
+        assert!(section(symfile, ".debug_line").is_none());
+        assert!(symbols(symfile).contains(&("gdb_jit_registered".to_owned(), STT_FUNC, 2)));
+
+        drop(memory);
+        assert!(!registered().contains(&addr));
+    }
+
+    #[test]
+    fn registrations_unlink_in_any_order() {
+        let lines = LineTable::default();
+        let first = Registration::new("first", ptr::null(), 1, &lines);
+        let second = Registration::new("second", ptr::null(), 1, &lines);
+        let third = Registration::new("third", ptr::null(), 1, &lines);
+        let addrs = [&first, &second, &third].map(|reg| reg.symfile().as_ptr());
+
+        drop(second);
+        let symfiles = registered();
+        assert!(symfiles.contains(&addrs[0]) && symfiles.contains(&addrs[2]));
+        assert!(!symfiles.contains(&addrs[1]));
+
+        drop(third);
+        drop(first);
+        let symfiles = registered();
+        assert!(addrs.iter().all(|addr| !symfiles.contains(addr)));
+    }
+}
@@ -0,0 +1,844 @@
+//! Instruction lists.
+//!
+//! Code generators can collect `Inst`s in an `InstBuffer` instead of calling the
+//! encoders directly. The buffer can be inspected, printed and rewritten, e.g. by
+//! peephole passes, and is then lowered into an `Assembler` through the same
+//! encoders. Operands that an instruction does not accept are reported like the
+//! encoders report them, as `AsmDiagnostic::InvalidOperand` of the assembler.
+//!
+//! Labels of a buffer are created by the buffer and belong to it. Lowering creates
+//! an assembler label for each of them.
+
+use crate::assembler::{Assembler, Label, Mem, RipTarget, Segment};
+use crate::assembler_x64::*;
+use crate::constants_x64::*;
+use crate::reloc::RelocTarget;
+use crate::{CondCode, MachineMode};
+use std::fmt;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Operand {
+    Reg(Register),
+    Xmm(XMMRegister),
+    Imm(i64),
+    Mem(Mem),
+    Label(Label),
+}
+
+impl From<Register> for Operand {
+    fn from(reg: Register) -> Operand {
+        Operand::Reg(reg)
+    }
+}
+
+impl From<XMMRegister> for Operand {
+    fn from(reg: XMMRegister) -> Operand {
+        Operand::Xmm(reg)
+    }
+}
+
+impl From<Mem> for Operand {
+    fn from(mem: Mem) -> Operand {
+        Operand::Mem(mem)
+    }
+}
+
+/// Scalar SSE arithmetic.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum FloatOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Sqrt,
+}
+
+impl FloatOp {
+    fn opcode(self) -> u8 {
+        match self {
+            FloatOp::Add => 0x58,
+            FloatOp::Sub => 0x5c,
+            FloatOp::Mul => 0x59,
+            FloatOp::Div => 0x5e,
+            FloatOp::Sqrt => 0x51,
+        }
+    }
+}
+
+/// An x64 instruction, or a label or comment between instructions. Operands are in
+/// AT&T order like the arguments of the encoders: `src` before `dest`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Inst {
+    /// Binds a label to the position of the next instruction.
+    Bind(Label),
+    /// Attaches a comment to the next instruction.
+    Comment(String),
+    /// Moves between registers, memory and immediates. Float modes move between XMM
+    /// registers and memory, 32 and 64-bit integer modes also between general
+    /// purpose and XMM registers.
+    Mov {
+        mode: MachineMode,
+        src: Operand,
+        dest: Operand,
+    },
+    Lea {
+        src: Operand,
+        dest: Operand,
+    },
+    /// Zero-extends the byte or 16-bit `src` into the 32-bit `dest`.
+    Movzx {
+        mode: MachineMode,
+        src: Operand,
+        dest: Operand,
+    },
+    /// Sign-extends the byte or 16-bit `src` into the 64-bit `dest`.
+    Movsx {
+        mode: MachineMode,
+        src: Operand,
+        dest: Operand,
+    },
+    Alu {
+        mode: MachineMode,
+        op: AluOp,
+        src: Operand,
+        dest: Operand,
+    },
+    Test {
+        mode: MachineMode,
+        src: Operand,
+        dest: Operand,
+    },
+    /// Shifts `dest` by an immediate or by CL.
+    Shift {
+        mode: MachineMode,
+        op: ShiftOp,
+        count: Operand,
+        dest: Operand,
+    },
+    Neg {
+        mode: MachineMode,
+        dest: Operand,
+    },
+    Not {
+        mode: MachineMode,
+        dest: Operand,
+    },
+    Imul {
+        mode: MachineMode,
+        src: Operand,
+        dest: Operand,
+    },
+    /// Divides RDX:RAX by `src`, the quotient goes to RAX and the remainder to RDX.
+    Idiv {
+        mode: MachineMode,
+        src: Operand,
+    },
+    /// Sign-extends RAX into RDX, CDQ for 32-bit operands and CQO for 64-bit ones.
+    SignExtendRax {
+        mode: MachineMode,
+    },
+    Setcc {
+        cond: CondCode,
+        dest: Operand,
+    },
+    Cmov {
+        mode: MachineMode,
+        cond: CondCode,
+        src: Operand,
+        dest: Operand,
+    },
+    Float {
+        mode: MachineMode,
+        op: FloatOp,
+        src: Operand,
+        dest: Operand,
+    },
+    /// Compares `dest` with `src` and sets ZF, PF and CF like an unsigned compare.
+    FloatCmp {
+        mode: MachineMode,
+        src: Operand,
+        dest: Operand,
+    },
+    Push(Operand),
+    Pop(Operand),
+    /// Jumps to a label or to the address in a register.
+    Jmp(Operand),
+    Jcc {
+        cond: CondCode,
+        target: Label,
+    },
+    /// Calls a label, the address in a register or an absolute address. An absolute
+    /// address is loaded into R11 first, so R11 is overwritten for `Call(Imm(_))`.
+    Call(Operand),
+    Ret,
+    Nop,
+}
+
+/// Instructions and the labels they refer to.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct InstBuffer {
+    pub insts: Vec<Inst>,
+    /// Names of the labels, indexed by label.
+    labels: Vec<Option<String>>,
+}
+
+impl InstBuffer {
+    pub fn new() -> InstBuffer {
+        InstBuffer::default()
+    }
+
+    pub fn create_label(&mut self) -> Label {
+        self.labels.push(None);
+        self.labels.len() - 1
+    }
+
+    pub fn create_named_label(&mut self, name: impl Into<String>) -> Label {
+        self.labels.push(Some(name.into()));
+        self.labels.len() - 1
+    }
+
+    pub fn label_count(&self) -> usize {
+        self.labels.len()
+    }
+
+    pub fn label_name(&self, lbl: Label) -> String {
+        match self.labels.get(lbl) {
+            Some(Some(name)) => name.clone(),
+            _ => format!("L{}", lbl),
+        }
+    }
+
+    pub fn push(&mut self, inst: Inst) {
+        self.insts.push(inst);
+    }
+
+    pub fn len(&self) -> usize {
+        self.insts.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.insts.is_empty()
+    }
+
+    /// Appends the instructions to `asm`. Returns the assembler labels created for
+    /// the labels of the buffer, indexed by label.
+    pub fn lower(&self, asm: &mut Assembler) -> Vec<Label> {
+        let labels: Vec<Label> = self
+            .labels
+            .iter()
+            .map(|name| match name {
+                Some(name) => asm.create_named_label(name.as_str()),
+                None => asm.create_label(),
+            })
+            .collect();
+
+        for inst in &self.insts {
+            lower(asm, &labels, inst);
+        }
+
+        labels
+    }
+}
+
+impl fmt::Display for InstBuffer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let names = |lbl: Label| self.label_name(lbl);
+
+        for inst in &self.insts {
+            match inst {
+                Inst::Bind(lbl) => writeln!(f, "{}:", names(*lbl))?,
+                Inst::Comment(text) => writeln!(f, "  ; {}", text)?,
+                inst => {
+                    f.write_str("  ")?;
+                    write_inst(f, inst, &names)?;
+                    f.write_str("\n")?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Intel syntax, as printed by disassemblers.
+impl fmt::Display for Inst {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write_inst(f, self, &|lbl| format!("L{}", lbl))
+    }
+}
+
+fn map_label(asm: &mut Assembler, labels: &[Label], lbl: Label) -> Label {
+    match labels.get(lbl) {
+        Some(&lbl) => lbl,
+        None => asm.invalid_operand(format!("label {} was not created by the buffer", lbl)),
+    }
+}
+
+fn map_mem(asm: &mut Assembler, labels: &[Label], mem: Mem) -> Mem {
+    match mem {
+        Mem::RipRelative(RipTarget::Label(lbl)) => {
+            Mem::RipRelative(RipTarget::Label(map_label(asm, labels, lbl)))
+        }
+        mem => mem,
+    }
+}
+
+/// REX.W of the 32 and 64-bit instructions that have no mode-taking encoder.
+fn width(asm: &mut Assembler, mode: MachineMode) -> u8 {
+    match mode {
+        MachineMode::Int32 => 0,
+        MachineMode::Int64 | MachineMode::Ptr => 1,
+        mode => asm.invalid_mode(mode),
+    }
+}
+
+fn is_double(asm: &mut Assembler, mode: MachineMode) -> bool {
+    match mode {
+        MachineMode::Float32 => false,
+        MachineMode::Float64 => true,
+        mode => asm.invalid_mode(mode),
+    }
+}
+
+fn imm32(asm: &mut Assembler, imm: i64) -> i32 {
+    if !fits_i32(imm) {
+        asm.invalid_operand::<()>(format!("immediate {:#x} does not fit into 32 bits", imm));
+    }
+
+    imm as i32
+}
+
+fn lower(asm: &mut Assembler, labels: &[Label], inst: &Inst) {
+    use self::Operand::*;
+
+    let inst = inst.clone().map_mem(|mem| map_mem(asm, labels, mem));
+
+    match inst {
+        Inst::Bind(lbl) => {
+            let lbl = map_label(asm, labels, lbl);
+            asm.bind_label(lbl);
+        }
+        Inst::Comment(text) => asm.comment(text),
+
+        Inst::Mov { mode, src, dest } => match (mode, src, dest) {
+            (MachineMode::Float32, Xmm(src), Xmm(dest)) => movss(asm, dest, src),
+            (MachineMode::Float64, Xmm(src), Xmm(dest)) => movsd(asm, dest, src),
+            (MachineMode::Float32, Mem(src), Xmm(dest)) => movss_load(asm, dest, src),
+            (MachineMode::Float64, Mem(src), Xmm(dest)) => movsd_load(asm, dest, src),
+            (MachineMode::Float32, Xmm(src), Mem(dest)) => movss_store(asm, dest, src),
+            (MachineMode::Float64, Xmm(src), Mem(dest)) => movsd_store(asm, dest, src),
+            (MachineMode::Int32, Reg(src), Xmm(dest)) => movd_freg_reg(asm, dest, src),
+            (MachineMode::Int32, Xmm(src), Reg(dest)) => movd_reg_freg(asm, dest, src),
+            (MachineMode::Int64, Reg(src), Xmm(dest)) => movq_freg_reg(asm, dest, src),
+            (MachineMode::Int64, Xmm(src), Reg(dest)) => movq_reg_freg(asm, dest, src),
+            (mode, Reg(src), Reg(dest)) => mov_reg_reg(asm, mode, src, dest),
+            (mode, Mem(src), Reg(dest)) => mov_mem_reg(asm, mode, src, dest),
+            (mode, Reg(src), Mem(dest)) => mov_reg_mem(asm, mode, src, dest),
+            (MachineMode::Int64, Imm(imm), Reg(dest)) | (MachineMode::Ptr, Imm(imm), Reg(dest))
+                if !fits_i32(imm) =>
+            {
+                emit_movq_imm64_reg(asm, imm, dest)
+            }
+            (mode, Imm(imm), Reg(dest)) => {
+                let imm = imm32(asm, imm);
+                mov_imm_reg(asm, mode, imm, dest)
+            }
+            (mode, Imm(imm), Mem(dest)) => {
+                let imm = imm32(asm, imm);
+                mov_imm_mem(asm, mode, imm, dest)
+            }
+            _ => invalid(asm, &inst),
+        },
+        Inst::Lea {
+            src: Mem(src),
+            dest: Reg(dest),
+        } => lea(asm, dest, src),
+        Inst::Movzx {
+            mode,
+            src: Reg(src),
+            dest: Reg(dest),
+        } => movzx(asm, mode, src, dest),
+        Inst::Movzx {
+            mode,
+            src: Mem(src),
+            dest: Reg(dest),
+        } => movzx_mem(asm, mode, src, dest),
+        Inst::Movsx {
+            mode,
+            src: Reg(src),
+            dest: Reg(dest),
+        } => movsx(asm, 1, mode, src, dest),
+        Inst::Movsx {
+            mode,
+            src: Mem(src),
+            dest: Reg(dest),
+        } => movsx_mem(asm, 1, mode, src, dest),
+
+        Inst::Alu {
+            mode,
+            op,
+            src,
+            dest,
+        } => match (src, dest) {
+            (Reg(src), Reg(dest)) => alu_reg_reg(asm, mode, op, src, dest),
+            (Reg(src), Mem(dest)) => alu_reg_mem(asm, mode, op, src, dest),
+            (Mem(src), Reg(dest)) => alu_mem_reg(asm, mode, op, src, dest),
+            (Imm(imm), Reg(dest)) => {
+                let imm = imm32(asm, imm);
+                alu_imm_reg(asm, mode, op, imm, dest)
+            }
+            (Imm(imm), Mem(dest)) => {
+                let imm = imm32(asm, imm);
+                alu_imm_mem(asm, mode, op, imm, dest)
+            }
+            _ => invalid(asm, &inst),
+        },
+        Inst::Test {
+            mode,
+            src: Reg(src),
+            dest: Reg(dest),
+        } => match width(asm, mode) {
+            0 => emit_testl_reg_reg(asm, src, dest),
+            _ => emit_testq_reg_reg(asm, src, dest),
+        },
+        Inst::Shift {
+            mode,
+            op,
+            count,
+            dest: Reg(dest),
+        } => match count {
+            Imm(imm) if (0..=0xff).contains(&imm) => shift_reg_imm(asm, mode, op, dest, imm as u8),
+            Reg(RCX) => shift_reg_cl(asm, mode, op, dest),
+            _ => invalid(asm, &inst),
+        },
+        Inst::Neg {
+            mode,
+            dest: Reg(dest),
+        } => {
+            let x64 = width(asm, mode);
+            emit_neg_reg(asm, x64, dest)
+        }
+        Inst::Not {
+            mode,
+            dest: Reg(dest),
+        } => {
+            let x64 = width(asm, mode);
+            emit_not_reg(asm, x64, dest)
+        }
+        Inst::Imul {
+            mode,
+            src: Reg(src),
+            dest: Reg(dest),
+        } => {
+            let x64 = width(asm, mode);
+            emit_imul_reg_reg(asm, x64, src, dest)
+        }
+        Inst::Idiv {
+            mode,
+            src: Reg(src),
+        } => {
+            let x64 = width(asm, mode);
+            emit_idiv_reg_reg(asm, x64, src)
+        }
+        Inst::SignExtendRax { mode } => match width(asm, mode) {
+            0 => emit_cdq(asm),
+            _ => emit_cqo(asm),
+        },
+        Inst::Setcc {
+            cond,
+            dest: Reg(dest),
+        } => emit_setb_reg(asm, cond, dest),
+        Inst::Cmov {
+            mode,
+            cond,
+            src: Reg(src),
+            dest: Reg(dest),
+        } => {
+            let x64 = width(asm, mode);
+            cmov(asm, x64, dest, src, cond)
+        }
+
+        Inst::Float {
+            mode,
+            op,
+            src,
+            dest,
+        } => {
+            let dbl = is_double(asm, mode);
+
+            match (src, dest) {
+                (Xmm(src), Xmm(dest)) => sse_float_freg_freg(asm, dbl, op.opcode(), dest, src),
+                (Mem(src), Xmm(dest)) => sse_float_freg_mem(asm, dbl, op.opcode(), dest, src),
+                _ => invalid(asm, &inst),
+            }
+        }
+        Inst::FloatCmp {
+            mode,
+            src: Xmm(src),
+            dest: Xmm(dest),
+        } => match is_double(asm, mode) {
+            false => ucomiss(asm, dest, src),
+            true => ucomisd(asm, dest, src),
+        },
+
+        Inst::Push(Reg(reg)) => emit_pushq_reg(asm, reg),
+        Inst::Pop(Reg(reg)) => emit_popq_reg(asm, reg),
+        Inst::Jmp(Label(lbl)) => {
+            let lbl = map_label(asm, labels, lbl);
+            asm.jump(lbl)
+        }
+        Inst::Jmp(Reg(reg)) => emit_jmp_reg(asm, reg),
+        Inst::Jcc { cond, target } => {
+            let target = map_label(asm, labels, target);
+            asm.jump_if(cond, target)
+        }
+        Inst::Call(Label(lbl)) => {
+            let lbl = map_label(asm, labels, lbl);
+            asm.call_rel(RelocTarget::Label(lbl))
+        }
+        Inst::Call(Reg(reg)) => emit_callq_reg(asm, reg),
+        Inst::Call(Imm(addr)) => asm.call_abs(RelocTarget::Address(addr as u64)),
+        Inst::Ret => emit_retq(asm),
+        Inst::Nop => emit_nop(asm),
+
+        inst => invalid(asm, &inst),
+    }
+}
+
+fn invalid(asm: &mut Assembler, inst: &Inst) {
+    asm.invalid_operand::<()>(format!("invalid operands for `{}`", inst));
+}
+
+impl Inst {
+    /// The operands of the instruction.
+    pub fn operands(&self) -> Vec<&Operand> {
+        let mut operands = Vec::new();
+        self.for_each_operand(|operand| operands.push(operand));
+        operands
+    }
+
+    pub fn operands_mut(&mut self) -> Vec<&mut Operand> {
+        match self {
+            Inst::Bind(_)
+            | Inst::Comment(_)
+            | Inst::SignExtendRax { .. }
+            | Inst::Jcc { .. }
+            | Inst::Ret
+            | Inst::Nop => Vec::new(),
+            Inst::Mov { src, dest, .. }
+            | Inst::Lea { src, dest }
+            | Inst::Movzx { src, dest, .. }
+            | Inst::Movsx { src, dest, .. }
+            | Inst::Alu { src, dest, .. }
+            | Inst::Test { src, dest, .. }
+            | Inst::Imul { src, dest, .. }
+            | Inst::Cmov { src, dest, .. }
+            | Inst::Float { src, dest, .. }
+            | Inst::FloatCmp { src, dest, .. } => vec![src, dest],
+            Inst::Shift { count, dest, .. } => vec![count, dest],
+            Inst::Neg { dest, .. } | Inst::Not { dest, .. } | Inst::Setcc { dest, .. } => {
+                vec![dest]
+            }
+            Inst::Idiv { src, .. } => vec![src],
+            Inst::Push(operand) | Inst::Pop(operand) | Inst::Jmp(operand) | Inst::Call(operand) => {
+                vec![operand]
+            }
+        }
+    }
+
+    fn for_each_operand<'a>(&'a self, mut f: impl FnMut(&'a Operand)) {
+        match self {
+            Inst::Bind(_)
+            | Inst::Comment(_)
+            | Inst::SignExtendRax { .. }
+            | Inst::Jcc { .. }
+            | Inst::Ret
+            | Inst::Nop => {}
+            Inst::Mov { src, dest, .. }
+            | Inst::Lea { src, dest }
+            | Inst::Movzx { src, dest, .. }
+            | Inst::Movsx { src, dest, .. }
+            | Inst::Alu { src, dest, .. }
+            | Inst::Test { src, dest, .. }
+            | Inst::Imul { src, dest, .. }
+            | Inst::Cmov { src, dest, .. }
+            | Inst::Float { src, dest, .. }
+            | Inst::FloatCmp { src, dest, .. } => {
+                f(src);
+                f(dest);
+            }
+            Inst::Shift { count, dest, .. } => {
+                f(count);
+                f(dest);
+            }
+            Inst::Neg { dest, .. } | Inst::Not { dest, .. } | Inst::Setcc { dest, .. } => f(dest),
+            Inst::Idiv { src, .. } => f(src),
+            Inst::Push(operand) | Inst::Pop(operand) | Inst::Jmp(operand) | Inst::Call(operand) => {
+                f(operand)
+            }
+        }
+    }
+
+    /// Replaces every memory operand by `f` of it.
+    pub fn map_mem(mut self, mut f: impl FnMut(Mem) -> Mem) -> Inst {
+        for operand in self.operands_mut() {
+            if let Operand::Mem(mem) = operand {
+                *mem = f(*mem);
+            }
+        }
+
+        self
+    }
+
+    /// The labels the instruction jumps to or addresses.
+    pub fn label_refs(&self) -> Vec<Label> {
+        let mut labels = Vec::new();
+
+        if let Inst::Jcc { target, .. } = self {
+            labels.push(*target);
+        }
+
+        self.for_each_operand(|operand| match operand {
+            Operand::Label(lbl) | Operand::Mem(Mem::RipRelative(RipTarget::Label(lbl))) => {
+                labels.push(*lbl)
+            }
+            _ => {}
+        });
+
+        labels
+    }
+}
+
+fn cond_suffix(cond: CondCode) -> &'static str {
+    match cond {
+        CondCode::Zero | CondCode::Equal => "e",
+        CondCode::NonZero | CondCode::NotEqual => "ne",
+        CondCode::Greater => "g",
+        CondCode::GreaterEq => "ge",
+        CondCode::Less => "l",
+        CondCode::LessEq => "le",
+        CondCode::UnsignedGreater => "a",
+        CondCode::UnsignedGreaterEq => "ae",
+        CondCode::UnsignedLess => "b",
+        CondCode::UnsignedLessEq => "be",
+    }
+}
+
+/// The register and operand size of a general purpose register name as printed by
+/// disassemblers.
+pub(crate) fn parse_reg(name: &str) -> Option<(Register, MachineMode)> {
+    const REGS: [Register; 16] = [
+        RAX, RCX, RDX, RBX, RSP, RBP, RSI, RDI, R8, R9, R10, R11, R12, R13, R14, R15,
+    ];
+    let modes = [
+        MachineMode::Int64,
+        MachineMode::Int32,
+        MachineMode::Int16,
+        MachineMode::Int8,
+    ];
+
+    modes.iter().find_map(|&mode| {
+        let reg = REGS.iter().find(|reg| reg.name(mode) == name)?;
+        Some((*reg, mode))
+    })
+}
+
+/// The condition of a `j`, `set` or `cmov` mnemonic suffix.
+pub(crate) fn parse_cond(suffix: &str) -> Option<CondCode> {
+    let cond = match suffix {
+        "e" => CondCode::Equal,
+        "ne" => CondCode::NotEqual,
+        "g" => CondCode::Greater,
+        "ge" => CondCode::GreaterEq,
+        "l" => CondCode::Less,
+        "le" => CondCode::LessEq,
+        "a" => CondCode::UnsignedGreater,
+        "ae" => CondCode::UnsignedGreaterEq,
+        "b" => CondCode::UnsignedLess,
+        "be" => CondCode::UnsignedLessEq,
+        _ => return None,
+    };
+
+    Some(cond)
+}
+
+fn ptr_size(mode: MachineMode) -> &'static str {
+    match mode {
+        MachineMode::Int8 => "byte",
+        MachineMode::Int16 => "word",
+        MachineMode::Int32 | MachineMode::Float32 => "dword",
+        _ => "qword",
+    }
+}
+
+/// Formats `value` like disassemblers do, in hex unless it is a single digit.
+fn hex(value: i64) -> String {
+    let abs = value.unsigned_abs();
+    let sign = if value < 0 { "-" } else { "" };
+
+    if abs > 9 {
+        format!("{}{:#x}", sign, abs)
+    } else {
+        format!("{}{}", sign, abs)
+    }
+}
+
+fn address(mem: &Mem, names: &dyn Fn(Label) -> String) -> String {
+    let (mut parts, disp) = match *mem {
+        Mem::Local(disp) => (vec![RBP.name(MachineMode::Int64).to_owned()], disp),
+        Mem::Base(base, disp) => (vec![base.name(MachineMode::Int64).to_owned()], disp),
+        Mem::Index(base, index, scale, disp) => (
+            vec![
+                base.name(MachineMode::Int64).to_owned(),
+                scaled(index, scale),
+            ],
+            disp,
+        ),
+        Mem::Offset(index, scale, disp) => (vec![scaled(index, scale)], disp),
+        Mem::RipRelative(RipTarget::Label(lbl)) => return format!("[rip + {}]", names(lbl)),
+        Mem::RipRelative(RipTarget::DSeg(disp)) => return format!("[rip + dseg-{}]", disp),
+        Mem::Absolute(addr) => return format!("[{}]", hex(addr as i64)),
+        Mem::Segment(Segment::Fs, addr) => return format!("fs:{}", address(&addr.mem(), names)),
+        Mem::Segment(Segment::Gs, addr) => return format!("gs:{}", address(&addr.mem(), names)),
+    };
+
+    if disp < 0 {
+        parts.push(format!("- {}", hex(-(disp as i64))));
+    } else if disp > 0 {
+        parts.push(format!("+ {}", hex(disp as i64)));
+    }
+
+    let mut out = parts[0].clone();
+    for part in &parts[1..] {
+        if !part.starts_with('-') && !part.starts_with('+') {
+            out.push_str(" + ");
+        } else {
+            out.push(' ');
+        }
+        out.push_str(part);
+    }
+
+    format!("[{}]", out)
+}
+
+fn scaled(index: Register, scale: i32) -> String {
+    let index = index.name(MachineMode::Int64);
+
+    if scale == 1 {
+        index.to_owned()
+    } else {
+        format!("{}*{}", index, scale)
+    }
+}
+
+fn operand(operand: &Operand, mode: MachineMode, names: &dyn Fn(Label) -> String) -> String {
+    match operand {
+        Operand::Reg(reg) => reg.name(mode).to_owned(),
+        Operand::Xmm(reg) => format!("xmm{}", *reg as usize),
+        Operand::Imm(imm) => hex(*imm),
+        Operand::Mem(mem) => format!("{} ptr {}", ptr_size(mode), address(mem, names)),
+        Operand::Label(lbl) => names(*lbl),
+    }
+}
+
+fn write_inst(f: &mut fmt::Formatter, inst: &Inst, names: &dyn Fn(Label) -> String) -> fmt::Result {
+    use self::MachineMode::*;
+
+    let op = |value: &Operand, mode: MachineMode| operand(value, mode, names);
+    let binary = |f: &mut fmt::Formatter, mnemonic: &str, src: &Operand, dest: &Operand, mode| {
+        write!(f, "{} {}, {}", mnemonic, op(dest, mode), op(src, mode))
+    };
+    let float_suffix = |mode| if mode == Float32 { "ss" } else { "sd" };
+
+    match inst {
+        Inst::Bind(lbl) => write!(f, "{}:", names(*lbl)),
+        Inst::Comment(text) => write!(f, "; {}", text),
+        Inst::Mov { mode, src, dest } => match (src, dest) {
+            (Operand::Xmm(_), Operand::Xmm(_))
+            | (Operand::Mem(_), Operand::Xmm(_))
+            | (Operand::Xmm(_), Operand::Mem(_)) => {
+                binary(f, &format!("mov{}", float_suffix(*mode)), src, dest, *mode)
+            }
+            (Operand::Reg(_), Operand::Xmm(_)) | (Operand::Xmm(_), Operand::Reg(_)) => {
+                let mnemonic = if *mode == Int32 { "movd" } else { "movq" };
+                binary(f, mnemonic, src, dest, *mode)
+            }
+            (Operand::Imm(imm), Operand::Reg(_))
+                if (*mode == Int64 || *mode == Ptr) && (*imm as i32) as i64 != *imm =>
+            {
+                binary(f, "movabs", src, dest, *mode)
+            }
+            _ => binary(f, "mov", src, dest, *mode),
+        },
+        Inst::Lea { src, dest } => match src {
+            Operand::Mem(mem) => write!(f, "lea {}, {}", op(dest, Int64), address(mem, names)),
+            src => binary(f, "lea", src, dest, Int64),
+        },
+        Inst::Movzx { mode, src, dest } => {
+            write!(f, "movzx {}, {}", op(dest, Int32), op(src, *mode))
+        }
+        Inst::Movsx { mode, src, dest } => {
+            write!(f, "movsx {}, {}", op(dest, Int64), op(src, *mode))
+        }
+        Inst::Alu {
+            mode,
+            op,
+            src,
+            dest,
+        } => {
+            let mnemonic = format!("{:?}", op).to_lowercase();
+            binary(f, &mnemonic, src, dest, *mode)
+        }
+        Inst::Test { mode, src, dest } => binary(f, "test", src, dest, *mode),
+        Inst::Shift {
+            mode,
+            op: shift,
+            count,
+            dest,
+        } => {
+            let mnemonic = format!("{:?}", shift).to_lowercase();
+            write!(f, "{} {}, {}", mnemonic, op(dest, *mode), op(count, Int8))
+        }
+        Inst::Neg { mode, dest } => write!(f, "neg {}", op(dest, *mode)),
+        Inst::Not { mode, dest } => write!(f, "not {}", op(dest, *mode)),
+        Inst::Imul { mode, src, dest } => binary(f, "imul", src, dest, *mode),
+        Inst::Idiv { mode, src } => write!(f, "idiv {}", op(src, *mode)),
+        Inst::SignExtendRax { mode } => f.write_str(if *mode == Int32 { "cdq" } else { "cqo" }),
+        Inst::Setcc { cond, dest } => write!(f, "set{} {}", cond_suffix(*cond), op(dest, Int8)),
+        Inst::Cmov {
+            mode,
+            cond,
+            src,
+            dest,
+        } => binary(f, &format!("cmov{}", cond_suffix(*cond)), src, dest, *mode),
+        Inst::Float {
+            mode,
+            op: float,
+            src,
+            dest,
+        } => {
+            let mnemonic = format!("{:?}", float).to_lowercase();
+            binary(
+                f,
+                &format!("{}{}", mnemonic, float_suffix(*mode)),
+                src,
+                dest,
+                *mode,
+            )
+        }
+        Inst::FloatCmp { mode, src, dest } => binary(
+            f,
+            &format!("ucomi{}", float_suffix(*mode)),
+            src,
+            dest,
+            *mode,
+        ),
+        Inst::Push(value) => write!(f, "push {}", op(value, Int64)),
+        Inst::Pop(value) => write!(f, "pop {}", op(value, Int64)),
+        Inst::Jmp(target) => write!(f, "jmp {}", op(target, Int64)),
+        Inst::Jcc { cond, target } => write!(f, "j{} {}", cond_suffix(*cond), names(*target)),
+        Inst::Call(target) => write!(f, "call {}", op(target, Int64)),
+        Inst::Ret => f.write_str("ret"),
+        Inst::Nop => f.write_str("nop"),
+    }
+}
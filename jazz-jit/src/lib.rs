@@ -7,9 +7,28 @@
 pub mod assembler;
 pub mod assembler_x64;
 pub mod avx;
+pub mod avx512;
+pub mod call;
+pub mod code_cache;
 pub mod constants_x64;
+pub mod cpu;
+pub mod disasm;
 pub mod dseg;
+pub mod elf;
+pub mod function;
+#[cfg(feature = "gdb-jit")]
+pub mod gdb_jit;
 pub mod generic;
+pub mod inst;
+pub mod object;
+pub mod peephole;
+#[cfg(target_os = "linux")]
+pub mod perf;
+pub mod regalloc;
+pub mod reloc;
+pub mod source;
+pub mod symbols;
+pub mod unwind;
 pub mod utils;
 pub use self::utils::*;
 
@@ -22,12 +41,15 @@ pub enum MachineMode {
     Float32,
     Float64,
     Ptr,
+    /// Last so the discriminants C callers pass keep their meaning.
+    Int16,
 }
 
 impl MachineMode {
     pub fn size(self) -> usize {
         match self {
             MachineMode::Int8 => 1,
+            MachineMode::Int16 => 2,
             MachineMode::Int32 => 4,
             MachineMode::Int64 => 8,
             MachineMode::Ptr => 8,
@@ -56,42 +78,146 @@ pub enum CondCode {
 
 const PAGE_SIZE: usize = 4096;
 
-use core::mem;
+/// Protection of a code mapping. Code pages are never writable and executable at
+/// the same time: they are filled while `Writable` and sealed as `Executable`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[repr(C)]
+pub enum ProtType {
+    Executable,
+    Writable,
+    None,
+}
+
+impl ProtType {
+    #[cfg(target_family = "unix")]
+    fn to_libc(self) -> libc::c_int {
+        match self {
+            ProtType::None => libc::PROT_NONE,
+            ProtType::Writable => libc::PROT_READ | libc::PROT_WRITE,
+            ProtType::Executable => libc::PROT_READ | libc::PROT_EXEC,
+        }
+    }
+
+    #[cfg(target_family = "windows")]
+    fn to_winapi(self) -> u32 {
+        use winapi::um::winnt::{PAGE_EXECUTE_READ, PAGE_NOACCESS, PAGE_READWRITE};
+
+        match self {
+            ProtType::None => PAGE_NOACCESS,
+            ProtType::Writable => PAGE_READWRITE,
+            ProtType::Executable => PAGE_EXECUTE_READ,
+        }
+    }
+}
 
+/// Rounds `size` up to a whole number of pages.
+pub fn page_align(size: usize) -> usize {
+    let size = if size == 0 { 1 } else { size };
+    (size + PAGE_SIZE - 1) & !(PAGE_SIZE - 1)
+}
+
+/// Maps `size` bytes (rounded up to pages) with protection `prot`.
 #[cfg(target_family = "unix")]
-fn setup(size: usize) -> *mut u8 {
-    unsafe {
-        let size = size * PAGE_SIZE;
-        let mut content: *mut libc::c_void = mem::uninitialized();
-        libc::posix_memalign(&mut content, 4096, size);
-        let result = libc::mmap(
-            content,
+pub(crate) fn reserve(size: usize, prot: ProtType) -> *mut u8 {
+    let size = page_align(size);
+    let result = unsafe {
+        libc::mmap(
+            ::std::ptr::null_mut(),
             size,
-            libc::PROT_EXEC | libc::PROT_READ | libc::PROT_WRITE,
-            libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+            prot.to_libc(),
+            libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_NORESERVE,
             -1,
             0,
-        );
-        mem::transmute(result)
+        )
+    };
+
+    if result == libc::MAP_FAILED {
+        panic!("mmap failed");
     }
+
+    result as *mut u8
 }
 
 #[cfg(target_family = "windows")]
-fn setup(size: usize) -> *mut u8 {
-    unsafe {
-        let _size = size * PAGE_SIZE;
+pub(crate) fn reserve(size: usize, prot: ProtType) -> *mut u8 {
+    use winapi::um::winnt::{MEM_COMMIT, MEM_RESERVE};
 
-        let mem: *mut u8 = mem::transmute(winapi::um::memoryapi::VirtualAlloc(
+    let size = page_align(size);
+    let mem = unsafe {
+        winapi::um::memoryapi::VirtualAlloc(
             ::std::ptr::null_mut(),
-            _size,
-            winapi::um::winnt::MEM_COMMIT,
-            winapi::um::winnt::PAGE_EXECUTE_READWRITE,
-        ));
-        mem
+            size,
+            MEM_COMMIT | MEM_RESERVE,
+            prot.to_winapi(),
+        )
+    };
+
+    if mem.is_null() {
+        panic!("VirtualAlloc failed");
     }
+
+    mem as *mut u8
 }
 
-#[derive(Copy, Clone)]
+#[cfg(target_family = "unix")]
+pub(crate) fn release(ptr: *const u8, size: usize) {
+    let res = unsafe { libc::munmap(ptr as *mut libc::c_void, page_align(size)) };
+
+    if res != 0 {
+        panic!("munmap failed");
+    }
+}
+
+#[cfg(target_family = "windows")]
+pub(crate) fn release(ptr: *const u8, _size: usize) {
+    use winapi::um::winnt::MEM_RELEASE;
+
+    let res = unsafe { winapi::um::memoryapi::VirtualFree(ptr as *mut _, 0, MEM_RELEASE) };
+
+    if res == 0 {
+        panic!("VirtualFree failed");
+    }
+}
+
+/// Returns the pages of a region to the OS while keeping the address range reserved.
+#[cfg(target_family = "unix")]
+pub(crate) fn discard(ptr: *const u8, size: usize) {
+    unsafe {
+        libc::madvise(ptr as *mut libc::c_void, size, libc::MADV_DONTNEED);
+    }
+}
+
+#[cfg(target_family = "windows")]
+pub(crate) fn discard(_ptr: *const u8, _size: usize) {}
+
+/// Changes the protection of every page touched by `[ptr, ptr + size)`.
+#[cfg(target_family = "unix")]
+pub(crate) fn protect(ptr: *const u8, size: usize, prot: ProtType) {
+    let start = ptr as usize & !(PAGE_SIZE - 1);
+    let size = page_align(ptr as usize + size - start);
+    let res = unsafe { libc::mprotect(start as *mut libc::c_void, size, prot.to_libc()) };
+
+    if res != 0 {
+        panic!("mprotect failed");
+    }
+}
+
+#[cfg(target_family = "windows")]
+pub(crate) fn protect(ptr: *const u8, size: usize, prot: ProtType) {
+    let start = ptr as usize & !(PAGE_SIZE - 1);
+    let size = page_align(ptr as usize + size - start);
+    let mut old = 0;
+    let res = unsafe {
+        winapi::um::memoryapi::VirtualProtect(start as *mut _, size, prot.to_winapi(), &mut old)
+    };
+
+    if res == 0 {
+        panic!("VirtualProtect failed");
+    }
+}
+
+/// A finalized piece of code. Memory handed out by a `CodeCache` is returned to
+/// it when the handle is dropped.
 #[repr(C)]
 pub struct Memory {
     start: *const u8,
@@ -99,17 +225,19 @@ pub struct Memory {
 
     pointer: *const u8,
     size: usize,
+
+    cache: Option<Arc<CodeCache>>,
+    name: Option<String>,
+    listing: Option<Box<Listing>>,
+    relocations: Vec<Relocation>,
+    unwind: Option<unwind::Registration>,
+    lines: LineTable,
+
+    #[cfg(feature = "gdb-jit")]
+    gdb: Option<gdb_jit::Registration>,
 }
 
 impl Memory {
-    pub fn new(ptr: *const u8) -> Memory {
-        Memory {
-            start: unsafe { ptr.offset(0) },
-            end: ptr,
-            pointer: ptr,
-            size: 0xdead,
-        }
-    }
     pub fn start(&self) -> *const u8 {
         self.start
     }
@@ -118,6 +246,8 @@ impl Memory {
         self.end
     }
 
+    /// Start of the region: the data segment, or the code if the data segment is
+    /// placed behind it. Code is entered at `start()`.
     pub fn ptr(&self) -> *const u8 {
         self.pointer
     }
@@ -125,37 +255,110 @@ impl Memory {
     pub fn size(&self) -> usize {
         self.size
     }
-}
 
-use self::assembler::Assembler;
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
 
-#[no_mangle]
-pub fn get_executable_memory(buf: &Assembler) -> Memory {
-    let data = copy_vec(buf.data());
-    let dseg = &buf.dseg;
-    let total_size = data.len() + dseg.size() as usize;
-    let ptr = setup(total_size);
+    /// Name of the code, or one derived from its address if it has none.
+    pub fn symbol_name(&self) -> String {
+        match &self.name {
+            Some(name) => name.clone(),
+            None => format!("jit_{:x}", self.start as usize),
+        }
+    }
 
-    dseg.finish(ptr);
+    /// Relocations of the code, with positions relative to `start()`.
+    pub fn relocations(&self) -> &[Relocation] {
+        &self.relocations
+    }
 
-    let start;
-    unsafe {
-        start = ptr.offset(dseg.size() as isize);
-        ::core::ptr::copy_nonoverlapping(data.as_ptr(), start as *mut u8, data.len());
-    };
+    /// Source positions of the code, with offsets relative to `start()`.
+    pub fn line_table(&self) -> &LineTable {
+        &self.lines
+    }
 
-    let memory = Memory {
-        start,
-        end: unsafe { ptr.offset(total_size as isize) },
-        pointer: ptr,
-        size: total_size,
-    };
+    /// The source position of the instruction at `addr`, if it is in this code.
+    pub fn source_position(&self, addr: *const u8) -> Option<SourcePosition> {
+        if addr < self.start || addr >= self.end {
+            return None;
+        }
+
+        self.lines.lookup(addr as usize - self.start as usize)
+    }
+
+    /// Intel syntax listing of the code, annotated like `Assembler::disassemble` if the
+    /// assembler was told to `keep_listing`.
+    pub fn disassemble(&self) -> String {
+        self.disassemble_with(Syntax::Intel)
+    }
+
+    pub fn disassemble_with(&self, syntax: Syntax) -> String {
+        let code = unsafe {
+            ::std::slice::from_raw_parts(self.start, self.end as usize - self.start as usize)
+        };
+
+        match &self.listing {
+            Some(listing) => listing.render(code, self.start as u64, syntax),
+            None => Listing::empty().render(code, self.start as u64, syntax),
+        }
+    }
+
+    /// Remaps the whole region (data segment and code) as read-write. Blocks of a
+    /// `CodeCache` have pages of their own, other code stays executable.
+    /// The code must not be executed until `seal` is called.
+    pub fn make_writable(&self) {
+        protect(self.pointer, self.size, ProtType::Writable);
+    }
 
-    memory
+    /// Remaps the region as read-execute after patching.
+    pub fn seal(&self) {
+        protect(self.pointer, self.size, ProtType::Executable);
+    }
+
+    /// Makes the region writable, hands it to `f` and seals it again.
+    /// The slice starts at `ptr()`.
+    pub fn patch<F: FnOnce(&mut [u8])>(&self, f: F) {
+        self.make_writable();
+        let buf = unsafe { ::std::slice::from_raw_parts_mut(self.pointer as *mut u8, self.size) };
+        f(buf);
+        self.seal();
+    }
+}
+
+impl Drop for Memory {
+    fn drop(&mut self) {
+        // debuggers and the unwinder must forget about the code before it is reused
+        #[cfg(feature = "gdb-jit")]
+        drop(self.gdb.take());
+        drop(self.unwind.take());
+
+        if self.name.is_some() {
+            symbols::unregister(self.start);
+        }
+
+        if let Some(cache) = self.cache.take() {
+            cache.free(self.pointer, self.size);
+        }
+    }
+}
+
+use self::assembler::{Assembler, Code};
+use self::code_cache::CodeCache;
+pub use self::cpu::{CpuFeature, CpuFeatures};
+use self::disasm::{Listing, Syntax};
+pub use self::function::JitFunction;
+use self::reloc::{RelocError, Relocation};
+use self::source::{LineTable, SourcePosition};
+use std::sync::Arc;
+
+/// Copies the data segment and code of finalized code into the global code cache.
+pub fn get_executable_memory(code: &Code) -> Result<Memory, RelocError> {
+    CodeCache::global().emit(code)
 }
 
 pub mod c_api {
-    use super::MachineMode;
+    use super::{get_executable_memory, MachineMode, Memory};
     use crate::assembler::Mem;
     use crate::assembler::*;
     use crate::constants_x64::*;
@@ -176,6 +379,40 @@ pub mod c_api {
     pub fn mem_index(reg: Register, reg2: Register, v1: i32, v2: i32) -> Mem {
         Mem::Index(reg, reg2, v1, v2)
     }
+    #[no_mangle]
+    pub fn mem_label(lbl: Label) -> Mem {
+        Mem::RipRelative(RipTarget::Label(lbl))
+    }
+    #[no_mangle]
+    pub fn mem_dseg(disp: i32) -> Mem {
+        Mem::RipRelative(RipTarget::DSeg(disp))
+    }
+    #[no_mangle]
+    pub fn mem_absolute(addr: u64) -> Mem {
+        Mem::Absolute(addr)
+    }
+    #[no_mangle]
+    pub fn mem_segment(segment: Segment, disp: i32) -> Mem {
+        Mem::Segment(segment, SegmentAddr::Absolute(disp as u64))
+    }
+    #[no_mangle]
+    pub fn mem_segment_base(segment: Segment, reg: Register, off: i32) -> Mem {
+        Mem::Segment(segment, SegmentAddr::Base(reg, off))
+    }
+    #[no_mangle]
+    pub fn mem_segment_index(
+        segment: Segment,
+        reg: Register,
+        reg2: Register,
+        v1: i32,
+        v2: i32,
+    ) -> Mem {
+        Mem::Segment(segment, SegmentAddr::Index(reg, reg2, v1, v2))
+    }
+    #[no_mangle]
+    pub fn mem_segment_offset(segment: Segment, reg: Register, v1: i32, v2: i32) -> Mem {
+        Mem::Segment(segment, SegmentAddr::Offset(reg, v1, v2))
+    }
 
     #[no_mangle]
     pub fn asm_load_int(buf: &mut Assembler, mode: MachineMode, imm: i64, dst: Register) {
@@ -186,4 +423,112 @@ pub mod c_api {
     pub fn asm_load_float(buf: &mut Assembler, mode: MachineMode, imm: f64, dst: XMMRegister) {
         buf.load_float_const(mode, dst, imm);
     }
+
+    /// Finalizes the code of `buf`, which is left empty, and copies it into the
+    /// global code cache. The handle is opaque to C and has to be released with
+    /// `memory_free`. Returns null if the code has errors or a relocation fails.
+    #[no_mangle]
+    pub extern "C" fn memory_emit(buf: &mut Assembler) -> *mut Memory {
+        let asm = ::std::mem::replace(buf, Assembler::new());
+
+        match asm.finalize().ok().map(|code| get_executable_memory(&code)) {
+            Some(Ok(memory)) => Box::into_raw(Box::new(memory)),
+            _ => ::std::ptr::null_mut(),
+        }
+    }
+
+    /// Entry point of the code of a handle from `memory_emit`.
+    #[no_mangle]
+    pub extern "C" fn memory_start(mem: &Memory) -> *const u8 {
+        mem.start()
+    }
+
+    /// Returns the code of a handle from `memory_emit` to the code cache.
+    ///
+    /// # Safety
+    ///
+    /// `mem` has to come from `memory_emit` and may not be used afterwards.
+    #[no_mangle]
+    pub unsafe extern "C" fn memory_free(mem: *mut Memory) {
+        if !mem.is_null() {
+            drop(Box::from_raw(mem));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler_x64::*;
+    use crate::constants_x64::*;
+
+    /// `fn() -> i32` returning `value`.
+    fn constant(cache: &Arc<CodeCache>, value: i32) -> Memory {
+        let mut asm = Assembler::new();
+        emit_movl_imm_reg(&mut asm, value, RAX);
+        emit_retq(&mut asm);
+
+        cache.emit(&asm.finalize().unwrap()).unwrap()
+    }
+
+    /// Permissions of the mapping `addr` is in, like "r-x".
+    #[cfg(target_os = "linux")]
+    pub(crate) fn permissions(addr: *const u8) -> String {
+        let maps = std::fs::read_to_string("/proc/self/maps").unwrap();
+
+        for line in maps.lines() {
+            let mut fields = line.split_whitespace();
+            let range = fields.next().unwrap();
+            let perms = fields.next().unwrap();
+            let dash = range.find('-').unwrap();
+            let start = usize::from_str_radix(&range[..dash], 16).unwrap();
+            let end = usize::from_str_radix(&range[dash + 1..], 16).unwrap();
+
+            if (start..end).contains(&(addr as usize)) {
+                return perms[..3].to_owned();
+            }
+        }
+
+        panic!("{:p} is not mapped", addr);
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn code_is_never_writable_and_executable() {
+        let cache = CodeCache::new(1 << 16);
+        let memory = constant(&cache, 1);
+        assert_eq!(permissions(memory.start()), "r-x");
+
+        // the immediate of `mov eax, imm32` follows the opcode
+        let imm = memory.start() as usize - memory.ptr() as usize + 1;
+        memory.patch(|buf| {
+            assert_eq!(permissions(buf.as_ptr()), "rw-");
+            buf[imm] = 2;
+        });
+        assert_eq!(permissions(memory.start()), "r-x");
+
+        let fun: JitFunction<extern "C" fn() -> i32> = unsafe { JitFunction::borrowed(&memory) };
+        assert_eq!(fun.call(), 2);
+
+        memory.make_writable();
+        assert_eq!(permissions(memory.start()), "rw-");
+        memory.seal();
+        assert_eq!(permissions(memory.start()), "r-x");
+    }
+
+    #[test]
+    fn drop_returns_memory_to_the_cache() {
+        let cache = CodeCache::new(1 << 16);
+        let memory = constant(&cache, 1);
+        let ptr = memory.ptr();
+        assert_eq!(cache.stats().allocations, 1);
+
+        drop(memory);
+        let stats = cache.stats();
+        assert_eq!((stats.allocations, stats.used), (0, 0));
+
+        // the block is handed out again
+        let memory = constant(&cache, 2);
+        assert_eq!(memory.ptr(), ptr);
+    }
 }
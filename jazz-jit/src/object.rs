@@ -0,0 +1,257 @@
+//! Relocatable object files holding finalized code.
+//!
+//! The code of every buffer goes to `.text`, its data segment to `.rodata`. Named
+//! buffers become global functions, references to symbols the object does not
+//! define are left to the linker. Fixed addresses are written into the code as they
+//! are, so the object can only be linked where they are valid.
+
+use crate::assembler::Code;
+use crate::elf::*;
+use crate::reloc::{RelocError, RelocKind, RelocTarget};
+use byteorder::{LittleEndian, WriteBytesExt};
+
+/// Alignment of every function in `.text`.
+const FUNCTION_ALIGNMENT: usize = 16;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum RelaSymbol {
+    Text,
+    Rodata,
+    Symbol(String),
+}
+
+#[derive(Clone, Debug)]
+struct Rela {
+    offset: u64,
+    kind: u32,
+    symbol: RelaSymbol,
+    addend: i64,
+}
+
+#[derive(Clone, Debug)]
+struct Function {
+    name: String,
+    offset: u64,
+    size: u64,
+    global: bool,
+}
+
+/// Whether the rel32 field at `at` is the target of a `call` or `jmp`. The byte
+/// in front of a RIP-relative displacement is a ModRM byte, which never has these
+/// values.
+fn is_branch(code: &[u8], at: usize) -> bool {
+    at > 0 && (code[at - 1] == 0xE8 || code[at - 1] == 0xE9)
+}
+
+/// Collects finalized code and writes it as an ELF64 x86-64 relocatable object.
+#[derive(Clone, Debug, Default)]
+pub struct ObjectFile {
+    text: Vec<u8>,
+    rodata: Vec<u8>,
+    rodata_align: u64,
+    functions: Vec<Function>,
+    relocations: Vec<Rela>,
+}
+
+impl ObjectFile {
+    pub fn new() -> ObjectFile {
+        ObjectFile {
+            rodata_align: 1,
+            ..ObjectFile::default()
+        }
+    }
+
+    /// Adds the code and data segment of `code` as a function. Code without a name
+    /// gets a local symbol.
+    pub fn add_function(&mut self, code: &Code) -> Result<(), RelocError> {
+        let start = align(self.text.len(), FUNCTION_ALIGNMENT);
+        let dseg = &code.dseg;
+        let pool_align = dseg.alignment() as usize;
+        let pool = align(self.rodata.len(), pool_align);
+        let pool_size = dseg.size() as usize;
+
+        let mut text = code.data.clone();
+        let mut relocations = Vec::new();
+
+        for reloc in &code.relocations {
+            let field = start as u64 + reloc.at as u64;
+            let (symbol, addend) = match &reloc.target {
+                RelocTarget::Label(lbl) => {
+                    let pos = match code.labels.get(*lbl).copied().flatten() {
+                        Some(pos) => pos,
+                        None => return Err(RelocError::UnboundLabel(*lbl)),
+                    };
+
+                    if reloc.kind == RelocKind::Rel32 {
+                        // within the function, nothing left for the linker
+                        reloc.write(&mut text, 0, pos as u64)?;
+                        continue;
+                    }
+
+                    (RelaSymbol::Text, (start + pos) as i64)
+                }
+                RelocTarget::DSeg(disp) => {
+                    let entry = pool + pool_size - *disp as usize;
+                    (RelaSymbol::Rodata, entry as i64)
+                }
+                RelocTarget::Address(addr) => {
+                    if reloc.kind == RelocKind::Rel32 {
+                        return Err(RelocError::FixedAddress { at: reloc.at });
+                    }
+
+                    reloc.write(&mut text, 0, *addr)?;
+                    continue;
+                }
+                RelocTarget::Symbol(name) => (RelaSymbol::Symbol(name.clone()), 0),
+            };
+            let kind = match (reloc.kind, &symbol) {
+                (RelocKind::Abs64, _) => R_X86_64_64,
+                // the symbol may be a function of a shared object, reached through the
+                // PLT, which PC32 does not allow in position independent executables
+                (RelocKind::Rel32, RelaSymbol::Symbol(_)) if is_branch(&text, reloc.at) => {
+                    R_X86_64_PLT32
+                }
+                (RelocKind::Rel32, _) => R_X86_64_PC32,
+            };
+
+            // the linker only reads the addend from the relocation
+            text[reloc.at..reloc.at + reloc.size()]
+                .iter_mut()
+                .for_each(|byte| *byte = 0);
+
+            relocations.push(Rela {
+                offset: field,
+                kind,
+                symbol,
+                addend: addend + reloc.addend,
+            });
+        }
+
+        if pool_size > 0 {
+            self.rodata.resize(pool + pool_size, 0);
+            dseg.finish(self.rodata[pool..].as_mut_ptr());
+            self.rodata_align = self.rodata_align.max(pool_align as u64);
+        }
+
+        let (name, global) = match &code.name {
+            Some(name) => (name.clone(), true),
+            None => (format!("jit_{}", self.functions.len()), false),
+        };
+
+        self.text.resize(start, 0xcc);
+        self.text.extend_from_slice(&text);
+        self.relocations.extend(relocations);
+        self.functions.push(Function {
+            name,
+            offset: start as u64,
+            size: code.data.len() as u64,
+            global,
+        });
+
+        Ok(())
+    }
+
+    /// Lays out the object file.
+    pub fn finish(self) -> Vec<u8> {
+        let mut elf = ElfWriter::new(ET_REL);
+
+        let mut text = Section::new(".text", SHT_PROGBITS, SHF_ALLOC | SHF_EXECINSTR);
+        text.align = FUNCTION_ALIGNMENT as u64;
+        text.data = self.text;
+        let text = elf.add_section(text);
+
+        let mut rodata = Section::new(".rodata", SHT_PROGBITS, SHF_ALLOC);
+        rodata.align = self.rodata_align;
+        rodata.data = self.rodata;
+        let rodata = elf.add_section(rodata);
+
+        // without it linkers assume the stack has to be executable
+        elf.add_section(Section::new(".note.GNU-stack", SHT_PROGBITS, 0));
+
+        // `ElfWriter` keeps the order of symbols with the same binding, so locals
+        // followed by globals get the indices assigned here
+        let mut symbols = vec![
+            Symbol {
+                name: String::new(),
+                value: 0,
+                size: 0,
+                bind: STB_LOCAL,
+                kind: STT_SECTION,
+                section: text,
+            },
+            Symbol {
+                name: String::new(),
+                value: 0,
+                size: 0,
+                bind: STB_LOCAL,
+                kind: STT_SECTION,
+                section: rodata,
+            },
+        ];
+
+        let mut functions = self.functions;
+        functions.sort_by_key(|function| function.global);
+        for function in &functions {
+            symbols.push(Symbol {
+                name: function.name.clone(),
+                value: function.offset,
+                size: function.size,
+                bind: if function.global {
+                    STB_GLOBAL
+                } else {
+                    STB_LOCAL
+                },
+                kind: STT_FUNC,
+                section: text,
+            });
+        }
+
+        let mut rela = Section::new(".rela.text", SHT_RELA, SHF_INFO_LINK);
+        rela.align = 8;
+        rela.entsize = RELA_SIZE as u64;
+        rela.info = text as u32;
+
+        for reloc in &self.relocations {
+            let symbol = match &reloc.symbol {
+                RelaSymbol::Text => 0,
+                RelaSymbol::Rodata => 1,
+                RelaSymbol::Symbol(name) => {
+                    match symbols.iter().position(|sym| sym.name == *name) {
+                        Some(idx) => idx,
+                        None => {
+                            symbols.push(Symbol {
+                                name: name.clone(),
+                                value: 0,
+                                size: 0,
+                                bind: STB_GLOBAL,
+                                kind: STT_NOTYPE,
+                                section: SHN_UNDEF,
+                            });
+                            symbols.len() - 1
+                        }
+                    }
+                }
+            };
+
+            let data = &mut rela.data;
+            let info = (symbol as u64 + 1) << 32 | reloc.kind as u64;
+            data.write_u64::<LittleEndian>(reloc.offset).unwrap();
+            data.write_u64::<LittleEndian>(info).unwrap();
+            data.write_i64::<LittleEndian>(reloc.addend).unwrap();
+        }
+
+        // the symbol table is added right behind the relocations
+        let idx = elf.add_section(rela);
+        elf.section_mut(idx).link = idx as u32 + 1;
+
+        for symbol in symbols {
+            elf.add_symbol(symbol);
+        }
+
+        elf.finish()
+    }
+}
+
+fn align(pos: usize, align: usize) -> usize {
+    (pos + align - 1) & !(align - 1)
+}
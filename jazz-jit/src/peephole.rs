@@ -0,0 +1,664 @@
+//! Peephole optimization of emitted code.
+//!
+//! `Peephole::run` decodes the code of an `Assembler` before it is finalized and
+//! offers the instructions from every position on to a set of rules. The `Inst`s a
+//! rule replaces instructions with are lowered through the encoders and spliced into
+//! the code, and labels, jumps, relocations, data segment references, comments, call
+//! frame information and source positions are moved along.
+//!
+//! Rules only see straight-line code. A run of instructions ends in front of an
+//! instruction a label is bound to, and at instructions with a relocation, a data
+//! segment or RIP-relative label reference, or bytes that could not be decoded.
+//! Those are never rewritten.
+
+use crate::assembler::{Assembler, JumpKind, Label};
+use crate::assembler_x64::{AluOp, ShiftOp};
+use crate::constants_x64::*;
+use crate::inst::{parse_cond, parse_reg, Inst, InstBuffer, Operand};
+use crate::MachineMode;
+use capstone::prelude::*;
+use std::collections::{BTreeMap, BTreeSet};
+
+/// A decoded instruction.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Insn {
+    pub pos: usize,
+    pub len: usize,
+    pub mnemonic: String,
+    pub operands: String,
+    /// The instruction, if it has one of the forms of `Inst` with register and
+    /// immediate operands or is a jump to a label.
+    pub inst: Option<Inst>,
+    /// Position of the label a jump goes to, if it is bound.
+    pub target: Option<usize>,
+    /// Never offered to rules.
+    fixed: bool,
+}
+
+impl Insn {
+    pub fn end(&self) -> usize {
+        self.pos + self.len
+    }
+
+    fn fixed(pos: usize, len: usize) -> Insn {
+        Insn {
+            pos,
+            len,
+            mnemonic: String::new(),
+            operands: String::new(),
+            inst: None,
+            target: None,
+            fixed: true,
+        }
+    }
+}
+
+pub trait Rule {
+    /// Name the hits of the rule are reported under.
+    fn name(&self) -> &'static str;
+
+    /// Rewrites instructions at the start of `insns`, which is never empty. Returns
+    /// the number of instructions replaced and the instructions replacing them.
+    /// Replacements may not refer to labels.
+    fn rewrite(&self, insns: &[Insn]) -> Option<(usize, Vec<Inst>)>;
+}
+
+/// A set of rules.
+pub struct Peephole {
+    rules: Vec<Box<dyn Rule>>,
+}
+
+impl Default for Peephole {
+    fn default() -> Peephole {
+        Peephole::new()
+    }
+}
+
+impl Peephole {
+    /// All rules of this module.
+    pub fn new() -> Peephole {
+        Peephole::empty()
+            .with_rule(MovToSelf)
+            .with_rule(ZeroWithXor)
+            .with_rule(JumpToNext)
+            .with_rule(PushPop)
+            .with_rule(UntagTag)
+    }
+
+    pub fn empty() -> Peephole {
+        Peephole { rules: Vec::new() }
+    }
+
+    /// Adds a rule. Rules are tried in the order they were added.
+    pub fn with_rule(mut self, rule: impl Rule + 'static) -> Peephole {
+        self.rules.push(Box::new(rule));
+        self
+    }
+
+    /// Rewrites the code of `asm` until no rule matches any more and returns how
+    /// often each rule matched. Code with errors is left as it is for `finalize` to
+    /// report them.
+    pub fn run(&self, asm: &mut Assembler) -> Vec<(&'static str, usize)> {
+        debug_assert!(asm.island.is_none(), "peephole pass after finalization");
+        let mut hits = vec![0; self.rules.len()];
+
+        let cs = Capstone::new()
+            .x86()
+            .mode(arch::x86::ArchMode::Mode64)
+            .syntax(arch::x86::ArchSyntax::Intel)
+            .build();
+
+        if let (Ok(cs), false) = (cs, asm.has_errors()) {
+            while self.pass(asm, &cs, &mut hits) {}
+        }
+
+        self.rules
+            .iter()
+            .map(|rule| rule.name())
+            .zip(hits)
+            .collect()
+    }
+
+    /// Applies the rules once over the whole code. Returns whether anything changed.
+    fn pass(&self, asm: &mut Assembler, cs: &Capstone, hits: &mut [usize]) -> bool {
+        let insns = decode(asm, cs);
+        let bound: BTreeSet<usize> = asm.labels.iter().filter_map(|&pos| pos).collect();
+        let mut edits = Vec::new();
+        let mut i = 0;
+
+        while i < insns.len() {
+            let end = insns[i..]
+                .iter()
+                .enumerate()
+                .position(|(j, insn)| insn.fixed || (j > 0 && bound.contains(&insn.pos)))
+                .map_or(insns.len(), |j| i + j);
+
+            match self.rewrite(asm, &insns[i..end], hits) {
+                Some((count, code)) => {
+                    let start = insns[i].pos;
+                    let len = insns[i + count - 1].end() - start;
+                    edits.push(Edit { start, len, code });
+                    i += count;
+                }
+                None => i += 1,
+            }
+        }
+
+        if edits.is_empty() {
+            return false;
+        }
+
+        splice(asm, &edits);
+        true
+    }
+
+    fn rewrite(
+        &self,
+        asm: &Assembler,
+        insns: &[Insn],
+        hits: &mut [usize],
+    ) -> Option<(usize, Vec<u8>)> {
+        if insns.is_empty() {
+            return None;
+        }
+
+        for (rule, hits) in self.rules.iter().zip(hits.iter_mut()) {
+            if let Some((count, insts)) = rule.rewrite(insns) {
+                debug_assert!(count > 0 && count <= insns.len());
+
+                if let Some(code) = lower(asm, insts) {
+                    *hits += 1;
+                    return Some((count, code));
+                }
+            }
+        }
+
+        None
+    }
+}
+
+/// Encodes a replacement. Replacements that the encoders reject or that need
+/// labels, relocations or data segment entries are not used.
+fn lower(asm: &Assembler, insts: Vec<Inst>) -> Option<Vec<u8>> {
+    let mut buf = InstBuffer::new();
+    for inst in insts {
+        buf.push(inst);
+    }
+
+    let mut scratch = Assembler::with_features(asm.features);
+    buf.lower(&mut scratch);
+
+    if scratch.has_errors()
+        || !scratch.jumps.is_empty()
+        || !scratch.relocations.is_empty()
+        || !scratch.dseg_refs.is_empty()
+    {
+        None
+    } else {
+        Some(scratch.data)
+    }
+}
+
+/// `len` bytes at `start` replaced by `code`.
+struct Edit {
+    start: usize,
+    len: usize,
+    code: Vec<u8>,
+}
+
+/// Applies `edits`, which are sorted and do not overlap.
+fn splice(asm: &mut Assembler, edits: &[Edit]) {
+    let new_pos = |pos: usize| -> usize {
+        let mut delta = 0isize;
+
+        for edit in edits {
+            if pos >= edit.start + edit.len {
+                delta += edit.code.len() as isize - edit.len as isize;
+            } else if pos > edit.start {
+                // e.g. the call frame information of a removed push takes effect
+                // behind the replacement
+                return (edit.start as isize + delta) as usize + edit.code.len();
+            } else {
+                break;
+            }
+        }
+
+        (pos as isize + delta) as usize
+    };
+
+    let old_data = ::std::mem::take(&mut asm.data);
+    asm.prefixes = 0..0;
+    let mut last = 0;
+    for edit in edits {
+        asm.data.extend_from_slice(&old_data[last..edit.start]);
+        asm.data.extend_from_slice(&edit.code);
+        last = edit.start + edit.len;
+    }
+    asm.data.extend_from_slice(&old_data[last..]);
+
+    // the jumps of replaced instructions are gone
+    asm.jumps.retain(|jmp| {
+        !edits
+            .iter()
+            .any(|edit| jmp.at >= edit.start && jmp.at < edit.start + edit.len)
+    });
+    for jmp in asm.jumps.iter_mut() {
+        jmp.at = new_pos(jmp.at);
+    }
+
+    asm.move_positions(&new_pos);
+}
+
+fn decode(asm: &Assembler, cs: &Capstone) -> Vec<Insn> {
+    let mut jumps = BTreeMap::new();
+    // raw label displacements written by `emit_label` are data in the code
+    let mut data = Vec::new();
+    // fields that depend on where the code or what it refers to ends up
+    let mut refs: Vec<usize> = asm
+        .relocations
+        .iter()
+        .map(|reloc| reloc.at)
+        .chain(asm.dseg_refs.iter().copied())
+        .collect();
+
+    for jmp in &asm.jumps {
+        match jmp.kind {
+            JumpKind::Label => data.push(jmp.at),
+            JumpKind::Rip(_) => refs.push(jmp.at),
+            JumpKind::Jmp | JumpKind::Jcc | JumpKind::Short => {
+                jumps.insert(jmp.at, jmp.to);
+            }
+        }
+    }
+
+    data.sort_unstable();
+    refs.sort_unstable();
+
+    let mut insns = Vec::new();
+    let mut pos = 0;
+    for at in data {
+        decode_range(asm, cs, &jumps, pos, at, &mut insns);
+        insns.push(Insn::fixed(at, 4));
+        pos = at + 4;
+    }
+    decode_range(asm, cs, &jumps, pos, asm.data.len(), &mut insns);
+
+    for insn in insns.iter_mut() {
+        let idx = refs.partition_point(|&at| at < insn.pos);
+        if refs.get(idx).is_some_and(|&at| at < insn.end()) {
+            insn.fixed = true;
+        }
+    }
+
+    insns
+}
+
+fn decode_range(
+    asm: &Assembler,
+    cs: &Capstone,
+    jumps: &BTreeMap<usize, Label>,
+    start: usize,
+    end: usize,
+    insns: &mut Vec<Insn>,
+) {
+    let mut pos = start;
+
+    if let Ok(decoded) = cs.disasm_all(&asm.data[start..end], start as u64) {
+        for insn in decoded.iter() {
+            let len = insn.bytes().len();
+            let mnemonic = insn.mnemonic().unwrap_or("").to_owned();
+            let operands = insn.op_str().unwrap_or("").to_owned();
+            let jump = jumps.range(pos..pos + len).next().map(|(_, &lbl)| lbl);
+            let target = jump.and_then(|lbl| asm.labels.get(lbl).copied().flatten());
+
+            insns.push(Insn {
+                pos,
+                len,
+                inst: parse(&mnemonic, &operands, jump),
+                mnemonic,
+                operands,
+                target,
+                fixed: false,
+            });
+            pos += len;
+        }
+    }
+
+    if pos < end {
+        insns.push(Insn::fixed(pos, end - pos));
+    }
+}
+
+#[derive(Copy, Clone)]
+enum Arg {
+    Reg(Register, MachineMode),
+    Imm(i64),
+}
+
+fn parse_arg(text: &str) -> Option<Arg> {
+    if let Some((reg, mode)) = parse_reg(text) {
+        return Some(Arg::Reg(reg, mode));
+    }
+
+    let (negative, digits) = match text.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, text),
+    };
+    let value = match digits.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).ok()?,
+        None => digits.parse::<u64>().ok()?,
+    };
+
+    Some(Arg::Imm(if negative {
+        (value as i64).wrapping_neg()
+    } else {
+        value as i64
+    }))
+}
+
+/// Immediates are printed unsigned at the operand size.
+fn sign_extend(imm: i64, mode: MachineMode) -> i64 {
+    match mode {
+        MachineMode::Int8 => imm as i8 as i64,
+        MachineMode::Int16 => imm as i16 as i64,
+        MachineMode::Int32 => imm as i32 as i64,
+        _ => imm,
+    }
+}
+
+fn alu_op(mnemonic: &str) -> Option<AluOp> {
+    let op = match mnemonic {
+        "add" => AluOp::Add,
+        "or" => AluOp::Or,
+        "and" => AluOp::And,
+        "sub" => AluOp::Sub,
+        "xor" => AluOp::Xor,
+        "cmp" => AluOp::Cmp,
+        _ => return None,
+    };
+
+    Some(op)
+}
+
+fn shift_op(mnemonic: &str) -> Option<ShiftOp> {
+    let op = match mnemonic {
+        "rol" => ShiftOp::Rol,
+        "ror" => ShiftOp::Ror,
+        "shl" => ShiftOp::Shl,
+        "shr" => ShiftOp::Shr,
+        "sar" => ShiftOp::Sar,
+        _ => return None,
+    };
+
+    Some(op)
+}
+
+/// Describes a decoded instruction as `Inst`, if it only has register and
+/// immediate operands or jumps to `jump`.
+fn parse(mnemonic: &str, operands: &str, jump: Option<Label>) -> Option<Inst> {
+    use self::Operand::{Imm, Reg};
+
+    if let Some(target) = jump {
+        return match mnemonic {
+            "jmp" => Some(Inst::Jmp(Operand::Label(target))),
+            _ => Some(Inst::Jcc {
+                cond: parse_cond(mnemonic.strip_prefix('j')?)?,
+                target,
+            }),
+        };
+    }
+
+    let args = if operands.is_empty() {
+        Vec::new()
+    } else {
+        operands
+            .split(", ")
+            .map(parse_arg)
+            .collect::<Option<Vec<_>>>()?
+    };
+
+    let inst = match (mnemonic, args.as_slice()) {
+        ("ret", []) => Inst::Ret,
+        ("nop", []) => Inst::Nop,
+        ("push", [Arg::Reg(reg, MachineMode::Int64)]) => Inst::Push(Reg(*reg)),
+        ("pop", [Arg::Reg(reg, MachineMode::Int64)]) => Inst::Pop(Reg(*reg)),
+        ("neg", [Arg::Reg(dest, mode)]) => Inst::Neg {
+            mode: *mode,
+            dest: Reg(*dest),
+        },
+        ("not", [Arg::Reg(dest, mode)]) => Inst::Not {
+            mode: *mode,
+            dest: Reg(*dest),
+        },
+        ("mov", [Arg::Reg(dest, mode), Arg::Reg(src, _)]) => Inst::Mov {
+            mode: *mode,
+            src: Reg(*src),
+            dest: Reg(*dest),
+        },
+        ("mov", [Arg::Reg(dest, mode), Arg::Imm(imm)])
+        | ("movabs", [Arg::Reg(dest, mode), Arg::Imm(imm)]) => Inst::Mov {
+            mode: *mode,
+            src: Imm(sign_extend(*imm, *mode)),
+            dest: Reg(*dest),
+        },
+        ("test", [Arg::Reg(dest, mode), Arg::Reg(src, _)]) => Inst::Test {
+            mode: *mode,
+            src: Reg(*src),
+            dest: Reg(*dest),
+        },
+        (mnemonic, [Arg::Reg(dest, mode), src]) if alu_op(mnemonic).is_some() => Inst::Alu {
+            mode: *mode,
+            op: alu_op(mnemonic)?,
+            src: match *src {
+                Arg::Reg(src, _) => Reg(src),
+                Arg::Imm(imm) => Imm(sign_extend(imm, *mode)),
+            },
+            dest: Reg(*dest),
+        },
+        (mnemonic, [Arg::Reg(dest, mode), count]) if shift_op(mnemonic).is_some() => Inst::Shift {
+            mode: *mode,
+            op: shift_op(mnemonic)?,
+            count: match *count {
+                Arg::Reg(RCX, MachineMode::Int8) => Reg(RCX),
+                Arg::Imm(imm) => Imm(imm),
+                Arg::Reg(..) => return None,
+            },
+            dest: Reg(*dest),
+        },
+        _ => return None,
+    };
+
+    Some(inst)
+}
+
+/// Whether `insns` overwrite the flags before anything reads them. Calls and
+/// returns end the lifetime of the flags, other jumps and the end of `insns` are
+/// taken as reading them.
+pub fn flags_dead(insns: &[Insn]) -> bool {
+    const READ: [&str; 6] = ["adc", "sbb", "rcl", "rcr", "pushfq", "lahf"];
+    const WRITE: [&str; 8] = ["add", "sub", "cmp", "neg", "and", "or", "xor", "test"];
+
+    for insn in insns {
+        let mnemonic = insn.mnemonic.as_str();
+
+        if insn.fixed
+            || mnemonic.starts_with("set")
+            || mnemonic.starts_with("cmov")
+            || READ.contains(&mnemonic)
+        {
+            return false;
+        }
+
+        if mnemonic == "call" || mnemonic == "ret" {
+            return true;
+        }
+
+        if mnemonic.starts_with('j') {
+            return false;
+        }
+
+        if WRITE.contains(&mnemonic) {
+            return true;
+        }
+    }
+
+    false
+}
+
+/// Removes `mov reg, reg` with the same register, except 32-bit moves, which clear
+/// the upper half of the register.
+pub struct MovToSelf;
+
+impl Rule for MovToSelf {
+    fn name(&self) -> &'static str {
+        "mov_to_self"
+    }
+
+    fn rewrite(&self, insns: &[Insn]) -> Option<(usize, Vec<Inst>)> {
+        match insns[0].inst {
+            Some(Inst::Mov {
+                mode,
+                src: Operand::Reg(src),
+                dest: Operand::Reg(dest),
+            }) if src == dest && mode != MachineMode::Int32 => Some((1, Vec::new())),
+            _ => None,
+        }
+    }
+}
+
+/// Replaces `mov reg, 0` by the shorter `xor reg, reg` where the flags it sets are
+/// not used.
+pub struct ZeroWithXor;
+
+impl Rule for ZeroWithXor {
+    fn name(&self) -> &'static str {
+        "zero_with_xor"
+    }
+
+    fn rewrite(&self, insns: &[Insn]) -> Option<(usize, Vec<Inst>)> {
+        match insns[0].inst {
+            Some(Inst::Mov {
+                mode: MachineMode::Int32,
+                src: Operand::Imm(0),
+                dest: Operand::Reg(dest),
+            })
+            | Some(Inst::Mov {
+                mode: MachineMode::Int64,
+                src: Operand::Imm(0),
+                dest: Operand::Reg(dest),
+            }) if flags_dead(&insns[1..]) => Some((
+                1,
+                vec![Inst::Alu {
+                    mode: MachineMode::Int32,
+                    op: AluOp::Xor,
+                    src: Operand::Reg(dest),
+                    dest: Operand::Reg(dest),
+                }],
+            )),
+            _ => None,
+        }
+    }
+}
+
+/// Removes jumps to the instruction right behind them.
+pub struct JumpToNext;
+
+impl Rule for JumpToNext {
+    fn name(&self) -> &'static str {
+        "jump_to_next"
+    }
+
+    fn rewrite(&self, insns: &[Insn]) -> Option<(usize, Vec<Inst>)> {
+        let insn = &insns[0];
+
+        match insn.inst {
+            Some(Inst::Jmp(Operand::Label(_))) | Some(Inst::Jcc { .. })
+                if insn.target == Some(insn.end()) =>
+            {
+                Some((1, Vec::new()))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Removes a push that is popped right away into the same register and replaces it
+/// by a move if it is popped into another one.
+pub struct PushPop;
+
+impl Rule for PushPop {
+    fn name(&self) -> &'static str {
+        "push_pop"
+    }
+
+    fn rewrite(&self, insns: &[Insn]) -> Option<(usize, Vec<Inst>)> {
+        let (push, pop) = match insns {
+            [push, pop, ..] => (push.inst.as_ref()?, pop.inst.as_ref()?),
+            _ => return None,
+        };
+
+        match (push, pop) {
+            (Inst::Push(Operand::Reg(src)), Inst::Pop(Operand::Reg(dest))) if src == dest => {
+                Some((2, Vec::new()))
+            }
+            (Inst::Push(Operand::Reg(src)), Inst::Pop(Operand::Reg(dest))) => Some((
+                2,
+                vec![Inst::Mov {
+                    mode: MachineMode::Int64,
+                    src: Operand::Reg(*src),
+                    dest: Operand::Reg(*dest),
+                }],
+            )),
+            _ => None,
+        }
+    }
+}
+
+/// Replaces untagging a number by a shift right and tagging it again by a shift
+/// left with clearing the tag bit, where the flags are not used.
+pub struct UntagTag;
+
+impl Rule for UntagTag {
+    fn name(&self) -> &'static str {
+        "untag_tag"
+    }
+
+    fn rewrite(&self, insns: &[Insn]) -> Option<(usize, Vec<Inst>)> {
+        let (untag, tag) = match insns {
+            [untag, tag, ..] => (untag.inst.as_ref()?, tag.inst.as_ref()?),
+            _ => return None,
+        };
+
+        match (untag, tag) {
+            (
+                Inst::Shift {
+                    mode,
+                    op: ShiftOp::Shr,
+                    count: Operand::Imm(1),
+                    dest,
+                }
+                | Inst::Shift {
+                    mode,
+                    op: ShiftOp::Sar,
+                    count: Operand::Imm(1),
+                    dest,
+                },
+                Inst::Shift {
+                    mode: tag_mode,
+                    op: ShiftOp::Shl,
+                    count: Operand::Imm(1),
+                    dest: tag_dest,
+                },
+            ) if mode == tag_mode && dest == tag_dest && flags_dead(&insns[2..]) => Some((
+                2,
+                vec![Inst::Alu {
+                    mode: *mode,
+                    op: AluOp::And,
+                    src: Operand::Imm(-2),
+                    dest: *dest,
+                }],
+            )),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,353 @@
+//! Symbol information for `perf`.
+//!
+//! Two outputs can be enabled independently:
+//!
+//! * a perf map, `/tmp/perf-<pid>.map`, with a `start size name` line for every
+//!   finalized buffer. `perf report` picks it up on its own.
+//! * a jitdump file, `jit-<pid>.dump`, holding a code-load record with the raw code
+//!   of every buffer, preceded by a debug-info record if the code has source
+//!   positions. Record with `perf record -k mono` and run `perf inject --jit` on the
+//!   result to annotate jitted instructions.
+//!
+//! Names come from `Assembler::set_name`, unnamed code shows up as `jit_<address>`.
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+use byteorder::{LittleEndian, WriteBytesExt};
+
+use crate::source::{self, LineTable};
+
+const JITDUMP_MAGIC: u32 = 0x4a69_5444;
+const JITDUMP_VERSION: u32 = 1;
+const JIT_CODE_LOAD: u32 = 0;
+const JIT_CODE_DEBUG_INFO: u32 = 2;
+const JIT_CODE_CLOSE: u32 = 3;
+const EM_X86_64: u32 = 62;
+
+struct JitDump {
+    file: File,
+    path: PathBuf,
+    /// perf finds the dump through this mapping of the file
+    marker: *mut libc::c_void,
+    code_index: u64,
+}
+
+unsafe impl Send for JitDump {}
+
+struct Perf {
+    map: Option<File>,
+    dump: Option<JitDump>,
+}
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static PERF: Mutex<Perf> = Mutex::new(Perf {
+    map: None,
+    dump: None,
+});
+
+fn timestamp() -> u64 {
+    let mut ts = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut ts) };
+
+    ts.tv_sec as u64 * 1_000_000_000 + ts.tv_nsec as u64
+}
+
+fn update_enabled(perf: &Perf) {
+    ENABLED.store(perf.map.is_some() || perf.dump.is_some(), Ordering::Release);
+}
+
+/// Starts appending to `/tmp/perf-<pid>.map`.
+pub fn enable_perf_map() -> io::Result<()> {
+    enable_perf_map_at(Path::new(&format!("/tmp/perf-{}.map", std::process::id())))
+}
+
+fn enable_perf_map_at(path: &Path) -> io::Result<()> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+
+    let mut perf = PERF.lock().unwrap();
+    perf.map = Some(file);
+    update_enabled(&perf);
+    Ok(())
+}
+
+/// Creates `jit-<pid>.dump` in `dir` and starts writing code-load records to it.
+pub fn enable_jitdump(dir: &Path) -> io::Result<PathBuf> {
+    let path = dir.join(format!("jit-{}.dump", std::process::id()));
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(&path)?;
+
+    let mut header = Vec::with_capacity(40);
+    header.write_u32::<LittleEndian>(JITDUMP_MAGIC)?;
+    header.write_u32::<LittleEndian>(JITDUMP_VERSION)?;
+    header.write_u32::<LittleEndian>(40)?;
+    header.write_u32::<LittleEndian>(EM_X86_64)?;
+    header.write_u32::<LittleEndian>(0)?;
+    header.write_u32::<LittleEndian>(std::process::id())?;
+    header.write_u64::<LittleEndian>(timestamp())?;
+    header.write_u64::<LittleEndian>(0)?;
+    file.write_all(&header)?;
+
+    let marker = {
+        use std::os::unix::io::AsRawFd;
+
+        unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                crate::PAGE_SIZE,
+                libc::PROT_READ | libc::PROT_EXEC,
+                libc::MAP_PRIVATE,
+                file.as_raw_fd(),
+                0,
+            )
+        }
+    };
+
+    if marker == libc::MAP_FAILED {
+        return Err(io::Error::last_os_error());
+    }
+
+    let mut perf = PERF.lock().unwrap();
+    perf.dump = Some(JitDump {
+        file,
+        path: path.clone(),
+        marker,
+        code_index: 0,
+    });
+    update_enabled(&perf);
+    Ok(path)
+}
+
+/// Closes both outputs. The jitdump file gets a close record.
+pub fn disable() {
+    let mut perf = PERF.lock().unwrap();
+    perf.map = None;
+
+    if let Some(mut dump) = perf.dump.take() {
+        let mut record = Vec::with_capacity(16);
+        record.write_u32::<LittleEndian>(JIT_CODE_CLOSE).unwrap();
+        record.write_u32::<LittleEndian>(16).unwrap();
+        record.write_u64::<LittleEndian>(timestamp()).unwrap();
+        let _ = dump.file.write_all(&record);
+
+        unsafe { libc::munmap(dump.marker, crate::PAGE_SIZE) };
+    }
+
+    update_enabled(&perf);
+}
+
+/// Path of the jitdump file, if enabled.
+pub fn jitdump_path() -> Option<PathBuf> {
+    let perf = PERF.lock().unwrap();
+    perf.dump.as_ref().map(|dump| dump.path.clone())
+}
+
+/// Reports `size` bytes of code at `start` to the enabled outputs.
+pub(crate) fn code_loaded(name: &str, start: *const u8, size: usize, lines: &LineTable) {
+    if !ENABLED.load(Ordering::Acquire) {
+        return;
+    }
+
+    let mut perf = PERF.lock().unwrap();
+
+    if let Some(map) = &mut perf.map {
+        let _ = writeln!(map, "{:x} {:x} {}", start as usize, size, name);
+    }
+
+    if let Some(dump) = &mut perf.dump {
+        // perf attributes debug info to the code load that follows it
+        if !lines.is_empty() {
+            let _ = dump.file.write_all(&debug_info(start, lines));
+        }
+
+        let code = unsafe { std::slice::from_raw_parts(start, size) };
+        let total_size = 16 + 40 + name.len() + 1 + size;
+
+        let mut record = Vec::with_capacity(total_size);
+        record.write_u32::<LittleEndian>(JIT_CODE_LOAD).unwrap();
+        record.write_u32::<LittleEndian>(total_size as u32).unwrap();
+        record.write_u64::<LittleEndian>(timestamp()).unwrap();
+        record
+            .write_u32::<LittleEndian>(std::process::id())
+            .unwrap();
+        record
+            .write_u32::<LittleEndian>(unsafe { libc::syscall(libc::SYS_gettid) } as u32)
+            .unwrap();
+        record.write_u64::<LittleEndian>(start as u64).unwrap();
+        record.write_u64::<LittleEndian>(start as u64).unwrap();
+        record.write_u64::<LittleEndian>(size as u64).unwrap();
+        record.write_u64::<LittleEndian>(dump.code_index).unwrap();
+        record.extend_from_slice(name.as_bytes());
+        record.push(0);
+        record.extend_from_slice(code);
+
+        dump.code_index += 1;
+        let _ = dump.file.write_all(&record);
+    }
+}
+
+/// A debug-info record with one entry per row of `lines`, padded to eight bytes.
+fn debug_info(start: *const u8, lines: &LineTable) -> Vec<u8> {
+    let mut entries = Vec::new();
+
+    for &(offset, position) in lines.rows() {
+        entries
+            .write_u64::<LittleEndian>(start as u64 + offset as u64)
+            .unwrap();
+        entries.write_u32::<LittleEndian>(position.line).unwrap();
+        entries.write_u32::<LittleEndian>(0).unwrap(); // discriminator
+        entries.extend_from_slice(source::file_name(position.file).as_bytes());
+        entries.push(0);
+    }
+
+    let total_size = (16 + 16 + entries.len() + 7) & !7;
+
+    let mut record = Vec::with_capacity(total_size);
+    record
+        .write_u32::<LittleEndian>(JIT_CODE_DEBUG_INFO)
+        .unwrap();
+    record.write_u32::<LittleEndian>(total_size as u32).unwrap();
+    record.write_u64::<LittleEndian>(timestamp()).unwrap();
+    record.write_u64::<LittleEndian>(start as u64).unwrap();
+    record
+        .write_u64::<LittleEndian>(lines.rows().len() as u64)
+        .unwrap();
+    record.extend_from_slice(&entries);
+    record.resize(total_size, 0);
+    record
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::Assembler;
+    use crate::assembler_x64::*;
+    use crate::code_cache::CodeCache;
+    use crate::Memory;
+    use byteorder::{ByteOrder, LittleEndian};
+    use std::sync::Arc;
+
+    /// The outputs are global, tests enabling them take turns.
+    static SERIAL: Mutex<()> = Mutex::new(());
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("jazz-jit-{}-{}", name, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn function(cache: &Arc<CodeCache>, name: &str) -> Memory {
+        let mut asm = Assembler::new();
+        asm.set_name(name);
+        emit_nop(&mut asm);
+        emit_retq(&mut asm);
+
+        cache.emit(&asm.finalize().unwrap()).unwrap()
+    }
+
+    fn code(memory: &Memory) -> &[u8] {
+        let size = memory.end() as usize - memory.start() as usize;
+        unsafe { std::slice::from_raw_parts(memory.start(), size) }
+    }
+
+    #[test]
+    fn perf_map_lines() {
+        let _serial = SERIAL.lock().unwrap();
+        let dir = temp_dir("perf-map");
+        let path = dir.join("perf.map");
+        enable_perf_map_at(&path).unwrap();
+
+        let cache = CodeCache::new(1 << 16);
+        let first = function(&cache, "perf_map_first");
+        let second = function(&cache, "perf_map_second");
+        disable();
+
+        let map = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        // `<start> <size> <name>` in hex, without a 0x prefix
+        for memory in &[first, second] {
+            let line = map
+                .lines()
+                .find(|line| line.ends_with(memory.name().unwrap()))
+                .unwrap();
+            let fields: Vec<&str> = line.splitn(3, ' ').collect();
+
+            assert_eq!(
+                usize::from_str_radix(fields[0], 16).unwrap(),
+                memory.start() as usize
+            );
+            assert_eq!(
+                usize::from_str_radix(fields[1], 16).unwrap(),
+                code(memory).len()
+            );
+            assert_eq!(fields[2], memory.name().unwrap());
+        }
+    }
+
+    #[test]
+    fn jitdump_records() {
+        let _serial = SERIAL.lock().unwrap();
+        let dir = temp_dir("jitdump-records");
+        let path = enable_jitdump(&dir).unwrap();
+        assert_eq!(jitdump_path(), Some(path.clone()));
+
+        let cache = CodeCache::new(1 << 16);
+        let memory = function(&cache, "jitdump_record");
+        disable();
+        assert_eq!(jitdump_path(), None);
+
+        let dump = std::fs::read(&path).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let u32_at = |pos: usize| LittleEndian::read_u32(&dump[pos..]);
+        let u64_at = |pos: usize| LittleEndian::read_u64(&dump[pos..]);
+
+        assert_eq!(u32_at(0), JITDUMP_MAGIC);
+        assert_eq!(u32_at(4), JITDUMP_VERSION);
+        assert_eq!(u32_at(8), 40);
+        assert_eq!(u32_at(12), EM_X86_64);
+        assert_eq!(u32_at(20), std::process::id());
+
+        let mut records = Vec::new();
+        let mut pos = 40;
+        while pos < dump.len() {
+            let (id, size) = (u32_at(pos), u32_at(pos + 4) as usize);
+            records.push((pos, id, size));
+            pos += size;
+        }
+        assert_eq!(pos, dump.len());
+
+        // timestamps never go back, the close record comes last
+        let timestamps: Vec<u64> = records.iter().map(|&(pos, _, _)| u64_at(pos + 8)).collect();
+        assert!(timestamps.windows(2).all(|pair| pair[0] <= pair[1]));
+        assert_eq!(
+            records.last().map(|&(_, id, size)| (id, size)),
+            Some((JIT_CODE_CLOSE, 16))
+        );
+
+        let name = b"jitdump_record\0";
+        let &(pos, _, size) = records
+            .iter()
+            .find(|&&(pos, id, _)| id == JIT_CODE_LOAD && dump[pos + 56..].starts_with(name))
+            .unwrap();
+        let code = code(&memory);
+
+        assert_eq!(u32_at(pos + 16), std::process::id());
+        assert_eq!(u64_at(pos + 24), memory.start() as u64);
+        assert_eq!(u64_at(pos + 32), memory.start() as u64);
+        assert_eq!(u64_at(pos + 40), code.len() as u64);
+        assert_eq!(size, 56 + name.len() + code.len());
+        assert_eq!(&dump[pos + 56 + name.len()..pos + size], code);
+    }
+}
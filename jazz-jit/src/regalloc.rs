@@ -0,0 +1,593 @@
+//! Linear-scan register allocation over virtual registers.
+//!
+//! A code generator describes its code to a `Function` before emitting it: one
+//! entry per instruction with the virtual registers it reads and writes, the
+//! physical registers some of them have to be in, and the registers it destroys.
+//! `Function::allocate` assigns every virtual register a physical register or a
+//! frame slot for its whole lifetime. While emitting, the code generator takes the
+//! operands from `Allocation::location` and brings operands in and out of their
+//! fixed registers with `Allocation::emit_before` and `Allocation::emit_after`.
+//!
+//! Instruction `i` reads its operands at point `2 * i` and writes its results at
+//! point `2 * i + 1`. A virtual register lives from the first to the last point it
+//! is mentioned at, extended over the loops it is live around.
+//!
+//! Spilled operands are frame slots, so an instruction with two spilled operands
+//! would need a memory to memory form x64 does not have. The code generator moves
+//! one of them through `Registers::scratch` first, which is never assigned. Float
+//! operands have no scratch register, instructions that need one of them in a
+//! register ask for a fixed operand.
+//!
+//! Slot `i` is at `frame - 8 * (i + 1)`, so the prologue reserves `8 * slots()`
+//! bytes right below the frame pointer and pushes the callee-saved registers below
+//! that area, where they do not overlap a slot.
+
+use crate::assembler::{Assembler, Mem};
+use crate::constants_x64::*;
+use crate::inst::Operand as InstOperand;
+use crate::MachineMode;
+
+/// The register file a virtual register is allocated from.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum RegClass {
+    Gpr,
+    Float,
+}
+
+impl RegClass {
+    pub fn of(reg: Reg) -> RegClass {
+        match reg {
+            Reg::Gpr(_) => RegClass::Gpr,
+            Reg::Float(_) => RegClass::Float,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct VReg(usize);
+
+impl VReg {
+    pub fn index(self) -> usize {
+        self.0
+    }
+}
+
+/// A frame slot, `index` 8-byte slots below the frame pointer `src`, at
+/// `src - 8 * (index + 1)`.
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Hash)]
+pub struct Spill {
+    pub src: Register,
+    pub index: i32,
+}
+
+impl Spill {
+    pub fn mem(&self) -> Mem {
+        Mem::Base(self.src, -8 * (self.index + 1))
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Location {
+    Reg(Reg),
+    Spill(Spill),
+}
+
+/// A virtual register read or written by an instruction, optionally in a fixed
+/// register.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Operand {
+    pub vreg: VReg,
+    pub fixed: Option<Reg>,
+}
+
+impl Operand {
+    pub fn any(vreg: VReg) -> Operand {
+        Operand { vreg, fixed: None }
+    }
+
+    pub fn fixed(vreg: VReg, reg: Reg) -> Operand {
+        Operand {
+            vreg,
+            fixed: Some(reg),
+        }
+    }
+}
+
+impl From<VReg> for Operand {
+    fn from(vreg: VReg) -> Operand {
+        Operand::any(vreg)
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+struct InstInfo {
+    uses: Vec<Operand>,
+    defs: Vec<Operand>,
+    clobbers: Vec<Reg>,
+}
+
+/// Registers the allocator assigns, in the order it prefers them, the frame
+/// pointer spill slots are addressed from and the scratch register for
+/// instructions with two spilled operands. `frame` and `scratch` may not be in
+/// `gprs`.
+#[derive(Clone, Debug)]
+pub struct Registers {
+    pub gprs: Vec<Register>,
+    pub floats: Vec<XMMRegister>,
+    pub frame: Register,
+    pub scratch: Register,
+}
+
+impl Default for Registers {
+    /// Caller-saved registers first. RSP and RBP hold the frame and R11 is the
+    /// scratch register of the assembler.
+    fn default() -> Registers {
+        Registers {
+            gprs: vec![
+                RAX, RCX, RDX, RSI, RDI, R8, R9, R10, RBX, R12, R13, R14, R15,
+            ],
+            floats: vec![
+                XMM0, XMM1, XMM2, XMM3, XMM4, XMM5, XMM6, XMM7, XMM8, XMM9, XMM10, XMM11, XMM12,
+                XMM13, XMM14, XMM15,
+            ],
+            frame: RBP,
+            scratch: R11,
+        }
+    }
+}
+
+impl Registers {
+    fn of(&self, class: RegClass) -> Vec<Reg> {
+        match class {
+            RegClass::Gpr => self.gprs.iter().map(|&reg| Reg::Gpr(reg)).collect(),
+            RegClass::Float => self.floats.iter().map(|&reg| Reg::Float(reg)).collect(),
+        }
+    }
+}
+
+/// Integer and float argument registers of the System V calling convention.
+pub const ARG_GPRS: [Register; 6] = [RDI, RSI, RDX, RCX, R8, R9];
+pub const ARG_FLOATS: [XMMRegister; 8] = [XMM0, XMM1, XMM2, XMM3, XMM4, XMM5, XMM6, XMM7];
+
+/// Registers a System V call may change.
+pub fn caller_saved() -> Vec<Reg> {
+    let gprs = [RAX, RCX, RDX, RSI, RDI, R8, R9, R10, R11];
+    let floats = Registers::default().floats;
+
+    gprs.iter()
+        .map(|&reg| Reg::Gpr(reg))
+        .chain(floats.into_iter().map(Reg::Float))
+        .collect()
+}
+
+/// The code to allocate registers for.
+#[derive(Clone, Debug, Default)]
+pub struct Function {
+    classes: Vec<RegClass>,
+    insts: Vec<InstInfo>,
+    /// (from, to) of jumps to an earlier instruction
+    loops: Vec<(usize, usize)>,
+}
+
+impl Function {
+    pub fn new() -> Function {
+        Function::default()
+    }
+
+    pub fn vreg(&mut self, class: RegClass) -> VReg {
+        self.classes.push(class);
+        VReg(self.classes.len() - 1)
+    }
+
+    pub fn class(&self, vreg: VReg) -> RegClass {
+        self.classes[vreg.0]
+    }
+
+    /// Index of the next instruction.
+    pub fn pos(&self) -> usize {
+        self.insts.len()
+    }
+
+    /// Adds an instruction that reads `uses`, writes `defs` and destroys
+    /// `clobbers`. Returns its index.
+    pub fn inst(&mut self, uses: &[Operand], defs: &[Operand], clobbers: &[Reg]) -> usize {
+        for operand in uses.iter().chain(defs) {
+            if let Some(reg) = operand.fixed {
+                assert_eq!(RegClass::of(reg), self.class(operand.vreg));
+            }
+        }
+
+        self.insts.push(InstInfo {
+            uses: uses.to_vec(),
+            defs: defs.to_vec(),
+            clobbers: clobbers.to_vec(),
+        });
+
+        self.insts.len() - 1
+    }
+
+    /// Records a jump from instruction `from` to instruction `to`. Only jumps back
+    /// to an earlier instruction matter: everything live at the target stays live
+    /// up to the jump.
+    pub fn jump(&mut self, from: usize, to: usize) {
+        if to <= from {
+            self.loops.push((from, to));
+        }
+    }
+
+    /// Adds a call that takes `args` in the argument registers and returns
+    /// `result` in RAX or XMM0. Arguments that do not fit into the argument
+    /// registers are read from anywhere, pushing them is up to the caller.
+    pub fn call(&mut self, args: &[VReg], result: Option<VReg>) -> usize {
+        let (mut gprs, mut floats) = (ARG_GPRS.iter(), ARG_FLOATS.iter());
+        let uses: Vec<Operand> = args
+            .iter()
+            .map(|&arg| {
+                let reg = match self.class(arg) {
+                    RegClass::Gpr => gprs.next().map(|&reg| Reg::Gpr(reg)),
+                    RegClass::Float => floats.next().map(|&reg| Reg::Float(reg)),
+                };
+
+                Operand {
+                    vreg: arg,
+                    fixed: reg,
+                }
+            })
+            .collect();
+        let defs: Vec<Operand> = result
+            .map(|result| match self.class(result) {
+                RegClass::Gpr => Operand::fixed(result, Reg::Gpr(RAX)),
+                RegClass::Float => Operand::fixed(result, Reg::Float(XMM0)),
+            })
+            .into_iter()
+            .collect();
+
+        self.inst(&uses, &defs, &caller_saved())
+    }
+
+    /// Adds a signed division of `dividend` by `divisor` as CQO and IDIV: the
+    /// dividend is read from RAX, the quotient and remainder written to RAX and RDX.
+    pub fn idiv(
+        &mut self,
+        dividend: VReg,
+        divisor: VReg,
+        quotient: VReg,
+        remainder: VReg,
+    ) -> usize {
+        self.inst(
+            &[
+                Operand::fixed(dividend, Reg::Gpr(RAX)),
+                Operand::any(divisor),
+            ],
+            &[
+                Operand::fixed(quotient, Reg::Gpr(RAX)),
+                Operand::fixed(remainder, Reg::Gpr(RDX)),
+            ],
+            &[Reg::Gpr(RDX)],
+        )
+    }
+
+    /// Adds a shift of `value` by the count in CL into `dest`. `dest` is never put
+    /// into RCX, so moving `value` into it does not destroy the count.
+    pub fn shift(&mut self, value: VReg, count: VReg, dest: VReg) -> usize {
+        self.inst(
+            &[Operand::any(value), Operand::fixed(count, Reg::Gpr(RCX))],
+            &[Operand::any(dest)],
+            &[Reg::Gpr(RCX)],
+        )
+    }
+
+    /// First and last point of every virtual register.
+    fn ranges(&self) -> Vec<Option<(usize, usize)>> {
+        let mut ranges: Vec<Option<(usize, usize)>> = vec![None; self.classes.len()];
+        let mut extend = |vreg: VReg, point: usize| {
+            let range = &mut ranges[vreg.0];
+            *range = match *range {
+                Some((start, end)) => Some((start.min(point), end.max(point))),
+                None => Some((point, point)),
+            };
+        };
+
+        for (i, inst) in self.insts.iter().enumerate() {
+            for operand in &inst.uses {
+                extend(operand.vreg, 2 * i);
+            }
+            for operand in &inst.defs {
+                extend(operand.vreg, 2 * i + 1);
+            }
+        }
+
+        // a loop may be inside another one, extend until nothing changes
+        let mut changed = true;
+        while changed {
+            changed = false;
+
+            for &(from, to) in &self.loops {
+                for range in ranges.iter_mut().flatten() {
+                    let live_in = range.0 < 2 * to && range.1 >= 2 * to;
+
+                    if live_in && range.1 < 2 * from + 1 {
+                        range.1 = 2 * from + 1;
+                        changed = true;
+                    }
+                }
+            }
+        }
+
+        ranges
+    }
+
+    /// Points at which registers are reserved, with the virtual register that may
+    /// use the register there: the operand fixed to it, or none for a clobber.
+    fn reserved(&self) -> Vec<(Reg, usize, Option<VReg>)> {
+        let mut reserved = Vec::new();
+
+        for (i, inst) in self.insts.iter().enumerate() {
+            for (operands, point) in [(&inst.uses, 2 * i), (&inst.defs, 2 * i + 1)].iter() {
+                for operand in operands.iter() {
+                    if let Some(reg) = operand.fixed {
+                        reserved.push((reg, *point, Some(operand.vreg)));
+                    }
+                }
+            }
+
+            for &reg in &inst.clobbers {
+                for point in [2 * i, 2 * i + 1].iter() {
+                    let fixed = reserved
+                        .iter()
+                        .any(|&(fixed, at, _)| fixed == reg && at == *point);
+
+                    if !fixed {
+                        reserved.push((reg, *point, None));
+                    }
+                }
+            }
+        }
+
+        reserved
+    }
+
+    /// Assigns every virtual register one of `regs` or a frame slot. A virtual
+    /// register never shares a register with another one live at the same time, and
+    /// never sits in a register reserved for another operand or clobbered while it
+    /// is live.
+    pub fn allocate(&self, regs: &Registers) -> Allocation {
+        debug_assert!(!regs.gprs.contains(&regs.frame) && !regs.gprs.contains(&regs.scratch));
+        let ranges = self.ranges();
+        let reserved = self.reserved();
+
+        let free = |reg: Reg, vreg: VReg, (start, end): (usize, usize)| {
+            !reserved.iter().any(|&(fixed, point, owner)| {
+                fixed == reg && point >= start && point <= end && owner != Some(vreg)
+            })
+        };
+
+        // registers the operands of a virtual register are fixed to come first
+        let mut hints: Vec<Vec<Reg>> = vec![Vec::new(); self.classes.len()];
+        for inst in &self.insts {
+            for operand in inst.uses.iter().chain(&inst.defs) {
+                if let Some(reg) = operand.fixed {
+                    if !hints[operand.vreg.0].contains(&reg) {
+                        hints[operand.vreg.0].push(reg);
+                    }
+                }
+            }
+        }
+
+        let mut order: Vec<VReg> = (0..self.classes.len())
+            .filter(|&idx| ranges[idx].is_some())
+            .map(VReg)
+            .collect();
+        order.sort_by_key(|vreg| ranges[vreg.0].unwrap());
+
+        let mut assigned: Vec<Option<Reg>> = vec![None; self.classes.len()];
+        let mut spilled = Vec::new();
+        let mut active: Vec<VReg> = Vec::new();
+
+        for vreg in order {
+            let range = ranges[vreg.0].unwrap();
+            let class = self.class(vreg);
+            active.retain(|active| ranges[active.0].unwrap().1 >= range.0);
+
+            let allocatable = regs.of(class);
+            let candidates = hints[vreg.0]
+                .iter()
+                .filter(|reg| allocatable.contains(reg))
+                .chain(allocatable.iter());
+            let taken = |reg: Reg, active: &[VReg]| {
+                active.iter().any(|other| assigned[other.0] == Some(reg))
+            };
+
+            let reg = candidates
+                .copied()
+                .find(|&reg| !taken(reg, &active) && free(reg, vreg, range));
+
+            if let Some(reg) = reg {
+                assigned[vreg.0] = Some(reg);
+                active.push(vreg);
+                continue;
+            }
+
+            // spill whichever lives longest, this one or an active one whose register
+            // it could take
+            let victim = active
+                .iter()
+                .copied()
+                .filter(|other| {
+                    let reg = assigned[other.0].unwrap();
+                    RegClass::of(reg) == class
+                        && ranges[other.0].unwrap().1 > range.1
+                        && free(reg, vreg, range)
+                })
+                .max_by_key(|other| ranges[other.0].unwrap().1);
+
+            match victim {
+                Some(victim) => {
+                    assigned[vreg.0] = assigned[victim.0].take();
+                    active.retain(|&other| other != victim);
+                    active.push(vreg);
+                    spilled.push(victim);
+                }
+                None => spilled.push(vreg),
+            }
+        }
+
+        // spilled registers share slots if their lifetimes do not overlap
+        spilled.sort_by_key(|vreg| ranges[vreg.0].unwrap());
+        let mut slot_ends: Vec<usize> = Vec::new();
+        let mut slots = vec![None; self.classes.len()];
+        for vreg in spilled {
+            let (start, end) = ranges[vreg.0].unwrap();
+            let index = match slot_ends.iter().position(|&slot_end| slot_end < start) {
+                Some(index) => index,
+                None => {
+                    slot_ends.push(0);
+                    slot_ends.len() - 1
+                }
+            };
+
+            slot_ends[index] = end;
+            slots[vreg.0] = Some(Spill {
+                src: regs.frame,
+                index: index as i32,
+            });
+        }
+
+        let locations = (0..self.classes.len())
+            .map(|idx| match (assigned[idx], slots[idx]) {
+                (Some(reg), _) => Some(Location::Reg(reg)),
+                (None, Some(slot)) => Some(Location::Spill(slot)),
+                (None, None) => None,
+            })
+            .collect();
+
+        Allocation {
+            locations,
+            insts: self.insts.clone(),
+            slots: slot_ends.len(),
+        }
+    }
+}
+
+/// Where the virtual registers of a `Function` live.
+#[derive(Clone, Debug)]
+pub struct Allocation {
+    locations: Vec<Option<Location>>,
+    insts: Vec<InstInfo>,
+    slots: usize,
+}
+
+/// A move between a location and a fixed register.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Move {
+    pub src: Location,
+    pub dest: Location,
+}
+
+impl Allocation {
+    /// The location of `vreg`, which must be mentioned by an instruction.
+    pub fn location(&self, vreg: VReg) -> Location {
+        self.locations[vreg.0].expect("virtual register is never used")
+    }
+
+    /// The location of `vreg` as an operand of an `Inst`.
+    pub fn operand(&self, vreg: VReg) -> InstOperand {
+        match self.location(vreg) {
+            Location::Reg(Reg::Gpr(reg)) => InstOperand::Reg(reg),
+            Location::Reg(Reg::Float(reg)) => InstOperand::Xmm(reg),
+            Location::Spill(slot) => InstOperand::Mem(slot.mem()),
+        }
+    }
+
+    /// Number of frame slots used for spilled virtual registers. The frame needs
+    /// `8 * slots()` bytes below the frame pointer, with the callee-saved registers
+    /// pushed below them.
+    pub fn slots(&self) -> usize {
+        self.slots
+    }
+
+    /// Callee-saved registers that were assigned and have to be preserved.
+    pub fn callee_saved(&self) -> Vec<Register> {
+        let mut saved: Vec<Register> = self
+            .locations
+            .iter()
+            .filter_map(|location| match location {
+                Some(Location::Reg(Reg::Gpr(reg))) => Some(*reg),
+                _ => None,
+            })
+            .filter(|reg| [RBX, R12, R13, R14, R15].contains(reg))
+            .collect();
+        saved.sort();
+        saved.dedup();
+        saved
+    }
+
+    /// Moves of operands into the registers instruction `inst` reads them from.
+    pub fn before(&self, inst: usize) -> Vec<Move> {
+        self.insts[inst]
+            .uses
+            .iter()
+            .filter_map(|operand| {
+                let dest = Location::Reg(operand.fixed?);
+                let src = self.location(operand.vreg);
+                if src != dest {
+                    Some(Move { src, dest })
+                } else {
+                    None
+                }
+            })
+            .collect()
+    }
+
+    /// Moves of results out of the registers instruction `inst` writes them to.
+    pub fn after(&self, inst: usize) -> Vec<Move> {
+        self.insts[inst]
+            .defs
+            .iter()
+            .filter_map(|operand| {
+                let src = Location::Reg(operand.fixed?);
+                let dest = self.location(operand.vreg);
+                if src != dest {
+                    Some(Move { src, dest })
+                } else {
+                    None
+                }
+            })
+            .collect()
+    }
+
+    /// Emits `before(inst)`. Other operands are never in the registers moved to,
+    /// so the moves can be done in any order.
+    pub fn emit_before(&self, asm: &mut Assembler, inst: usize) {
+        for mv in self.before(inst) {
+            emit_move(asm, mv);
+        }
+    }
+
+    pub fn emit_after(&self, asm: &mut Assembler, inst: usize) {
+        for mv in self.after(inst) {
+            emit_move(asm, mv);
+        }
+    }
+}
+
+/// Moves all 64 bits of a general purpose register or the low 64 bits of an XMM
+/// register.
+pub fn emit_move(asm: &mut Assembler, mv: Move) {
+    let mode = |reg: Reg| match reg {
+        Reg::Gpr(_) => MachineMode::Int64,
+        Reg::Float(_) => MachineMode::Float64,
+    };
+
+    match (mv.src, mv.dest) {
+        (Location::Reg(Reg::Gpr(src)), Location::Reg(Reg::Gpr(dest))) => {
+            asm.copy_reg(MachineMode::Int64, dest, src)
+        }
+        (Location::Reg(Reg::Float(src)), Location::Reg(Reg::Float(dest))) => {
+            asm.copy_freg(MachineMode::Float64, dest, src)
+        }
+        (Location::Spill(slot), Location::Reg(dest)) => asm.load_mem(mode(dest), dest, slot.mem()),
+        (Location::Reg(src), Location::Spill(slot)) => asm.store_mem(mode(src), slot.mem(), src),
+        _ => asm.invalid_operand(format!("cannot move {:?} to {:?}", mv.src, mv.dest)),
+    }
+}
@@ -0,0 +1,164 @@
+//! Relocation records.
+//!
+//! Code is position independent except for the fields recorded as relocations:
+//! absolute addresses, references to the data segment and references to symbols
+//! outside the code. `CodeCache::emit` fills them in once the address of the code
+//! is known, `CodeCache::copy` adjusts them when the code is moved and object files
+//! turn them into relocation entries.
+
+use crate::assembler::{Assembler, Label};
+use crate::symbols;
+use byteorder::{ByteOrder, LittleEndian};
+use std::fmt;
+
+/// Encoding of a relocated field.
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Hash)]
+#[repr(C)]
+pub enum RelocKind {
+    /// 64-bit absolute address, `S + A`.
+    Abs64,
+    /// 32-bit displacement from the start of the field, `S + A - P`. The addend
+    /// accounts for the distance to the end of the instruction.
+    Rel32,
+}
+
+/// What a relocated field refers to.
+#[derive(Clone, PartialEq, Eq, Debug, Hash)]
+pub enum RelocTarget {
+    /// A label of the same code.
+    Label(Label),
+    /// An entry of the data segment, identified by the displacement `DSeg` returned
+    /// for it.
+    DSeg(i32),
+    /// A fixed address outside of the code, e.g. a runtime function.
+    Address(u64),
+    /// A symbol registered in `symbols`, or defined by the linker for object files.
+    Symbol(String),
+}
+
+impl RelocTarget {
+    /// Whether the target moves together with the code.
+    pub fn is_internal(&self) -> bool {
+        match self {
+            RelocTarget::Label(_) | RelocTarget::DSeg(_) => true,
+            RelocTarget::Address(_) | RelocTarget::Symbol(_) => false,
+        }
+    }
+}
+
+/// A field of the code that depends on where the code or its target is placed.
+#[derive(Clone, PartialEq, Eq, Debug, Hash)]
+pub struct Relocation {
+    /// Position of the field in the code.
+    pub at: usize,
+    pub kind: RelocKind,
+    pub target: RelocTarget,
+    pub addend: i64,
+}
+
+/// A relocation that cannot be applied at the address the code is placed at.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum RelocError {
+    /// The symbol is not registered.
+    UnknownSymbol(String),
+    /// The label was never bound.
+    UnboundLabel(Label),
+    /// The target of a `Rel32` field is more than 2GB away.
+    OutOfRange { at: usize, disp: i64 },
+    /// A `Rel32` field refers to a fixed address, which an object file cannot
+    /// express.
+    FixedAddress { at: usize },
+}
+
+impl fmt::Display for RelocError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RelocError::UnknownSymbol(name) => write!(f, "symbol {} is not registered", name),
+            RelocError::UnboundLabel(label) => write!(f, "label {} is never bound", label),
+            RelocError::OutOfRange { at, disp } => write!(
+                f,
+                "relocation at {:#x} needs displacement {:#x}, which does not fit into 32 bits",
+                at, disp
+            ),
+            RelocError::FixedAddress { at } => write!(
+                f,
+                "relocation at {:#x} is relative to a fixed address",
+                at
+            ),
+        }
+    }
+}
+
+impl std::error::Error for RelocError {}
+
+impl Relocation {
+    /// Size of the field in bytes.
+    pub fn size(&self) -> usize {
+        match self.kind {
+            RelocKind::Abs64 => 8,
+            RelocKind::Rel32 => 4,
+        }
+    }
+
+    /// Address of the target of the relocation in `asm`, whose code starts at `start`.
+    pub fn target_address(&self, asm: &Assembler, start: u64) -> Result<u64, RelocError> {
+        match &self.target {
+            RelocTarget::Label(lbl) => match asm.labels.get(*lbl).copied().flatten() {
+                Some(pos) => Ok(start + pos as u64),
+                None => Err(RelocError::UnboundLabel(*lbl)),
+            },
+            RelocTarget::DSeg(disp) => {
+                let offset = asm.dseg_start() + (asm.dseg.size() - disp) as i64;
+                Ok(start.wrapping_add(offset as u64))
+            }
+            RelocTarget::Address(addr) => Ok(*addr),
+            RelocTarget::Symbol(name) => match symbols::address(name) {
+                Some(addr) => Ok(addr as u64),
+                None => Err(RelocError::UnknownSymbol(name.clone())),
+            },
+        }
+    }
+
+    /// Writes the field for `target` into `code`, whose first byte is at `start`.
+    pub fn write(&self, code: &mut [u8], start: u64, target: u64) -> Result<(), RelocError> {
+        let value = target.wrapping_add(self.addend as u64);
+
+        match self.kind {
+            RelocKind::Abs64 => LittleEndian::write_u64(&mut code[self.at..], value),
+            RelocKind::Rel32 => {
+                let disp = value.wrapping_sub(start + self.at as u64) as i64;
+
+                if disp != disp as i32 as i64 {
+                    return Err(RelocError::OutOfRange { at: self.at, disp });
+                }
+
+                LittleEndian::write_i32(&mut code[self.at..], disp as i32);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Adjusts the field in `code` after the code was moved by `delta` bytes.
+    pub fn moved(&self, code: &mut [u8], delta: i64) -> Result<(), RelocError> {
+        match (self.kind, self.target.is_internal()) {
+            (RelocKind::Abs64, true) => {
+                let value = LittleEndian::read_u64(&code[self.at..]);
+                LittleEndian::write_u64(&mut code[self.at..], value.wrapping_add(delta as u64));
+            }
+            (RelocKind::Rel32, false) => {
+                let disp = LittleEndian::read_i32(&code[self.at..]) as i64 - delta;
+
+                if disp != disp as i32 as i64 {
+                    return Err(RelocError::OutOfRange { at: self.at, disp });
+                }
+
+                LittleEndian::write_i32(&mut code[self.at..], disp as i32);
+            }
+            // both ends move, or neither does
+            _ => {}
+        }
+
+        Ok(())
+    }
+}
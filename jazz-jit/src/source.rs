@@ -0,0 +1,232 @@
+//! Source positions of generated code.
+//!
+//! Front ends call `Assembler::set_source_position` before the instructions of a
+//! source construct. The positions end up in a `LineTable` that maps offsets into
+//! the code back to the source, is kept by the finalized `Memory`, and is handed to
+//! debuggers as DWARF `.debug_line` and to `perf` as jitdump debug-info records.
+//!
+//! Positions refer to files by id. The names of the files are registered with
+//! `register_file`.
+
+use crate::assembler::Assembler;
+use crate::unwind::{sleb128, uleb128};
+use byteorder::{LittleEndian, WriteBytesExt};
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+#[repr(C)]
+pub struct SourcePosition {
+    pub file: u32,
+    /// 1-based, like the lines reported by debuggers.
+    pub line: u32,
+    /// 1-based, 0 if unknown.
+    pub column: u32,
+}
+
+static FILES: Mutex<BTreeMap<u32, String>> = Mutex::new(BTreeMap::new());
+
+pub fn register_file(file: u32, path: impl Into<String>) {
+    FILES.lock().unwrap().insert(file, path.into());
+}
+
+/// The path registered for `file`, or a placeholder.
+pub fn file_name(file: u32) -> String {
+    match FILES.lock().unwrap().get(&file) {
+        Some(path) => path.clone(),
+        None => format!("<file {}>", file),
+    }
+}
+
+impl Assembler {
+    /// Attributes the code emitted from here on to `line` and `column` of `file`.
+    pub fn set_source_position(&mut self, file: u32, line: u32, column: u32) {
+        let position = SourcePosition { file, line, column };
+        let pos = self.pos();
+
+        match self.source_positions.last_mut() {
+            Some(last) if last.0 == pos => last.1 = position,
+            Some(last) if last.1 == position => {}
+            _ => self.source_positions.push((pos, position)),
+        }
+    }
+}
+
+/// Source positions of code, sorted by the offset they start at. A position is valid
+/// up to the offset of the next one, the last one up to the end of the code.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct LineTable {
+    rows: Vec<(usize, SourcePosition)>,
+}
+
+impl LineTable {
+    pub fn new(rows: Vec<(usize, SourcePosition)>) -> LineTable {
+        debug_assert!(rows.windows(2).all(|rows| rows[0].0 <= rows[1].0));
+        LineTable { rows }
+    }
+
+    pub fn rows(&self) -> &[(usize, SourcePosition)] {
+        &self.rows
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rows.is_empty()
+    }
+
+    /// The position of the code at `offset`.
+    pub fn lookup(&self, offset: usize) -> Option<SourcePosition> {
+        let idx = self.rows.partition_point(|&(start, _)| start <= offset);
+
+        if idx == 0 {
+            None
+        } else {
+            Some(self.rows[idx - 1].1)
+        }
+    }
+
+    /// Files in the order of their first row.
+    fn files(&self) -> Vec<u32> {
+        let mut files = Vec::new();
+
+        for &(_, position) in &self.rows {
+            if !files.contains(&position.file) {
+                files.push(position.file);
+            }
+        }
+
+        files
+    }
+
+    /// Builds a DWARF 4 `.debug_line` section for `size` bytes of code at `start`.
+    pub fn debug_line(&self, start: u64, size: usize) -> Vec<u8> {
+        let files = self.files();
+        // minimum_instruction_length, maximum_operations_per_instruction and
+        // default_is_stmt come first
+        let mut header = vec![1, 1, 1, LINE_BASE as u8, LINE_RANGE, OPCODE_BASE];
+        header.extend_from_slice(&STANDARD_OPCODE_LENGTHS);
+        header.push(0); // no include directories
+
+        for &file in &files {
+            header.extend_from_slice(file_name(file).as_bytes());
+            header.push(0);
+            uleb128(&mut header, 0); // directory
+            uleb128(&mut header, 0); // modification time
+            uleb128(&mut header, 0); // length
+        }
+        header.push(0);
+
+        let mut program = vec![0];
+        uleb128(&mut program, 9);
+        program.push(DW_LNE_SET_ADDRESS);
+        program.write_u64::<LittleEndian>(start).unwrap();
+
+        let (mut file, mut line, mut column, mut address) = (1, 1, 0, 0);
+        for &(offset, position) in &self.rows {
+            let file_idx = files.iter().position(|&f| f == position.file).unwrap() + 1;
+
+            if file_idx != file {
+                program.push(DW_LNS_SET_FILE);
+                uleb128(&mut program, file_idx as u64);
+                file = file_idx;
+            }
+
+            if position.column != column {
+                program.push(DW_LNS_SET_COLUMN);
+                uleb128(&mut program, position.column as u64);
+                column = position.column;
+            }
+
+            if position.line != line {
+                program.push(DW_LNS_ADVANCE_LINE);
+                sleb128(&mut program, position.line as i64 - line as i64);
+                line = position.line;
+            }
+
+            if offset != address {
+                program.push(DW_LNS_ADVANCE_PC);
+                uleb128(&mut program, (offset - address) as u64);
+                address = offset;
+            }
+
+            program.push(DW_LNS_COPY);
+        }
+
+        program.push(DW_LNS_ADVANCE_PC);
+        uleb128(&mut program, (size - address.min(size)) as u64);
+        program.push(0);
+        uleb128(&mut program, 1);
+        program.push(DW_LNE_END_SEQUENCE);
+
+        let mut out = Vec::new();
+        let unit_length = 2 + 4 + header.len() + program.len();
+        out.write_u32::<LittleEndian>(unit_length as u32).unwrap();
+        out.write_u16::<LittleEndian>(DWARF_VERSION).unwrap();
+        out.write_u32::<LittleEndian>(header.len() as u32).unwrap();
+        out.extend_from_slice(&header);
+        out.extend_from_slice(&program);
+        out
+    }
+}
+
+const DWARF_VERSION: u16 = 4;
+
+const LINE_BASE: i8 = -5;
+const LINE_RANGE: u8 = 14;
+const OPCODE_BASE: u8 = 13;
+const STANDARD_OPCODE_LENGTHS: [u8; 12] = [0, 1, 1, 1, 1, 0, 0, 0, 1, 0, 0, 1];
+
+const DW_LNS_COPY: u8 = 0x01;
+const DW_LNS_ADVANCE_PC: u8 = 0x02;
+const DW_LNS_ADVANCE_LINE: u8 = 0x03;
+const DW_LNS_SET_FILE: u8 = 0x04;
+const DW_LNS_SET_COLUMN: u8 = 0x05;
+const DW_LNE_END_SEQUENCE: u8 = 0x01;
+const DW_LNE_SET_ADDRESS: u8 = 0x02;
+
+const DW_TAG_COMPILE_UNIT: u64 = 0x11;
+const DW_AT_NAME: u64 = 0x03;
+const DW_AT_STMT_LIST: u64 = 0x10;
+const DW_AT_LOW_PC: u64 = 0x11;
+const DW_AT_HIGH_PC: u64 = 0x12;
+const DW_FORM_ADDR: u64 = 0x01;
+const DW_FORM_DATA8: u64 = 0x07;
+const DW_FORM_STRING: u64 = 0x08;
+const DW_FORM_SEC_OFFSET: u64 = 0x17;
+
+/// Builds the `.debug_abbrev` and `.debug_info` sections of a compile unit named
+/// `name` that covers `size` bytes of code at `start` and whose lines are the
+/// `.debug_line` section at offset 0.
+pub fn compile_unit(name: &str, start: u64, size: usize) -> (Vec<u8>, Vec<u8>) {
+    let mut abbrev = Vec::new();
+    uleb128(&mut abbrev, 1);
+    uleb128(&mut abbrev, DW_TAG_COMPILE_UNIT);
+    abbrev.push(0); // no children
+
+    for &(attribute, form) in &[
+        (DW_AT_NAME, DW_FORM_STRING),
+        (DW_AT_STMT_LIST, DW_FORM_SEC_OFFSET),
+        (DW_AT_LOW_PC, DW_FORM_ADDR),
+        (DW_AT_HIGH_PC, DW_FORM_DATA8),
+    ] {
+        uleb128(&mut abbrev, attribute);
+        uleb128(&mut abbrev, form);
+    }
+    abbrev.extend_from_slice(&[0, 0, 0]);
+
+    let mut unit = Vec::new();
+    unit.write_u16::<LittleEndian>(DWARF_VERSION).unwrap();
+    unit.write_u32::<LittleEndian>(0).unwrap(); // abbreviations at offset 0
+    unit.push(8); // address size
+    uleb128(&mut unit, 1);
+    unit.extend_from_slice(name.as_bytes());
+    unit.push(0);
+    unit.write_u32::<LittleEndian>(0).unwrap();
+    unit.write_u64::<LittleEndian>(start).unwrap();
+    unit.write_u64::<LittleEndian>(size as u64).unwrap();
+
+    let mut info = Vec::new();
+    info.write_u32::<LittleEndian>(unit.len() as u32).unwrap();
+    info.extend_from_slice(&unit);
+
+    (abbrev, info)
+}
@@ -0,0 +1,33 @@
+//! Names of known code addresses.
+//!
+//! Named code emitted through a `CodeCache` is registered here while it is alive.
+//! Runtime functions called from generated code can be registered by hand. The
+//! disassembler uses the table to resolve call targets.
+
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+static SYMBOLS: Mutex<BTreeMap<usize, String>> = Mutex::new(BTreeMap::new());
+
+pub fn register(addr: *const u8, name: impl Into<String>) {
+    SYMBOLS.lock().unwrap().insert(addr as usize, name.into());
+}
+
+pub fn unregister(addr: *const u8) {
+    SYMBOLS.lock().unwrap().remove(&(addr as usize));
+}
+
+/// The name registered for exactly `addr`.
+pub fn lookup(addr: *const u8) -> Option<String> {
+    SYMBOLS.lock().unwrap().get(&(addr as usize)).cloned()
+}
+
+/// The address registered for `name`.
+pub fn address(name: &str) -> Option<*const u8> {
+    SYMBOLS
+        .lock()
+        .unwrap()
+        .iter()
+        .find(|&(_, symbol)| symbol == name)
+        .map(|(&addr, _)| addr as *const u8)
+}
@@ -0,0 +1,361 @@
+//! Unwind information for generated code.
+//!
+//! The assembler follows the canonical frame address (CFA) through pushes, pops,
+//! stack pointer adjustments and frame pointer moves and records DWARF call frame
+//! instructions for it. Code that changes the stack in other ways records its own
+//! with `Assembler::cfi`. `CodeCache` turns the instructions into an `.eh_frame`
+//! section and registers it with the unwinder, so that panics and backtraces can
+//! walk through generated frames.
+
+use crate::align;
+use crate::assembler::Assembler;
+use crate::constants_x64::*;
+use byteorder::{LittleEndian, WriteBytesExt};
+
+/// A call frame instruction, taking effect behind the instruction it follows.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[repr(C)]
+pub enum CfiOp {
+    /// The CFA is `reg + offset`.
+    DefCfa(Register, i32),
+    DefCfaRegister(Register),
+    DefCfaOffset(i32),
+    /// `reg` is saved at `CFA + offset`.
+    Offset(Register, i32),
+    /// `reg` holds the value of the caller again.
+    Restore(Register),
+    /// Pushes the current rules, e.g. before an epilogue that is not at the end.
+    RememberState,
+    /// Pops the rules pushed by `RememberState`.
+    RestoreState,
+}
+
+/// Where the CFA is while the code is being emitted.
+#[derive(Clone, Debug)]
+pub struct Frame {
+    /// Distance from the CFA down to RSP.
+    depth: i32,
+    /// RSP or RBP.
+    cfa_reg: Register,
+    /// `depth` when RBP was set up as frame pointer.
+    fp_depth: i32,
+    /// Saved registers and the `depth` of their slots.
+    saved: Vec<(Register, i32)>,
+    /// Frames pushed by `RememberState`.
+    remembered: Vec<Frame>,
+}
+
+impl Frame {
+    /// The frame on entry: the CFA is behind the return address.
+    pub fn new() -> Frame {
+        Frame {
+            depth: 8,
+            cfa_reg: RSP,
+            fp_depth: 0,
+            saved: Vec::new(),
+            remembered: Vec::new(),
+        }
+    }
+
+    fn set_cfa_offset(&mut self, offset: i32) {
+        if self.cfa_reg == RSP {
+            self.depth = offset;
+        } else {
+            self.fp_depth = offset;
+        }
+    }
+}
+
+impl Default for Frame {
+    fn default() -> Frame {
+        Frame::new()
+    }
+}
+
+impl Assembler {
+    /// Records `op` at the current position and updates the tracked frame.
+    pub fn cfi(&mut self, op: CfiOp) {
+        let frame = &mut self.frame;
+
+        match op {
+            CfiOp::DefCfa(reg, offset) => {
+                frame.cfa_reg = reg;
+                frame.set_cfa_offset(offset);
+            }
+            CfiOp::DefCfaRegister(reg) => frame.cfa_reg = reg,
+            CfiOp::DefCfaOffset(offset) => frame.set_cfa_offset(offset),
+            CfiOp::Offset(reg, offset) => {
+                frame.saved.retain(|&(saved, _)| saved != reg);
+                frame.saved.push((reg, -offset));
+            }
+            CfiOp::Restore(reg) => frame.saved.retain(|&(saved, _)| saved != reg),
+            CfiOp::RememberState => {
+                let state = Frame {
+                    remembered: Vec::new(),
+                    ..frame.clone()
+                };
+                frame.remembered.push(state);
+            }
+            CfiOp::RestoreState => {
+                if let Some(state) = frame.remembered.pop() {
+                    let remembered = ::std::mem::take(&mut frame.remembered);
+                    *frame = Frame {
+                        remembered,
+                        ..state
+                    };
+                }
+            }
+        }
+
+        let pos = self.pos();
+        self.cfi_ops.push((pos, op));
+    }
+
+    /// Moves RSP by `delta` bytes, positive when the stack grows.
+    pub(crate) fn cfi_adjust_sp(&mut self, delta: i32) {
+        self.frame.depth = self.frame.depth.wrapping_add(delta);
+
+        if self.frame.cfa_reg == RSP {
+            let depth = self.frame.depth;
+            self.cfi_ops.push((self.pos(), CfiOp::DefCfaOffset(depth)));
+        }
+    }
+
+    pub(crate) fn cfi_push(&mut self, reg: Register) {
+        self.cfi_adjust_sp(8);
+
+        // only the first push saves the value of the caller
+        if reg != RSP && !self.frame.saved.iter().any(|&(saved, _)| saved == reg) {
+            let depth = self.frame.depth;
+            self.frame.saved.push((reg, depth));
+            self.cfi_ops.push((self.pos(), CfiOp::Offset(reg, -depth)));
+        }
+    }
+
+    pub(crate) fn cfi_pop(&mut self, reg: Register) {
+        let depth = self.frame.depth;
+
+        if self.frame.saved.contains(&(reg, depth)) {
+            self.frame.saved.retain(|&(saved, _)| saved != reg);
+            self.cfi_ops.push((self.pos(), CfiOp::Restore(reg)));
+        }
+
+        self.cfi_adjust_sp(-8);
+    }
+
+    /// Follows the frame pointer being set up from RSP and RSP being reset from it.
+    pub(crate) fn cfi_mov(&mut self, src: Register, dest: Register) {
+        let frame = &mut self.frame;
+
+        if src == RSP && dest == RBP && frame.cfa_reg == RSP {
+            frame.cfa_reg = RBP;
+            frame.fp_depth = frame.depth;
+            self.cfi_ops.push((self.pos(), CfiOp::DefCfaRegister(RBP)));
+        } else if src == RBP && dest == RSP && frame.cfa_reg == RBP {
+            frame.cfa_reg = RSP;
+            frame.depth = frame.fp_depth;
+            self.cfi_ops.push((self.pos(), CfiOp::DefCfaRegister(RSP)));
+        }
+    }
+
+    /// Whether the CFA is relative to the frame pointer, so that RSP may be moved in
+    /// ways the frame does not follow.
+    pub(crate) fn cfi_frame_pointer(&self) -> bool {
+        self.frame.cfa_reg == RBP
+    }
+}
+
+/// DWARF number of `reg`.
+fn dwarf_reg(reg: Register) -> u8 {
+    match reg {
+        RAX => 0,
+        RDX => 1,
+        RCX => 2,
+        RBX => 3,
+        RSI => 4,
+        RDI => 5,
+        RBP => 6,
+        RSP => 7,
+        reg => reg as u8,
+    }
+}
+
+/// Return address column.
+const RA: u8 = 16;
+const DATA_ALIGN: i32 = -8;
+
+const DW_CFA_ADVANCE_LOC: u8 = 0x40;
+const DW_CFA_OFFSET: u8 = 0x80;
+const DW_CFA_RESTORE: u8 = 0xc0;
+const DW_CFA_NOP: u8 = 0x00;
+const DW_CFA_ADVANCE_LOC1: u8 = 0x02;
+const DW_CFA_ADVANCE_LOC2: u8 = 0x03;
+const DW_CFA_ADVANCE_LOC4: u8 = 0x04;
+const DW_CFA_REMEMBER_STATE: u8 = 0x0a;
+const DW_CFA_RESTORE_STATE: u8 = 0x0b;
+const DW_CFA_DEF_CFA: u8 = 0x0c;
+const DW_CFA_DEF_CFA_REGISTER: u8 = 0x0d;
+const DW_CFA_DEF_CFA_OFFSET: u8 = 0x0e;
+
+pub(crate) fn uleb128(out: &mut Vec<u8>, mut value: u64) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+
+        if value == 0 {
+            out.push(byte);
+            return;
+        }
+
+        out.push(byte | 0x80);
+    }
+}
+
+pub(crate) fn sleb128(out: &mut Vec<u8>, mut value: i64) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+
+        if (value == 0 && byte & 0x40 == 0) || (value == -1 && byte & 0x40 != 0) {
+            out.push(byte);
+            return;
+        }
+
+        out.push(byte | 0x80);
+    }
+}
+
+fn instruction(out: &mut Vec<u8>, op: CfiOp) {
+    match op {
+        CfiOp::DefCfa(reg, offset) => {
+            out.push(DW_CFA_DEF_CFA);
+            uleb128(out, dwarf_reg(reg) as u64);
+            uleb128(out, offset as u64);
+        }
+        CfiOp::DefCfaRegister(reg) => {
+            out.push(DW_CFA_DEF_CFA_REGISTER);
+            uleb128(out, dwarf_reg(reg) as u64);
+        }
+        CfiOp::DefCfaOffset(offset) => {
+            out.push(DW_CFA_DEF_CFA_OFFSET);
+            uleb128(out, offset as u64);
+        }
+        CfiOp::Offset(reg, offset) => {
+            out.push(DW_CFA_OFFSET | dwarf_reg(reg));
+            uleb128(out, (offset / DATA_ALIGN) as u64);
+        }
+        CfiOp::Restore(reg) => out.push(DW_CFA_RESTORE | dwarf_reg(reg)),
+        CfiOp::RememberState => out.push(DW_CFA_REMEMBER_STATE),
+        CfiOp::RestoreState => out.push(DW_CFA_RESTORE_STATE),
+    }
+}
+
+/// Appends the length of the entry starting at `start` and pads it with no-ops to a
+/// multiple of eight bytes.
+fn finish_entry(out: &mut Vec<u8>, start: usize) {
+    let len = start + align((out.len() - start) as i32, 8) as usize;
+    out.resize(len, DW_CFA_NOP);
+
+    let len = (out.len() - start - 4) as u32;
+    out[start..start + 4].copy_from_slice(&len.to_le_bytes());
+}
+
+/// Builds an `.eh_frame` section with one CIE and one FDE for `len` bytes of code at
+/// `start`, terminated by a zero length.
+pub fn eh_frame(start: u64, len: usize, ops: &[(usize, CfiOp)]) -> Vec<u8> {
+    let mut out = Vec::new();
+
+    // CIE, pointers in FDEs are absolute
+    out.extend_from_slice(&[0; 4]);
+    out.write_u32::<LittleEndian>(0).unwrap();
+    out.push(1);
+    out.extend_from_slice(b"zR\0");
+    uleb128(&mut out, 1);
+    sleb128(&mut out, DATA_ALIGN as i64);
+    out.push(RA);
+    uleb128(&mut out, 1);
+    out.push(0x00);
+    instruction(&mut out, CfiOp::DefCfa(RSP, 8));
+    out.push(DW_CFA_OFFSET | RA);
+    uleb128(&mut out, 1);
+    finish_entry(&mut out, 0);
+
+    // FDE
+    let fde = out.len();
+    out.extend_from_slice(&[0; 4]);
+    out.write_u32::<LittleEndian>((fde + 4) as u32).unwrap();
+    out.write_u64::<LittleEndian>(start).unwrap();
+    out.write_u64::<LittleEndian>(len as u64).unwrap();
+    uleb128(&mut out, 0);
+
+    let mut loc = 0;
+    for &(pos, op) in ops {
+        let delta = pos - loc;
+
+        if delta > 0 {
+            if delta < 0x40 {
+                out.push(DW_CFA_ADVANCE_LOC | delta as u8);
+            } else if delta <= 0xff {
+                out.push(DW_CFA_ADVANCE_LOC1);
+                out.push(delta as u8);
+            } else if delta <= 0xffff {
+                out.push(DW_CFA_ADVANCE_LOC2);
+                out.write_u16::<LittleEndian>(delta as u16).unwrap();
+            } else {
+                out.push(DW_CFA_ADVANCE_LOC4);
+                out.write_u32::<LittleEndian>(delta as u32).unwrap();
+            }
+
+            loc = pos;
+        }
+
+        instruction(&mut out, op);
+    }
+    finish_entry(&mut out, fde);
+
+    out.write_u32::<LittleEndian>(0).unwrap();
+    out
+}
+
+#[cfg(all(target_os = "linux", target_env = "gnu"))]
+extern "C" {
+    fn __register_frame(begin: *const u8);
+    fn __deregister_frame(begin: *const u8);
+}
+
+/// Unwind information registered with the unwinder, deregistered on drop.
+pub struct Registration {
+    ops: Vec<(usize, CfiOp)>,
+    eh_frame: Box<[u8]>,
+}
+
+impl Registration {
+    /// Registers the unwind information of `len` bytes of code at `start`.
+    pub fn new(start: *const u8, len: usize, ops: Vec<(usize, CfiOp)>) -> Registration {
+        let eh_frame = eh_frame(start as u64, len, &ops).into_boxed_slice();
+
+        #[cfg(all(target_os = "linux", target_env = "gnu"))]
+        unsafe {
+            __register_frame(eh_frame.as_ptr());
+        }
+
+        Registration { ops, eh_frame }
+    }
+
+    pub fn ops(&self) -> &[(usize, CfiOp)] {
+        &self.ops
+    }
+
+    pub fn eh_frame(&self) -> &[u8] {
+        &self.eh_frame
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        #[cfg(all(target_os = "linux", target_env = "gnu"))]
+        unsafe {
+            __deregister_frame(self.eh_frame.as_ptr());
+        }
+    }
+}
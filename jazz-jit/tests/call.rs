@@ -0,0 +1,241 @@
+//! Calls into native functions, checked by running them.
+
+mod common;
+
+use common::*;
+use jazz_jit::assembler::Mem;
+use jazz_jit::assembler_x64::*;
+use jazz_jit::call::{parallel_move, Arg, CallBuilder};
+use jazz_jit::code_cache::CodeCache;
+use jazz_jit::reloc::RelocTarget;
+use jazz_jit::{JitFunction, MachineMode};
+
+#[test]
+fn parallel_moves() {
+    let mut c = Checker::new();
+
+    c.check("mov r10, rsi; mov rsi, rdi; mov rdi, r10", |asm| {
+        parallel_move(
+            asm,
+            vec![
+                (Reg::Gpr(RSI), Reg::Gpr(RDI)),
+                (Reg::Gpr(RDI), Reg::Gpr(RSI)),
+            ],
+        )
+    });
+    // the chain hanging off the cycle goes first
+    c.check(
+        "mov r8, rdx; mov r10, rsi; mov rsi, rdx; mov rdx, rdi; mov rdi, r10",
+        |asm| {
+            parallel_move(
+                asm,
+                vec![
+                    (Reg::Gpr(RSI), Reg::Gpr(RDI)),
+                    (Reg::Gpr(RDX), Reg::Gpr(RSI)),
+                    (Reg::Gpr(RDI), Reg::Gpr(RDX)),
+                    (Reg::Gpr(RDX), Reg::Gpr(R8)),
+                ],
+            )
+        },
+    );
+    c.check(
+        "movsd xmm15, xmm1; movsd xmm1, xmm0; movsd xmm0, xmm15",
+        |asm| {
+            parallel_move(
+                asm,
+                vec![
+                    (Reg::Float(XMM1), Reg::Float(XMM0)),
+                    (Reg::Float(XMM0), Reg::Float(XMM1)),
+                    (Reg::Float(XMM2), Reg::Float(XMM2)),
+                ],
+            )
+        },
+    );
+    c.check(
+        "mov r10, rsi; mov rsi, rdi; mov rdi, r10; movabs r11, 0; call r11",
+        |asm| {
+            CallBuilder::new()
+                .arg(RSI)
+                .arg(RDI)
+                .stack_depth(0)
+                .call(asm, RelocTarget::Address(0))
+        },
+    );
+
+    c.rejects("R11 argument", |asm| {
+        CallBuilder::new()
+            .arg(R11)
+            .call(asm, RelocTarget::Address(0))
+    });
+    c.rejects("RSP-relative argument without stack depth", |asm| {
+        CallBuilder::new()
+            .arg(Arg::Mem(Mem::Base(RSP, 8)))
+            .call(asm, RelocTarget::Address(0))
+    });
+    c.rejects("no stack depth and no frame pointer", |asm| {
+        CallBuilder::new().call(asm, RelocTarget::Address(0))
+    });
+
+    c.finish();
+}
+
+extern "C" fn ints(a: i64, b: i64, c: i64, d: i64, e: i64, f: i64, g: i64, h: i64) -> i64 {
+    [a, b, c, d, e, f, g, h]
+        .iter()
+        .fold(0i64, |acc, &x| acc.wrapping_mul(31).wrapping_add(x))
+}
+
+/// `fn(a, b, c, p) -> i64` calling `ints(c, a, b, p[0], -7, a, p, p[1]) + 1000`, with
+/// the 1000 kept in R9 over the call.
+fn call_ints(asm: &mut Assembler) {
+    emit_pushq_reg(asm, RBP);
+    emit_mov_reg_reg(asm, 1, RSP, RBP);
+    emit_subq_imm_reg(asm, 16, RSP);
+    asm.store_mem(MachineMode::Int64, Mem::Local(-8), Reg::Gpr(RDI));
+    asm.load_int_const(MachineMode::Int64, R9, 1000);
+
+    CallBuilder::new()
+        .arg(RDX)
+        .arg(RDI)
+        .arg(RSI)
+        .arg(Arg::Mem(Mem::Base(RCX, 0)))
+        .arg(-7)
+        .arg(Arg::Mem(Mem::Base(RSP, 8)))
+        .arg(RCX)
+        .arg(Arg::Mem(Mem::Base(RCX, 8)))
+        .live(Reg::Gpr(R9))
+        .result(Reg::Gpr(RAX))
+        .stack_depth(0)
+        .call(asm, RelocTarget::Address(ints as *const () as u64));
+
+    emit_add_reg_reg(asm, 1, R9, RAX);
+    emit_mov_reg_reg(asm, 1, RBP, RSP);
+    emit_popq_reg(asm, RBP);
+    emit_retq(asm);
+}
+
+fn floats(x: [f64; 10], n: i64) -> f64 {
+    x.iter().fold(n as f64, |acc, &x| acc * 3.0 + x)
+}
+
+extern "C" fn floats_abi(
+    x0: f64,
+    x1: f64,
+    x2: f64,
+    x3: f64,
+    x4: f64,
+    x5: f64,
+    x6: f64,
+    x7: f64,
+    x8: f64,
+    x9: f64,
+    n: i64,
+) -> f64 {
+    floats([x0, x1, x2, x3, x4, x5, x6, x7, x8, x9], n)
+}
+
+/// `fn(x, y, n) -> f64` calling `floats(y, x, 0.5, 1.5, y, x, 2, 3, x, 4.5, n) + 2`,
+/// with RSP aligned at runtime and the 2 kept in XMM8 over the call.
+fn call_floats(asm: &mut Assembler) {
+    emit_pushq_reg(asm, RBP);
+    emit_mov_reg_reg(asm, 1, RSP, RBP);
+    emit_pushq_reg(asm, RBX);
+    asm.load_float_const(MachineMode::Float64, XMM8, 2.0);
+    let four_and_a_half = asm.dseg.add_double(4.5);
+
+    CallBuilder::new()
+        .arg(XMM1)
+        .arg(XMM0)
+        .arg(0.5)
+        .arg(1.5)
+        .arg(XMM1)
+        .arg(XMM0)
+        .arg(2.0)
+        .arg(3.0)
+        .arg(XMM0)
+        .arg(Arg::FloatMem(Mem::RipRelative(
+            jazz_jit::assembler::RipTarget::DSeg(four_and_a_half),
+        )))
+        .arg(RDI)
+        .live(Reg::Float(XMM8))
+        .result(Reg::Float(XMM3))
+        .call(asm, RelocTarget::Address(floats_abi as *const () as u64));
+
+    asm.float_add(MachineMode::Float64, XMM0, XMM3, XMM8);
+    emit_popq_reg(asm, RBX);
+    emit_mov_reg_reg(asm, 1, RBP, RSP);
+    emit_popq_reg(asm, RBP);
+    emit_retq(asm);
+}
+
+#[test]
+fn calls_run() {
+    let cache = CodeCache::new(1 << 20);
+
+    let mut asm = assembler();
+    call_ints(&mut asm);
+    let fun: JitFunction<extern "C" fn(i64, i64, i64, *const i64) -> i64> =
+        unsafe { JitFunction::new(cache.emit(&asm.finalize().unwrap()).unwrap()) };
+    let p = [11i64, -12];
+    let expected = ints(3, 1, 2, 11, -7, 1, p.as_ptr() as i64, -12);
+    assert_eq!(fun.call(1, 2, 3, p.as_ptr()), expected + 1000);
+
+    let mut asm = assembler();
+    call_floats(&mut asm);
+    let fun: JitFunction<extern "C" fn(f64, f64, i64) -> f64> =
+        unsafe { JitFunction::new(cache.emit(&asm.finalize().unwrap()).unwrap()) };
+    let (x, y) = (0.25, -1.0);
+    let expected = floats([y, x, 0.5, 1.5, y, x, 2.0, 3.0, x, 4.5], 7);
+    assert_eq!(fun.call(x, y, 7), expected + 2.0);
+}
+
+/// Returns RSP as it was before the call.
+fn stack_pointer(asm: &mut Assembler) {
+    emit_mov_reg_reg(asm, 1, RSP, RAX);
+    emit_addq_imm_reg(asm, 8, RAX);
+    emit_retq(asm);
+}
+
+#[test]
+fn stack_is_aligned() {
+    let cache = CodeCache::new(1 << 20);
+    let mut asm = assembler();
+    stack_pointer(&mut asm);
+    let target = cache.emit(&asm.finalize().unwrap()).unwrap();
+    let target = RelocTarget::Address(target.start() as u64);
+
+    // with an odd number of stack arguments and saved registers, RSP pushed by one
+    // and two slots, known and unknown behind a frame pointer
+    for &(pushes, known) in [(1, true), (2, true), (1, false), (2, false)].iter() {
+        let mut asm = assembler();
+        if !known {
+            emit_pushq_reg(&mut asm, RBP);
+            emit_mov_reg_reg(&mut asm, 1, RSP, RBP);
+        }
+        for _ in 0..pushes {
+            emit_pushq_reg(&mut asm, RBX);
+        }
+
+        let mut call = CallBuilder::new().live(Reg::Gpr(RSI)).result(Reg::Gpr(RAX));
+        for idx in 0..7 {
+            call = call.arg(idx as i64);
+        }
+        if known {
+            // entered with RSP 8 below a boundary
+            call = call.stack_depth((8 + 8 * pushes) % 16);
+        }
+        call.call(&mut asm, target.clone());
+
+        for _ in 0..pushes {
+            emit_popq_reg(&mut asm, RBX);
+        }
+        if !known {
+            emit_popq_reg(&mut asm, RBP);
+        }
+        emit_retq(&mut asm);
+
+        let fun: JitFunction<extern "C" fn() -> u64> =
+            unsafe { JitFunction::new(cache.emit(&asm.finalize().unwrap()).unwrap()) };
+        assert_eq!(fun.call() % 16, 0, "{} pushes, known: {}", pushes, known);
+    }
+}
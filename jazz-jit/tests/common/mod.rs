@@ -0,0 +1,430 @@
+//! Helpers shared by the integration tests: capstone, the encoder checker and the
+//! operand tables the expected instructions are built from.
+
+#![allow(dead_code)]
+
+pub use capstone::prelude::*;
+pub use jazz_jit::assembler::{Assembler, Mem, Segment, SegmentAddr};
+pub use jazz_jit::constants_x64::*;
+pub use jazz_jit::MachineMode;
+
+pub const GPRS: [Register; 16] = [
+    RAX, RCX, RDX, RBX, RSP, RBP, RSI, RDI, R8, R9, R10, R11, R12, R13, R14, R15,
+];
+
+pub const XMMS: [XMMRegister; 16] = [
+    XMM0, XMM1, XMM2, XMM3, XMM4, XMM5, XMM6, XMM7, XMM8, XMM9, XMM10, XMM11, XMM12, XMM13, XMM14,
+    XMM15,
+];
+
+pub const YMMS: [YMMRegister; 16] = [
+    YMM0, YMM1, YMM2, YMM3, YMM4, YMM5, YMM6, YMM7, YMM8, YMM9, YMM10, YMM11, YMM12, YMM13, YMM14,
+    YMM15,
+];
+
+pub const ZMMS: [ZMMRegister; 32] = [
+    ZMM0, ZMM1, ZMM2, ZMM3, ZMM4, ZMM5, ZMM6, ZMM7, ZMM8, ZMM9, ZMM10, ZMM11, ZMM12, ZMM13, ZMM14,
+    ZMM15, ZMM16, ZMM17, ZMM18, ZMM19, ZMM20, ZMM21, ZMM22, ZMM23, ZMM24, ZMM25, ZMM26, ZMM27,
+    ZMM28, ZMM29, ZMM30, ZMM31,
+];
+
+pub fn r64(reg: Register) -> &'static str {
+    reg.name(MachineMode::Int64)
+}
+
+pub fn r32(reg: Register) -> &'static str {
+    reg.name(MachineMode::Int32)
+}
+
+pub fn r16(reg: Register) -> &'static str {
+    reg.name(MachineMode::Int16)
+}
+
+pub fn r8(reg: Register) -> &'static str {
+    reg.name(MachineMode::Int8)
+}
+
+/// Name of `reg` as a 64-bit register if `x64` is set, as a 32-bit one otherwise.
+pub fn gpr(x64: u8, reg: Register) -> &'static str {
+    if x64 != 0 {
+        r64(reg)
+    } else {
+        r32(reg)
+    }
+}
+
+/// Name of `reg` for an operand of `mode` and the matching `ptr` size.
+pub fn sized(mode: MachineMode, reg: Register) -> (&'static str, &'static str) {
+    match mode {
+        MachineMode::Int8 => (r8(reg), "byte"),
+        MachineMode::Int16 => (r16(reg), "word"),
+        MachineMode::Int32 => (r32(reg), "dword"),
+        _ => (r64(reg), "qword"),
+    }
+}
+
+pub fn xmm(reg: XMMRegister) -> String {
+    format!("xmm{}", reg as usize)
+}
+
+pub fn ymm(reg: YMMRegister) -> String {
+    format!("ymm{}", reg as usize)
+}
+
+pub fn zmm(reg: ZMMRegister) -> String {
+    format!("zmm{}", reg as usize)
+}
+
+/// Formats an immediate or displacement the way capstone prints it.
+pub fn hex(value: i64) -> String {
+    let abs = value.unsigned_abs();
+    let digits = if abs > 9 {
+        format!("{:#x}", abs)
+    } else {
+        abs.to_string()
+    };
+
+    if value < 0 {
+        format!("-{}", digits)
+    } else {
+        digits
+    }
+}
+
+/// Formats an immediate that capstone prints as an unsigned value of `bits` bits.
+pub fn unsigned(value: i64, bits: u32) -> String {
+    let mask = if bits == 64 {
+        u64::MAX
+    } else {
+        (1u64 << bits) - 1
+    };
+    let value = value as u64 & mask;
+
+    if value > 9 {
+        format!("{:#x}", value)
+    } else {
+        value.to_string()
+    }
+}
+
+/// The address part of a memory operand, e.g. `[rax + rcx*4 + 0x10]`.
+pub fn address(base: Option<&str>, index: Option<(Register, i32)>, disp: i32) -> String {
+    let mut parts = Vec::new();
+
+    if let Some(base) = base {
+        parts.push(base.to_owned());
+    }
+
+    if let Some((index, scale)) = index {
+        if scale == 1 {
+            parts.push(r64(index).to_owned());
+        } else {
+            parts.push(format!("{}*{}", r64(index), scale));
+        }
+    }
+
+    let mut out = format!("[{}", parts.join(" + "));
+
+    if disp != 0 || parts.is_empty() {
+        let sign = if disp < 0 { "-" } else { "+" };
+        out.push_str(&format!(" {} {}", sign, hex((disp as i64).abs())));
+    }
+
+    out.push(']');
+    out
+}
+
+/// An assembler that may use every extension, so the encodings checked do not
+/// depend on the machine running the tests.
+pub fn assembler() -> Assembler {
+    Assembler::new()
+}
+
+pub const DISPS: [i32; 5] = [0, 8, -8, 0x100, -0x1000];
+pub const SCALES: [i32; 4] = [1, 2, 4, 8];
+
+/// Base registers with the displacements that select each ModRM form. RSP/R12
+/// need a SIB byte and RBP/R13 cannot be encoded without a displacement.
+pub fn base_forms() -> Vec<(Register, i32, String)> {
+    let mut forms = Vec::new();
+
+    for &base in GPRS.iter() {
+        for &disp in DISPS.iter() {
+            forms.push((base, disp, address(Some(r64(base)), None, disp)));
+        }
+    }
+
+    forms
+}
+
+/// Base, index, scale and displacement combinations: every register as base and as
+/// index, every scale and every displacement size.
+pub fn index_forms() -> Vec<(Register, Register, i32, i32, String)> {
+    let mut forms = Vec::new();
+    let mut push = |base: Register, index: Register, scale: i32, disp: i32| {
+        let addr = address(Some(r64(base)), Some((index, scale)), disp);
+        forms.push((base, index, scale, disp, addr));
+    };
+
+    for &base in GPRS.iter() {
+        push(base, RCX, 4, 0);
+        push(base, R9, 8, 8);
+    }
+
+    for &index in GPRS.iter().filter(|&&reg| reg != RSP) {
+        push(RAX, index, 2, 0);
+        push(R13, index, 1, -8);
+    }
+
+    for &scale in SCALES.iter() {
+        for &disp in DISPS.iter() {
+            push(RBP, R12, scale, disp);
+            push(R12, RBP, scale, disp);
+        }
+    }
+
+    forms
+}
+
+/// All `Mem` variants over the interesting registers and displacements.
+pub fn mem_forms() -> Vec<(Mem, String)> {
+    let mut forms = Vec::new();
+
+    for (base, disp, addr) in base_forms() {
+        forms.push((Mem::Base(base, disp), addr));
+    }
+
+    for &disp in DISPS.iter() {
+        forms.push((Mem::Local(disp), address(Some("rbp"), None, disp)));
+        forms.push((Mem::Base(RIP, disp), address(Some("rip"), None, disp)));
+    }
+
+    for (base, index, scale, disp, addr) in index_forms() {
+        forms.push((Mem::Index(base, index, scale, disp), addr));
+    }
+
+    for &index in GPRS.iter().filter(|&&reg| reg != RSP) {
+        for &scale in SCALES.iter() {
+            let addr = address(None, Some((index, scale)), 0x40);
+            forms.push((Mem::Offset(index, scale, 0x40), addr));
+        }
+    }
+
+    // capstone prints negative absolute addresses of byte operands as 0
+    for &addr in [0x40u64, 0x7fff_fff0].iter() {
+        forms.push((Mem::Absolute(addr), format!("[{:#x}]", addr)));
+    }
+
+    forms.push((
+        Mem::Segment(Segment::Fs, SegmentAddr::Absolute(0x28)),
+        "fs:[0x28]".to_owned(),
+    ));
+    forms.push((
+        Mem::Segment(Segment::Gs, SegmentAddr::Absolute(0x7fff_fff0)),
+        "gs:[0x7ffffff0]".to_owned(),
+    ));
+    forms.push((
+        Mem::Segment(Segment::Fs, SegmentAddr::Base(R13, -8)),
+        format!("fs:{}", address(Some("r13"), None, -8)),
+    ));
+    forms.push((
+        Mem::Segment(Segment::Gs, SegmentAddr::Index(RAX, R9, 8, 0x100)),
+        format!("gs:{}", address(Some("rax"), Some((R9, 8)), 0x100)),
+    ));
+    forms.push((
+        Mem::Segment(Segment::Fs, SegmentAddr::Offset(RCX, 4, 0x40)),
+        format!("fs:{}", address(None, Some((RCX, 4)), 0x40)),
+    ));
+
+    forms
+}
+
+pub const CONDS: [(jazz_jit::CondCode, &str); 12] = {
+    use jazz_jit::CondCode::*;
+
+    [
+        (Zero, "e"),
+        (NonZero, "ne"),
+        (Equal, "e"),
+        (NotEqual, "ne"),
+        (Greater, "g"),
+        (GreaterEq, "ge"),
+        (Less, "l"),
+        (LessEq, "le"),
+        (UnsignedGreater, "a"),
+        (UnsignedGreaterEq, "ae"),
+        (UnsignedLess, "b"),
+        (UnsignedLessEq, "be"),
+    ]
+};
+
+pub fn capstone() -> Capstone {
+    Capstone::new()
+        .x86()
+        .mode(arch::x86::ArchMode::Mode64)
+        .syntax(arch::x86::ArchSyntax::Intel)
+        .build()
+        .unwrap()
+}
+
+/// Decodes `code` into `mnemonic operands` lines joined with `; `.
+pub fn disassemble(cs: &Capstone, code: &[u8]) -> String {
+    let insns = cs.disasm_all(code, 0).unwrap();
+    let mut lines = Vec::new();
+    let mut decoded = 0;
+
+    for insn in insns.iter() {
+        let text = format!(
+            "{} {}",
+            insn.mnemonic().unwrap_or(""),
+            insn.op_str().unwrap_or("")
+        );
+        // capstone leaves spaces between a destination and the missing opmask
+        let text = text.split_whitespace().collect::<Vec<_>>().join(" ");
+        lines.push(text.replace(" ,", ","));
+        decoded += insn.bytes().len();
+    }
+
+    if decoded < code.len() {
+        lines.push(format!("<{} bytes not decoded>", code.len() - decoded));
+    }
+
+    lines.join("; ")
+}
+
+/// Operand size in bits of a decoded instruction, taken from its `ptr` size or its
+/// first general purpose register operand.
+fn operand_bits(insn: &str) -> Option<u32> {
+    for &(size, bits) in [("byte", 8), ("dword", 32), ("qword", 64), ("word", 16)].iter() {
+        if insn.contains(&format!("{} ptr", size)) {
+            return Some(bits);
+        }
+    }
+
+    let first = insn.split_whitespace().nth(1)?.trim_end_matches(',');
+    [
+        (MachineMode::Int8, 8),
+        (MachineMode::Int16, 16),
+        (MachineMode::Int32, 32),
+        (MachineMode::Int64, 64),
+    ]
+    .iter()
+    .find(|&&(mode, _)| GPRS.iter().any(|reg| reg.name(mode) == first))
+    .map(|&(_, bits)| bits)
+}
+
+/// Rewrites hexadecimal numbers spelled with all digits of the operand size and the
+/// top bit set as negative values, e.g. `0xffffff80` of a 32-bit operation as
+/// `-0x80`. Capstone prints sign-extended immediates signed for some instructions
+/// and unsigned for others, this makes both spellings compare equal while `0x80`
+/// and `-0x80` stay different.
+pub fn normalize(text: &str) -> String {
+    let insns: Vec<String> = text.split("; ").map(normalize_insn).collect();
+    insns.join("; ")
+}
+
+fn normalize_insn(insn: &str) -> String {
+    let bits = operand_bits(insn);
+    let mut out = String::new();
+    let mut rest = insn;
+
+    while let Some(start) = rest.find("0x") {
+        out.push_str(&rest[..start]);
+        let digits = rest[start + 2..]
+            .find(|c: char| !c.is_ascii_hexdigit())
+            .map_or(rest.len() - start - 2, |len| len);
+        let token = &rest[start + 2..start + 2 + digits];
+        let value = u64::from_str_radix(token, 16).unwrap_or(0);
+        let negative = out.ends_with('-');
+
+        let signed = match (bits, token.len()) {
+            (Some(8), 2) if value >= 0x80 => Some(value as u8 as i8 as i64),
+            (Some(16), 4) if value >= 0x8000 => Some(value as u16 as i16 as i64),
+            (Some(32), 8) if value >= 0x8000_0000 => Some(value as u32 as i32 as i64),
+            (Some(64), 16) if value >= 0x8000_0000_0000_0000 => Some(value as i64),
+            _ => None,
+        };
+
+        match signed {
+            Some(signed) if !negative => out.push_str(&hex(signed)),
+            _ => out.push_str(&rest[start..start + 2 + digits]),
+        }
+
+        rest = &rest[start + 2 + digits..];
+    }
+
+    out.push_str(rest);
+    out
+}
+
+/// Collects mismatches between encoder output and the expected instructions.
+pub struct Checker {
+    cs: Capstone,
+    checked: usize,
+    failures: Vec<String>,
+}
+
+impl Checker {
+    pub fn new() -> Checker {
+        Checker {
+            cs: capstone(),
+            checked: 0,
+            failures: Vec::new(),
+        }
+    }
+
+    /// Runs `emit` on a fresh assembler and compares the decoded result.
+    pub fn check<F: FnOnce(&mut Assembler)>(&mut self, expected: impl AsRef<str>, emit: F) {
+        let expected = expected.as_ref();
+        let mut asm = assembler();
+        emit(&mut asm);
+        self.checked += 1;
+
+        if asm.has_errors() {
+            let errors: Vec<String> = asm.diagnostics.iter().map(|d| d.to_string()).collect();
+            self.failures
+                .push(format!("{}: rejected: {}", expected, errors.join(", ")));
+            return;
+        }
+
+        let actual = disassemble(&self.cs, asm.data());
+
+        if normalize(&actual) != normalize(expected) {
+            self.failures.push(format!(
+                "{}: got {} ({:02x?})",
+                expected,
+                actual,
+                asm.data()
+            ));
+        }
+    }
+
+    /// Checks that `emit` records an error instead of emitting code.
+    pub fn rejects<F: FnOnce(&mut Assembler)>(&mut self, what: impl AsRef<str>, emit: F) {
+        let mut asm = assembler();
+        emit(&mut asm);
+        self.checked += 1;
+
+        if !asm.has_errors() {
+            self.failures.push(format!(
+                "{}: accepted as {}",
+                what.as_ref(),
+                disassemble(&self.cs, asm.data())
+            ));
+        }
+    }
+
+    pub fn finish(self) {
+        if self.failures.is_empty() {
+            return;
+        }
+
+        let shown: Vec<&str> = self.failures.iter().take(50).map(|s| s.as_str()).collect();
+        panic!(
+            "{} of {} encodings are wrong:\n{}",
+            self.failures.len(),
+            self.checked,
+            shown.join("\n")
+        );
+    }
+}
@@ -0,0 +1,944 @@
+//! VEX encoded instructions.
+
+use crate::*;
+use jazz_jit::assembler::{Assembler, Mem};
+use jazz_jit::assembler_x64::*;
+use jazz_jit::avx::*;
+
+type ThreeOp = fn(&mut Assembler, XMMRegister, XMMRegister, XMMRegister);
+type ThreeOpMem = fn(&mut Assembler, XMMRegister, XMMRegister, Mem);
+type AvxThreeOp = extern "C" fn(&mut Assembler, XMMRegister, XMMRegister, XMMRegister);
+type AvxThreeOpMem = extern "C" fn(&mut Assembler, XMMRegister, XMMRegister, Mem);
+
+const SRCS: [XMMRegister; 4] = [XMM0, XMM7, XMM8, XMM15];
+
+macro_rules! ops {
+    ($ty:ident, $mem_ty:ident; $(($name:ident, $mem:ident, $size:expr)),* $(,)*) => {
+        [$((stringify!($name), $name as $ty, $mem as $mem_ty, $size)),*]
+    };
+}
+
+fn check_three_op(
+    c: &mut Checker,
+    name: &str,
+    size: &str,
+    emit: impl Fn(&mut Assembler, XMMRegister, XMMRegister, XMMRegister),
+    emit_mem: impl Fn(&mut Assembler, XMMRegister, XMMRegister, Mem),
+) {
+    for &dst in XMMS.iter() {
+        for &src1 in SRCS.iter() {
+            for &src2 in XMMS.iter() {
+                let expected = format!("{} {}, {}, {}", name, xmm(dst), xmm(src1), xmm(src2));
+                c.check(expected, |asm| emit(asm, dst, src1, src2));
+            }
+        }
+    }
+
+    for (mem, addr) in mem_forms() {
+        for &dst in SRCS.iter() {
+            for &src1 in [XMM1, XMM14].iter() {
+                let expected = format!(
+                    "{} {}, {}, {} ptr {}",
+                    name,
+                    xmm(dst),
+                    xmm(src1),
+                    size,
+                    addr
+                );
+                c.check(expected, |asm| emit_mem(asm, dst, src1, mem));
+            }
+        }
+    }
+}
+
+/// Instructions without a second source take it as XMM0, which encodes an unused
+/// VEX.vvvv field.
+fn check_two_op(
+    c: &mut Checker,
+    name: &str,
+    size: &str,
+    emit: impl Fn(&mut Assembler, XMMRegister, XMMRegister, XMMRegister),
+    emit_mem: impl Fn(&mut Assembler, XMMRegister, XMMRegister, Mem),
+) {
+    for &dst in XMMS.iter() {
+        for &src in XMMS.iter() {
+            c.check(format!("{} {}, {}", name, xmm(dst), xmm(src)), |asm| {
+                emit(asm, dst, XMM0, src)
+            });
+        }
+    }
+
+    for (mem, addr) in mem_forms() {
+        for &dst in SRCS.iter() {
+            let expected = format!("{} {}, {} ptr {}", name, xmm(dst), size, addr);
+            c.check(expected, |asm| emit_mem(asm, dst, XMM0, mem));
+        }
+    }
+}
+
+#[test]
+fn vex_integer() {
+    let table = ops![ThreeOp, ThreeOpMem;
+        (vpunpcklbw, vpunpcklbw_mem, "xmmword"),
+        (vpunpcklwd, vpunpcklwd_mem, "xmmword"),
+        (vpunpckldq, vpunpckldq_mem, "xmmword"),
+        (vpacksswb, vpacksswb_mem, "xmmword"),
+        (vpackuswb, vpackuswb_mem, "xmmword"),
+        (vpunpckhbw, vpunpckhbw_mem, "xmmword"),
+        (vpunpckhwd, vpunpckhwd_mem, "xmmword"),
+        (vpunpckhdq, vpunpckhdq_mem, "xmmword"),
+        (vpackssdw, vpackssdw_mem, "xmmword"),
+        (vpunpcklqdq, vpunpcklqdq_mem, "xmmword"),
+        (vpunpckhqdq, vpunpckhqdq_mem, "xmmword"),
+        (vpaddb, vpaddb_mem, "xmmword"),
+        (vpaddw, vpaddw_mem, "xmmword"),
+        (vpaddd, vpaddd_mem, "xmmword"),
+        (vpaddsb, vpaddsb_mem, "xmmword"),
+        (vpaddsw, vpaddsw_mem, "xmmword"),
+        (vpaddusb, vpaddusb_mem, "xmmword"),
+        (vpaddusw, vpaddusw_mem, "xmmword"),
+        (vpcmpeqb, vpcmpeqb_mem, "xmmword"),
+        (vpcmpeqw, vpcmpeqw_mem, "xmmword"),
+        (vpcmpeqd, vpcmpeqd_mem, "xmmword"),
+        (vpcmpgtb, vpcmpgtb_mem, "xmmword"),
+        (vpcmpgtw, vpcmpgtw_mem, "xmmword"),
+        (vpcmpgtd, vpcmpgtd_mem, "xmmword"),
+        (vpmaxsw, vpmaxsw_mem, "xmmword"),
+        (vpmaxub, vpmaxub_mem, "xmmword"),
+        (vpminsw, vpminsw_mem, "xmmword"),
+        (vpminub, vpminub_mem, "xmmword"),
+        (vpmullw, vpmullw_mem, "xmmword"),
+        (vpmuludq, vpmuludq_mem, "xmmword"),
+        (vpsllw, vpsllw_mem, "xmmword"),
+        (vpslld, vpslld_mem, "xmmword"),
+        (vpsraw, vpsraw_mem, "xmmword"),
+        (vpsrad, vpsrad_mem, "xmmword"),
+        (vpsrlw, vpsrlw_mem, "xmmword"),
+        (vpsrld, vpsrld_mem, "xmmword"),
+        (vpsubb, vpsubb_mem, "xmmword"),
+        (vpsubw, vpsubw_mem, "xmmword"),
+        (vpsubd, vpsubd_mem, "xmmword"),
+        (vpsubsb, vpsubsb_mem, "xmmword"),
+        (vpsubsw, vpsubsw_mem, "xmmword"),
+        (vpsubusb, vpsubusb_mem, "xmmword"),
+        (vpsubusw, vpsubusw_mem, "xmmword"),
+        (vpand, vpand_mem, "xmmword"),
+        (vpor, vpor_mem, "xmmword"),
+        (vpxor, vpxor_mem, "xmmword"),
+    ];
+    let mut c = Checker::new();
+
+    for &(name, emit, emit_mem, size) in table.iter() {
+        check_three_op(&mut c, name, size, emit, emit_mem);
+    }
+
+    check_two_op(&mut c, "vcvtps2dq", "xmmword", vcvtps2dq, vcvtps2dq_mem);
+
+    c.finish();
+}
+
+#[test]
+fn vex_float() {
+    let table = ops![AvxThreeOp, AvxThreeOpMem;
+        (vaddps, vaddps_mem, "xmmword"),
+        (vaddsd, vaddsd_mem, "qword"),
+        (vaddss, vaddss_mem, "dword"),
+        (vandnpd, vandnpd_mem, "xmmword"),
+        (vandnps, vandnps_mem, "xmmword"),
+        (vsubpd, vsubpd_mem, "xmmword"),
+        (vsubsd, vsubsd_mem, "qword"),
+        (vsubss, vsubss_mem, "dword"),
+        (vsubps, vsubps_mem, "xmmword"),
+        (vmulpd, vmulpd_mem, "xmmword"),
+        (vmulps, vmulps_mem, "xmmword"),
+        (vmulss, vmulss_mem, "dword"),
+        (vmulsd, vmulsd_mem, "qword"),
+        (vdivpd, vdivpd_mem, "xmmword"),
+        (vdivps, vdivps_mem, "xmmword"),
+        (vdivss, vdivss_mem, "dword"),
+        (vdivsd, vdivsd_mem, "qword"),
+        (vsqrtss, vsqrtss_mem, "dword"),
+        (vsqrtsd, vsqrtsd_mem, "qword"),
+    ];
+    let mut c = Checker::new();
+
+    // `extern "C"` function pointers do not implement the `Fn` traits
+    for &(name, emit, emit_mem, size) in table.iter() {
+        check_three_op(
+            &mut c,
+            name,
+            size,
+            |asm, dst, src1, src2| emit(asm, dst, src1, src2),
+            |asm, dst, src1, src2| emit_mem(asm, dst, src1, src2),
+        );
+    }
+
+    check_two_op(
+        &mut c,
+        "vsqrtpd",
+        "xmmword",
+        |asm, dst, src1, src2| vsqrtpd(asm, dst, src1, src2),
+        |asm, dst, src1, src2| vsqrtpd_mem(asm, dst, src1, src2),
+    );
+    check_two_op(
+        &mut c,
+        "vsqrtps",
+        "xmmword",
+        |asm, dst, src1, src2| vsqrtps(asm, dst, src1, src2),
+        |asm, dst, src1, src2| vsqrtps_mem(asm, dst, src1, src2),
+    );
+
+    // the generic forms take the opcode
+    check_three_op(
+        &mut c,
+        "vaddps",
+        "xmmword",
+        |asm, dst, src1, src2| vps(asm, 0x58, dst, src1, src2),
+        |asm, dst, src1, src2| vpsm(asm, 0x58, dst, src1, src2),
+    );
+    check_three_op(
+        &mut c,
+        "vaddpd",
+        "xmmword",
+        |asm, dst, src1, src2| vpd(asm, 0x58, dst, src1, src2),
+        |asm, dst, src1, src2| vpdm(asm, 0x58, dst, src1, src2),
+    );
+    check_three_op(
+        &mut c,
+        "vfmadd231sd",
+        "qword",
+        |asm, dst, src1, src2| vfmasd(asm, 0xb9, dst, src1, src2),
+        |asm, dst, src1, src2| vfmasdm(asm, 0xb9, dst, src1, src2),
+    );
+    check_three_op(
+        &mut c,
+        "vfmadd231ss",
+        "dword",
+        |asm, dst, src1, src2| vfmass(asm, 0xb9, dst, src1, src2),
+        |asm, dst, src1, src2| vfmassm(asm, 0xb9, dst, src1, src2),
+    );
+
+    for &dst in XMMS.iter() {
+        for &src1 in SRCS.iter() {
+            for &src2 in XMMS.iter() {
+                let expected = format!("vaddpd {}, {}, {}", xmm(dst), xmm(src1), xmm(src2));
+                c.check(expected, |asm| vaddpd(asm, dst, src1, src2));
+            }
+        }
+
+        for &src in XMMS.iter() {
+            c.check(format!("vmovaps {}, {}", xmm(dst), xmm(src)), |asm| {
+                vmovaps(asm, dst, src)
+            });
+        }
+    }
+
+    for (mem, addr) in mem_forms() {
+        for &dst in SRCS.iter() {
+            c.check(
+                format!("vmovaps {}, xmmword ptr {}", xmm(dst), addr),
+                |asm| vmovaps_mem(asm, dst, mem),
+            );
+        }
+    }
+
+    c.finish();
+}
+
+#[test]
+fn vex_gpr_moves() {
+    let mut c = Checker::new();
+
+    for &freg in XMMS.iter() {
+        for &reg in GPRS.iter() {
+            let f = xmm(freg);
+
+            c.check(format!("vmovd {}, {}", f, r32(reg)), |asm| {
+                vmovd_freg_reg(asm, freg, reg)
+            });
+            c.check(format!("vmovd {}, {}", r32(reg), f), |asm| {
+                vmovd_reg_freg(asm, reg, freg)
+            });
+            c.check(format!("vmovq {}, {}", f, r64(reg)), |asm| {
+                vmovq_freg_reg(asm, freg, reg)
+            });
+            c.check(format!("vmovq {}, {}", r64(reg), f), |asm| {
+                vmovq_reg_freg(asm, reg, freg)
+            });
+        }
+    }
+
+    for (mem, addr) in mem_forms() {
+        for &freg in SRCS.iter() {
+            c.check(format!("vmovd {}, dword ptr {}", xmm(freg), addr), |asm| {
+                vmovd_freg_mem(asm, freg, mem)
+            });
+            c.check(format!("vmovq {}, qword ptr {}", xmm(freg), addr), |asm| {
+                vmovq_freg_mem(asm, freg, mem)
+            });
+        }
+    }
+
+    c.finish();
+}
+
+type YmmThreeOp = extern "C" fn(&mut Assembler, YMMRegister, YMMRegister, YMMRegister);
+type YmmThreeOpMem = extern "C" fn(&mut Assembler, YMMRegister, YMMRegister, Mem);
+
+const YSRCS: [YMMRegister; 4] = [YMM0, YMM7, YMM8, YMM15];
+
+fn check_ymm_three_op(c: &mut Checker, name: &str, emit: YmmThreeOp, emit_mem: YmmThreeOpMem) {
+    for &dst in YMMS.iter() {
+        for &src1 in YSRCS.iter() {
+            for &src2 in YMMS.iter() {
+                let expected = format!("{} {}, {}, {}", name, ymm(dst), ymm(src1), ymm(src2));
+                c.check(expected, |asm| emit(asm, dst, src1, src2));
+            }
+        }
+    }
+
+    for (mem, addr) in mem_forms() {
+        for &dst in YSRCS.iter() {
+            let expected = format!("{} {}, ymm14, ymmword ptr {}", name, ymm(dst), addr);
+            c.check(expected, |asm| emit_mem(asm, dst, YMM14, mem));
+        }
+    }
+}
+
+macro_rules! ymm_ops {
+    ($($name:ident),* $(,)*) => {
+        paste::item! {
+            [$((stringify!($name), [<$name _ymm>] as YmmThreeOp, [<$name _ymm_mem>] as YmmThreeOpMem)),*]
+        }
+    };
+}
+
+#[test]
+fn vex_256() {
+    let table = ymm_ops![
+        vaddps,
+        vaddpd,
+        vandnps,
+        vandnpd,
+        vsubps,
+        vsubpd,
+        vmulps,
+        vmulpd,
+        vdivps,
+        vdivpd,
+        vandps,
+        vandpd,
+        vorps,
+        vorpd,
+        vxorps,
+        vxorpd,
+        vminps,
+        vminpd,
+        vmaxps,
+        vmaxpd,
+        vhaddps,
+        vhaddpd,
+        vunpcklps,
+        vunpckhps,
+        vunpcklpd,
+        vunpckhpd,
+        vpunpcklbw,
+        vpunpcklwd,
+        vpunpckldq,
+        vpacksswb,
+        vpackuswb,
+        vpunpckhbw,
+        vpunpckhwd,
+        vpunpckhdq,
+        vpackssdw,
+        vpunpcklqdq,
+        vpunpckhqdq,
+        vpaddb,
+        vpaddw,
+        vpaddd,
+        vpaddq,
+        vpaddsb,
+        vpaddsw,
+        vpaddusb,
+        vpaddusw,
+        vpcmpeqb,
+        vpcmpeqw,
+        vpcmpeqd,
+        vpcmpeqq,
+        vpcmpgtb,
+        vpcmpgtw,
+        vpcmpgtd,
+        vpcmpgtq,
+        vpmaxsw,
+        vpmaxub,
+        vpminsw,
+        vpminub,
+        vpmullw,
+        vpmuludq,
+        vpsubb,
+        vpsubw,
+        vpsubd,
+        vpsubq,
+        vpsubsb,
+        vpsubsw,
+        vpsubusb,
+        vpsubusw,
+        vpand,
+        vpandn,
+        vpor,
+        vpxor,
+        vpavgb,
+        vpavgw,
+        vpmulhuw,
+        vpmulhw,
+        vpmaddwd,
+        vpsadbw,
+        vpshufb,
+        vpmuldq,
+        vpackusdw,
+        vpminsb,
+        vpminsd,
+        vpminuw,
+        vpminud,
+        vpmaxsb,
+        vpmaxsd,
+        vpmaxuw,
+        vpmaxud,
+        vpmulld,
+        vpsrlvd,
+        vpsrlvq,
+        vpsravd,
+        vpsllvd,
+        vpsllvq,
+        vpermilps,
+        vpermilpd,
+        vfmadd132ps,
+        vfmadd213pd,
+        vfmadd231ps,
+        vfmsub132pd,
+        vfmsub213ps,
+        vfmsub231pd,
+        vfnmadd132ps,
+        vfnmadd213pd,
+        vfnmadd231ps,
+        vfnmsub132pd,
+        vfnmsub213ps,
+        vfnmsub231pd,
+    ];
+    let mut c = Checker::new();
+
+    for &(name, emit, emit_mem) in table.iter() {
+        check_ymm_three_op(&mut c, name, emit, emit_mem);
+    }
+
+    check_ymm_three_op(&mut c, "vpermd", vpermd, vpermd_mem);
+    check_ymm_three_op(&mut c, "vpermps", vpermps, vpermps_mem);
+
+    c.finish();
+}
+
+#[test]
+fn vex_128_additions() {
+    let table = ops![AvxThreeOp, AvxThreeOpMem;
+        (vandps, vandps_mem, "xmmword"),
+        (vorpd, vorpd_mem, "xmmword"),
+        (vxorps, vxorps_mem, "xmmword"),
+        (vminss, vminss_mem, "dword"),
+        (vmaxsd, vmaxsd_mem, "qword"),
+        (vhaddps, vhaddps_mem, "xmmword"),
+        (vpaddq, vpaddq_mem, "xmmword"),
+        (vpsllq, vpsllq_mem, "xmmword"),
+        (vpsrlq, vpsrlq_mem, "xmmword"),
+        (vpshufb, vpshufb_mem, "xmmword"),
+        (vpmulld, vpmulld_mem, "xmmword"),
+        (vpsllvq, vpsllvq_mem, "xmmword"),
+        (vfmadd132ss, vfmadd132ss_mem, "dword"),
+        (vfmadd213sd, vfmadd213sd_mem, "qword"),
+        (vfmsub231ss, vfmsub231ss_mem, "dword"),
+        (vfnmadd132sd, vfnmadd132sd_mem, "qword"),
+        (vfnmsub213ss, vfnmsub213ss_mem, "dword"),
+        (vfmadd231ps, vfmadd231ps_mem, "xmmword"),
+        (vfnmsub132pd, vfnmsub132pd_mem, "xmmword"),
+    ];
+    let mut c = Checker::new();
+
+    for &(name, emit, emit_mem, size) in table.iter() {
+        check_three_op(
+            &mut c,
+            name,
+            size,
+            |asm, dst, src1, src2| emit(asm, dst, src1, src2),
+            |asm, dst, src1, src2| emit_mem(asm, dst, src1, src2),
+        );
+    }
+
+    c.finish();
+}
+
+#[test]
+fn vex_moves_and_two_operand() {
+    type Mov = extern "C" fn(&mut Assembler, XMMRegister, XMMRegister);
+    type MovMem = extern "C" fn(&mut Assembler, XMMRegister, Mem);
+    type MovStore = extern "C" fn(&mut Assembler, Mem, XMMRegister);
+    type YMov = extern "C" fn(&mut Assembler, YMMRegister, YMMRegister);
+    type YMovMem = extern "C" fn(&mut Assembler, YMMRegister, Mem);
+    type YMovStore = extern "C" fn(&mut Assembler, Mem, YMMRegister);
+
+    macro_rules! movs {
+        ($($name:ident),* $(,)*) => {
+            paste::item! {
+                [$((
+                    stringify!($name),
+                    $name as Mov,
+                    [<$name _mem>] as MovMem,
+                    [<$name _store>] as MovStore,
+                    [<$name _ymm>] as YMov,
+                    [<$name _ymm_mem>] as YMovMem,
+                    [<$name _ymm_store>] as YMovStore,
+                )),*]
+            }
+        };
+    }
+
+    macro_rules! two_ops {
+        ($($name:ident),* $(,)*) => {
+            paste::item! {
+                [$((
+                    stringify!($name),
+                    $name as Mov,
+                    [<$name _mem>] as MovMem,
+                    [<$name _ymm>] as YMov,
+                    [<$name _ymm_mem>] as YMovMem,
+                )),*]
+            }
+        };
+    }
+
+    let moves = movs![vmovups, vmovupd, vmovaps, vmovapd, vmovdqa, vmovdqu];
+    let two = two_ops![vcvtdq2ps, vcvttps2dq, vrsqrtps, vrcpps, vptest, vpabsb, vpabsw, vpabsd];
+    let mut c = Checker::new();
+
+    for &(name, mov, load, store, ymov, yload, ystore) in moves.iter() {
+        for &dst in XMMS.iter() {
+            for &src in XMMS.iter() {
+                c.check(format!("{} {}, {}", name, xmm(dst), xmm(src)), |asm| {
+                    mov(asm, dst, src)
+                });
+            }
+        }
+
+        for &dst in YMMS.iter() {
+            for &src in YMMS.iter() {
+                c.check(format!("{} {}, {}", name, ymm(dst), ymm(src)), |asm| {
+                    ymov(asm, dst, src)
+                });
+            }
+        }
+
+        for (mem, addr) in mem_forms() {
+            for (&x, &y) in SRCS.iter().zip(YSRCS.iter()) {
+                c.check(
+                    format!("{} {}, xmmword ptr {}", name, xmm(x), addr),
+                    |asm| load(asm, x, mem),
+                );
+                c.check(
+                    format!("{} xmmword ptr {}, {}", name, addr, xmm(x)),
+                    |asm| store(asm, mem, x),
+                );
+                c.check(
+                    format!("{} {}, ymmword ptr {}", name, ymm(y), addr),
+                    |asm| yload(asm, y, mem),
+                );
+                c.check(
+                    format!("{} ymmword ptr {}, {}", name, addr, ymm(y)),
+                    |asm| ystore(asm, mem, y),
+                );
+            }
+        }
+    }
+
+    for &(name, op, op_mem, yop, yop_mem) in two.iter() {
+        for &dst in XMMS.iter() {
+            for &src in XMMS.iter() {
+                c.check(format!("{} {}, {}", name, xmm(dst), xmm(src)), |asm| {
+                    op(asm, dst, src)
+                });
+            }
+        }
+
+        for &dst in YMMS.iter() {
+            for &src in YMMS.iter() {
+                c.check(format!("{} {}, {}", name, ymm(dst), ymm(src)), |asm| {
+                    yop(asm, dst, src)
+                });
+            }
+        }
+
+        for (mem, addr) in mem_forms() {
+            for (&x, &y) in SRCS.iter().zip(YSRCS.iter()) {
+                c.check(
+                    format!("{} {}, xmmword ptr {}", name, xmm(x), addr),
+                    |asm| op_mem(asm, x, mem),
+                );
+                c.check(
+                    format!("{} {}, ymmword ptr {}", name, ymm(y), addr),
+                    |asm| yop_mem(asm, y, mem),
+                );
+            }
+        }
+    }
+
+    c.check("vzeroupper", |asm| vzeroupper(asm));
+    c.check("vzeroall", |asm| vzeroall(asm));
+
+    c.finish();
+}
+
+#[test]
+fn vex_shifts() {
+    type Shift = extern "C" fn(&mut Assembler, YMMRegister, YMMRegister, XMMRegister);
+    type ShiftImm = extern "C" fn(&mut Assembler, XMMRegister, XMMRegister, u8);
+    type YShiftImm = extern "C" fn(&mut Assembler, YMMRegister, YMMRegister, u8);
+
+    macro_rules! shifts {
+        ($($name:ident),* $(,)*) => {
+            paste::item! {
+                [$((
+                    stringify!($name),
+                    [<$name _ymm>] as Shift,
+                    [<$name _imm>] as ShiftImm,
+                    [<$name _ymm_imm>] as YShiftImm,
+                )),*]
+            }
+        };
+    }
+
+    let table = shifts![vpsllw, vpslld, vpsllq, vpsraw, vpsrad, vpsrlw, vpsrld, vpsrlq];
+    let mut c = Checker::new();
+
+    for &(name, shift, shift_imm, yshift_imm) in table.iter() {
+        for i in 0..16 {
+            let (dst, src) = (XMMS[i], XMMS[15 - i]);
+            let (ydst, ysrc) = (YMMS[i], YMMS[(i + 3) % 16]);
+
+            c.check(
+                format!("{} {}, {}, {}", name, ymm(ydst), ymm(ysrc), xmm(src)),
+                |asm| shift(asm, ydst, ysrc, src),
+            );
+            c.check(format!("{} {}, {}, 3", name, xmm(dst), xmm(src)), |asm| {
+                shift_imm(asm, dst, src, 3)
+            });
+            c.check(
+                format!("{} {}, {}, 0x1f", name, ymm(ydst), ymm(ysrc)),
+                |asm| yshift_imm(asm, ydst, ysrc, 0x1f),
+            );
+        }
+    }
+
+    c.finish();
+}
+
+#[test]
+fn vex_shuffles() {
+    type Imm = extern "C" fn(&mut Assembler, XMMRegister, XMMRegister, XMMRegister, u8);
+    type YImm = extern "C" fn(&mut Assembler, YMMRegister, YMMRegister, YMMRegister, u8);
+    type Imm2 = extern "C" fn(&mut Assembler, XMMRegister, XMMRegister, u8);
+    type YImm2 = extern "C" fn(&mut Assembler, YMMRegister, YMMRegister, u8);
+
+    let three: [(&str, Imm, YImm); 7] = [
+        ("vshufps", vshufps, vshufps_ymm),
+        ("vshufpd", vshufpd, vshufpd_ymm),
+        ("vpblendd", vpblendd, vpblendd_ymm),
+        ("vblendps", vblendps, vblendps_ymm),
+        ("vblendpd", vblendpd, vblendpd_ymm),
+        ("vpblendw", vpblendw, vpblendw_ymm),
+        ("vpalignr", vpalignr, vpalignr_ymm),
+    ];
+    let two: [(&str, Imm2, YImm2); 7] = [
+        ("vpshufd", vpshufd, vpshufd_ymm),
+        ("vpshufhw", vpshufhw, vpshufhw_ymm),
+        ("vpshuflw", vpshuflw, vpshuflw_ymm),
+        ("vpermilps", vpermilps_imm, vpermilps_imm_ymm),
+        ("vpermilpd", vpermilpd_imm, vpermilpd_imm_ymm),
+        ("vroundps", vroundps, vroundps_ymm),
+        ("vroundpd", vroundpd, vroundpd_ymm),
+    ];
+    let mut c = Checker::new();
+
+    for i in 0..16 {
+        let (x0, x1, x2) = (XMMS[i], XMMS[(i + 5) % 16], XMMS[15 - i]);
+        let (y0, y1, y2) = (YMMS[i], YMMS[(i + 5) % 16], YMMS[15 - i]);
+
+        for &(name, op, yop) in three.iter() {
+            c.check(
+                format!("{} {}, {}, {}, 1", name, xmm(x0), xmm(x1), xmm(x2)),
+                |asm| op(asm, x0, x1, x2, 1),
+            );
+            c.check(
+                format!("{} {}, {}, {}, 0x1b", name, ymm(y0), ymm(y1), ymm(y2)),
+                |asm| yop(asm, y0, y1, y2, 0x1b),
+            );
+        }
+
+        for &(name, op, yop) in two.iter() {
+            c.check(format!("{} {}, {}, 1", name, xmm(x0), xmm(x2)), |asm| {
+                op(asm, x0, x2, 1)
+            });
+            c.check(format!("{} {}, {}, 0x1b", name, ymm(y0), ymm(y2)), |asm| {
+                yop(asm, y0, y2, 0x1b)
+            });
+        }
+
+        c.check(
+            format!("vperm2f128 {}, {}, {}, 0x21", ymm(y0), ymm(y1), ymm(y2)),
+            |asm| vperm2f128(asm, y0, y1, y2, 0x21),
+        );
+        c.check(
+            format!("vperm2i128 {}, {}, {}, 0x21", ymm(y0), ymm(y1), ymm(y2)),
+            |asm| vperm2i128(asm, y0, y1, y2, 0x21),
+        );
+        c.check(format!("vpermq {}, {}, 0x4e", ymm(y0), ymm(y2)), |asm| {
+            vpermq(asm, y0, y2, 0x4e)
+        });
+        c.check(format!("vpermpd {}, {}, 0x4e", ymm(y0), ymm(y2)), |asm| {
+            vpermpd(asm, y0, y2, 0x4e)
+        });
+        c.check(
+            format!("vinsertf128 {}, {}, {}, 1", ymm(y0), ymm(y1), xmm(x2)),
+            |asm| vinsertf128(asm, y0, y1, x2, 1),
+        );
+        c.check(
+            format!("vinserti128 {}, {}, {}, 1", ymm(y0), ymm(y1), xmm(x2)),
+            |asm| vinserti128(asm, y0, y1, x2, 1),
+        );
+        c.check(format!("vextractf128 {}, {}, 1", xmm(x0), ymm(y2)), |asm| {
+            vextractf128(asm, x0, y2, 1)
+        });
+        c.check(format!("vextracti128 {}, {}, 1", xmm(x0), ymm(y2)), |asm| {
+            vextracti128(asm, x0, y2, 1)
+        });
+    }
+
+    for (mem, addr) in mem_forms() {
+        c.check(
+            format!("vshufps xmm3, xmm9, xmmword ptr {}, 5", addr),
+            |asm| vshufps_mem(asm, XMM3, XMM9, mem, 5),
+        );
+        c.check(format!("vpshufd ymm12, ymmword ptr {}, 5", addr), |asm| {
+            vpshufd_ymm_mem(asm, YMM12, mem, 5)
+        });
+        c.check(format!("vpermq ymm1, ymmword ptr {}, 5", addr), |asm| {
+            vpermq_mem(asm, YMM1, mem, 5)
+        });
+        c.check(
+            format!("vinserti128 ymm1, ymm10, xmmword ptr {}, 1", addr),
+            |asm| vinserti128_mem(asm, YMM1, YMM10, mem, 1),
+        );
+        c.check(
+            format!("vextractf128 xmmword ptr {}, ymm9, 1", addr),
+            |asm| vextractf128_store(asm, mem, YMM9, 1),
+        );
+    }
+
+    c.finish();
+}
+
+#[test]
+fn vex_broadcasts() {
+    type Bcast = extern "C" fn(&mut Assembler, XMMRegister, XMMRegister);
+    type BcastMem = extern "C" fn(&mut Assembler, XMMRegister, Mem);
+    type YBcast = extern "C" fn(&mut Assembler, YMMRegister, XMMRegister);
+    type YBcastMem = extern "C" fn(&mut Assembler, YMMRegister, Mem);
+
+    let table: [(&str, &str, Bcast, BcastMem, YBcast, YBcastMem); 5] = [
+        (
+            "vbroadcastss",
+            "dword",
+            vbroadcastss,
+            vbroadcastss_mem,
+            vbroadcastss_ymm,
+            vbroadcastss_ymm_mem,
+        ),
+        (
+            "vpbroadcastb",
+            "byte",
+            vpbroadcastb,
+            vpbroadcastb_mem,
+            vpbroadcastb_ymm,
+            vpbroadcastb_ymm_mem,
+        ),
+        (
+            "vpbroadcastw",
+            "word",
+            vpbroadcastw,
+            vpbroadcastw_mem,
+            vpbroadcastw_ymm,
+            vpbroadcastw_ymm_mem,
+        ),
+        (
+            "vpbroadcastd",
+            "dword",
+            vpbroadcastd,
+            vpbroadcastd_mem,
+            vpbroadcastd_ymm,
+            vpbroadcastd_ymm_mem,
+        ),
+        (
+            "vpbroadcastq",
+            "qword",
+            vpbroadcastq,
+            vpbroadcastq_mem,
+            vpbroadcastq_ymm,
+            vpbroadcastq_ymm_mem,
+        ),
+    ];
+    let mut c = Checker::new();
+
+    for &(name, size, op, op_mem, yop, yop_mem) in table.iter() {
+        for &dst in XMMS.iter() {
+            for &src in SRCS.iter() {
+                c.check(format!("{} {}, {}", name, xmm(dst), xmm(src)), |asm| {
+                    op(asm, dst, src)
+                });
+            }
+        }
+
+        for &dst in YMMS.iter() {
+            for &src in SRCS.iter() {
+                c.check(format!("{} {}, {}", name, ymm(dst), xmm(src)), |asm| {
+                    yop(asm, dst, src)
+                });
+            }
+        }
+
+        for (mem, addr) in mem_forms() {
+            c.check(format!("{} xmm9, {} ptr {}", name, size, addr), |asm| {
+                op_mem(asm, XMM9, mem)
+            });
+            c.check(format!("{} ymm2, {} ptr {}", name, size, addr), |asm| {
+                yop_mem(asm, YMM2, mem)
+            });
+        }
+    }
+
+    for &dst in YMMS.iter() {
+        for &src in SRCS.iter() {
+            c.check(format!("vbroadcastsd {}, {}", ymm(dst), xmm(src)), |asm| {
+                vbroadcastsd(asm, dst, src)
+            });
+        }
+    }
+
+    for (mem, addr) in mem_forms() {
+        c.check(format!("vbroadcastsd ymm8, qword ptr {}", addr), |asm| {
+            vbroadcastsd_mem(asm, YMM8, mem)
+        });
+        c.check(
+            format!("vbroadcastf128 ymm8, xmmword ptr {}", addr),
+            |asm| vbroadcastf128_mem(asm, YMM8, mem),
+        );
+    }
+
+    // the capstone bundled with the tests does not decode vbroadcasti128
+    for &(mem, bytes) in [
+        (Mem::Base(RAX, 0), &[0xc4, 0x62, 0x7d, 0x5a, 0x18][..]),
+        (
+            Mem::Base(R12, 8),
+            &[0xc4, 0x42, 0x7d, 0x5a, 0x5c, 0x24, 0x08][..],
+        ),
+        (
+            Mem::Index(R13, R9, 4, -8),
+            &[0xc4, 0x02, 0x7d, 0x5a, 0x5c, 0x8d, 0xf8][..],
+        ),
+    ]
+    .iter()
+    {
+        let mut asm = assembler();
+        vbroadcasti128_mem(&mut asm, YMM11, mem);
+        assert_eq!(asm.data(), bytes, "vbroadcasti128 ymm11, {:?}", mem);
+    }
+
+    c.finish();
+}
+
+#[test]
+fn vex_gathers() {
+    let mut c = Checker::new();
+
+    for &base in GPRS.iter() {
+        for &scale in SCALES.iter() {
+            for &disp in DISPS.iter() {
+                for &(dst, index, mask) in [(0, 1, 2), (9, 14, 3), (15, 8, 7)].iter() {
+                    let addr = |index: String| {
+                        let mut out = format!("[{} + {}", r64(base), index);
+                        if scale != 1 {
+                            out.push_str(&format!("*{}", scale));
+                        }
+                        if disp != 0 {
+                            let sign = if disp < 0 { "-" } else { "+" };
+                            out.push_str(&format!(" {} {}", sign, hex((disp as i64).abs())));
+                        }
+                        out.push(']');
+                        out
+                    };
+                    let (xd, xi, xm) = (XMMS[dst], XMMS[index], XMMS[mask]);
+                    let (yd, yi, ym) = (YMMS[dst], YMMS[index], YMMS[mask]);
+                    let xa = addr(xmm(xi));
+                    let ya = addr(ymm(yi));
+
+                    c.check(
+                        format!("vgatherdps {}, dword ptr {}, {}", xmm(xd), xa, xmm(xm)),
+                        |asm| vgatherdps(asm, xd, base, xi, scale, disp, xm),
+                    );
+                    c.check(
+                        format!("vgatherdps {}, dword ptr {}, {}", ymm(yd), ya, ymm(ym)),
+                        |asm| vgatherdps_ymm(asm, yd, base, yi, scale, disp, ym),
+                    );
+                    c.check(
+                        format!("vgatherdpd {}, qword ptr {}, {}", ymm(yd), xa, ymm(ym)),
+                        |asm| vgatherdpd_ymm(asm, yd, base, xi, scale, disp, ym),
+                    );
+                    c.check(
+                        format!("vgatherqps {}, dword ptr {}, {}", xmm(xd), ya, xmm(xm)),
+                        |asm| vgatherqps_ymm(asm, xd, base, yi, scale, disp, xm),
+                    );
+                    c.check(
+                        format!("vgatherqpd {}, qword ptr {}, {}", xmm(xd), xa, xmm(xm)),
+                        |asm| vgatherqpd(asm, xd, base, xi, scale, disp, xm),
+                    );
+                    c.check(
+                        format!("vpgatherdd {}, dword ptr {}, {}", ymm(yd), ya, ymm(ym)),
+                        |asm| vpgatherdd_ymm(asm, yd, base, yi, scale, disp, ym),
+                    );
+                    c.check(
+                        format!("vpgatherdq {}, qword ptr {}, {}", xmm(xd), xa, xmm(xm)),
+                        |asm| vpgatherdq(asm, xd, base, xi, scale, disp, xm),
+                    );
+                    c.check(
+                        format!("vpgatherqd {}, dword ptr {}, {}", xmm(xd), ya, xmm(xm)),
+                        |asm| vpgatherqd_ymm(asm, xd, base, yi, scale, disp, xm),
+                    );
+                    c.check(
+                        format!("vpgatherqq {}, qword ptr {}, {}", ymm(yd), ya, ymm(ym)),
+                        |asm| vpgatherqq_ymm(asm, yd, base, yi, scale, disp, ym),
+                    );
+                }
+            }
+        }
+    }
+
+    c.rejects("destination used as index", |asm| {
+        vgatherdps(asm, XMM1, RAX, XMM1, 4, 0, XMM2)
+    });
+    c.rejects("mask used as index", |asm| {
+        vgatherdps(asm, XMM1, RAX, XMM2, 4, 0, XMM2)
+    });
+    c.rejects("rip as base", |asm| {
+        vgatherdps(asm, XMM1, RIP, XMM2, 4, 0, XMM3)
+    });
+    c.rejects("scale 3", |asm| {
+        vgatherdps(asm, XMM1, RAX, XMM2, 3, 0, XMM3)
+    });
+
+    c.finish();
+}
@@ -0,0 +1,938 @@
+//! EVEX encoded instructions.
+
+use crate::*;
+use jazz_jit::assembler::{Assembler, Mem};
+use jazz_jit::assembler_x64::RoundMode;
+use jazz_jit::avx512::*;
+
+type ThreeOp = extern "C" fn(&mut Assembler, ZMMRegister, ZMMRegister, ZMMRegister, Masking);
+type ThreeOpMem = extern "C" fn(&mut Assembler, ZMMRegister, ZMMRegister, Mem, Masking);
+type ThreeOpRound =
+    extern "C" fn(&mut Assembler, ZMMRegister, ZMMRegister, ZMMRegister, RoundMode, Masking);
+
+/// Second operands of the register forms, covering every combination of the two
+/// high bits of the register number.
+const SRCS: [ZMMRegister; 5] = [ZMM0, ZMM9, ZMM16, ZMM27, ZMM31];
+
+const MASKINGS: [Masking; 5] = [
+    Masking::Unmasked,
+    Masking::Merge(K1),
+    Masking::Merge(K7),
+    Masking::Zero(K2),
+    Masking::Zero(K5),
+];
+
+const ROUNDING: [(RoundMode, &str); 4] = [
+    (RoundMode::Nearest, "{rn-sae}"),
+    (RoundMode::Down, "{rd-sae}"),
+    (RoundMode::Up, "{ru-sae}"),
+    (RoundMode::Toward, "{rz-sae}"),
+];
+
+/// `mem_forms` without index registers from R8 up. The capstone used here does not
+/// decode EVEX instructions with EVEX.X set for a memory operand, these are covered
+/// by `evex_objdump_bytes`.
+fn evex_mem_forms() -> Vec<(Mem, String)> {
+    mem_forms()
+        .into_iter()
+        .filter(|(mem, _)| match *mem {
+            Mem::Index(_, index, _, _) | Mem::Offset(index, _, _) => index.msb() == 0,
+            _ => true,
+        })
+        .collect()
+}
+
+/// The bytes `emit` assembles.
+fn code(emit: impl FnOnce(&mut Assembler)) -> Vec<u8> {
+    let mut asm = assembler();
+    emit(&mut asm);
+    asm.data().to_vec()
+}
+
+/// `code` of an unmasked instruction with EVEX.aaa and EVEX.z set for `masking`.
+/// The capstone used here does not decode the masked forms of many instructions.
+fn with_masking(code: &[u8], masking: Masking) -> Vec<u8> {
+    let mut code = code.to_vec();
+
+    code[3] |= match masking {
+        Masking::Unmasked => 0,
+        Masking::Merge(k) => k as u8,
+        Masking::Zero(k) => 0x80 | k as u8,
+    };
+
+    code
+}
+
+/// The destination as capstone prints it with `masking` applied.
+fn masked(dst: String, masking: Masking) -> String {
+    match masking {
+        Masking::Unmasked => dst,
+        Masking::Merge(k) => format!("{} {{k{}}}", dst, k as usize),
+        Masking::Zero(k) => format!("{} {{k{}}} {{z}}", dst, k as usize),
+    }
+}
+
+/// dword or qword elements and the number of them in a ZMM register.
+fn element(qword: bool) -> (&'static str, usize) {
+    if qword {
+        ("qword", 8)
+    } else {
+        ("dword", 16)
+    }
+}
+
+macro_rules! ops {
+    ($($name:ident: $qword:expr),* $(,)*) => {
+        paste::item! {
+            [$((
+                stringify!($name),
+                $qword,
+                [<$name _zmm>] as ThreeOp,
+                [<$name _zmm_mem>] as ThreeOpMem,
+                [<$name _zmm_bcst>] as ThreeOpMem,
+            )),*]
+        }
+    };
+}
+
+/// `code` without the opcode map, the SIMD prefix and the opcode, which leaves the
+/// encoding of the operands.
+fn operands(code: &[u8]) -> Vec<u8> {
+    // EVEX follows the segment override of the memory operand, if there is one
+    let evex = code.iter().position(|&byte| byte == 0x62).unwrap();
+    let mut code = code.to_vec();
+    code[evex + 1] &= !0x03;
+    code[evex + 2] &= !0x03;
+    code.remove(evex + 4);
+    code
+}
+
+/// The memory and broadcast forms of vaddps or vaddpd, which capstone decodes. The
+/// operands of every other instruction with the same element size are encoded the
+/// same way.
+fn reference(qword: bool) -> (ThreeOpMem, ThreeOpMem) {
+    if qword {
+        (vaddpd_zmm_mem, vaddpd_zmm_bcst)
+    } else {
+        (vaddps_zmm_mem, vaddps_zmm_bcst)
+    }
+}
+
+fn check_three_op(
+    c: &mut Checker,
+    name: &str,
+    qword: bool,
+    emit: ThreeOp,
+    emit_mem: ThreeOpMem,
+    emit_bcst: Option<ThreeOpMem>,
+) {
+    // the capstone used here decodes neither the EVEX forms of FMA nor vpshufb,
+    // those are in `evex_objdump_bytes`
+    if !name.starts_with("vf") && name != "vpshufb" {
+        for &dst in ZMMS.iter() {
+            for &src1 in SRCS.iter() {
+                for &src2 in SRCS.iter() {
+                    let expected = format!("{} {}, {}, {}", name, zmm(dst), zmm(src1), zmm(src2));
+                    c.check(expected, |asm| {
+                        emit(asm, dst, src1, src2, Masking::Unmasked)
+                    });
+                }
+            }
+        }
+    }
+
+    let unmasked = code(|asm| emit(asm, ZMM17, ZMM3, ZMM30, Masking::Unmasked));
+
+    for &masking in MASKINGS.iter() {
+        assert_eq!(
+            code(|asm| emit(asm, ZMM17, ZMM3, ZMM30, masking)),
+            with_masking(&unmasked, masking),
+            "{} {}, zmm3, zmm30",
+            name,
+            masked(zmm(ZMM17), masking)
+        );
+    }
+
+    let (reference_mem, reference_bcst) = reference(qword);
+
+    for (mem, addr) in mem_forms() {
+        for &(dst, src1) in [(ZMM1, ZMM30), (ZMM24, ZMM8)].iter() {
+            assert_eq!(
+                operands(&code(|asm| emit_mem(
+                    asm,
+                    dst,
+                    src1,
+                    mem,
+                    Masking::Zero(K2)
+                ))),
+                operands(&code(|asm| reference_mem(
+                    asm,
+                    dst,
+                    src1,
+                    mem,
+                    Masking::Zero(K2)
+                ))),
+                "{} {}, {}, zmmword ptr {}",
+                name,
+                zmm(dst),
+                zmm(src1),
+                addr
+            );
+
+            if let Some(emit_bcst) = emit_bcst {
+                assert_eq!(
+                    operands(&code(|asm| emit_bcst(
+                        asm,
+                        dst,
+                        src1,
+                        mem,
+                        Masking::Unmasked
+                    ))),
+                    operands(&code(|asm| reference_bcst(
+                        asm,
+                        dst,
+                        src1,
+                        mem,
+                        Masking::Unmasked
+                    ))),
+                    "{} {}, {}, {{1to}} {}",
+                    name,
+                    zmm(dst),
+                    zmm(src1),
+                    addr
+                );
+            }
+        }
+    }
+}
+
+#[test]
+fn evex_arith() {
+    let table = ops![
+        vaddps: false, vaddpd: true, vsubps: false, vsubpd: true, vmulps: false, vmulpd: true,
+        vdivps: false, vdivpd: true, vminps: false, vminpd: true, vmaxps: false, vmaxpd: true,
+        vunpcklps: false, vunpcklpd: true, vunpckhps: false, vunpckhpd: true, vpaddd: false,
+        vpaddq: true, vpsubd: false, vpsubq: true, vpmulld: false, vpmuludq: true, vpmuldq: true,
+        vpandd: false, vpandq: true, vpandnd: false, vpandnq: true, vpord: false, vporq: true,
+        vpxord: false, vpxorq: true, vpminsd: false, vpminsq: true, vpminud: false, vpminuq: true,
+        vpmaxsd: false, vpmaxsq: true, vpmaxud: false, vpmaxuq: true, vpsrlvd: false,
+        vpsrlvq: true, vpsravd: false, vpsravq: true, vpsllvd: false, vpsllvq: true,
+        vpermd: false, vpermq: true, vpermps: false, vpermpd: true, vfmadd132ps: false,
+        vfmadd213pd: true, vfmadd231ps: false, vfmsub132pd: true, vfmsub213ps: false,
+        vfmsub231pd: true, vfnmadd132ps: false, vfnmadd213pd: true, vfnmadd231ps: false,
+        vfnmsub132pd: true, vfnmsub213ps: false, vfnmsub231pd: true,
+    ];
+    let mut c = Checker::new();
+
+    for &(name, qword, emit, emit_mem, emit_bcst) in table.iter() {
+        check_three_op(&mut c, name, qword, emit, emit_mem, Some(emit_bcst));
+    }
+
+    for (mem, addr) in evex_mem_forms() {
+        // capstone takes EVEX.V' for the top bit of the index in SIB operands, the
+        // upper sixteen registers as first source are in `evex_objdump_bytes`
+        for &(dst, src1) in [(ZMM1, ZMM14), (ZMM24, ZMM8)].iter() {
+            for &qword in [false, true].iter() {
+                let name = if qword { "vaddpd" } else { "vaddps" };
+                let (size, count) = element(qword);
+                let (emit_mem, emit_bcst) = reference(qword);
+                let (d, s) = (zmm(dst), zmm(src1));
+
+                c.check(
+                    format!("{} {}, {}, zmmword ptr {}", name, d, s, addr),
+                    |asm| emit_mem(asm, dst, src1, mem, Masking::Unmasked),
+                );
+                c.check(
+                    format!(
+                        "{} {}, {}, {} ptr {}{{1to{}}}",
+                        name, d, s, size, addr, count
+                    ),
+                    |asm| emit_bcst(asm, dst, src1, mem, Masking::Unmasked),
+                );
+            }
+        }
+    }
+
+    // byte and word instructions cannot broadcast
+    let bw: [(&str, ThreeOp, ThreeOpMem); 8] = [
+        ("vpaddb", vpaddb_zmm, vpaddb_zmm_mem),
+        ("vpaddw", vpaddw_zmm, vpaddw_zmm_mem),
+        ("vpsubb", vpsubb_zmm, vpsubb_zmm_mem),
+        ("vpsubw", vpsubw_zmm, vpsubw_zmm_mem),
+        ("vpmullw", vpmullw_zmm, vpmullw_zmm_mem),
+        ("vpminub", vpminub_zmm, vpminub_zmm_mem),
+        ("vpmaxub", vpmaxub_zmm, vpmaxub_zmm_mem),
+        ("vpshufb", vpshufb_zmm, vpshufb_zmm_mem),
+    ];
+
+    for &(name, emit, emit_mem) in bw.iter() {
+        check_three_op(&mut c, name, false, emit, emit_mem, None);
+    }
+
+    c.finish();
+}
+
+#[test]
+fn evex_rounding() {
+    let table: [(ThreeOp, ThreeOpRound); 10] = [
+        (vaddps_zmm, vaddps_zmm_round),
+        (vaddpd_zmm, vaddpd_zmm_round),
+        (vsubps_zmm, vsubps_zmm_round),
+        (vsubpd_zmm, vsubpd_zmm_round),
+        (vmulps_zmm, vmulps_zmm_round),
+        (vmulpd_zmm, vmulpd_zmm_round),
+        (vdivps_zmm, vdivps_zmm_round),
+        (vdivpd_zmm, vdivpd_zmm_round),
+        (vfmadd231ps_zmm, vfmadd231ps_zmm_round),
+        (vfnmsub132pd_zmm, vfnmsub132pd_zmm_round),
+    ];
+    let mut c = Checker::new();
+
+    // capstone does not decode embedded rounding: the rounding forms are the
+    // register forms checked in `evex_arith` with EVEX.b set and the mode in L'L
+    for &(emit, emit_round) in table.iter() {
+        for &(mode, _) in ROUNDING.iter() {
+            for &(dst, src1, src2) in [(ZMM0, ZMM1, ZMM2), (ZMM31, ZMM16, ZMM9)].iter() {
+                let mut expected = code(|asm| emit(asm, dst, src1, src2, Masking::Zero(K6)));
+                expected[3] = expected[3] & 0x9f | 0x10 | (mode as u8) << 5;
+                assert_eq!(
+                    code(|asm| emit_round(asm, dst, src1, src2, mode, Masking::Zero(K6))),
+                    expected
+                );
+            }
+        }
+    }
+
+    for &(mode, _) in ROUNDING.iter() {
+        let round = 0x10 | (mode as u8) << 5;
+        let mut expected = code(|asm| vsqrtps_zmm(asm, ZMM20, ZMM3, Masking::Unmasked));
+        expected[3] = expected[3] & 0x9f | round;
+        assert_eq!(
+            code(|asm| vsqrtps_zmm_round(asm, ZMM20, ZMM3, mode, Masking::Unmasked)),
+            expected
+        );
+
+        let mut expected = code(|asm| vsqrtpd_zmm(asm, ZMM3, ZMM20, Masking::Unmasked));
+        expected[3] = expected[3] & 0x9f | round;
+        assert_eq!(
+            code(|asm| vsqrtpd_zmm_round(asm, ZMM3, ZMM20, mode, Masking::Unmasked)),
+            expected
+        );
+    }
+
+    for &dst in ZMMS.iter() {
+        for &src in SRCS.iter() {
+            c.check(format!("vsqrtps {}, {}", zmm(dst), zmm(src)), |asm| {
+                vsqrtps_zmm(asm, dst, src, Masking::Unmasked)
+            });
+            c.check(format!("vsqrtpd {}, {}", zmm(dst), zmm(src)), |asm| {
+                vsqrtpd_zmm(asm, dst, src, Masking::Unmasked)
+            });
+        }
+    }
+
+    for (mem, addr) in evex_mem_forms() {
+        c.check(format!("vsqrtps zmm5, zmmword ptr {}", addr), |asm| {
+            vsqrtps_zmm_mem(asm, ZMM5, mem, Masking::Unmasked)
+        });
+        c.check(
+            format!("vsqrtpd zmm25, qword ptr {}{{1to8}}", addr),
+            |asm| vsqrtpd_zmm_bcst(asm, ZMM25, mem, Masking::Unmasked),
+        );
+    }
+
+    c.finish();
+}
+
+#[test]
+fn evex_compressed_displacements() {
+    // full vector: N is 64, broadcasts scale by the element size
+    let unmasked = Masking::Unmasked;
+    assert_eq!(
+        code(|asm| vaddps_zmm_mem(asm, ZMM0, ZMM1, Mem::Base(RAX, 0x40), unmasked)),
+        [0x62, 0xf1, 0x74, 0x48, 0x58, 0x40, 0x01]
+    );
+    assert_eq!(
+        code(|asm| vaddps_zmm_mem(asm, ZMM0, ZMM1, Mem::Base(RAX, -0x2000), unmasked)),
+        [0x62, 0xf1, 0x74, 0x48, 0x58, 0x40, 0x80]
+    );
+    assert_eq!(
+        code(|asm| vaddps_zmm_mem(asm, ZMM0, ZMM1, Mem::Base(RAX, 0x20), unmasked)),
+        [0x62, 0xf1, 0x74, 0x48, 0x58, 0x80, 0x20, 0x00, 0x00, 0x00]
+    );
+    assert_eq!(
+        code(|asm| vaddps_zmm_mem(asm, ZMM0, ZMM1, Mem::Base(RAX, 0x2000), unmasked)),
+        [0x62, 0xf1, 0x74, 0x48, 0x58, 0x80, 0x00, 0x20, 0x00, 0x00]
+    );
+    assert_eq!(
+        code(|asm| vaddps_zmm_bcst(asm, ZMM0, ZMM1, Mem::Base(RAX, 0x8), unmasked)),
+        [0x62, 0xf1, 0x74, 0x58, 0x58, 0x40, 0x02]
+    );
+    assert_eq!(
+        code(|asm| vaddpd_zmm_bcst(asm, ZMM0, ZMM1, Mem::Base(RAX, 0x8), unmasked)),
+        [0x62, 0xf1, 0xf5, 0x58, 0x58, 0x40, 0x01]
+    );
+
+    // scalar broadcasts: N is the element size
+    assert_eq!(
+        code(|asm| vbroadcastss_zmm_mem(asm, ZMM2, Mem::Index(RSP, RCX, 4, -4), unmasked)),
+        [0x62, 0xf2, 0x7d, 0x48, 0x18, 0x54, 0x8c, 0xff]
+    );
+    assert_eq!(
+        code(|asm| vbroadcastsd_zmm_mem(asm, ZMM2, Mem::Base(R12, 0x3f8), unmasked)),
+        [0x62, 0xd2, 0xfd, 0x48, 0x19, 0x54, 0x24, 0x7f]
+    );
+
+    // RIP relative and absolute operands always use four bytes
+    assert_eq!(
+        code(|asm| vmovdqu32_zmm_mem(asm, ZMM7, Mem::Base(RIP, 0x40), unmasked)),
+        [0x62, 0xf1, 0x7e, 0x48, 0x6f, 0x3d, 0x40, 0x00, 0x00, 0x00]
+    );
+}
+
+#[test]
+fn evex_compares_and_masks() {
+    type Cmp = extern "C" fn(&mut Assembler, KRegister, ZMMRegister, ZMMRegister, Masking);
+    type CmpImm = extern "C" fn(&mut Assembler, KRegister, ZMMRegister, ZMMRegister, u8, Masking);
+    type CmpImmBcst = extern "C" fn(&mut Assembler, KRegister, ZMMRegister, Mem, u8, Masking);
+
+    let cmps: [(&str, Cmp); 4] = [
+        ("vpcmpeqd", vpcmpeqd_zmm),
+        ("vpcmpeqq", vpcmpeqq_zmm),
+        ("vpcmpgtd", vpcmpgtd_zmm),
+        ("vpcmpgtq", vpcmpgtq_zmm),
+    ];
+    // capstone prints the predicate of the integer compares as part of the
+    // mnemonic, 4 is "not equal"
+    let preds: [(&str, CmpImm, CmpImmBcst, bool); 6] = [
+        ("vcmpneqps", vcmpps_zmm, vcmpps_zmm_bcst, false),
+        ("vcmpneqpd", vcmppd_zmm, vcmppd_zmm_bcst, true),
+        ("vpcmpneqd", vpcmpd_zmm, vpcmpd_zmm_bcst, false),
+        ("vpcmpneqq", vpcmpq_zmm, vpcmpq_zmm_bcst, true),
+        ("vpcmpnequd", vpcmpud_zmm, vpcmpud_zmm_bcst, false),
+        ("vpcmpnequq", vpcmpuq_zmm, vpcmpuq_zmm_bcst, true),
+    ];
+    let ks = [K0, K1, K2, K3, K4, K5, K6, K7];
+    let mut c = Checker::new();
+
+    for &k in ks.iter() {
+        for &src1 in SRCS.iter() {
+            for &src2 in SRCS.iter() {
+                let (k_str, a, b) = (format!("k{}", k as usize), zmm(src1), zmm(src2));
+
+                for &(name, emit) in cmps.iter() {
+                    let expected = format!("{} {}, {}, {}", name, k_str, a, b);
+                    c.check(expected, |asm| emit(asm, k, src1, src2, Masking::Unmasked));
+                }
+
+                for &(name, emit, _, _) in preds.iter() {
+                    let expected = format!("{} {}, {}, {}", name, k_str, a, b);
+                    c.check(expected, |asm| {
+                        emit(asm, k, src1, src2, 4, Masking::Unmasked)
+                    });
+
+                    // capstone does not decode compares under a mask, only EVEX.aaa changes
+                    let mut expected = code(|asm| emit(asm, k, src1, src2, 4, Masking::Unmasked));
+                    expected[3] |= 1;
+                    assert_eq!(
+                        code(|asm| emit(asm, k, src1, src2, 4, Masking::Merge(K1))),
+                        expected
+                    );
+                }
+            }
+        }
+    }
+
+    for (mem, addr) in evex_mem_forms() {
+        for &(name, _, emit, qword) in preds.iter() {
+            let (size, count) = element(qword);
+            let expected = format!("{} k2, zmm11, {} ptr {}{{1to{}}}", name, size, addr, count);
+            let bytes = code(|asm| emit(asm, K2, ZMM11, mem, 4, Masking::Unmasked));
+
+            // capstone does not decode vcmpps and vcmppd with a broadcast, their
+            // operands are those of vaddps and vaddpd followed by the predicate
+            if name.starts_with("vp") {
+                c.check(expected, |asm| {
+                    emit(asm, K2, ZMM11, mem, 4, Masking::Unmasked)
+                });
+            } else {
+                let (_, reference_bcst) = reference(qword);
+                let mut reference = operands(&code(|asm| {
+                    reference_bcst(asm, ZMM2, ZMM11, mem, Masking::Unmasked)
+                }));
+                reference.push(4);
+                assert_eq!(operands(&bytes), reference, "{}", expected);
+            }
+        }
+
+        c.check(format!("vpcmpeqd k7, zmm4, zmmword ptr {}", addr), |asm| {
+            vpcmpeqd_zmm_mem(asm, K7, ZMM4, mem, Masking::Unmasked)
+        });
+    }
+
+    for &dst in ks.iter() {
+        for &reg in GPRS.iter() {
+            let k = format!("k{}", dst as usize);
+            c.check(format!("kmovw {}, {}", k, r32(reg)), |asm| {
+                kmovw_k_reg(asm, dst, reg)
+            });
+            c.check(format!("kmovw {}, {}", r32(reg), k), |asm| {
+                kmovw_reg_k(asm, reg, dst)
+            });
+        }
+
+        for &src in ks.iter() {
+            let (d, s) = (format!("k{}", dst as usize), format!("k{}", src as usize));
+            c.check(format!("kmovw {}, {}", d, s), |asm| kmovw(asm, dst, src));
+            c.check(format!("knotw {}, {}", d, s), |asm| knotw(asm, dst, src));
+            c.check(format!("kortestw {}, {}", d, s), |asm| {
+                kortestw(asm, dst, src)
+            });
+            c.check(format!("kandw {}, k3, {}", d, s), |asm| {
+                kandw(asm, dst, K3, src)
+            });
+            c.check(format!("korw {}, {}, k6", d, s), |asm| {
+                korw(asm, dst, src, K6)
+            });
+            c.check(format!("kxorw {}, {}, {}", d, s, s), |asm| {
+                kxorw(asm, dst, src, src)
+            });
+        }
+    }
+
+    c.finish();
+}
+
+#[test]
+fn evex_moves_and_broadcasts() {
+    type Mov = extern "C" fn(&mut Assembler, ZMMRegister, ZMMRegister, Masking);
+    type Load = extern "C" fn(&mut Assembler, ZMMRegister, Mem, Masking);
+    type Store = extern "C" fn(&mut Assembler, Mem, ZMMRegister, Masking);
+    type Bcast = extern "C" fn(&mut Assembler, ZMMRegister, XMMRegister, Masking);
+
+    macro_rules! movs {
+        ($($name:ident),* $(,)*) => {
+            paste::item! {
+                [$((
+                    stringify!($name),
+                    [<$name _zmm>] as Mov,
+                    [<$name _zmm_mem>] as Load,
+                    [<$name _zmm_store>] as Store,
+                )),*]
+            }
+        };
+    }
+
+    let moves =
+        movs![vmovups, vmovupd, vmovaps, vmovapd, vmovdqa32, vmovdqa64, vmovdqu32, vmovdqu64,];
+    let broadcasts: [(&str, &str, Bcast, Load); 4] = [
+        (
+            "vbroadcastss",
+            "dword",
+            vbroadcastss_zmm,
+            vbroadcastss_zmm_mem,
+        ),
+        (
+            "vbroadcastsd",
+            "qword",
+            vbroadcastsd_zmm,
+            vbroadcastsd_zmm_mem,
+        ),
+        (
+            "vpbroadcastd",
+            "dword",
+            vpbroadcastd_zmm,
+            vpbroadcastd_zmm_mem,
+        ),
+        (
+            "vpbroadcastq",
+            "qword",
+            vpbroadcastq_zmm,
+            vpbroadcastq_zmm_mem,
+        ),
+    ];
+    let mut c = Checker::new();
+
+    for &(name, mov, load, store) in moves.iter() {
+        for &dst in ZMMS.iter() {
+            for &src in SRCS.iter() {
+                c.check(format!("{} {}, {}", name, zmm(dst), zmm(src)), |asm| {
+                    mov(asm, dst, src, Masking::Unmasked)
+                });
+            }
+        }
+
+        for &masking in MASKINGS.iter() {
+            let dst = masked(zmm(ZMM22), masking);
+            c.check(format!("{} {}, zmm9", name, dst), |asm| {
+                mov(asm, ZMM22, ZMM9, masking)
+            });
+        }
+
+        for (mem, addr) in evex_mem_forms() {
+            for &reg in [ZMM2, ZMM13, ZMM29].iter() {
+                c.check(
+                    format!("{} {}, zmmword ptr {}", name, zmm(reg), addr),
+                    |asm| load(asm, reg, mem, Masking::Unmasked),
+                );
+            }
+
+            c.check(
+                format!("{} zmmword ptr {} {{k4}}, zmm18", name, addr),
+                |asm| store(asm, mem, ZMM18, Masking::Merge(K4)),
+            );
+        }
+    }
+
+    for &(name, size, bcast, load) in broadcasts.iter() {
+        for &dst in ZMMS.iter() {
+            for &src in XMMS.iter() {
+                c.check(format!("{} {}, {}", name, zmm(dst), xmm(src)), |asm| {
+                    bcast(asm, dst, src, Masking::Unmasked)
+                });
+            }
+        }
+
+        for (mem, addr) in evex_mem_forms() {
+            let dst = masked(zmm(ZMM30), Masking::Zero(K1));
+            c.check(format!("{} {}, {} ptr {}", name, dst, size, addr), |asm| {
+                load(asm, ZMM30, mem, Masking::Zero(K1))
+            });
+        }
+    }
+
+    for &dst in ZMMS.iter() {
+        for &src in GPRS.iter() {
+            c.check(format!("vpbroadcastd {}, {}", zmm(dst), r32(src)), |asm| {
+                vpbroadcastd_zmm_reg(asm, dst, src, Masking::Unmasked)
+            });
+            c.check(format!("vpbroadcastq {}, {}", zmm(dst), r64(src)), |asm| {
+                vpbroadcastq_zmm_reg(asm, dst, src, Masking::Unmasked)
+            });
+        }
+    }
+
+    c.finish();
+}
+
+#[test]
+fn evex_immediates() {
+    type Imm = extern "C" fn(&mut Assembler, ZMMRegister, ZMMRegister, ZMMRegister, u8, Masking);
+    type ImmMem = extern "C" fn(&mut Assembler, ZMMRegister, ZMMRegister, Mem, u8, Masking);
+
+    // vpternlogd and vpternlogq are in `evex_objdump_bytes`
+    let table: [(&str, bool, Imm, ImmMem, ImmMem); 2] = [
+        (
+            "vshufps",
+            false,
+            vshufps_zmm,
+            vshufps_zmm_mem,
+            vshufps_zmm_bcst,
+        ),
+        (
+            "vshufpd",
+            true,
+            vshufpd_zmm,
+            vshufpd_zmm_mem,
+            vshufpd_zmm_bcst,
+        ),
+    ];
+    let mut c = Checker::new();
+
+    for &(name, qword, emit, emit_mem, emit_bcst) in table.iter() {
+        let (size, count) = element(qword);
+
+        for &dst in ZMMS.iter() {
+            for &src in SRCS.iter() {
+                let expected = format!("{} {}, zmm12, {}, 0x96", name, zmm(dst), zmm(src));
+                c.check(expected, |asm| {
+                    emit(asm, dst, ZMM12, src, 0x96, Masking::Unmasked)
+                });
+            }
+        }
+
+        for (mem, addr) in evex_mem_forms() {
+            c.check(
+                format!("{} zmm3, zmm13, zmmword ptr {}, 0x1b", name, addr),
+                |asm| emit_mem(asm, ZMM3, ZMM13, mem, 0x1b, Masking::Unmasked),
+            );
+            // capstone does not decode broadcasts of vshufps and vshufpd, their
+            // operands are those of vaddps and vaddpd followed by the immediate
+            let (_, reference_bcst) = reference(qword);
+            let mut reference = operands(&code(|asm| {
+                reference_bcst(asm, ZMM3, ZMM13, mem, Masking::Zero(K1))
+            }));
+            reference.push(0x1b);
+            assert_eq!(
+                operands(&code(|asm| {
+                    emit_bcst(asm, ZMM3, ZMM13, mem, 0x1b, Masking::Zero(K1))
+                })),
+                reference,
+                "{} zmm3 {{k1}} {{z}}, zmm13, {} ptr {}{{1to{}}}, 0x1b",
+                name,
+                size,
+                addr,
+                count
+            );
+        }
+    }
+
+    c.finish();
+}
+
+#[test]
+fn evex_invalid_operands() {
+    let mut c = Checker::new();
+
+    c.rejects("k0 as merge mask", |asm| {
+        vaddps_zmm(asm, ZMM0, ZMM1, ZMM2, Masking::Merge(K0))
+    });
+    c.rejects("k0 as zero mask", |asm| {
+        vaddps_zmm(asm, ZMM0, ZMM1, ZMM2, Masking::Zero(K0))
+    });
+    c.rejects("zero masking of a store", |asm| {
+        vmovups_zmm_store(asm, Mem::Base(RAX, 0), ZMM1, Masking::Zero(K1))
+    });
+    c.rejects("zero masking of a compare", |asm| {
+        vpcmpeqd_zmm(asm, K1, ZMM1, ZMM2, Masking::Zero(K2))
+    });
+    c.rejects("illegal register", |asm| {
+        vaddps_zmm(asm, kNoZmmRegister, ZMM1, ZMM2, Masking::Unmasked)
+    });
+    c.rejects("rsp as index", |asm| {
+        vaddps_zmm_mem(
+            asm,
+            ZMM0,
+            ZMM1,
+            Mem::Index(RAX, RSP, 1, 0),
+            Masking::Unmasked,
+        )
+    });
+
+    c.finish();
+}
+
+/// Forms the capstone used here cannot decode, or decodes with a wrong disp8*N:
+/// embedded rounding, masked compares, FMA, vpshufb, vpternlog, the opmask
+/// instructions with W1, and memory operands indexed by R8 to R15 or with the upper
+/// sixteen registers as first source. The bytes are checked against objdump.
+#[test]
+fn evex_objdump_bytes() {
+    let unmasked = Masking::Unmasked;
+    let k5 = Masking::Merge(K5);
+    let cases: [(&str, Vec<u8>, &[u8]); 38] = [
+        (
+            "vaddps zmm0{k6}{z},zmm1,zmm2{rn-sae}",
+            code(|asm| {
+                vaddps_zmm_round(asm, ZMM0, ZMM1, ZMM2, RoundMode::Nearest, Masking::Zero(K6))
+            }),
+            &[0x62, 0xf1, 0x74, 0x9e, 0x58, 0xc2],
+        ),
+        (
+            "vsqrtpd zmm3,zmm20{rz-sae}",
+            code(|asm| vsqrtpd_zmm_round(asm, ZMM3, ZMM20, RoundMode::Toward, unmasked)),
+            &[0x62, 0xb1, 0xfd, 0x78, 0x51, 0xdc],
+        ),
+        (
+            "vfmadd231ps zmm31{k2},zmm16,zmm9{ru-sae}",
+            code(|asm| {
+                vfmadd231ps_zmm_round(asm, ZMM31, ZMM16, ZMM9, RoundMode::Up, Masking::Merge(K2))
+            }),
+            &[0x62, 0x42, 0x7d, 0x52, 0xb8, 0xf9],
+        ),
+        (
+            "vdivpd zmm8,zmm27,zmm17{rd-sae}",
+            code(|asm| vdivpd_zmm_round(asm, ZMM8, ZMM27, ZMM17, RoundMode::Down, unmasked)),
+            &[0x62, 0x31, 0xa5, 0x30, 0x5e, 0xc1],
+        ),
+        (
+            "vcmpneqps k0{k1},zmm0,zmm0",
+            code(|asm| vcmpps_zmm(asm, K0, ZMM0, ZMM0, 4, Masking::Merge(K1))),
+            &[0x62, 0xf1, 0x7c, 0x49, 0xc2, 0xc0, 0x04],
+        ),
+        (
+            "vpcmpltuq k7{k7},zmm27,zmm9",
+            code(|asm| vpcmpuq_zmm(asm, K7, ZMM27, ZMM9, 1, Masking::Merge(K7))),
+            &[0x62, 0xd3, 0xa5, 0x47, 0x1e, 0xf9, 0x01],
+        ),
+        (
+            "vpcmpeqd k3{k5},zmm16,zmm31",
+            code(|asm| vpcmpeqd_zmm(asm, K3, ZMM16, ZMM31, Masking::Merge(K5))),
+            &[0x62, 0x91, 0x7d, 0x45, 0x76, 0xdf],
+        ),
+        (
+            "vcmpneqps k2,zmm19,DWORD BCST [rax+0x8]",
+            code(|asm| vcmpps_zmm_bcst(asm, K2, ZMM19, Mem::Base(RAX, 8), 4, unmasked)),
+            &[0x62, 0xf1, 0x64, 0x50, 0xc2, 0x50, 0x02, 0x04],
+        ),
+        (
+            "vpternlogd zmm0,zmm12,zmm0,0x96",
+            code(|asm| vpternlogd_zmm(asm, ZMM0, ZMM12, ZMM0, 0x96, unmasked)),
+            &[0x62, 0xf3, 0x1d, 0x48, 0x25, 0xc0, 0x96],
+        ),
+        (
+            "vpternlogq zmm31{k3},zmm16,zmm9,0xca",
+            code(|asm| vpternlogq_zmm(asm, ZMM31, ZMM16, ZMM9, 0xca, Masking::Merge(K3))),
+            &[0x62, 0x43, 0xfd, 0x43, 0x25, 0xf9, 0xca],
+        ),
+        (
+            "vpternlogd zmm3,zmm21,ZMMWORD PTR [rax+0x40],0x1b",
+            code(|asm| vpternlogd_zmm_mem(asm, ZMM3, ZMM21, Mem::Base(RAX, 0x40), 0x1b, unmasked)),
+            &[0x62, 0xf3, 0x55, 0x40, 0x25, 0x58, 0x01, 0x1b],
+        ),
+        (
+            "vpternlogq zmm3{k1}{z},zmm21,QWORD BCST [rbx+rcx*8-0x8],0x1b",
+            code(|asm| {
+                vpternlogq_zmm_bcst(
+                    asm,
+                    ZMM3,
+                    ZMM21,
+                    Mem::Index(RBX, RCX, 8, -8),
+                    0x1b,
+                    Masking::Zero(K1),
+                )
+            }),
+            &[0x62, 0xf3, 0xd5, 0xd1, 0x25, 0x5c, 0xcb, 0xff, 0x1b],
+        ),
+        (
+            "vfmadd132ps zmm31{k5},zmm16,zmm9",
+            code(|asm| vfmadd132ps_zmm(asm, ZMM31, ZMM16, ZMM9, k5)),
+            &[0x62, 0x42, 0x7d, 0x45, 0x98, 0xf9],
+        ),
+        (
+            "vfmadd213pd zmm31{k5},zmm16,zmm9",
+            code(|asm| vfmadd213pd_zmm(asm, ZMM31, ZMM16, ZMM9, k5)),
+            &[0x62, 0x42, 0xfd, 0x45, 0xa8, 0xf9],
+        ),
+        (
+            "vfmadd231ps zmm31{k5},zmm16,zmm9",
+            code(|asm| vfmadd231ps_zmm(asm, ZMM31, ZMM16, ZMM9, k5)),
+            &[0x62, 0x42, 0x7d, 0x45, 0xb8, 0xf9],
+        ),
+        (
+            "vfmsub132pd zmm31{k5},zmm16,zmm9",
+            code(|asm| vfmsub132pd_zmm(asm, ZMM31, ZMM16, ZMM9, k5)),
+            &[0x62, 0x42, 0xfd, 0x45, 0x9a, 0xf9],
+        ),
+        (
+            "vfmsub213ps zmm31{k5},zmm16,zmm9",
+            code(|asm| vfmsub213ps_zmm(asm, ZMM31, ZMM16, ZMM9, k5)),
+            &[0x62, 0x42, 0x7d, 0x45, 0xaa, 0xf9],
+        ),
+        (
+            "vfmsub231pd zmm31{k5},zmm16,zmm9",
+            code(|asm| vfmsub231pd_zmm(asm, ZMM31, ZMM16, ZMM9, k5)),
+            &[0x62, 0x42, 0xfd, 0x45, 0xba, 0xf9],
+        ),
+        (
+            "vfnmadd132ps zmm31{k5},zmm16,zmm9",
+            code(|asm| vfnmadd132ps_zmm(asm, ZMM31, ZMM16, ZMM9, k5)),
+            &[0x62, 0x42, 0x7d, 0x45, 0x9c, 0xf9],
+        ),
+        (
+            "vfnmadd213pd zmm31{k5},zmm16,zmm9",
+            code(|asm| vfnmadd213pd_zmm(asm, ZMM31, ZMM16, ZMM9, k5)),
+            &[0x62, 0x42, 0xfd, 0x45, 0xac, 0xf9],
+        ),
+        (
+            "vfnmadd231ps zmm31{k5},zmm16,zmm9",
+            code(|asm| vfnmadd231ps_zmm(asm, ZMM31, ZMM16, ZMM9, k5)),
+            &[0x62, 0x42, 0x7d, 0x45, 0xbc, 0xf9],
+        ),
+        (
+            "vfnmsub132pd zmm31{k5},zmm16,zmm9",
+            code(|asm| vfnmsub132pd_zmm(asm, ZMM31, ZMM16, ZMM9, k5)),
+            &[0x62, 0x42, 0xfd, 0x45, 0x9e, 0xf9],
+        ),
+        (
+            "vfnmsub213ps zmm31{k5},zmm16,zmm9",
+            code(|asm| vfnmsub213ps_zmm(asm, ZMM31, ZMM16, ZMM9, k5)),
+            &[0x62, 0x42, 0x7d, 0x45, 0xae, 0xf9],
+        ),
+        (
+            "vfnmsub231pd zmm31{k5},zmm16,zmm9",
+            code(|asm| vfnmsub231pd_zmm(asm, ZMM31, ZMM16, ZMM9, k5)),
+            &[0x62, 0x42, 0xfd, 0x45, 0xbe, 0xf9],
+        ),
+        (
+            "vfmadd231ps zmm4,zmm21,ZMMWORD PTR [r13+0x80]",
+            code(|asm| vfmadd231ps_zmm_mem(asm, ZMM4, ZMM21, Mem::Base(R13, 0x80), unmasked)),
+            &[0x62, 0xd2, 0x55, 0x40, 0xb8, 0x65, 0x02],
+        ),
+        (
+            "vfnmsub132pd zmm4,zmm21,QWORD BCST [rsp-0x8]",
+            code(|asm| vfnmsub132pd_zmm_bcst(asm, ZMM4, ZMM21, Mem::Base(RSP, -8), unmasked)),
+            &[0x62, 0xf2, 0xd5, 0x50, 0x9e, 0x64, 0x24, 0xff],
+        ),
+        (
+            "vpshufb zmm0,zmm1,zmm2",
+            code(|asm| vpshufb_zmm(asm, ZMM0, ZMM1, ZMM2, unmasked)),
+            &[0x62, 0xf2, 0x75, 0x48, 0x00, 0xc2],
+        ),
+        (
+            "vpshufb zmm28{k3}{z},zmm19,zmm11",
+            code(|asm| vpshufb_zmm(asm, ZMM28, ZMM19, ZMM11, Masking::Zero(K3))),
+            &[0x62, 0x42, 0x65, 0xc3, 0x00, 0xe3],
+        ),
+        (
+            "vpshufb zmm7,zmm8,ZMMWORD PTR [rax+rbx*2+0x40]",
+            code(|asm| vpshufb_zmm_mem(asm, ZMM7, ZMM8, Mem::Index(RAX, RBX, 2, 0x40), unmasked)),
+            &[0x62, 0xf2, 0x3d, 0x48, 0x00, 0x7c, 0x58, 0x01],
+        ),
+        (
+            "vaddps zmm1,zmm30,ZMMWORD PTR [rsp]",
+            code(|asm| vaddps_zmm_mem(asm, ZMM1, ZMM30, Mem::Base(RSP, 0), unmasked)),
+            &[0x62, 0xf1, 0x0c, 0x40, 0x58, 0x0c, 0x24],
+        ),
+        (
+            "vmovups zmm2,ZMMWORD PTR [r8+r9*8+0x8]",
+            code(|asm| vmovups_zmm_mem(asm, ZMM2, Mem::Index(R8, R9, 8, 8), unmasked)),
+            &[
+                0x62, 0x91, 0x7c, 0x48, 0x10, 0x94, 0xc8, 0x08, 0x00, 0x00, 0x00,
+            ],
+        ),
+        (
+            "vaddpd zmm1,zmm30,QWORD BCST [rax+r15*2+0x10]",
+            code(|asm| vaddpd_zmm_bcst(asm, ZMM1, ZMM30, Mem::Index(RAX, R15, 2, 0x10), unmasked)),
+            &[0x62, 0xb1, 0x8d, 0x50, 0x58, 0x4c, 0x78, 0x02],
+        ),
+        (
+            "vmovdqu64 ZMMWORD PTR [r12*4+0x100]{k4},zmm17",
+            code(|asm| {
+                vmovdqu64_zmm_store(asm, Mem::Offset(R12, 4, 0x100), ZMM17, Masking::Merge(K4))
+            }),
+            &[
+                0x62, 0xa1, 0xfe, 0x4c, 0x7f, 0x0c, 0xa5, 0x00, 0x01, 0x00, 0x00,
+            ],
+        ),
+        (
+            "kmovq k7,r15",
+            code(|asm| kmovq_k_reg(asm, K7, R15)),
+            &[0xc4, 0xc1, 0xfb, 0x92, 0xff],
+        ),
+        (
+            "kmovq k0,rax",
+            code(|asm| kmovq_k_reg(asm, K0, RAX)),
+            &[0xc4, 0xe1, 0xfb, 0x92, 0xc0],
+        ),
+        (
+            "kmovq r12,k5",
+            code(|asm| kmovq_reg_k(asm, R12, K5)),
+            &[0xc4, 0x61, 0xfb, 0x93, 0xe5],
+        ),
+        (
+            "kmovq rcx,k1",
+            code(|asm| kmovq_reg_k(asm, RCX, K1)),
+            &[0xc4, 0xe1, 0xfb, 0x93, 0xc9],
+        ),
+        (
+            "kortestq k3,k6",
+            code(|asm| kortestq(asm, K3, K6)),
+            &[0xc4, 0xe1, 0xf8, 0x98, 0xde],
+        ),
+    ];
+
+    for (objdump, actual, expected) in cases.iter() {
+        assert_eq!(&actual[..], *expected, "{}", objdump);
+    }
+}
@@ -0,0 +1,219 @@
+//! Encoders of instructions from an extension the target lacks.
+
+use crate::*;
+use jazz_jit::assembler::{AsmDiagnostic, Assembler};
+use jazz_jit::assembler_x64::*;
+use jazz_jit::avx::*;
+use jazz_jit::avx512::{self, Masking};
+use jazz_jit::{CpuFeature, CpuFeatures};
+
+/// The extensions `emit` is missing on a target with all of them but `without`.
+fn missing(without: &[CpuFeature], emit: impl FnOnce(&mut Assembler)) -> Vec<CpuFeature> {
+    let features = without
+        .iter()
+        .fold(CpuFeatures::all(), |features, &feature| {
+            features.without(feature)
+        });
+    let mut asm = Assembler::with_features(features);
+    emit(&mut asm);
+
+    asm.diagnostics
+        .iter()
+        .filter_map(|diagnostic| match *diagnostic {
+            AsmDiagnostic::MissingCpuFeature { feature, .. } => Some(feature),
+            _ => None,
+        })
+        .collect()
+}
+
+#[test]
+fn encoders_require_extensions() {
+    use jazz_jit::CpuFeature::*;
+
+    let round = |asm: &mut Assembler| roundsd(asm, XMM1, XMM2, RoundMode::Down);
+    assert_eq!(missing(&[Sse41], round), [Sse41]);
+    assert_eq!(missing(&[Avx2], round), []);
+    assert_eq!(missing(&[Ssse3], |asm| pshufb(asm, XMM1, XMM2)), [Ssse3]);
+
+    // BMI uses VEX prefixes, but not AVX
+    assert_eq!(missing(&[Popcnt], |asm| popcnt(asm, 1, RAX, RCX)), [Popcnt]);
+    assert_eq!(missing(&[Lzcnt], |asm| lzcnt(asm, 1, RAX, RCX)), [Lzcnt]);
+    assert_eq!(missing(&[Bmi1], |asm| tzcnt(asm, 0, RAX, RCX)), [Bmi1]);
+    assert_eq!(missing(&[Avx], |asm| andn(asm, 1, RAX, RCX, RDX)), []);
+    assert_eq!(missing(&[Bmi2], |asm| pext(asm, 1, RAX, RCX, RDX)), [Bmi2]);
+
+    // most integer instructions were extended to 256 bits by AVX2
+    assert_eq!(missing(&[Avx2], |asm| vpaddd(asm, XMM1, XMM2, XMM3)), []);
+    assert_eq!(
+        missing(&[Avx2], |asm| vpaddd_ymm(asm, YMM1, YMM2, YMM3)),
+        [Avx2]
+    );
+    assert_eq!(
+        missing(&[Avx2], |asm| vaddps_ymm(asm, YMM1, YMM2, YMM3)),
+        []
+    );
+    assert_eq!(
+        missing(&[Avx2], |asm| vpsllvd(asm, XMM1, XMM2, XMM3)),
+        [Avx2]
+    );
+    assert_eq!(
+        missing(&[Avx2], |asm| vpermq(asm, YMM1, YMM2, 0x1b)),
+        [Avx2]
+    );
+    assert_eq!(missing(&[Avx], |asm| vaddps(asm, XMM1, XMM2, XMM3)), [Avx]);
+    assert_eq!(
+        missing(&[Fma], |asm| vfmadd231ps(asm, XMM1, XMM2, XMM3)),
+        [Fma]
+    );
+
+    // broadcasts from a register are AVX2, vbroadcastss from memory is AVX
+    let mem = Mem::Base(RAX, 0);
+    assert_eq!(
+        missing(&[Avx2], |asm| vbroadcastss(asm, XMM1, XMM2)),
+        [Avx2]
+    );
+    assert_eq!(missing(&[Avx2], |asm| vbroadcastss_mem(asm, XMM1, mem)), []);
+    assert_eq!(
+        missing(&[Avx2], |asm| vpbroadcastd_mem(asm, XMM1, mem)),
+        [Avx2]
+    );
+
+    let gather = |asm: &mut Assembler| vgatherdps(asm, XMM1, RAX, XMM2, 4, 0, XMM3);
+    assert_eq!(missing(&[Avx2], gather), [Avx2]);
+
+    let no_mask = Masking::Unmasked;
+    let evex_add = |asm: &mut Assembler| avx512::vpaddd_zmm(asm, ZMM1, ZMM2, ZMM3, no_mask);
+    let evex_add_bytes = |asm: &mut Assembler| avx512::vpaddb_zmm(asm, ZMM1, ZMM2, ZMM3, no_mask);
+    assert_eq!(missing(&[Avx512F], evex_add), [Avx512F]);
+    assert_eq!(missing(&[Avx512Bw], evex_add), []);
+    assert_eq!(missing(&[Avx512Bw], evex_add_bytes), [Avx512Bw]);
+    assert_eq!(missing(&[Avx512Bw], |asm| avx512::kmovw(asm, K1, K2)), []);
+    assert_eq!(
+        missing(&[Avx512Bw], |asm| avx512::kortestq(asm, K1, K2)),
+        [Avx512Bw]
+    );
+}
+
+#[test]
+fn missing_extensions_are_reported_once_per_instruction() {
+    let mut asm = Assembler::with_features(CpuFeatures::none().with(CpuFeature::Sse2));
+    vaddps(&mut asm, XMM1, XMM2, XMM3);
+    vmovdqu_store(&mut asm, Mem::Base(RAX, 0), XMM1);
+
+    assert_eq!(
+        asm.diagnostics,
+        [
+            AsmDiagnostic::MissingCpuFeature {
+                at: 0,
+                feature: CpuFeature::Avx,
+            },
+            AsmDiagnostic::MissingCpuFeature {
+                at: 4,
+                feature: CpuFeature::Avx,
+            },
+        ]
+    );
+    assert_eq!(
+        asm.diagnostics[0].to_string(),
+        "instruction at 0x0 needs avx, which the target does not support"
+    );
+    assert!(asm.finalize().is_err());
+}
+
+#[test]
+fn new_assemblers_target_every_extension() {
+    let mut asm = Assembler::new();
+    assert_eq!(asm.features, CpuFeatures::all());
+    vaddps(&mut asm, XMM1, XMM2, XMM3);
+    avx512::kortestq(&mut asm, K1, K2);
+    assert_eq!(asm.fix_forward_jumps(), Ok(()));
+}
+
+#[test]
+fn resolving_jumps_returns_the_errors() {
+    let mut asm = Assembler::with_features(CpuFeatures::none().with(CpuFeature::Sse2));
+    vaddps(&mut asm, XMM1, XMM2, XMM3);
+    let lbl = asm.create_label();
+    asm.jump(lbl);
+
+    // resolving again reports every problem once
+    let first = asm.fix_forward_jumps().unwrap_err();
+    let second = asm.fix_forward_jumps().unwrap_err();
+    assert_eq!(first.diagnostics, second.diagnostics);
+    assert!(matches!(
+        first.diagnostics[..],
+        [
+            AsmDiagnostic::MissingCpuFeature { .. },
+            AsmDiagnostic::UnboundLabel { .. }
+        ]
+    ));
+    assert_eq!(asm.finalize().unwrap_err().diagnostics, first.diagnostics);
+}
+
+#[test]
+fn generators_query_the_target() {
+    let sse2 = CpuFeatures::none().with(CpuFeature::Sse2);
+    let asm = Assembler::with_features(sse2);
+    assert!(asm.has_feature(CpuFeature::Sse2));
+    assert!(!asm.has_feature(CpuFeature::Sse41));
+
+    let features = sse2.with(CpuFeature::Avx).with(CpuFeature::Bmi2);
+    assert_eq!(features.to_string(), "sse2 bmi2 avx");
+    assert_eq!(format!("{:?}", features), "{Sse2, Bmi2, Avx}");
+    assert_eq!(
+        features.without(CpuFeature::Avx),
+        sse2.with(CpuFeature::Bmi2)
+    );
+    assert_eq!(features.iter().count(), 3);
+}
+
+#[test]
+fn override_for_testing_changes_host_assemblers() {
+    let host = CpuFeatures::host();
+    assert_eq!(CpuFeatures::current(), host);
+    assert_eq!(Assembler::for_host().features, host);
+
+    let sse2 = CpuFeatures::none().with(CpuFeature::Sse2);
+
+    {
+        let _sse2 = CpuFeatures::override_for_testing(sse2);
+        assert_eq!(Assembler::for_host().features, sse2);
+
+        {
+            let _none = CpuFeatures::override_for_testing(CpuFeatures::none());
+            let mut asm = Assembler::for_host();
+            roundsd(&mut asm, XMM1, XMM2, RoundMode::Down);
+            assert!(asm.has_errors());
+        }
+
+        assert_eq!(CpuFeatures::current(), sse2);
+
+        // other threads still see the host
+        let other = std::thread::spawn(CpuFeatures::current).join().unwrap();
+        assert_eq!(other, host);
+    }
+
+    assert_eq!(CpuFeatures::current(), host);
+}
+
+#[test]
+#[cfg(target_arch = "x86_64")]
+fn host_features_are_detected() {
+    let host = CpuFeatures::host();
+
+    for feature in CpuFeature::ALL.iter().cloned() {
+        let detected = match feature {
+            CpuFeature::Sse2 => is_x86_feature_detected!("sse2"),
+            CpuFeature::Sse41 => is_x86_feature_detected!("sse4.1"),
+            CpuFeature::Popcnt => is_x86_feature_detected!("popcnt"),
+            CpuFeature::Avx2 => is_x86_feature_detected!("avx2"),
+            CpuFeature::Avx512F => is_x86_feature_detected!("avx512f"),
+            _ => continue,
+        };
+
+        assert_eq!(host.has(feature), detected, "{}", feature);
+    }
+
+    // every x86-64 CPU has SSE2
+    assert!(host.has(CpuFeature::Sse2));
+}
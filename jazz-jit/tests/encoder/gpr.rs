@@ -0,0 +1,726 @@
+//! Register and immediate forms of the integer instructions.
+
+use crate::*;
+use jazz_jit::assembler::Assembler;
+use jazz_jit::assembler_x64::*;
+use jazz_jit::{CpuFeature, CpuFeatures, MachineMode};
+
+type RegReg = fn(&mut Assembler, u8, Register, Register);
+type Reg = fn(&mut Assembler, u8, Register);
+
+const IMMS: [i32; 8] = [0, 1, -1, 0x7f, 0x80, -0x80, 0x1234_5678, i32::MIN];
+
+#[test]
+fn immediates_compare_at_operand_width() {
+    assert_eq!(normalize("add eax, 0xffffff80"), "add eax, -0x80");
+    assert_eq!(
+        normalize("mov qword ptr [rax], 0xffffffffffffff80"),
+        "mov qword ptr [rax], -0x80"
+    );
+    assert_eq!(normalize("add al, 0x80"), "add al, -0x80");
+    // a wrongly sign-extended imm8 does not pass for 0x80
+    assert_eq!(normalize("add eax, 0x80"), "add eax, 0x80");
+    assert_eq!(normalize("add rcx, 0xff80"), "add rcx, 0xff80");
+}
+
+#[test]
+fn alu_reg_reg() {
+    let ops: [(&str, RegReg); 7] = [
+        ("or", emit_or_reg_reg),
+        ("and", emit_and_reg_reg),
+        ("xor", emit_xor_reg_reg),
+        ("mov", emit_mov_reg_reg),
+        ("add", emit_add_reg_reg),
+        ("sub", emit_sub_reg_reg),
+        ("cmp", emit_cmp_reg_reg),
+    ];
+    let mut c = Checker::new();
+
+    for &(name, emit) in ops.iter() {
+        for x64 in 0..2 {
+            for &src in GPRS.iter() {
+                for &dest in GPRS.iter() {
+                    let expected = format!("{} {}, {}", name, gpr(x64, dest), gpr(x64, src));
+                    c.check(expected, |asm| emit(asm, x64, src, dest));
+                }
+            }
+        }
+    }
+
+    c.finish();
+}
+
+#[test]
+fn imul_xchg_test() {
+    let mut c = Checker::new();
+
+    for x64 in 0..2 {
+        for &src in GPRS.iter() {
+            for &dest in GPRS.iter() {
+                let (d, s) = (gpr(x64, dest), gpr(x64, src));
+                c.check(format!("imul {}, {}", d, s), |asm| {
+                    emit_imul_reg_reg(asm, x64, src, dest)
+                });
+                c.check(format!("xchg {}, {}", d, s), |asm| {
+                    xchg(asm, x64, src, dest)
+                });
+            }
+        }
+    }
+
+    for &op1 in GPRS.iter() {
+        for &op2 in GPRS.iter() {
+            c.check(format!("test {}, {}", r32(op2), r32(op1)), |asm| {
+                emit_testl_reg_reg(asm, op1, op2)
+            });
+            c.check(format!("test {}, {}", r64(op2), r64(op1)), |asm| {
+                emit_testq_reg_reg(asm, op1, op2)
+            });
+        }
+    }
+
+    c.finish();
+}
+
+#[test]
+fn cmov() {
+    let mut c = Checker::new();
+
+    for &(cond, cc) in CONDS.iter() {
+        for x64 in 0..2 {
+            for &src in GPRS.iter() {
+                for &dest in GPRS.iter() {
+                    let expected = format!("cmov{} {}, {}", cc, gpr(x64, dest), gpr(x64, src));
+                    c.check(expected, |asm| {
+                        jazz_jit::assembler_x64::cmov(asm, x64, dest, src, cond)
+                    });
+                }
+            }
+        }
+    }
+
+    c.finish();
+}
+
+#[test]
+fn unary() {
+    let ops: [(&str, &str, Reg); 7] = [
+        ("neg", "", emit_neg_reg),
+        ("not", "", emit_not_reg),
+        ("idiv", "", emit_idiv_reg_reg),
+        ("shl", ", cl", emit_shl_reg_cl),
+        ("shr", ", cl", emit_shr_reg_cl),
+        ("sar", ", cl", emit_sar_reg_cl),
+        ("shl", ", cl", emit_sal_reg_cl),
+    ];
+    let mut c = Checker::new();
+
+    for &(name, suffix, emit) in ops.iter() {
+        for x64 in 0..2 {
+            for &reg in GPRS.iter() {
+                let expected = format!("{} {}{}", name, gpr(x64, reg), suffix);
+                c.check(expected, |asm| emit(asm, x64, reg));
+            }
+        }
+    }
+
+    for &reg in GPRS.iter() {
+        c.check(format!("not {}", r8(reg)), |asm| {
+            emit_not_reg_byte(asm, reg)
+        });
+        c.check(format!("push {}", r64(reg)), |asm| emit_pushq_reg(asm, reg));
+        c.check(format!("pop {}", r64(reg)), |asm| emit_popq_reg(asm, reg));
+        c.check(format!("jmp {}", r64(reg)), |asm| emit_jmp_reg(asm, reg));
+        c.check(format!("call {}", r64(reg)), |asm| emit_callq_reg(asm, reg));
+    }
+
+    c.finish();
+}
+
+#[test]
+fn shifts_by_immediate() {
+    let mut c = Checker::new();
+
+    for &reg in GPRS.iter() {
+        for &imm in [1u8, 3, 63].iter() {
+            c.check(format!("shl {}, {}", r64(reg), hex(imm as i64)), |asm| {
+                emit_shlq_reg(asm, imm, reg)
+            });
+            c.check(format!("shl {}, {}", r32(reg), hex(imm as i64)), |asm| {
+                emit_shll_reg(asm, imm, reg)
+            });
+
+            for x64 in 0..2 {
+                c.check(
+                    format!("shr {}, {}", gpr(x64, reg), hex(imm as i64)),
+                    |asm| emit_shr_reg_imm(asm, x64, reg, imm),
+                );
+            }
+        }
+    }
+
+    c.finish();
+}
+
+#[test]
+fn move_immediate() {
+    let mut c = Checker::new();
+
+    for &reg in GPRS.iter() {
+        for &imm in IMMS.iter() {
+            c.check(
+                format!("mov {}, {}", r32(reg), unsigned(imm as i64, 32)),
+                |asm| emit_movl_imm_reg(asm, imm, reg),
+            );
+            c.check(format!("mov {}, {}", r64(reg), hex(imm as i64)), |asm| {
+                emit_movq_imm_reg(asm, imm, reg)
+            });
+        }
+
+        for &imm in [0, -1, 0x1234_5678_9abc_def0, i64::MIN].iter() {
+            c.check(
+                format!("movabs {}, {}", r64(reg), unsigned(imm, 64)),
+                |asm| emit_movq_imm64_reg(asm, imm, reg),
+            );
+        }
+    }
+
+    c.finish();
+}
+
+#[test]
+fn alu_immediate() {
+    type RegImm = fn(&mut Assembler, i32, Register);
+    let ops: [(&str, RegImm); 3] = [
+        ("sub", emit_subq_imm_reg),
+        ("add", emit_addq_imm_reg),
+        ("and", emit_andq_imm_reg),
+    ];
+    let mut c = Checker::new();
+
+    for &reg in GPRS.iter() {
+        for &imm in IMMS.iter() {
+            for &(name, emit) in ops.iter() {
+                let expected = format!("{} {}, {}", name, r64(reg), hex(imm as i64));
+                c.check(expected, |asm| emit(asm, imm, reg));
+            }
+
+            for &(mode, x64) in [
+                (MachineMode::Int32, 0),
+                (MachineMode::Int64, 1),
+                (MachineMode::Ptr, 1),
+            ]
+            .iter()
+            {
+                let expected = format!("cmp {}, {}", gpr(x64, reg), hex(imm as i64));
+                c.check(expected, |asm| emit_cmp_imm_reg(asm, mode, imm, reg));
+            }
+        }
+    }
+
+    c.finish();
+}
+
+#[test]
+fn byte_registers() {
+    let mut c = Checker::new();
+
+    for &reg in GPRS.iter() {
+        for &imm in [1u8, 0x80, 0xff].iter() {
+            let imm_str = unsigned(imm as i64, 8);
+            c.check(format!("xor {}, {}", r8(reg), imm_str), |asm| {
+                emit_xorb_imm_reg(asm, imm, reg)
+            });
+            c.check(format!("and {}, {}", r8(reg), imm_str), |asm| {
+                emit_andb_imm_reg(asm, imm, reg)
+            });
+            c.check(format!("cmp {}, {}", r8(reg), imm_str), |asm| {
+                emit_cmpb_imm_reg(asm, imm, reg)
+            });
+        }
+
+        for &(cond, cc) in CONDS.iter() {
+            c.check(format!("set{} {}", cc, r8(reg)), |asm| {
+                emit_setb_reg(asm, cond, reg)
+            });
+        }
+
+        c.check(format!("setp {}", r8(reg)), |asm| {
+            emit_setb_reg_parity(asm, reg, true)
+        });
+        c.check(format!("setnp {}", r8(reg)), |asm| {
+            emit_setb_reg_parity(asm, reg, false)
+        });
+    }
+
+    for &src in GPRS.iter() {
+        for &dest in GPRS.iter() {
+            c.check(format!("mov {}, {}", r8(dest), r8(src)), |asm| {
+                emit_movb_reg_reg(asm, src, dest)
+            });
+            c.check(format!("movzx {}, {}", r32(dest), r8(src)), |asm| {
+                emit_movzbl_reg_reg(asm, src, dest)
+            });
+            c.check(format!("movsxd {}, {}", r64(dest), r32(src)), |asm| {
+                emit_movsx(asm, src, dest)
+            });
+
+            for x64 in 0..2 {
+                c.check(format!("movzx {}, {}", gpr(x64, dest), r8(src)), |asm| {
+                    emit_movzx_byte(asm, x64, src, dest)
+                });
+            }
+        }
+    }
+
+    c.finish();
+}
+
+#[test]
+fn cmpxchg_xadd() {
+    let modes = [MachineMode::Int8, MachineMode::Int32, MachineMode::Int64];
+    let mut c = Checker::new();
+
+    for &mode in modes.iter() {
+        for &src in GPRS.iter() {
+            for &dest in GPRS.iter() {
+                let (d, s) = match mode {
+                    MachineMode::Int8 => (r8(dest), r8(src)),
+                    MachineMode::Int32 => (r32(dest), r32(src)),
+                    _ => (r64(dest), r64(src)),
+                };
+                c.check(format!("cmpxchg {}, {}", d, s), |asm| {
+                    cmpxchg(asm, mode, src, dest)
+                });
+                c.check(format!("xadd {}, {}", d, s), |asm| {
+                    xadd(asm, mode, src, dest)
+                });
+            }
+        }
+    }
+
+    c.finish();
+}
+
+const SIZED: [MachineMode; 4] = [
+    MachineMode::Int8,
+    MachineMode::Int16,
+    MachineMode::Int32,
+    MachineMode::Int64,
+];
+
+const ALU_OPS: [(AluOp, &str); 6] = [
+    (AluOp::Add, "add"),
+    (AluOp::Or, "or"),
+    (AluOp::And, "and"),
+    (AluOp::Sub, "sub"),
+    (AluOp::Xor, "xor"),
+    (AluOp::Cmp, "cmp"),
+];
+
+const SHIFT_OPS: [(ShiftOp, &str); 5] = [
+    (ShiftOp::Rol, "rol"),
+    (ShiftOp::Ror, "ror"),
+    (ShiftOp::Shl, "shl"),
+    (ShiftOp::Shr, "shr"),
+    (ShiftOp::Sar, "sar"),
+];
+
+#[test]
+fn sized_reg_reg() {
+    let mut c = Checker::new();
+
+    for &mode in SIZED.iter() {
+        for &src in GPRS.iter() {
+            for &dest in GPRS.iter() {
+                let (d, s) = (sized(mode, dest).0, sized(mode, src).0);
+
+                for &(op, name) in ALU_OPS.iter() {
+                    c.check(format!("{} {}, {}", name, d, s), |asm| {
+                        jazz_jit::assembler_x64::alu_reg_reg(asm, mode, op, src, dest)
+                    });
+                }
+                c.check(format!("mov {}, {}", d, s), |asm| {
+                    mov_reg_reg(asm, mode, src, dest)
+                });
+            }
+        }
+    }
+
+    for &mode in [MachineMode::Int8, MachineMode::Int16].iter() {
+        for &src in GPRS.iter() {
+            for &dest in GPRS.iter() {
+                let s = sized(mode, src).0;
+
+                c.check(format!("movzx {}, {}", r32(dest), s), |asm| {
+                    movzx(asm, mode, src, dest)
+                });
+                for x64 in 0..2 {
+                    c.check(format!("movsx {}, {}", gpr(x64, dest), s), |asm| {
+                        movsx(asm, x64, mode, src, dest)
+                    });
+                }
+            }
+        }
+    }
+
+    c.finish();
+}
+
+#[test]
+fn sized_immediates_and_shifts() {
+    let mut c = Checker::new();
+
+    for &mode in SIZED.iter() {
+        let imms: &[i32] = match mode {
+            MachineMode::Int8 => &[0, 1, -1, 0x7f, 0x80, -0x80, 0xff],
+            MachineMode::Int16 => &[0, 1, -1, 0x80, -0x80, 0x1234, 0xffff],
+            _ => &IMMS,
+        };
+
+        for &dest in GPRS.iter() {
+            let d = sized(mode, dest).0;
+
+            for &imm in imms.iter() {
+                for &(op, name) in ALU_OPS.iter() {
+                    c.check(format!("{} {}, {}", name, d, hex(imm as i64)), |asm| {
+                        alu_imm_reg(asm, mode, op, imm, dest)
+                    });
+                }
+                c.check(format!("mov {}, {}", d, hex(imm as i64)), |asm| {
+                    mov_imm_reg(asm, mode, imm, dest)
+                });
+            }
+
+            for &(op, name) in SHIFT_OPS.iter() {
+                c.check(format!("{} {}, cl", name, d), |asm| {
+                    shift_reg_cl(asm, mode, op, dest)
+                });
+
+                for &imm in [1u8, 3, 7].iter() {
+                    c.check(format!("{} {}, {}", name, d, imm), |asm| {
+                        shift_reg_imm(asm, mode, op, dest, imm)
+                    });
+                }
+            }
+        }
+    }
+
+    c.rejects("byte immediate above 0xff", |asm| {
+        alu_imm_reg(asm, MachineMode::Int8, AluOp::Add, 0x100, RCX)
+    });
+    c.rejects("16-bit immediate above 0xffff", |asm| {
+        mov_imm_reg(asm, MachineMode::Int16, 0x10000, RCX)
+    });
+    c.rejects("movzx from 32 bits", |asm| {
+        movzx(asm, MachineMode::Int32, RAX, RCX)
+    });
+    c.rejects("float mode", |asm| {
+        shift_reg_cl(asm, MachineMode::Float32, ShiftOp::Shl, RAX)
+    });
+
+    c.finish();
+}
+
+#[test]
+fn sized_operations() {
+    let mut c = Checker::new();
+
+    c.check("add ax, cx; mov edx, eax", |asm| {
+        asm.int_add(MachineMode::Int16, RDX, RAX, RCX)
+    });
+    c.check("xor sil, r9b", |asm| {
+        asm.int_xor(MachineMode::Int8, RSI, RSI, R9)
+    });
+    c.check("sub r8, rdi", |asm| {
+        asm.int_sub(MachineMode::Int64, R8, R8, RDI)
+    });
+    c.check("mov ecx, edx; sar di, cl", |asm| {
+        asm.int_sar(MachineMode::Int16, RDI, RDI, RDX)
+    });
+    c.check("shl bpl, cl; mov eax, ebp", |asm| {
+        asm.int_shl(MachineMode::Int8, RAX, RBP, RCX)
+    });
+    c.check("cmp di, si", |asm| {
+        asm.cmp_reg(MachineMode::Int16, RDI, RSI)
+    });
+    c.check("cmp dil, sil", |asm| {
+        asm.cmp_reg(MachineMode::Int8, RDI, RSI)
+    });
+    c.check("cmp ax, 0x1234", |asm| {
+        asm.cmp_reg_imm(MachineMode::Int16, RAX, 0x1234)
+    });
+    c.rejects("float mode", |asm| {
+        asm.int_or(MachineMode::Float64, RAX, RAX, RCX)
+    });
+
+    c.finish();
+}
+
+#[test]
+fn rotates() {
+    let mut c = Checker::new();
+
+    for x64 in 0..2 {
+        for &dest in GPRS.iter() {
+            let d = gpr(x64, dest);
+
+            c.check(format!("rol {}, cl", d), |asm| {
+                emit_rol_reg_cl(asm, x64, dest)
+            });
+            c.check(format!("ror {}, cl", d), |asm| {
+                emit_ror_reg_cl(asm, x64, dest)
+            });
+
+            for &imm in [1u8, 3, 31].iter() {
+                c.check(format!("rol {}, {}", d, hex(imm as i64)), |asm| {
+                    emit_rol_reg_imm(asm, x64, dest, imm)
+                });
+                c.check(format!("ror {}, {}", d, hex(imm as i64)), |asm| {
+                    emit_ror_reg_imm(asm, x64, dest, imm)
+                });
+            }
+        }
+    }
+
+    c.finish();
+}
+
+type BitTest = (&'static str, RegReg, fn(&mut Assembler, u8, Register, u8));
+
+#[test]
+fn bit_tests_and_counts() {
+    let tests: [BitTest; 4] = [
+        ("bt", bt, bt_imm),
+        ("bts", bts, bts_imm),
+        ("btr", btr, btr_imm),
+        ("btc", btc, btc_imm),
+    ];
+    let counts: [(&str, RegReg); 5] = [
+        ("bsf", bsf),
+        ("bsr", bsr),
+        ("popcnt", popcnt),
+        ("lzcnt", lzcnt),
+        ("tzcnt", tzcnt),
+    ];
+    let mut c = Checker::new();
+
+    for x64 in 0..2 {
+        for &src in GPRS.iter() {
+            for &dest in GPRS.iter() {
+                let (d, s) = (gpr(x64, dest), gpr(x64, src));
+
+                for &(name, emit, _) in tests.iter() {
+                    c.check(format!("{} {}, {}", name, d, s), |asm| {
+                        emit(asm, x64, src, dest)
+                    });
+                }
+
+                for &(name, emit) in counts.iter() {
+                    c.check(format!("{} {}, {}", name, d, s), |asm| {
+                        emit(asm, x64, src, dest)
+                    });
+                }
+            }
+
+            for &(name, _, emit) in tests.iter() {
+                c.check(format!("{} {}, 0x3f", name, gpr(x64, src)), |asm| {
+                    emit(asm, x64, src, 0x3f)
+                });
+            }
+        }
+    }
+
+    c.finish();
+}
+
+#[test]
+fn bmi() {
+    type Three = fn(&mut Assembler, u8, Register, Register, Register);
+    let three: [(&str, Three); 7] = [
+        ("andn", andn),
+        ("bextr", bextr),
+        ("pdep", pdep),
+        ("pext", pext),
+        ("shlx", shlx),
+        ("sarx", sarx),
+        ("shrx", shrx),
+    ];
+    let mut c = Checker::new();
+
+    for x64 in 0..2 {
+        for &dest in GPRS.iter() {
+            for &src in GPRS.iter() {
+                let (d, s) = (gpr(x64, dest), gpr(x64, src));
+
+                for &other in [RAX, RSP, R9, R15].iter() {
+                    let o = gpr(x64, other);
+
+                    for &(name, emit) in three.iter() {
+                        c.check(format!("{} {}, {}, {}", name, d, s, o), |asm| {
+                            emit(asm, x64, dest, src, other)
+                        });
+                    }
+                }
+
+                c.check(format!("blsr {}, {}", d, s), |asm| {
+                    blsr(asm, x64, dest, src)
+                });
+                c.check(format!("blsi {}, {}", d, s), |asm| {
+                    blsi(asm, x64, dest, src)
+                });
+                c.check(format!("rorx {}, {}, 0xd", d, s), |asm| {
+                    rorx(asm, x64, dest, src, 13)
+                });
+            }
+        }
+    }
+
+    c.rejects("rip in VEX.vvvv", |asm| andn(asm, 1, RAX, RIP, RCX));
+
+    c.finish();
+}
+
+#[test]
+fn count_zeros_fallbacks() {
+    let mut c = Checker::new();
+    let bsr = CpuFeatures::all().without(CpuFeature::Lzcnt);
+    let bsf = CpuFeatures::all().without(CpuFeature::Bmi1);
+
+    c.check("lzcnt eax, ecx", |asm| {
+        asm.int_clz(MachineMode::Int32, RAX, RCX)
+    });
+    c.check(
+        "bsr esi, r9d; jne 0xb; mov esi, 0x3f; xor sil, 0x1f",
+        |asm| {
+            asm.features = bsr;
+            asm.int_clz(MachineMode::Int32, RSI, R9);
+            asm.fix_forward_jumps().unwrap();
+        },
+    );
+    c.check(
+        "bsr rax, rax; jne 0xb; mov eax, 0x7f; xor al, 0x3f",
+        |asm| {
+            asm.features = bsr;
+            asm.int_clz(MachineMode::Int64, RAX, RAX);
+            asm.fix_forward_jumps().unwrap();
+        },
+    );
+
+    c.check("tzcnt rdx, r8", |asm| {
+        asm.int_ctz(MachineMode::Int64, RDX, R8)
+    });
+    c.check("bsf edx, r8d; jne 0xb; mov edx, 0x20", |asm| {
+        asm.features = bsf;
+        asm.int_ctz(MachineMode::Int32, RDX, R8);
+        asm.fix_forward_jumps().unwrap();
+    });
+
+    c.finish();
+}
+
+#[test]
+fn no_operands() {
+    let mut c = Checker::new();
+
+    c.check("ret", emit_retq);
+    c.check("ret 8", |asm| emit_retq_imm(asm, 8));
+    c.check("ret", |asm| emit_retq_imm(asm, 0));
+    c.check("nop", emit_nop);
+    c.check("cdq", emit_cdq);
+    c.check("cqo", emit_cqo);
+    c.check("cpuid", cpuid);
+    c.check("mfence", mfence);
+    c.check("lfence", lfence);
+    c.check("sfence", sfence);
+    c.check("pause", pause);
+
+    c.finish();
+}
+
+#[test]
+fn jumps() {
+    let mut c = Checker::new();
+
+    for &(cond, cc) in CONDS.iter() {
+        // backward jumps know their target and use rel8 when it fits
+        c.check(format!("nop; j{} 0", cc), |asm| {
+            let lbl = asm.create_label();
+            asm.bind_label(lbl);
+            emit_nop(asm);
+            emit_jcc(asm, cond, lbl);
+            asm.fix_forward_jumps().unwrap();
+        });
+
+        c.check(
+            format!("j{} 0x86; {}ret", cc, "nop; ".repeat(0x80)),
+            |asm| {
+                let lbl = asm.create_label();
+                emit_jcc(asm, cond, lbl);
+                for _ in 0..0x80 {
+                    emit_nop(asm);
+                }
+                asm.bind_label(lbl);
+                emit_retq(asm);
+                asm.fix_forward_jumps().unwrap();
+            },
+        );
+
+        c.check(format!("j{} 3; nop; ret", cc), |asm| {
+            let lbl = asm.create_label();
+            emit_jcc(asm, cond, lbl);
+            emit_nop(asm);
+            asm.bind_label(lbl);
+            emit_retq(asm);
+            asm.fix_forward_jumps().unwrap();
+        });
+
+        // positions taken with `pos()` stay valid without relaxation
+        c.check(format!("j{} 7; nop; ret", cc), |asm| {
+            let lbl = asm.create_label();
+            emit_jcc(asm, cond, lbl);
+            emit_nop(asm);
+            asm.bind_label(lbl);
+            emit_retq(asm);
+            asm.relax_jumps = false;
+            asm.fix_forward_jumps().unwrap();
+        });
+    }
+
+    c.check("nop; jmp 0", |asm| {
+        let lbl = asm.create_label();
+        asm.bind_label(lbl);
+        emit_nop(asm);
+        emit_jmp(asm, lbl);
+        asm.fix_forward_jumps().unwrap();
+    });
+
+    c.check(format!("{}jmp 0", "nop; ".repeat(0x100)), |asm| {
+        let lbl = asm.create_label();
+        asm.bind_label(lbl);
+        for _ in 0..0x100 {
+            emit_nop(asm);
+        }
+        emit_jmp(asm, lbl);
+        asm.fix_forward_jumps().unwrap();
+    });
+
+    // displacements are written once the label is bound, code can run without
+    // resolving its jumps
+    c.check("jmp 6; nop; ret", |asm| {
+        let lbl = asm.create_label();
+        emit_jmp(asm, lbl);
+        emit_nop(asm);
+        asm.bind_label(lbl);
+        emit_retq(asm);
+    });
+    c.check("sub rdi, 1; jne 0; ret", |asm| {
+        let top = asm.create_label();
+        asm.bind_label(top);
+        emit_subq_imm_reg(asm, 1, RDI);
+        emit_jcc(asm, jazz_jit::CondCode::NotEqual, top);
+        emit_retq(asm);
+    });
+
+    c.finish();
+}
@@ -0,0 +1,21 @@
+//! Differential tests for the x64 encoders.
+//!
+//! Every encoder is run over all register combinations and a set of memory operands,
+//! the output is decoded with capstone and compared against the instruction the
+//! encoder is supposed to produce. Mismatches are collected so one run reports all
+//! broken combinations of a test instead of the first one.
+
+extern crate capstone;
+extern crate jazz_jit;
+
+#[path = "../common/mod.rs"]
+mod common;
+
+mod avx;
+mod avx512;
+mod features;
+mod gpr;
+mod mem;
+mod sse;
+
+use common::*;
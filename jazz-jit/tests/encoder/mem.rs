@@ -0,0 +1,774 @@
+//! Memory operands of the integer instructions.
+
+use crate::*;
+use jazz_jit::assembler::{AsmDiagnostic, Assembler, Mem, RipTarget, Segment, SegmentAddr};
+use jazz_jit::assembler_x64::*;
+use jazz_jit::avx::vmovups_ymm_mem;
+use jazz_jit::avx512::{self, Masking};
+use jazz_jit::code_cache::CODE_ALIGNMENT;
+use jazz_jit::disasm::Syntax;
+use jazz_jit::dseg::{Placement, Value};
+use jazz_jit::{get_executable_memory, JitFunction, MachineMode};
+
+const MODES: [MachineMode; 5] = [
+    MachineMode::Int8,
+    MachineMode::Int16,
+    MachineMode::Int32,
+    MachineMode::Int64,
+    MachineMode::Ptr,
+];
+
+#[test]
+fn load_store_base() {
+    let mut c = Checker::new();
+
+    for (base, disp, addr) in base_forms() {
+        for &reg in GPRS.iter() {
+            c.check(format!("mov {}, byte ptr {}", r8(reg), addr), |asm| {
+                emit_movb_memq_reg(asm, base, disp, reg)
+            });
+            c.check(format!("mov {}, dword ptr {}", r32(reg), addr), |asm| {
+                emit_movl_memq_reg(asm, base, disp, reg)
+            });
+            c.check(format!("mov {}, qword ptr {}", r64(reg), addr), |asm| {
+                emit_movq_memq_reg(asm, base, disp, reg)
+            });
+            c.check(format!("movzx {}, byte ptr {}", r32(reg), addr), |asm| {
+                emit_movzbl_memq_reg(asm, base, disp, reg)
+            });
+
+            c.check(format!("mov byte ptr {}, {}", addr, r8(reg)), |asm| {
+                emit_movb_reg_memq(asm, reg, base, disp)
+            });
+            c.check(format!("mov dword ptr {}, {}", addr, r32(reg)), |asm| {
+                emit_movl_reg_memq(asm, reg, base, disp)
+            });
+            c.check(format!("mov qword ptr {}, {}", addr, r64(reg)), |asm| {
+                emit_movq_reg_memq(asm, reg, base, disp)
+            });
+
+            for &mode in MODES.iter() {
+                let (name, size) = sized(mode, reg);
+                c.check(format!("cmp {} ptr {}, {}", size, addr, name), |asm| {
+                    emit_cmp_mem_reg(asm, mode, base, disp, reg)
+                });
+            }
+        }
+
+        c.check(format!("mov byte ptr {}, 0x80", addr), |asm| {
+            emit_movb_imm_memq(asm, 0x80, base, disp)
+        });
+
+        for &mode in MODES.iter() {
+            let (_, size) = sized(mode, RAX);
+
+            for &imm in [1, -1, 0x100].iter() {
+                if mode == MachineMode::Int8 && imm == 0x100 {
+                    continue;
+                }
+
+                let imm_str = match mode {
+                    MachineMode::Int8 => unsigned(imm as i64, 8),
+                    MachineMode::Int16 => unsigned(imm as i64, 16),
+                    MachineMode::Int32 => unsigned(imm as i64, 32),
+                    _ => unsigned(imm as i64, 64),
+                };
+                c.check(format!("cmp {} ptr {}, {}", size, addr, imm_str), |asm| {
+                    emit_cmp_mem_imm(asm, mode, base, disp, imm)
+                });
+            }
+        }
+    }
+
+    // the displacement is always relative to the next instruction
+    c.check("mov rax, qword ptr [rip + 0x100]", |asm| {
+        emit_movq_memq_reg(asm, RIP, 0x100, RAX)
+    });
+    c.check("mov qword ptr [rip - 8], r9", |asm| {
+        emit_movq_reg_memq(asm, R9, RIP, -8)
+    });
+    c.check("movzx r10d, byte ptr [rip + 0x10]", |asm| {
+        emit_movzbl_memq_reg(asm, RIP, 0x10, R10)
+    });
+
+    c.finish();
+}
+
+#[test]
+fn sub_immediate_from_memory() {
+    let mut c = Checker::new();
+
+    for &base in GPRS.iter() {
+        let addr = address(Some(r64(base)), None, 0);
+
+        for &mode in MODES.iter() {
+            let (_, size) = sized(mode, RAX);
+
+            c.check(format!("sub {} ptr {}, 0x10", size, addr), |asm| {
+                emit_sub_imm_mem(asm, mode, base, 0x10)
+            });
+        }
+    }
+
+    c.finish();
+}
+
+#[test]
+fn load_store_index() {
+    let mut c = Checker::new();
+
+    for (base, index, scale, disp, addr) in index_forms() {
+        for &reg in [RAX, RSI, R8, R15].iter() {
+            for &mode in MODES.iter() {
+                let (name, size) = sized(mode, reg);
+
+                if mode.size() as i32 == scale {
+                    c.check(format!("mov {}, {} ptr {}", name, size, addr), |asm| {
+                        emit_mov_memindex_reg(asm, mode, base, index, scale, disp, reg)
+                    });
+                }
+
+                c.check(format!("mov {} ptr {}, {}", size, addr, name), |asm| {
+                    emit_mov_reg_memindex(asm, mode, reg, base, index, scale, disp)
+                });
+                c.check(format!("cmp {} ptr {}, {}", size, addr, name), |asm| {
+                    emit_cmp_memindex_reg(asm, mode, base, index, scale, disp, reg)
+                });
+            }
+
+            if scale == 1 {
+                for x64 in 0..2 {
+                    c.check(
+                        format!("movzx {}, byte ptr {}", gpr(x64, reg), addr),
+                        |asm| emit_movzx_memindex_byte_reg(asm, x64, base, index, disp, reg),
+                    );
+                }
+            }
+        }
+    }
+
+    c.finish();
+}
+
+#[test]
+fn load_store_array() {
+    let mut c = Checker::new();
+
+    for (base, index, scale, disp, _) in index_forms() {
+        if disp != 0 {
+            continue;
+        }
+
+        let addr = address(Some(r64(base)), Some((index, scale)), 0);
+
+        for &reg in [RAX, RDI, R8, R13].iter() {
+            c.check(format!("mov {}, qword ptr {}", r64(reg), addr), |asm| {
+                emit_movq_ar(asm, base, index, scale as u8, reg)
+            });
+            c.check(format!("mov {}, dword ptr {}", r32(reg), addr), |asm| {
+                emit_movl_ar(asm, base, index, scale as u8, reg)
+            });
+            c.check(format!("mov qword ptr {}, {}", addr, r64(reg)), |asm| {
+                emit_movq_ra(asm, reg, base, index, scale as u8)
+            });
+            c.check(format!("mov dword ptr {}, {}", addr, r32(reg)), |asm| {
+                emit_movl_ra(asm, reg, base, index, scale as u8)
+            });
+        }
+
+        // the scale of this one is given as the SIB bits
+        let bits = scale.trailing_zeros() as u8;
+        c.check(format!("mov byte ptr {}, 0x7f", addr), |asm| {
+            emit_movb_imm_memscaleq(asm, 0x7f, base, index, bits)
+        });
+    }
+
+    c.finish();
+}
+
+#[test]
+fn generic_mem() {
+    let mut c = Checker::new();
+
+    for (mem, addr) in mem_forms() {
+        for &reg in [RAX, RSP, R9, R12].iter() {
+            c.check(format!("lea {}, {}", r64(reg), addr), |asm| {
+                lea(asm, reg, mem)
+            });
+            c.check(format!("test dword ptr {}, {}", addr, r32(reg)), |asm| {
+                testl_reg_mem(asm, reg, mem)
+            });
+        }
+    }
+
+    c.finish();
+}
+
+#[test]
+fn atomics_mem() {
+    let mut c = Checker::new();
+
+    for (mem, addr) in mem_forms() {
+        for &reg in [RAX, RSI, R9, R12].iter() {
+            for &mode in MODES.iter() {
+                let (name, size) = sized(mode, reg);
+                let operands = format!("{} ptr {}, {}", size, addr, name);
+
+                c.check(format!("xchg {}", operands), |asm| {
+                    asm.atomic_swap(mode, mem, reg)
+                });
+                c.check(format!("lock cmpxchg {}", operands), |asm| {
+                    asm.atomic_cmpxchg(mode, mem, reg)
+                });
+                c.check(format!("lock xadd {}", operands), |asm| {
+                    asm.atomic_fetch_add(mode, mem, reg)
+                });
+            }
+        }
+
+        c.check(format!("lock cmpxchg16b xmmword ptr {}", addr), |asm| {
+            lock(asm);
+            cmpxchg16b(asm, mem)
+        });
+    }
+
+    c.finish();
+}
+
+#[test]
+fn sized_mem() {
+    let ops = [
+        (AluOp::Add, "add"),
+        (AluOp::Or, "or"),
+        (AluOp::And, "and"),
+        (AluOp::Sub, "sub"),
+        (AluOp::Xor, "xor"),
+        (AluOp::Cmp, "cmp"),
+    ];
+    let mut c = Checker::new();
+
+    for (mem, addr) in mem_forms() {
+        for &mode in MODES.iter() {
+            for &reg in [RAX, RSI, R9, R12].iter() {
+                let (name, size) = sized(mode, reg);
+
+                for &(op, op_name) in ops.iter() {
+                    c.check(
+                        format!("{} {} ptr {}, {}", op_name, size, addr, name),
+                        |asm| alu_reg_mem(asm, mode, op, reg, mem),
+                    );
+                    c.check(
+                        format!("{} {}, {} ptr {}", op_name, name, size, addr),
+                        |asm| alu_mem_reg(asm, mode, op, mem, reg),
+                    );
+                }
+                c.check(format!("mov {} ptr {}, {}", size, addr, name), |asm| {
+                    mov_reg_mem(asm, mode, reg, mem)
+                });
+                c.check(format!("mov {}, {} ptr {}", name, size, addr), |asm| {
+                    mov_mem_reg(asm, mode, mem, reg)
+                });
+            }
+
+            let (_, size) = sized(mode, RAX);
+
+            for &imm in [1, -1, 0x7f, 0x80].iter() {
+                for &(op, op_name) in ops.iter() {
+                    c.check(
+                        format!("{} {} ptr {}, {}", op_name, size, addr, hex(imm as i64)),
+                        |asm| alu_imm_mem(asm, mode, op, imm, mem),
+                    );
+                }
+                c.check(
+                    format!("mov {} ptr {}, {}", size, addr, hex(imm as i64)),
+                    |asm| mov_imm_mem(asm, mode, imm, mem),
+                );
+            }
+        }
+
+        for &mode in [MachineMode::Int8, MachineMode::Int16].iter() {
+            let (_, size) = sized(mode, RAX);
+
+            for &reg in [RAX, RSI, R9].iter() {
+                c.check(
+                    format!("movzx {}, {} ptr {}", r32(reg), size, addr),
+                    |asm| movzx_mem(asm, mode, mem, reg),
+                );
+                for x64 in 0..2 {
+                    c.check(
+                        format!("movsx {}, {} ptr {}", gpr(x64, reg), size, addr),
+                        |asm| movsx_mem(asm, x64, mode, mem, reg),
+                    );
+                }
+            }
+        }
+    }
+
+    c.finish();
+}
+
+#[test]
+fn load_store_words() {
+    let mut c = Checker::new();
+
+    for (mem, addr) in mem_forms() {
+        if let Mem::Offset(..) = mem {
+            continue;
+        }
+
+        for &reg in [RAX, RSI, R9, R12].iter() {
+            c.check(format!("movzx {}, word ptr {}", r32(reg), addr), |asm| {
+                asm.load_mem(MachineMode::Int16, Reg::Gpr(reg), mem)
+            });
+            c.check(format!("mov word ptr {}, {}", addr, r16(reg)), |asm| {
+                asm.store_mem(MachineMode::Int16, mem, Reg::Gpr(reg))
+            });
+        }
+    }
+
+    c.finish();
+}
+
+#[test]
+fn bit_tests_mem() {
+    type BitTestMem = fn(&mut Assembler, u8, Register, Mem);
+    type BitTestMemImm = fn(&mut Assembler, u8, Mem, u8);
+    let tests: [(&str, BitTestMem, BitTestMemImm); 4] = [
+        ("bt", bt_mem, bt_mem_imm),
+        ("bts", bts_mem, bts_mem_imm),
+        ("btr", btr_mem, btr_mem_imm),
+        ("btc", btc_mem, btc_mem_imm),
+    ];
+    let mut c = Checker::new();
+
+    for (mem, addr) in mem_forms() {
+        for &(name, emit, emit_imm) in tests.iter() {
+            for x64 in 0..2 {
+                let size = if x64 != 0 { "qword" } else { "dword" };
+
+                for &bit in [RAX, RBP, R10].iter() {
+                    let expected = format!("{} {} ptr {}, {}", name, size, addr, gpr(x64, bit));
+                    c.check(expected, |asm| emit(asm, x64, bit, mem));
+                }
+
+                c.check(format!("{} {} ptr {}, 0x21", name, size, addr), |asm| {
+                    emit_imm(asm, x64, mem, 0x21)
+                });
+            }
+        }
+    }
+
+    c.finish();
+}
+
+#[test]
+fn rip_relative_labels() {
+    let mut c = Checker::new();
+
+    // the displacement is relative to the end of the instruction, after any immediate
+    c.check("lea rax, [rip - 7]", |asm| {
+        let lbl = asm.create_label();
+        asm.bind_label(lbl);
+        lea(asm, RAX, Mem::RipRelative(RipTarget::Label(lbl)));
+        asm.fix_forward_jumps().unwrap();
+    });
+    c.check("mov r9d, dword ptr [rip + 1]; nop", |asm| {
+        let lbl = asm.create_label();
+        mov_mem_reg(
+            asm,
+            MachineMode::Int32,
+            Mem::RipRelative(RipTarget::Label(lbl)),
+            R9,
+        );
+        emit_nop(asm);
+        asm.bind_label(lbl);
+        asm.fix_forward_jumps().unwrap();
+    });
+
+    let imm_forms: [(&str, fn(&mut Assembler, Mem)); 4] = [
+        ("cmp dword ptr [rip - 0xa], 0x1234", |asm, mem| {
+            alu_imm_mem(asm, MachineMode::Int32, AluOp::Cmp, 0x1234, mem)
+        }),
+        ("add word ptr [rip - 8], 1", |asm, mem| {
+            alu_imm_mem(asm, MachineMode::Int16, AluOp::Add, 1, mem)
+        }),
+        ("mov byte ptr [rip - 7], 0x7f", |asm, mem| {
+            mov_imm_mem(asm, MachineMode::Int8, 0x7f, mem)
+        }),
+        ("bt qword ptr [rip - 9], 3", |asm, mem| {
+            bt_mem_imm(asm, 1, mem, 3)
+        }),
+    ];
+
+    for &(expected, emit) in imm_forms.iter() {
+        c.check(expected, |asm| {
+            let lbl = asm.create_label();
+            asm.bind_label(lbl);
+            emit(asm, Mem::RipRelative(RipTarget::Label(lbl)));
+            asm.fix_forward_jumps().unwrap();
+        });
+    }
+
+    // relaxing the jump moves the instruction but not its distance to the label
+    c.check("jmp 2; lea rax, [rip - 9]", |asm| {
+        let start = asm.create_label();
+        let after = asm.create_label();
+        asm.bind_label(start);
+        emit_jmp(asm, after);
+        asm.bind_label(after);
+        lea(asm, RAX, Mem::RipRelative(RipTarget::Label(start)));
+        asm.fix_forward_jumps().unwrap();
+    });
+
+    c.finish();
+
+    let mut asm = assembler();
+    let lbl = asm.create_label();
+    emit_nop(&mut asm);
+    lea(&mut asm, RAX, Mem::RipRelative(RipTarget::Label(lbl)));
+    assert_eq!(
+        asm.finalize().unwrap_err().diagnostics,
+        [AsmDiagnostic::UnboundLabel {
+            label: lbl,
+            references: vec![4],
+        }]
+    );
+}
+
+#[test]
+fn rip_relative_constants() {
+    let mut c = Checker::new();
+
+    // the data segment ends right in front of the code
+    c.check("movsd xmm1, qword ptr [rip - 0x10]", |asm| {
+        asm.load_float_const(MachineMode::Float64, XMM1, 1.5)
+    });
+    c.check("movsd xmm9, qword ptr [rip - 0x11]", |asm| {
+        asm.load_float_const(MachineMode::Float64, XMM9, 1.5)
+    });
+    c.check("nop; movss xmm12, dword ptr [rip - 0xe]", |asm| {
+        emit_nop(asm);
+        asm.load_float_const(MachineMode::Float32, XMM12, 1.5)
+    });
+    c.check("cmp dword ptr [rip - 0xb], 5", |asm| {
+        let disp = asm.dseg.add_int(7);
+        alu_imm_mem(
+            asm,
+            MachineMode::Int32,
+            AluOp::Cmp,
+            5,
+            Mem::RipRelative(RipTarget::DSeg(disp)),
+        )
+    });
+
+    c.finish();
+
+    // every constant is found at the address its displacement points to
+    let mut asm = assembler();
+    let consts = [1.5, -2.25, 1e100];
+    for (i, &value) in consts.iter().enumerate() {
+        let dest = if i % 2 == 0 { XMM2 } else { XMM10 };
+        asm.load_float_const(MachineMode::Float64, dest, value);
+        let disp = asm.dseg.add_int(i as i32);
+        bt_mem_imm(&mut asm, 1, Mem::RipRelative(RipTarget::DSeg(disp)), 1);
+    }
+
+    let code = asm.finalize().unwrap();
+    let mut expected = Vec::new();
+    for &value in consts.iter() {
+        expected.push(Value::Double(value));
+        expected.push(Value::Int(expected.len() as i32 / 2));
+    }
+    let mut found = Vec::new();
+    for &at in code.dseg_refs.iter() {
+        let disp = i32::from_le_bytes([
+            code.data[at],
+            code.data[at + 1],
+            code.data[at + 2],
+            code.data[at + 3],
+        ]);
+        // movsd ends with its displacement, bt with a one byte immediate
+        let end = if code.data[at - 2] == 0xBA {
+            at + 5
+        } else {
+            at + 4
+        };
+        let offset = code.dseg.size() + end as i32 + disp;
+        found.push(code.dseg.value_at(offset).cloned().unwrap());
+    }
+    assert_eq!(found, expected);
+}
+
+#[test]
+fn constant_pool() {
+    let mut asm = assembler();
+    let dseg = &mut asm.dseg;
+
+    // equal constants share an entry, floats are compared bitwise
+    let one = dseg.add_double(1.5);
+    assert_eq!(dseg.add_double(1.5), one);
+    assert_ne!(dseg.add_float(-0.0), dseg.add_float(0.0));
+    assert_eq!(dseg.add_double(f64::NAN), dseg.add_double(f64::NAN));
+    assert_eq!(dseg.add_int(7), dseg.add_int(7));
+    let ptr = 0x1234 as *const u8;
+    assert_eq!(dseg.add_addr(ptr), dseg.add_addr_reuse(ptr));
+
+    // vector constants are aligned to their size, the segment to the widest entry
+    let v128 = dseg.add_v128([1; 16]);
+    assert_eq!(v128 % 16, 0);
+    assert_eq!(dseg.size() % 16, 0);
+    let v256 = dseg.add_v256([2; 32]);
+    assert_eq!(v256 % 32, 0);
+    assert_eq!(dseg.alignment(), 32);
+    assert_eq!(dseg.size() % 32, 0);
+    let blob = dseg.add_blob(&[3, 4, 5], 8);
+    assert_eq!(blob % 8, 0);
+    assert_eq!(dseg.add_blob(&[3, 4, 5], 8), blob);
+    assert_eq!(
+        dseg.value_at(dseg.size() - blob),
+        Some(&Value::Blob(vec![3, 4, 5], 8))
+    );
+
+    let stats = dseg.stats();
+    assert_eq!(stats.entries, 9);
+    assert_eq!(stats.reused, 5);
+    assert_eq!(stats.size, dseg.size() as usize);
+    assert_eq!(
+        stats.padding,
+        stats.size - (8 + 4 + 4 + 8 + 4 + 8 + 16 + 32 + 3)
+    );
+
+    // movaps faults on misaligned constants, in front of the code and behind it
+    for &placement in [Placement::Front, Placement::Island].iter() {
+        let mut asm = assembler();
+        asm.dseg.set_placement(placement);
+        asm.dseg.add_int(3);
+
+        let mut lanes = [0; 16];
+        lanes[..8].copy_from_slice(&2.5f64.to_le_bytes());
+        let disp = asm.dseg.add_v128(lanes);
+        movaps_load(&mut asm, XMM0, Mem::RipRelative(RipTarget::DSeg(disp)));
+        asm.load_float_const(MachineMode::Float64, XMM1, 0.25);
+        addsd(&mut asm, XMM0, XMM1);
+        emit_retq(&mut asm);
+
+        let code = asm.finalize().unwrap();
+        assert_eq!(code.island.is_some(), placement == Placement::Island);
+        assert!(code.disassemble(Syntax::Intel).contains("= 0.25 (f64)"));
+
+        let memory = get_executable_memory(&code).unwrap();
+        assert_eq!(
+            memory.start() == memory.ptr(),
+            placement == Placement::Island
+        );
+        let fun: JitFunction<extern "C" fn() -> f64> = unsafe { JitFunction::new(memory) };
+        assert_eq!(fun.call(), 2.75);
+    }
+}
+
+#[test]
+fn invalid_constants_are_reported() {
+    let mut asm = assembler();
+    asm.dseg.add_blob(&[1, 2, 3], 3);
+    asm.dseg.add_blob(&[], 8);
+    asm.dseg.add_blob(&[1], 2 * CODE_ALIGNMENT as i32);
+    asm.dseg.align(0);
+    assert!(asm.dseg.is_empty());
+    assert!(asm.has_errors());
+    emit_retq(&mut asm);
+
+    let messages: Vec<String> = asm
+        .finalize()
+        .unwrap_err()
+        .diagnostics
+        .iter()
+        .map(|diagnostic| match diagnostic {
+            AsmDiagnostic::InvalidConstant { .. } => diagnostic.to_string(),
+            _ => panic!("unexpected {}", diagnostic),
+        })
+        .collect();
+    assert_eq!(
+        messages,
+        [
+            "alignment must be a power of two in the data segment",
+            "constants must not be empty in the data segment",
+            "alignment must not exceed the alignment of code blocks in the data segment",
+            "alignment must be a power of two in the data segment",
+        ]
+    );
+}
+
+#[test]
+fn many_constants_are_stored_once() {
+    let mut asm = assembler();
+    let disps: Vec<i32> = (0..10_000).map(|i| asm.dseg.add_int(i)).collect();
+
+    for (i, &disp) in disps.iter().enumerate() {
+        assert_eq!(asm.dseg.add_int(i as i32), disp);
+    }
+
+    // the same bytes at another alignment are a separate entry
+    let blob = asm.dseg.add_blob(&7i32.to_le_bytes(), 8);
+    assert_ne!(blob, disps[7]);
+    assert_eq!(blob % 8, 0);
+
+    let stats = asm.dseg.stats();
+    assert_eq!((stats.entries, stats.reused), (10_001, 10_000));
+}
+
+#[test]
+fn absolute_and_segments() {
+    let mut c = Checker::new();
+    let far = 0x12_3456_789a_u64;
+
+    for &mode in MODES.iter() {
+        let (name, size) = sized(mode, RAX);
+
+        c.check(
+            format!("movabs {}, {} ptr [{:#x}]", name, size, far),
+            |asm| movabs_load(asm, mode, far, RAX),
+        );
+        c.check(
+            format!("movabs {} ptr [{:#x}], {}", size, far, name),
+            |asm| movabs_store(asm, mode, RAX, far),
+        );
+        c.check(format!("mov {}, {} ptr fs:[0x28]", name, size), |asm| {
+            mov_mem_reg(
+                asm,
+                mode,
+                Mem::Segment(Segment::Fs, SegmentAddr::Absolute(0x28)),
+                RAX,
+            )
+        });
+    }
+
+    c.check(
+        "movabs r11, 0x123456789a; mov rcx, qword ptr [r11]",
+        |asm| asm.load_mem(MachineMode::Ptr, Reg::Gpr(RCX), Mem::Absolute(far)),
+    );
+    c.check("mov rcx, qword ptr gs:[0x10]", |asm| {
+        asm.load_mem(
+            MachineMode::Ptr,
+            Reg::Gpr(RCX),
+            Mem::Segment(Segment::Gs, SegmentAddr::Absolute(0x10)),
+        )
+    });
+    c.check("movzx r8d, word ptr fs:[0x20]", |asm| {
+        asm.load_mem(
+            MachineMode::Int16,
+            Reg::Gpr(R8),
+            Mem::Segment(Segment::Fs, SegmentAddr::Absolute(0x20)),
+        )
+    });
+    c.check("mov dword ptr fs:[0xfffffffffffffff8], esi", |asm| {
+        asm.store_mem(
+            MachineMode::Int32,
+            Mem::Segment(Segment::Fs, SegmentAddr::Absolute(-8i64 as u64)),
+            Reg::Gpr(RSI),
+        )
+    });
+    c.check("movsd xmm3, qword ptr gs:[0x30]", |asm| {
+        asm.load_mem(
+            MachineMode::Float64,
+            Reg::Float(XMM3),
+            Mem::Segment(Segment::Gs, SegmentAddr::Absolute(0x30)),
+        )
+    });
+    // the prefix goes in front of other legacy prefixes, REX, VEX and EVEX
+    c.check("lock add word ptr fs:[0x28], cx", |asm| {
+        lock(asm);
+        alu_reg_mem(
+            asm,
+            MachineMode::Int16,
+            AluOp::Add,
+            RCX,
+            Mem::Segment(Segment::Fs, SegmentAddr::Absolute(0x28)),
+        )
+    });
+    c.check("mov r9, qword ptr fs:[0x28]", |asm| {
+        asm.load_mem(
+            MachineMode::Ptr,
+            Reg::Gpr(R9),
+            Mem::Segment(Segment::Fs, SegmentAddr::Absolute(0x28)),
+        )
+    });
+    c.check("vmovups ymm9, ymmword ptr gs:[0x40]", |asm| {
+        vmovups_ymm_mem(
+            asm,
+            YMM9,
+            Mem::Segment(Segment::Gs, SegmentAddr::Absolute(0x40)),
+        )
+    });
+    c.check("vaddps zmm1, zmm2, zmmword ptr fs:[0x40]", |asm| {
+        avx512::vaddps_zmm_mem(
+            asm,
+            ZMM1,
+            ZMM2,
+            Mem::Segment(Segment::Fs, SegmentAddr::Absolute(0x40)),
+            Masking::Unmasked,
+        )
+    });
+    // base and index registers stay in REX, after the prefixes
+    c.check("mov r10, qword ptr fs:[r13 + r14*8 + 0x10]", |asm| {
+        asm.load_mem(
+            MachineMode::Ptr,
+            Reg::Gpr(R10),
+            Mem::Segment(Segment::Fs, SegmentAddr::Index(R13, R14, 8, 0x10)),
+        )
+    });
+    c.check("mov word ptr gs:[r12 - 8], r11w", |asm| {
+        asm.store_mem(
+            MachineMode::Int16,
+            Mem::Segment(Segment::Gs, SegmentAddr::Base(R12, -8)),
+            Reg::Gpr(R11),
+        )
+    });
+    c.check("movsd xmm12, qword ptr fs:[rax + r9*4]", |asm| {
+        asm.load_mem(
+            MachineMode::Float64,
+            Reg::Float(XMM12),
+            Mem::Segment(Segment::Fs, SegmentAddr::Index(RAX, R9, 4, 0)),
+        )
+    });
+    c.check(
+        "movabs r11, 0x123456789a; mov rcx, qword ptr gs:[r11]",
+        |asm| {
+            asm.load_mem(
+                MachineMode::Ptr,
+                Reg::Gpr(RCX),
+                Mem::Segment(Segment::Gs, SegmentAddr::Absolute(far)),
+            )
+        },
+    );
+
+    c.rejects("movabs with another register", |asm| {
+        movabs_load(asm, MachineMode::Int64, far, RCX)
+    });
+    c.rejects("absolute address beyond 32 bits", |asm| {
+        lea(asm, RAX, Mem::Absolute(far))
+    });
+
+    c.finish();
+}
+
+#[test]
+fn invalid_operands() {
+    let mut c = Checker::new();
+
+    c.rejects("rsp as index", |asm| {
+        lea(asm, RAX, Mem::Index(RAX, RSP, 1, 0))
+    });
+    c.rejects("scale 3", |asm| lea(asm, RAX, Mem::Index(RAX, RCX, 3, 0)));
+    c.rejects("rip as register", |asm| emit_mov_reg_reg(asm, 1, RIP, RAX));
+    c.rejects("scale not matching the mode", |asm| {
+        emit_mov_memindex_reg(asm, MachineMode::Int64, RAX, RCX, 4, 0, RDX)
+    });
+    c.rejects("float mode", |asm| {
+        emit_cmp_mem_reg(asm, MachineMode::Float64, RAX, 0, RDX)
+    });
+    c.rejects("float mode", |asm| {
+        xadd_mem(asm, MachineMode::Float32, RAX, Mem::Base(RDX, 0))
+    });
+
+    c.finish();
+}
@@ -0,0 +1,274 @@
+//! SSE encoders: scalar and packed floating point, conversions and the SSE2, SSSE3
+//! and SSE4.1 integer instructions.
+
+use crate::*;
+use jazz_jit::assembler::{Assembler, Mem};
+use jazz_jit::assembler_x64::*;
+
+type FregFreg = fn(&mut Assembler, XMMRegister, XMMRegister);
+type FregMem = fn(&mut Assembler, XMMRegister, Mem);
+
+/// Registers used as the xmm operand of memory forms.
+const MEM_XMMS: [XMMRegister; 4] = [XMM0, XMM7, XMM8, XMM15];
+
+macro_rules! ops {
+    ($($name:ident),* $(,)*) => {
+        [$((stringify!($name), $name as FregFreg)),*]
+    };
+}
+
+macro_rules! mem_ops {
+    ($(($name:ident, $mnemonic:expr, $size:expr)),* $(,)*) => {
+        [$(($mnemonic, $name as FregMem, $size)),*]
+    };
+}
+
+fn check_freg_freg(c: &mut Checker, name: &str, emit: FregFreg) {
+    for &dest in XMMS.iter() {
+        for &src in XMMS.iter() {
+            c.check(format!("{} {}, {}", name, xmm(dest), xmm(src)), |asm| {
+                emit(asm, dest, src)
+            });
+        }
+    }
+}
+
+fn check_freg_mem(c: &mut Checker, name: &str, size: &str, emit: FregMem) {
+    for (mem, addr) in mem_forms() {
+        for &dest in MEM_XMMS.iter() {
+            c.check(
+                format!("{} {}, {} ptr {}", name, xmm(dest), size, addr),
+                |asm| emit(asm, dest, mem),
+            );
+        }
+    }
+}
+
+#[test]
+fn scalar_and_packed() {
+    let regs = ops![
+        addss, addsd, subss, subsd, mulss, mulsd, divss, divsd, sqrtss, sqrtsd, movss, movsd,
+        cvtsd2ss, cvtss2sd, ucomiss, ucomisd, movaps, movups, addps, subps, mulps, divps, sqrtps,
+    ];
+    let mut c = Checker::new();
+
+    for &(name, emit) in regs.iter() {
+        check_freg_freg(&mut c, name, emit);
+    }
+
+    c.finish();
+}
+
+#[test]
+fn loads_and_stores() {
+    let loads = mem_ops![
+        (movups_load, "movups", "xmmword"),
+        (movaps_load, "movaps", "xmmword"),
+        (movss_load, "movss", "dword"),
+        (movsd_load, "movsd", "qword"),
+        (xorps, "xorps", "xmmword"),
+        (xorpd, "xorpd", "xmmword"),
+    ];
+    type MemFreg = fn(&mut Assembler, Mem, XMMRegister);
+    let stores: [(&str, MemFreg, &str); 4] = [
+        ("movups", movups_store, "xmmword"),
+        ("movaps", movaps_store, "xmmword"),
+        ("movss", movss_store, "dword"),
+        ("movsd", movsd_store, "qword"),
+    ];
+    let mut c = Checker::new();
+
+    for &(name, emit, size) in loads.iter() {
+        check_freg_mem(&mut c, name, size, emit);
+    }
+
+    for &(name, emit, size) in stores.iter() {
+        for (mem, addr) in mem_forms() {
+            for &src in MEM_XMMS.iter() {
+                c.check(
+                    format!("{} {} ptr {}, {}", name, size, addr, xmm(src)),
+                    |asm| emit(asm, mem, src),
+                );
+            }
+        }
+    }
+
+    c.finish();
+}
+
+#[test]
+fn conversions_and_moves() {
+    let mut c = Checker::new();
+
+    for &freg in XMMS.iter() {
+        for &reg in GPRS.iter() {
+            let f = xmm(freg);
+
+            for x64 in 0..2 {
+                let r = gpr(x64, reg);
+                c.check(format!("cvtsi2ss {}, {}", f, r), |asm| {
+                    cvtsi2ss(asm, freg, x64, reg)
+                });
+                c.check(format!("cvtsi2sd {}, {}", f, r), |asm| {
+                    cvtsi2sd(asm, freg, x64, reg)
+                });
+                c.check(format!("cvttss2si {}, {}", r, f), |asm| {
+                    cvttss2si(asm, x64, reg, freg)
+                });
+                c.check(format!("cvttsd2si {}, {}", r, f), |asm| {
+                    cvttsd2si(asm, x64, reg, freg)
+                });
+            }
+
+            c.check(format!("movd {}, {}", r32(reg), f), |asm| {
+                movd_reg_freg(asm, reg, freg)
+            });
+            c.check(format!("movq {}, {}", r64(reg), f), |asm| {
+                movq_reg_freg(asm, reg, freg)
+            });
+            c.check(format!("movd {}, {}", f, r32(reg)), |asm| {
+                movd_freg_reg(asm, freg, reg)
+            });
+            c.check(format!("movq {}, {}", f, r64(reg)), |asm| {
+                movq_freg_reg(asm, freg, reg)
+            });
+        }
+    }
+
+    for &dest in XMMS.iter() {
+        for &src in XMMS.iter() {
+            for &(mode, imm) in [
+                (RoundMode::Nearest, "8"),
+                (RoundMode::Down, "9"),
+                (RoundMode::Up, "0xa"),
+                (RoundMode::Toward, "0xb"),
+            ]
+            .iter()
+            {
+                let expected = format!("roundsd {}, {}, {}", xmm(dest), xmm(src), imm);
+                c.check(expected, |asm| roundsd(asm, dest, src, mode));
+            }
+        }
+    }
+
+    c.rejects("movlps between registers", |asm| movlps(asm, XMM0, XMM1));
+
+    c.finish();
+}
+
+#[test]
+fn sse2_integer() {
+    let regs = ops![
+        cvtps2dq, punpcklbw, punpcklwd, punpckldq, packsswb, packuswb, punpckhbw, punpckhwd,
+        punpckhdq, packssdw, punpcklqdq, punpckhqdq, paddb, paddw, paddd, paddsb, paddsw, paddusb,
+        paddusw, pcmpeqb, pcmpeqw, pcmpeqd, pcmpgtb, pcmpgtw, pcmpgtd, pmaxsw, pmaxub, pminsw,
+        pminub, pmullw, pmuludq, psllw, pslld, psraw, psrad, psrlw, psrld, psubb, psubw, psubd,
+        psubsb, psubsw, psubusb, psubusw, pand, por, pxor,
+    ];
+    let mems = mem_ops![
+        (cvtps2dq_mem, "cvtps2dq", "xmmword"),
+        (punpcklbw_mem, "punpcklbw", "xmmword"),
+        (punpcklwd_mem, "punpcklwd", "xmmword"),
+        (punpckldq_mem, "punpckldq", "xmmword"),
+        (packsswb_mem, "packsswb", "xmmword"),
+        (packuswb_mem, "packuswb", "xmmword"),
+        (punpckhbw_mem, "punpckhbw", "xmmword"),
+        (punpckhwd_mem, "punpckhwd", "xmmword"),
+        (punpckhdq_mem, "punpckhdq", "xmmword"),
+        (packssdw_mem, "packssdw", "xmmword"),
+        (punpcklqdq_mem, "punpcklqdq", "xmmword"),
+        (punpckhqdq_mem, "punpckhqdq", "xmmword"),
+        (paddb_mem, "paddb", "xmmword"),
+        (paddw_mem, "paddw", "xmmword"),
+        (paddd_mem, "paddd", "xmmword"),
+        (paddsb_mem, "paddsb", "xmmword"),
+        (paddsw_mem, "paddsw", "xmmword"),
+        (paddusb_mem, "paddusb", "xmmword"),
+        (paddusw_mem, "paddusw", "xmmword"),
+        (pcmpeqb_mem, "pcmpeqb", "xmmword"),
+        (pcmpeqw_mem, "pcmpeqw", "xmmword"),
+        (pcmpeqd_mem, "pcmpeqd", "xmmword"),
+        (pcmpgtb_mem, "pcmpgtb", "xmmword"),
+        (pcmpgtw_mem, "pcmpgtw", "xmmword"),
+        (pcmpgtd_mem, "pcmpgtd", "xmmword"),
+        (pmaxsw_mem, "pmaxsw", "xmmword"),
+        (pmaxub_mem, "pmaxub", "xmmword"),
+        (pminsw_mem, "pminsw", "xmmword"),
+        (pminub_mem, "pminub", "xmmword"),
+        (pmullw_mem, "pmullw", "xmmword"),
+        (pmuludq_mem, "pmuludq", "xmmword"),
+        (psllw_mem, "psllw", "xmmword"),
+        (pslld_mem, "pslld", "xmmword"),
+        (psraw_mem, "psraw", "xmmword"),
+        (psrad_mem, "psrad", "xmmword"),
+        (psrlw_mem, "psrlw", "xmmword"),
+        (psrld_mem, "psrld", "xmmword"),
+        (psubb_mem, "psubb", "xmmword"),
+        (psubw_mem, "psubw", "xmmword"),
+        (psubd_mem, "psubd", "xmmword"),
+        (psubsb_mem, "psubsb", "xmmword"),
+        (psubsw_mem, "psubsw", "xmmword"),
+        (psubusb_mem, "psubusb", "xmmword"),
+        (psubusw_mem, "psubusw", "xmmword"),
+        (pand_mem, "pand", "xmmword"),
+        (por_mem, "por", "xmmword"),
+        (pxor_mem, "pxor", "xmmword"),
+    ];
+    let mut c = Checker::new();
+
+    for &(name, emit) in regs.iter() {
+        check_freg_freg(&mut c, name, emit);
+    }
+
+    for &(name, emit, size) in mems.iter() {
+        check_freg_mem(&mut c, name, size, emit);
+    }
+
+    c.finish();
+}
+
+#[test]
+fn ssse3_and_sse41() {
+    let regs = ops![
+        pabsb, pabsw, pabsd, phaddd, phaddw, pshufb, psignb, psignw, psignd, ptest, pmovsxbw,
+        pmovsxwd, packusdw, pmovzxbw, pmovzxwd, pminsb, pminsd, pminuw, pminud, pmaxsb, pmaxsd,
+        pmaxuw, pmaxud, pmulld,
+    ];
+    let mems = mem_ops![
+        (pabsb_mem, "pabsb", "xmmword"),
+        (pabsw_mem, "pabsw", "xmmword"),
+        (pabsd_mem, "pabsd", "xmmword"),
+        (phaddd_mem, "phaddd", "xmmword"),
+        (phaddw_mem, "phaddw", "xmmword"),
+        (pshufb_mem, "pshufb", "xmmword"),
+        (psignb_mem, "psignb", "xmmword"),
+        (psignw_mem, "psignw", "xmmword"),
+        (psignd_mem, "psignd", "xmmword"),
+        (ptest_mem, "ptest", "xmmword"),
+        (pmovsxbw_mem, "pmovsxbw", "qword"),
+        (pmovsxwd_mem, "pmovsxwd", "qword"),
+        (packusdw_mem, "packusdw", "xmmword"),
+        (pmovzxbw_mem, "pmovzxbw", "qword"),
+        (pmovzxwd_mem, "pmovzxwd", "qword"),
+        (pminsb_mem, "pminsb", "xmmword"),
+        (pminsd_mem, "pminsd", "xmmword"),
+        (pminuw_mem, "pminuw", "xmmword"),
+        (pminud_mem, "pminud", "xmmword"),
+        (pmaxsb_mem, "pmaxsb", "xmmword"),
+        (pmaxsd_mem, "pmaxsd", "xmmword"),
+        (pmaxuw_mem, "pmaxuw", "xmmword"),
+        (pmaxud_mem, "pmaxud", "xmmword"),
+        (pmulld_mem, "pmulld", "xmmword"),
+    ];
+    let mut c = Checker::new();
+
+    for &(name, emit) in regs.iter() {
+        check_freg_freg(&mut c, name, emit);
+    }
+
+    for &(name, emit, size) in mems.iter() {
+        check_freg_mem(&mut c, name, size, emit);
+    }
+
+    c.finish();
+}
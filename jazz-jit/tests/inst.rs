@@ -0,0 +1,394 @@
+//! Instruction lists, printed and lowered through the encoders.
+
+mod common;
+
+use common::*;
+use jazz_jit::assembler::{AsmDiagnostic, Mem, RipTarget, Segment, SegmentAddr};
+use jazz_jit::assembler_x64::*;
+use jazz_jit::inst::{FloatOp, Inst, InstBuffer, Operand};
+use jazz_jit::{CondCode, MachineMode};
+
+fn lower_one(asm: &mut Assembler, inst: &Inst) {
+    let mut buf = InstBuffer::new();
+    buf.push(inst.clone());
+    buf.lower(asm);
+}
+
+/// Instructions without labels, over a few registers and all operand kinds.
+fn insts() -> Vec<Inst> {
+    use jazz_jit::inst::Operand::{Imm, Reg, Xmm};
+    use MachineMode::*;
+
+    let mut insts = Vec::new();
+    let mems = [
+        Mem::Base(RAX, 8),
+        Mem::Local(-16),
+        Mem::Index(R12, RCX, 4, 0x100),
+        Mem::Offset(R9, 8, -8),
+        Mem::Segment(Segment::Fs, SegmentAddr::Absolute(0x28)),
+        Mem::Segment(Segment::Gs, SegmentAddr::Index(R12, RCX, 8, -0x10)),
+    ];
+
+    for &(src, dest) in [(RCX, RAX), (R15, RSI), (RSP, R8)].iter() {
+        for &mode in [Int8, Int16, Int32, Int64].iter() {
+            insts.push(Inst::Mov {
+                mode,
+                src: Reg(src),
+                dest: Reg(dest),
+            });
+            insts.push(Inst::Mov {
+                mode,
+                src: Imm(-3),
+                dest: Operand::Mem(mems[0]),
+            });
+
+            for &op in [
+                AluOp::Add,
+                AluOp::Or,
+                AluOp::And,
+                AluOp::Sub,
+                AluOp::Xor,
+                AluOp::Cmp,
+            ]
+            .iter()
+            {
+                insts.push(Inst::Alu {
+                    mode,
+                    op,
+                    src: Reg(src),
+                    dest: Reg(dest),
+                });
+                insts.push(Inst::Alu {
+                    mode,
+                    op,
+                    src: Imm(0x12),
+                    dest: Reg(dest),
+                });
+            }
+
+            for &op in [ShiftOp::Rol, ShiftOp::Shl, ShiftOp::Sar].iter() {
+                insts.push(Inst::Shift {
+                    mode,
+                    op,
+                    count: Imm(3),
+                    dest: Reg(dest),
+                });
+                insts.push(Inst::Shift {
+                    mode,
+                    op,
+                    count: Reg(RCX),
+                    dest: Reg(dest),
+                });
+            }
+        }
+
+        for &mem in mems.iter() {
+            insts.push(Inst::Mov {
+                mode: Int64,
+                src: Operand::Mem(mem),
+                dest: Reg(dest),
+            });
+            insts.push(Inst::Mov {
+                mode: Int32,
+                src: Reg(src),
+                dest: Operand::Mem(mem),
+            });
+            insts.push(Inst::Lea {
+                src: Operand::Mem(mem),
+                dest: Reg(dest),
+            });
+            insts.push(Inst::Alu {
+                mode: Int64,
+                op: AluOp::Add,
+                src: Operand::Mem(mem),
+                dest: Reg(dest),
+            });
+            insts.push(Inst::Alu {
+                mode: Int16,
+                op: AluOp::Sub,
+                src: Reg(src),
+                dest: Operand::Mem(mem),
+            });
+            insts.push(Inst::Movzx {
+                mode: Int8,
+                src: Operand::Mem(mem),
+                dest: Reg(dest),
+            });
+        }
+
+        for &mode in [Int32, Int64].iter() {
+            insts.push(Inst::Mov {
+                mode,
+                src: Imm(0x1234),
+                dest: Reg(dest),
+            });
+            insts.push(Inst::Test {
+                mode,
+                src: Reg(src),
+                dest: Reg(dest),
+            });
+            insts.push(Inst::Neg {
+                mode,
+                dest: Reg(dest),
+            });
+            insts.push(Inst::Not {
+                mode,
+                dest: Reg(dest),
+            });
+            insts.push(Inst::Imul {
+                mode,
+                src: Reg(src),
+                dest: Reg(dest),
+            });
+            insts.push(Inst::Idiv {
+                mode,
+                src: Reg(src),
+            });
+            insts.push(Inst::SignExtendRax { mode });
+        }
+
+        for &mode in [Int8, Int16].iter() {
+            insts.push(Inst::Movzx {
+                mode,
+                src: Reg(src),
+                dest: Reg(dest),
+            });
+            insts.push(Inst::Movsx {
+                mode,
+                src: Reg(src),
+                dest: Reg(dest),
+            });
+        }
+
+        for &(cond, _) in CONDS.iter() {
+            insts.push(Inst::Setcc {
+                cond,
+                dest: Reg(dest),
+            });
+            insts.push(Inst::Cmov {
+                mode: Int64,
+                cond,
+                src: Reg(src),
+                dest: Reg(dest),
+            });
+        }
+
+        insts.push(Inst::Mov {
+            mode: Int64,
+            src: Imm(0x1234_5678_9abc),
+            dest: Reg(dest),
+        });
+        insts.push(Inst::Push(Reg(src)));
+        insts.push(Inst::Pop(Reg(dest)));
+        insts.push(Inst::Jmp(Reg(src)));
+        insts.push(Inst::Call(Reg(src)));
+    }
+
+    for &(src, dest) in [(XMM1, XMM0), (XMM15, XMM8)].iter() {
+        for &mode in [Float32, Float64].iter() {
+            insts.push(Inst::Mov {
+                mode,
+                src: Xmm(src),
+                dest: Xmm(dest),
+            });
+            insts.push(Inst::Mov {
+                mode,
+                src: Operand::Mem(mems[2]),
+                dest: Xmm(dest),
+            });
+            insts.push(Inst::Mov {
+                mode,
+                src: Xmm(src),
+                dest: Operand::Mem(mems[1]),
+            });
+            insts.push(Inst::FloatCmp {
+                mode,
+                src: Xmm(src),
+                dest: Xmm(dest),
+            });
+
+            for &op in [
+                FloatOp::Add,
+                FloatOp::Sub,
+                FloatOp::Mul,
+                FloatOp::Div,
+                FloatOp::Sqrt,
+            ]
+            .iter()
+            {
+                insts.push(Inst::Float {
+                    mode,
+                    op,
+                    src: Xmm(src),
+                    dest: Xmm(dest),
+                });
+                insts.push(Inst::Float {
+                    mode,
+                    op,
+                    src: Operand::Mem(mems[3]),
+                    dest: Xmm(dest),
+                });
+            }
+        }
+
+        for &mode in [Int32, Int64].iter() {
+            insts.push(Inst::Mov {
+                mode,
+                src: Reg(R10),
+                dest: Xmm(dest),
+            });
+            insts.push(Inst::Mov {
+                mode,
+                src: Xmm(src),
+                dest: Reg(RDX),
+            });
+        }
+    }
+
+    insts.push(Inst::Ret);
+    insts.push(Inst::Nop);
+    insts
+}
+
+#[test]
+fn printed_like_decoded() {
+    let mut c = Checker::new();
+
+    for inst in insts() {
+        c.check(inst.to_string(), |asm| lower_one(asm, &inst));
+    }
+
+    c.finish();
+}
+
+#[test]
+fn invalid_operands() {
+    use jazz_jit::inst::Operand::{Imm, Reg, Xmm};
+    use MachineMode::*;
+
+    let mut c = Checker::new();
+    let invalid = [
+        Inst::Mov {
+            mode: Int64,
+            src: Operand::Mem(Mem::Local(0)),
+            dest: Operand::Mem(Mem::Local(8)),
+        },
+        Inst::Mov {
+            mode: Int32,
+            src: Imm(1 << 40),
+            dest: Reg(RAX),
+        },
+        Inst::Alu {
+            mode: Int64,
+            op: AluOp::Add,
+            src: Reg(RAX),
+            dest: Imm(1),
+        },
+        Inst::Shift {
+            mode: Int64,
+            op: ShiftOp::Shl,
+            count: Reg(RDX),
+            dest: Reg(RAX),
+        },
+        Inst::Neg {
+            mode: Int8,
+            dest: Reg(RAX),
+        },
+        Inst::Float {
+            mode: Int64,
+            op: FloatOp::Add,
+            src: Xmm(XMM1),
+            dest: Xmm(XMM0),
+        },
+        Inst::Push(Xmm(XMM0)),
+        Inst::Jcc {
+            cond: CondCode::Zero,
+            target: 3,
+        },
+    ];
+
+    for inst in invalid.iter() {
+        c.rejects(inst.to_string(), |asm| lower_one(asm, inst));
+    }
+
+    c.finish();
+
+    let mut asm = assembler();
+    lower_one(&mut asm, &Inst::Push(Xmm(XMM0)));
+    assert_eq!(
+        asm.diagnostics[0],
+        AsmDiagnostic::InvalidOperand {
+            at: 0,
+            message: "invalid operands for `push xmm0`".to_owned(),
+        }
+    );
+}
+
+/// A loop that counts RDI down to zero, written once as a buffer and once directly.
+#[test]
+fn labels_lower_like_direct_code() {
+    let mut buf = InstBuffer::new();
+    let head = buf.create_named_label("head");
+    let done = buf.create_label();
+    let table = buf.create_label();
+
+    buf.push(Inst::Bind(head));
+    buf.push(Inst::Test {
+        mode: MachineMode::Int64,
+        src: Operand::Reg(RDI),
+        dest: Operand::Reg(RDI),
+    });
+    buf.push(Inst::Jcc {
+        cond: CondCode::Zero,
+        target: done,
+    });
+    buf.push(Inst::Comment("decrement".to_owned()));
+    buf.push(Inst::Alu {
+        mode: MachineMode::Int64,
+        op: AluOp::Sub,
+        src: Operand::Imm(1),
+        dest: Operand::Reg(RDI),
+    });
+    buf.push(Inst::Jmp(Operand::Label(head)));
+    buf.push(Inst::Bind(done));
+    buf.push(Inst::Lea {
+        src: Operand::Mem(Mem::RipRelative(RipTarget::Label(table))),
+        dest: Operand::Reg(RAX),
+    });
+    buf.push(Inst::Ret);
+    buf.push(Inst::Bind(table));
+    buf.push(Inst::Nop);
+
+    assert_eq!(
+        buf.to_string(),
+        "head:\n  test rdi, rdi\n  je L1\n  ; decrement\n  sub rdi, 1\n  jmp head\nL1:\n  \
+         lea rax, [rip + L2]\n  ret\nL2:\n  nop\n"
+    );
+
+    let mut lowered = assembler();
+    let labels = buf.lower(&mut lowered);
+    assert_eq!(labels.len(), 3);
+
+    let mut direct = assembler();
+    let (head, done, table) = (
+        direct.create_named_label("head"),
+        direct.create_label(),
+        direct.create_label(),
+    );
+    direct.bind_label(head);
+    emit_testq_reg_reg(&mut direct, RDI, RDI);
+    direct.jump_if(CondCode::Zero, done);
+    direct.comment("decrement");
+    emit_subq_imm_reg(&mut direct, 1, RDI);
+    direct.jump(head);
+    direct.bind_label(done);
+    lea(&mut direct, RAX, Mem::RipRelative(RipTarget::Label(table)));
+    emit_retq(&mut direct);
+    direct.bind_label(table);
+    emit_nop(&mut direct);
+
+    assert_eq!(
+        lowered.finalize().unwrap().data,
+        direct.finalize().unwrap().data
+    );
+}
@@ -0,0 +1,120 @@
+//! Object files written from finalized code, checked with the system toolchain.
+
+mod common;
+
+use common::*;
+use jazz_jit::assembler::{Code, Mem, RipTarget};
+use jazz_jit::assembler_x64::*;
+use jazz_jit::object::ObjectFile;
+use jazz_jit::reloc::{RelocError, RelocTarget};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+const MAIN: &str = r#"
+long aot_helper(long value) { return value * 2; }
+long aot_twice(long value);
+double aot_constant(void);
+long aot_self(void);
+long aot_abs(long value);
+
+int main(void) {
+    if (aot_twice(21) != 42) return 1;
+    if (aot_constant() != 1.5) return 2;
+    if (aot_self() != (long) aot_self) return 3;
+    if (aot_abs(-7) != 7) return 4;
+    return 0;
+}
+"#;
+
+fn function(name: &str, emit: impl FnOnce(&mut Assembler)) -> Code {
+    let mut asm = assembler();
+    asm.set_name(name);
+    emit(&mut asm);
+    emit_retq(&mut asm);
+    asm.finalize().unwrap()
+}
+
+fn functions() -> Vec<Code> {
+    let twice = function("aot_twice", |asm| {
+        emit_pushq_reg(asm, RBP);
+        asm.call_rel(RelocTarget::Symbol("aot_helper".into()));
+        emit_popq_reg(asm, RBP);
+    });
+
+    // movaps faults unless .rodata keeps the vector aligned
+    let constant = function("aot_constant", |asm| {
+        asm.dseg.add_int(3);
+        let mut lanes = [0; 16];
+        lanes[..8].copy_from_slice(&1.5f64.to_le_bytes());
+        let disp = asm.dseg.add_v128(lanes);
+        movaps_load(asm, XMM0, Mem::RipRelative(RipTarget::DSeg(disp)));
+    });
+
+    let this = function("aot_self", |asm| {
+        let start = asm.create_label();
+        asm.bind_label(start);
+        asm.load_addr(RAX, RelocTarget::Label(start));
+    });
+
+    // labs comes from the shared C library
+    let abs = function("aot_abs", |asm| {
+        emit_pushq_reg(asm, RBP);
+        asm.call_rel(RelocTarget::Symbol("labs".into()));
+        emit_popq_reg(asm, RBP);
+    });
+
+    vec![twice, constant, this, abs]
+}
+
+fn run(dir: &Path, program: &str, args: &[&str]) -> Option<String> {
+    let output = Command::new(program)
+        .args(args)
+        .current_dir(dir)
+        .output()
+        .ok()?;
+    assert!(
+        output.status.success(),
+        "{} failed: {}",
+        program,
+        String::from_utf8_lossy(&output.stderr)
+    );
+
+    Some(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+#[test]
+fn link_object_file() {
+    let mut object = ObjectFile::new();
+    for code in functions() {
+        object.add_function(&code).unwrap();
+    }
+
+    let dir: PathBuf = std::env::temp_dir().join(format!("jazz-jit-object-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("aot.o"), object.finish()).unwrap();
+    std::fs::write(dir.join("main.c"), MAIN).unwrap();
+
+    // the toolchain is optional, the tests only run what is installed
+    if let Some(dump) = run(&dir, "objdump", &["-dr", "aot.o"]) {
+        assert!(dump.contains("<aot_twice>:"));
+        assert!(dump.contains("R_X86_64_PLT32\taot_helper-0x4"));
+        assert!(dump.contains("R_X86_64_PLT32\tlabs-0x4"));
+        assert!(dump.contains("R_X86_64_PC32\t.rodata-0x4"));
+        assert!(dump.contains("R_X86_64_64\t.text+0x20"));
+    }
+
+    if run(&dir, "cc", &["main.c", "aot.o", "-o", "main"]).is_some() {
+        let status = Command::new(dir.join("main")).status().unwrap();
+        assert_eq!(status.code(), Some(0));
+    }
+
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    let mut asm = assembler();
+    asm.call_rel(RelocTarget::Address(0x1000));
+    let code = asm.finalize().unwrap();
+    assert_eq!(
+        ObjectFile::new().add_function(&code),
+        Err(RelocError::FixedAddress { at: 1 })
+    );
+}
@@ -0,0 +1,215 @@
+//! Peephole rules applied to emitted code before it is finalized.
+
+mod common;
+
+use common::*;
+use jazz_jit::assembler::{Mem, RipTarget};
+use jazz_jit::assembler_x64::*;
+use jazz_jit::code_cache::CodeCache;
+use jazz_jit::inst::Inst;
+use jazz_jit::peephole::{Insn, Peephole, Rule};
+use jazz_jit::unwind::CfiOp;
+use jazz_jit::{CondCode, JitFunction, MachineMode};
+
+/// Runs all rules over the code `emit` produces. Returns the decoded result and the
+/// rules that matched.
+fn optimize<F: FnOnce(&mut Assembler)>(emit: F) -> (String, Vec<(&'static str, usize)>) {
+    let mut asm = assembler();
+    emit(&mut asm);
+    let hits = Peephole::new().run(&mut asm);
+    let hits = hits.into_iter().filter(|&(_, hits)| hits > 0).collect();
+
+    (disassemble(&capstone(), asm.data()), hits)
+}
+
+#[test]
+fn rules() {
+    assert_eq!(
+        optimize(|asm| {
+            emit_mov_reg_reg(asm, 1, R9, R9);
+            emit_mov_reg_reg(asm, 0, RAX, RAX);
+            emit_retq(asm);
+        }),
+        ("mov eax, eax; ret".to_owned(), vec![("mov_to_self", 1)])
+    );
+
+    // the flags of the compare are used, those of the xor would not be
+    assert_eq!(
+        optimize(|asm| {
+            emit_movl_imm_reg(asm, 0, RAX);
+            emit_cmp_reg_reg(asm, 1, RSI, RDI);
+            mov_imm_reg(asm, MachineMode::Int64, 0, R10);
+            emit_setb_reg(asm, CondCode::Equal, RAX);
+            emit_retq(asm);
+        }),
+        (
+            "xor eax, eax; cmp rdi, rsi; mov r10, 0; sete al; ret".to_owned(),
+            vec![("zero_with_xor", 1)]
+        )
+    );
+
+    assert_eq!(
+        optimize(|asm| {
+            let next = asm.create_label();
+            asm.jump_if(CondCode::Less, next);
+            asm.bind_label(next);
+            emit_shr_reg_imm(asm, 1, RBX, 1);
+            emit_shlq_reg(asm, 1, RBX);
+            emit_retq(asm);
+        }),
+        (
+            "and rbx, 0xfffffffffffffffe; ret".to_owned(),
+            vec![("jump_to_next", 1), ("untag_tag", 1)]
+        )
+    );
+
+    // the inner pair only shows up once the outer one is gone
+    assert_eq!(
+        optimize(|asm| {
+            emit_pushq_reg(asm, RAX);
+            emit_pushq_reg(asm, R12);
+            emit_popq_reg(asm, R12);
+            emit_popq_reg(asm, RCX);
+            emit_retq(asm);
+        }),
+        ("mov rcx, rax; ret".to_owned(), vec![("push_pop", 2)])
+    );
+
+    // instructions a label is bound between are left alone, and the flags may be
+    // used behind a jump
+    assert_eq!(
+        optimize(|asm| {
+            let lbl = asm.create_label();
+            emit_pushq_reg(asm, RAX);
+            asm.bind_label(lbl);
+            emit_popq_reg(asm, RAX);
+            emit_shr_reg_imm(asm, 1, RAX, 1);
+            emit_shlq_reg(asm, 1, RAX);
+            emit_jmp_reg(asm, RAX);
+        }),
+        (
+            "push rax; pop rax; shr rax, 1; shl rax, 1; jmp rax".to_owned(),
+            vec![]
+        )
+    );
+}
+
+/// Sums the constant 3 `n` times, clearing the lowest bit after every addition, with
+/// a piece of each pattern.
+fn function(asm: &mut Assembler) {
+    let head = asm.create_label();
+    let done = asm.create_label();
+    let three = asm.dseg.add_int(3);
+
+    emit_pushq_reg(asm, RBX);
+    emit_popq_reg(asm, RBX);
+    mov_imm_reg(asm, MachineMode::Int64, 0, RAX);
+    emit_testq_reg_reg(asm, RDI, RDI);
+    asm.jump_if(CondCode::Zero, done);
+
+    asm.bind_label(head);
+    asm.comment("loop");
+    movsx_mem(
+        asm,
+        1,
+        MachineMode::Int16,
+        Mem::RipRelative(RipTarget::DSeg(three)),
+        RCX,
+    );
+    emit_add_reg_reg(asm, 1, RCX, RAX);
+    emit_shr_reg_imm(asm, 1, RAX, 1);
+    emit_shlq_reg(asm, 1, RAX);
+    emit_subq_imm_reg(asm, 1, RDI);
+    asm.jump_if(CondCode::NotEqual, head);
+    asm.jump(done);
+
+    asm.bind_label(done);
+    emit_retq(asm);
+}
+
+#[test]
+fn code_keeps_working() {
+    let mut asm = assembler();
+    function(&mut asm);
+    let len = asm.pos();
+
+    assert_eq!(
+        Peephole::new().run(&mut asm),
+        [
+            ("mov_to_self", 0),
+            ("zero_with_xor", 1),
+            ("jump_to_next", 1),
+            ("push_pop", 1),
+            ("untag_tag", 1),
+        ]
+    );
+
+    // two bytes of the push and pop, five of the mov, three of the shifts and the
+    // whole jump
+    assert_eq!(asm.pos(), len - 2 - 5 - 3 - 5);
+    assert_eq!(asm.labels[1], Some(asm.pos() - 1));
+    assert_eq!(asm.comments, [(asm.labels[0].unwrap(), "loop".to_owned())]);
+    // the push and pop are gone, so is their effect on the frame
+    assert_eq!(
+        asm.cfi_ops,
+        [
+            (0, CfiOp::DefCfaOffset(16)),
+            (0, CfiOp::Offset(RBX, -16)),
+            (0, CfiOp::Restore(RBX)),
+            (0, CfiOp::DefCfaOffset(8)),
+        ]
+    );
+
+    let optimized = asm.finalize().unwrap();
+    let mut asm = assembler();
+    function(&mut asm);
+    let plain = asm.finalize().unwrap();
+    let cache = CodeCache::new(1 << 20);
+
+    for code in vec![plain, optimized] {
+        let fun: JitFunction<extern "C" fn(i64) -> i64> =
+            unsafe { JitFunction::new(cache.emit(&code).unwrap()) };
+
+        for n in 0..5 {
+            assert_eq!(fun.call(n), 2 * n);
+        }
+    }
+}
+
+/// Drops every `nop`.
+struct NoNops;
+
+impl Rule for NoNops {
+    fn name(&self) -> &'static str {
+        "no_nops"
+    }
+
+    fn rewrite(&self, insns: &[Insn]) -> Option<(usize, Vec<Inst>)> {
+        let count = insns
+            .iter()
+            .take_while(|insn| insn.inst == Some(Inst::Nop))
+            .count();
+
+        if count > 0 {
+            Some((count, Vec::new()))
+        } else {
+            None
+        }
+    }
+}
+
+#[test]
+fn custom_rules() {
+    let mut asm = assembler();
+    emit_nop(&mut asm);
+    emit_nop(&mut asm);
+    emit_mov_reg_reg(&mut asm, 1, RAX, RAX);
+    asm.set_source_position(1, 2, 3);
+    emit_nop(&mut asm);
+    emit_retq(&mut asm);
+
+    let rules = Peephole::empty().with_rule(NoNops);
+    assert_eq!(rules.run(&mut asm), [("no_nops", 2)]);
+    assert_eq!(disassemble(&capstone(), asm.data()), "mov rax, rax; ret");
+    assert_eq!(asm.source_positions[0].0, 3);
+}
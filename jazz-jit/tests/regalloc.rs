@@ -0,0 +1,318 @@
+//! Register allocation over virtual registers, checked by running the allocated code.
+
+mod common;
+
+use common::*;
+use jazz_jit::assembler::Label;
+use jazz_jit::assembler_x64::*;
+use jazz_jit::code_cache::CodeCache;
+use jazz_jit::inst::{Inst, InstBuffer, Operand};
+use jazz_jit::regalloc::{Allocation, Function, Location, RegClass, Registers, VReg};
+use jazz_jit::{CondCode, JitFunction, MachineMode};
+use std::collections::HashSet;
+
+use MachineMode::Int64;
+
+fn lower(asm: &mut Assembler, inst: Inst) {
+    let mut buf = InstBuffer::new();
+    buf.push(inst);
+    buf.lower(asm);
+}
+
+/// Operations of the test program, each one instruction of the `Function` except
+/// for `Head`.
+enum Op {
+    Args,
+    Imm(VReg, i64),
+    Add(VReg, VReg, VReg),
+    Dec(VReg),
+    Head(Label),
+    LoopNonZero(VReg, Label),
+    Div(VReg),
+    Shl(VReg, VReg),
+    Call(usize),
+    Ret,
+}
+
+extern "C" fn combine(a: i64, b: i64) -> i64 {
+    a * 10 + b
+}
+
+/// `dest = src`, through the scratch register R11 when both are in memory.
+fn copy(asm: &mut Assembler, dest: Operand, src: Operand) {
+    lower(
+        asm,
+        Inst::Mov {
+            mode: Int64,
+            src,
+            dest: Operand::Reg(R11),
+        },
+    );
+    lower(
+        asm,
+        Inst::Mov {
+            mode: Int64,
+            src: Operand::Reg(R11),
+            dest,
+        },
+    );
+}
+
+fn emit(asm: &mut Assembler, ops: &[Op], alloc: &Allocation) {
+    let saved = alloc.callee_saved();
+    // after pushing RBP the stack is aligned, keep it that way
+    let frame = 8 * (alloc.slots() + saved.len());
+    let frame = (frame + 15) & !15;
+
+    emit_pushq_reg(asm, RBP);
+    emit_mov_reg_reg(asm, 1, RSP, RBP);
+    emit_subq_imm_reg(asm, (frame - 8 * saved.len()) as i32, RSP);
+    for &reg in &saved {
+        emit_pushq_reg(asm, reg);
+    }
+
+    let mut inst = 0;
+    for op in ops {
+        if let Op::Head(lbl) = *op {
+            asm.bind_label(lbl);
+            continue;
+        }
+
+        alloc.emit_before(asm, inst);
+
+        match *op {
+            Op::Args | Op::Head(_) => {}
+            Op::Imm(dest, value) => lower(
+                asm,
+                Inst::Mov {
+                    mode: Int64,
+                    src: Operand::Imm(value),
+                    dest: alloc.operand(dest),
+                },
+            ),
+            Op::Add(dest, lhs, rhs) => {
+                lower(
+                    asm,
+                    Inst::Mov {
+                        mode: Int64,
+                        src: alloc.operand(lhs),
+                        dest: Operand::Reg(R11),
+                    },
+                );
+                lower(
+                    asm,
+                    Inst::Alu {
+                        mode: Int64,
+                        op: AluOp::Add,
+                        src: alloc.operand(rhs),
+                        dest: Operand::Reg(R11),
+                    },
+                );
+                copy(asm, alloc.operand(dest), Operand::Reg(R11));
+            }
+            Op::Dec(value) => lower(
+                asm,
+                Inst::Alu {
+                    mode: Int64,
+                    op: AluOp::Sub,
+                    src: Operand::Imm(1),
+                    dest: alloc.operand(value),
+                },
+            ),
+            Op::LoopNonZero(value, head) => {
+                lower(
+                    asm,
+                    Inst::Alu {
+                        mode: Int64,
+                        op: AluOp::Cmp,
+                        src: Operand::Imm(0),
+                        dest: alloc.operand(value),
+                    },
+                );
+                asm.jump_if(CondCode::NotEqual, head);
+            }
+            Op::Div(divisor) => {
+                copy(asm, Operand::Reg(R11), alloc.operand(divisor));
+                lower(asm, Inst::SignExtendRax { mode: Int64 });
+                lower(
+                    asm,
+                    Inst::Idiv {
+                        mode: Int64,
+                        src: Operand::Reg(R11),
+                    },
+                );
+            }
+            Op::Shl(dest, value) => {
+                copy(asm, Operand::Reg(R11), alloc.operand(value));
+                lower(
+                    asm,
+                    Inst::Shift {
+                        mode: Int64,
+                        op: ShiftOp::Shl,
+                        count: Operand::Reg(RCX),
+                        dest: Operand::Reg(R11),
+                    },
+                );
+                copy(asm, alloc.operand(dest), Operand::Reg(R11));
+            }
+            Op::Call(target) => {
+                lower(
+                    asm,
+                    Inst::Mov {
+                        mode: Int64,
+                        src: Operand::Imm(target as i64),
+                        dest: Operand::Reg(R11),
+                    },
+                );
+                emit_callq_reg(asm, R11);
+            }
+            Op::Ret => {
+                for &reg in saved.iter().rev() {
+                    emit_popq_reg(asm, reg);
+                }
+                emit_mov_reg_reg(asm, 1, RBP, RSP);
+                emit_popq_reg(asm, RBP);
+                emit_retq(asm);
+            }
+        }
+
+        alloc.emit_after(asm, inst);
+        inst += 1;
+    }
+}
+
+/// `combine(sum(1..=n) / k << 2, sum(1..=n) % k) + sum(n + j for j in 0..16)`, with
+/// the sixteen summands kept live over everything else so some have to be spilled.
+#[test]
+fn code_runs() {
+    let mut asm = assembler();
+    let mut fun = Function::new();
+    let mut ops = Vec::new();
+    let gpr = |fun: &mut Function| fun.vreg(RegClass::Gpr);
+
+    let (n, k) = (gpr(&mut fun), gpr(&mut fun));
+    fun.inst(
+        &[],
+        &[
+            jazz_jit::regalloc::Operand::fixed(n, Reg::Gpr(RDI)),
+            jazz_jit::regalloc::Operand::fixed(k, Reg::Gpr(RSI)),
+        ],
+        &[],
+    );
+    ops.push(Op::Args);
+
+    let mut summands = Vec::new();
+    for j in 0..16 {
+        let (imm, summand) = (gpr(&mut fun), gpr(&mut fun));
+        fun.inst(&[], &[imm.into()], &[]);
+        ops.push(Op::Imm(imm, j));
+        fun.inst(&[n.into(), imm.into()], &[summand.into()], &[]);
+        ops.push(Op::Add(summand, n, imm));
+        summands.push(summand);
+    }
+
+    let (acc, counter, zero) = (gpr(&mut fun), gpr(&mut fun), gpr(&mut fun));
+    fun.inst(&[], &[zero.into()], &[]);
+    ops.push(Op::Imm(zero, 0));
+    fun.inst(&[n.into(), zero.into()], &[counter.into()], &[]);
+    ops.push(Op::Add(counter, n, zero));
+    fun.inst(&[], &[acc.into()], &[]);
+    ops.push(Op::Imm(acc, 0));
+
+    let head = asm.create_label();
+    ops.push(Op::Head(head));
+    let first = fun.inst(&[acc.into(), counter.into()], &[acc.into()], &[]);
+    ops.push(Op::Add(acc, acc, counter));
+    fun.inst(&[counter.into()], &[counter.into()], &[]);
+    ops.push(Op::Dec(counter));
+    let last = fun.inst(&[counter.into()], &[], &[]);
+    ops.push(Op::LoopNonZero(counter, head));
+    fun.jump(last, first);
+
+    let (quotient, remainder) = (gpr(&mut fun), gpr(&mut fun));
+    fun.idiv(acc, k, quotient, remainder);
+    ops.push(Op::Div(k));
+
+    let (count, shifted) = (gpr(&mut fun), gpr(&mut fun));
+    fun.inst(&[], &[count.into()], &[]);
+    ops.push(Op::Imm(count, 2));
+    fun.shift(quotient, count, shifted);
+    ops.push(Op::Shl(shifted, quotient));
+
+    let combined = gpr(&mut fun);
+    fun.call(&[shifted, remainder], Some(combined));
+    ops.push(Op::Call(combine as *const () as usize));
+
+    let mut total = combined;
+    for &summand in &summands {
+        let sum = gpr(&mut fun);
+        fun.inst(&[total.into(), summand.into()], &[sum.into()], &[]);
+        ops.push(Op::Add(sum, total, summand));
+        total = sum;
+    }
+
+    fun.inst(
+        &[jazz_jit::regalloc::Operand::fixed(total, Reg::Gpr(RAX))],
+        &[],
+        &[],
+    );
+    ops.push(Op::Ret);
+
+    let alloc = fun.allocate(&Registers::default());
+    assert!(alloc.slots() > 0);
+    assert!(!alloc.callee_saved().is_empty());
+    // live over the call, so never in a register the call may change
+    for &summand in &summands {
+        if let Location::Reg(reg) = alloc.location(summand) {
+            assert!(!jazz_jit::regalloc::caller_saved().contains(&reg));
+        }
+    }
+
+    emit(&mut asm, &ops, &alloc);
+    let code = asm.finalize().unwrap();
+    let cache = CodeCache::new(1 << 20);
+    let fun: JitFunction<extern "C" fn(i64, i64) -> i64> =
+        unsafe { JitFunction::new(cache.emit(&code).unwrap()) };
+
+    for &(n, k) in [(1, 1), (5, 4), (10, 7), (100, -9)].iter() {
+        let acc: i64 = (1..=n).sum();
+        let expected = combine((acc / k) << 2, acc % k) + (0..16).map(|j| n + j).sum::<i64>();
+        assert_eq!(fun.call(n, k), expected, "n = {}, k = {}", n, k);
+    }
+}
+
+#[test]
+fn floats_get_distinct_locations() {
+    let mut fun = Function::new();
+    let floats: Vec<VReg> = (0..20).map(|_| fun.vreg(RegClass::Float)).collect();
+    let result = fun.vreg(RegClass::Float);
+
+    for &float in &floats {
+        fun.inst(&[], &[float.into()], &[]);
+    }
+    fun.call(&[floats[0], floats[1]], Some(result));
+    let uses: Vec<_> = floats[2..]
+        .iter()
+        .chain(Some(&result))
+        .map(|&float| float.into())
+        .collect();
+    fun.inst(&uses, &[], &[]);
+
+    let alloc = fun.allocate(&Registers::default());
+    let locations: HashSet<Location> = floats.iter().map(|&f| alloc.location(f)).collect();
+    assert_eq!(locations.len(), floats.len());
+
+    // every float register is clobbered by the call
+    for &float in &floats[2..] {
+        assert!(matches!(alloc.location(float), Location::Spill(_)));
+    }
+    assert_eq!(alloc.location(result), Location::Reg(Reg::Float(XMM0)));
+    assert_eq!(alloc.slots(), 18);
+    // the arguments are put into their registers right away
+    assert_eq!(alloc.location(floats[1]), Location::Reg(Reg::Float(XMM1)));
+    assert!(alloc.before(20).is_empty());
+    assert_eq!(
+        alloc.operand(floats[2]),
+        Operand::Mem(jazz_jit::assembler::Mem::Base(RBP, -8))
+    );
+}
@@ -0,0 +1,146 @@
+//! Relocations recorded by the encoders and applied when code is emitted or moved.
+
+mod common;
+
+use common::*;
+use jazz_jit::assembler::{AsmDiagnostic, Mem, RipTarget};
+use jazz_jit::assembler_x64::*;
+use jazz_jit::code_cache::CodeCache;
+use jazz_jit::reloc::{RelocError, RelocKind, RelocTarget, Relocation};
+use jazz_jit::{symbols, JitFunction, MachineMode};
+
+extern "C" fn add_one(value: i64) -> i64 {
+    value + 1
+}
+
+#[test]
+fn recorded_relocations() {
+    let mut c = Checker::new();
+
+    c.check("movabs r9, 0", |asm| {
+        asm.load_addr(R9, RelocTarget::Address(0x1234))
+    });
+    c.check("call 5", |asm| {
+        asm.call_rel(RelocTarget::Symbol("f".into()))
+    });
+    c.check("movabs r11, 0; call r11", |asm| {
+        asm.call_abs(RelocTarget::Symbol("f".into()))
+    });
+
+    c.finish();
+
+    let mut asm = assembler();
+    let lbl = asm.create_label();
+    asm.jump(lbl);
+    asm.load_addr(RAX, RelocTarget::Label(lbl));
+    let disp = asm.dseg.add_int(7);
+    alu_imm_mem(
+        &mut asm,
+        MachineMode::Int32,
+        AluOp::Cmp,
+        5,
+        Mem::RipRelative(RipTarget::DSeg(disp)),
+    );
+    asm.bind_label(lbl);
+    asm.call_rel(RelocTarget::Address(0x1000));
+
+    // the jump shrinks to two bytes and moves the fields behind it
+    let code = asm.finalize().unwrap();
+    assert_eq!(
+        code.relocations,
+        [
+            Relocation {
+                at: 4,
+                kind: RelocKind::Abs64,
+                target: RelocTarget::Label(lbl),
+                addend: 0,
+            },
+            Relocation {
+                at: 14,
+                kind: RelocKind::Rel32,
+                target: RelocTarget::DSeg(disp),
+                addend: -5,
+            },
+            Relocation {
+                at: 20,
+                kind: RelocKind::Rel32,
+                target: RelocTarget::Address(0x1000),
+                addend: -4,
+            },
+        ]
+    );
+
+    let mut asm = assembler();
+    let lbl = asm.create_label();
+    asm.load_addr(RAX, RelocTarget::Label(lbl));
+    assert_eq!(
+        asm.finalize().unwrap_err().diagnostics,
+        [AsmDiagnostic::UnboundLabel {
+            label: lbl,
+            references: vec![2],
+        }]
+    );
+
+    let reloc = Relocation {
+        at: 0,
+        kind: RelocKind::Rel32,
+        target: RelocTarget::Address(0),
+        addend: -4,
+    };
+    let mut code = [0; 4];
+    assert_eq!(
+        reloc.write(&mut code, 0x1_0000_0000, 0x10),
+        Err(RelocError::OutOfRange {
+            at: 0,
+            disp: 0x10 - 4 - 0x1_0000_0000,
+        })
+    );
+}
+
+#[test]
+fn emit_and_copy() {
+    symbols::register(add_one as *const u8, "reloc_add_one");
+
+    // returns the address of `here`, plus one through a call to a runtime function
+    let mut asm = assembler();
+    let here = asm.create_label();
+    emit_pushq_reg(&mut asm, RBP);
+    asm.load_addr(RDI, RelocTarget::Label(here));
+    asm.call_abs(RelocTarget::Symbol("reloc_add_one".into()));
+    asm.bind_label(here);
+    emit_popq_reg(&mut asm, RBP);
+    emit_retq(&mut asm);
+    let here_at = 1 + 10 + 10 + 3;
+
+    let code = asm.finalize().unwrap();
+    let cache = CodeCache::new(1 << 20);
+    let memory = cache.emit(&code).unwrap();
+    assert_eq!(memory.relocations(), &code.relocations[..]);
+
+    let copy = cache.copy(&memory);
+    let start = memory.start() as i64;
+    let moved = copy.start() as i64;
+    assert_ne!(start, moved);
+
+    let fun: JitFunction<extern "C" fn() -> i64> = unsafe { JitFunction::new(memory) };
+    assert_eq!(fun.call(), start + here_at + 1);
+    drop(fun);
+
+    let fun: JitFunction<extern "C" fn() -> i64> = unsafe { JitFunction::new(copy) };
+    assert_eq!(fun.call(), moved + here_at + 1);
+}
+
+#[test]
+fn unknown_symbols_are_errors() {
+    let mut asm = assembler();
+    asm.call_abs(RelocTarget::Symbol("reloc_not_registered".into()));
+    emit_retq(&mut asm);
+
+    let code = asm.finalize().unwrap();
+    let cache = CodeCache::new(1 << 20);
+    assert_eq!(
+        cache.emit(&code).err(),
+        Some(RelocError::UnknownSymbol("reloc_not_registered".into()))
+    );
+    assert_eq!(cache.stats().used, 0);
+}
@@ -0,0 +1,175 @@
+//! Source positions recorded while emitting and exported for debuggers and perf.
+
+mod common;
+
+use common::*;
+use jazz_jit::assembler_x64::*;
+use jazz_jit::code_cache::CodeCache;
+use jazz_jit::elf::*;
+use jazz_jit::perf;
+use jazz_jit::source::{self, LineTable, SourcePosition};
+use std::convert::TryInto;
+use std::process::Command;
+
+fn position(file: u32, line: u32, column: u32) -> SourcePosition {
+    SourcePosition { file, line, column }
+}
+
+/// `xor eax, eax; jmp done; add rax, 1; done: ret` with a position before each
+/// instruction. The jump is relaxed.
+fn function() -> Assembler {
+    source::register_file(101, "main.ex");
+    source::register_file(102, "lib.ex");
+
+    let mut asm = assembler();
+    asm.set_name("source_positions");
+    let done = asm.create_label();
+
+    asm.set_source_position(101, 10, 5);
+    emit_xor_reg_reg(&mut asm, 0, RAX, RAX);
+    asm.jump(done);
+    asm.set_source_position(101, 11, 5);
+    emit_addq_imm_reg(&mut asm, 1, RAX);
+    // neither the same position again nor one that is replaced before any code
+    // adds a row
+    asm.set_source_position(101, 11, 5);
+    asm.bind_label(done);
+    asm.set_source_position(102, 2, 1);
+    asm.set_source_position(102, 3, 1);
+    emit_retq(&mut asm);
+
+    asm
+}
+
+#[test]
+fn positions_follow_relaxed_code() {
+    let asm = function();
+    let ret = asm.pos() - 1;
+    let code = asm.finalize().unwrap();
+
+    // the jump shrinks by three bytes
+    let rows = [
+        (0, position(101, 10, 5)),
+        (4, position(101, 11, 5)),
+        (ret - 3, position(102, 3, 1)),
+    ];
+    assert_eq!(code.source_positions, rows);
+
+    let memory = CodeCache::new(1 << 20).emit(&code).unwrap();
+    assert_eq!(memory.line_table(), &LineTable::new(rows.to_vec()));
+
+    let start = memory.start();
+    let at = |offset: usize| memory.source_position(start.wrapping_add(offset));
+    assert_eq!(at(0), Some(position(101, 10, 5)));
+    assert_eq!(at(3), Some(position(101, 10, 5)));
+    assert_eq!(at(5), Some(position(101, 11, 5)));
+    assert_eq!(at(ret - 3), Some(position(102, 3, 1)));
+    assert_eq!(at(ret - 2), None);
+    assert_eq!(memory.source_position(start.wrapping_sub(1)), None);
+}
+
+#[test]
+fn debug_line_section() {
+    let code = function().finalize().unwrap();
+    let lines = LineTable::new(code.source_positions.clone());
+    let start = 0x40_0000;
+    let size = code.data.len();
+
+    let mut elf = ElfWriter::new(ET_REL);
+    elf.add_section(Section::text_at(start, size as u64));
+    let (abbrev, info) = source::compile_unit("main.ex", start, size);
+    for (name, data) in vec![
+        (".debug_abbrev", abbrev),
+        (".debug_info", info),
+        (".debug_line", lines.debug_line(start, size)),
+    ] {
+        let mut section = Section::new(name, SHT_PROGBITS, 0);
+        section.data = data;
+        elf.add_section(section);
+    }
+
+    let path = std::env::temp_dir().join(format!("jazz-jit-lines-{}.o", std::process::id()));
+    std::fs::write(&path, elf.finish()).unwrap();
+    let output = Command::new("readelf")
+        .arg("--debug-dump=decodedline")
+        .arg(&path)
+        .output();
+    std::fs::remove_file(&path).unwrap();
+
+    // readelf is optional, like the rest of the toolchain
+    let output = match output {
+        Ok(output) => String::from_utf8_lossy(&output.stdout).into_owned(),
+        Err(_) => return,
+    };
+    let rows: Vec<Vec<&str>> = output
+        .lines()
+        .map(|line| line.split_whitespace().collect::<Vec<_>>())
+        .filter(|row| row.len() >= 3 && row[2].starts_with("0x"))
+        .collect();
+
+    assert_eq!(
+        rows,
+        [
+            vec!["main.ex", "10", "0x400000", "x"],
+            vec!["main.ex", "11", "0x400004", "x"],
+            vec!["lib.ex", "3", "0x400008", "x"],
+            vec!["lib.ex", "-", "0x400009"],
+        ]
+    );
+}
+
+#[test]
+fn jitdump_debug_info() {
+    let dir = std::env::temp_dir().join(format!("jazz-jit-jitdump-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = perf::enable_jitdump(&dir).unwrap();
+
+    let code = function().finalize().unwrap();
+    let memory = CodeCache::new(1 << 20).emit(&code).unwrap();
+    perf::disable();
+
+    let dump = std::fs::read(&path).unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    let u32_at = |pos: usize| u32::from_le_bytes(dump[pos..pos + 4].try_into().unwrap());
+    let u64_at = |pos: usize| u64::from_le_bytes(dump[pos..pos + 8].try_into().unwrap());
+
+    // the debug info comes right before the code load of the same address
+    let start = memory.start() as u64;
+    let mut pos = u32_at(8) as usize;
+    let mut debug_info = None;
+    while pos < dump.len() {
+        let (id, size) = (u32_at(pos), u32_at(pos + 4) as usize);
+
+        if id == 2 && u64_at(pos + 16) == start {
+            debug_info = Some(pos);
+            assert_eq!(size % 8, 0);
+            assert_eq!(u32_at(pos + size), 0);
+            assert_eq!(u64_at(pos + size + 24), start);
+        }
+
+        pos += size;
+    }
+
+    let pos = debug_info.expect("no debug info record");
+    assert_eq!(u64_at(pos + 24), 3);
+
+    let mut entries = Vec::new();
+    let mut entry = pos + 32;
+    for _ in 0..3 {
+        let name_start = entry + 16;
+        let name_end = name_start + dump[name_start..].iter().position(|&b| b == 0).unwrap();
+        let name = String::from_utf8_lossy(&dump[name_start..name_end]).into_owned();
+        entries.push((u64_at(entry) - start, u32_at(entry + 8), name));
+        entry = name_end + 1;
+    }
+
+    assert_eq!(
+        entries,
+        [
+            (0, 10, "main.ex".to_owned()),
+            (4, 11, "main.ex".to_owned()),
+            (8, 3, "lib.ex".to_owned()),
+        ]
+    );
+}
@@ -0,0 +1,170 @@
+//! Call frame information recorded for stack changes and used to unwind panics.
+
+mod common;
+
+use common::*;
+use jazz_jit::assembler_x64::*;
+use jazz_jit::call::CallBuilder;
+use jazz_jit::code_cache::CodeCache;
+use jazz_jit::reloc::RelocTarget;
+use jazz_jit::unwind::CfiOp::*;
+use jazz_jit::CondCode;
+use jazz_jit::JitFunction;
+use std::panic::catch_unwind;
+
+extern "C-unwind" fn fail_on_42(value: i64) -> i64 {
+    if value == 42 {
+        panic!("runtime function failed");
+    }
+
+    value
+}
+
+/// Calls `fail_on_42` with the first argument, with or without a frame pointer.
+fn caller(frame_pointer: bool) -> Assembler {
+    let mut asm = assembler();
+    // keeps the stack aligned at the call
+    let locals = if frame_pointer { 8 } else { 16 };
+
+    if frame_pointer {
+        emit_pushq_reg(&mut asm, RBP);
+        emit_mov_reg_reg(&mut asm, 1, RSP, RBP);
+    }
+    emit_pushq_reg(&mut asm, RBX);
+    emit_subq_imm_reg(&mut asm, locals, RSP);
+
+    emit_mov_reg_reg(&mut asm, 1, RDI, RBX);
+    asm.load_addr(RAX, RelocTarget::Address(fail_on_42 as *const u8 as u64));
+    emit_callq_reg(&mut asm, RAX);
+
+    emit_addq_imm_reg(&mut asm, locals, RSP);
+    emit_popq_reg(&mut asm, RBX);
+    if frame_pointer {
+        emit_mov_reg_reg(&mut asm, 1, RBP, RSP);
+        emit_popq_reg(&mut asm, RBP);
+    }
+    emit_retq(&mut asm);
+
+    asm
+}
+
+/// Like `caller`, with the call emitted by a `CallBuilder`. It aligns RSP at runtime
+/// behind a frame pointer and is given the stack depth without one.
+fn builder_caller(frame_pointer: bool) -> Assembler {
+    let mut asm = assembler();
+    let mut call = CallBuilder::new()
+        .arg(RDI)
+        .live(Reg::Gpr(RSI))
+        .result(Reg::Gpr(RAX));
+
+    if frame_pointer {
+        emit_pushq_reg(&mut asm, RBP);
+        emit_mov_reg_reg(&mut asm, 1, RSP, RBP);
+    } else {
+        // 8 below a boundary on entry, RBX is pushed
+        call = call.stack_depth(0);
+    }
+    emit_pushq_reg(&mut asm, RBX);
+
+    call.call(
+        &mut asm,
+        RelocTarget::Address(fail_on_42 as *const u8 as u64),
+    );
+
+    emit_popq_reg(&mut asm, RBX);
+    if frame_pointer {
+        emit_mov_reg_reg(&mut asm, 1, RBP, RSP);
+        emit_popq_reg(&mut asm, RBP);
+    }
+    emit_retq(&mut asm);
+
+    asm
+}
+
+#[test]
+fn tracked_frames() {
+    assert_eq!(
+        caller(true).cfi_ops,
+        [
+            (1, DefCfaOffset(16)),
+            (1, Offset(RBP, -16)),
+            (4, DefCfaRegister(RBP)),
+            (5, Offset(RBX, -24)),
+            (29, Restore(RBX)),
+            (32, DefCfaRegister(RSP)),
+            (33, Restore(RBP)),
+            (33, DefCfaOffset(8)),
+        ]
+    );
+
+    assert_eq!(
+        caller(false).cfi_ops,
+        [
+            (1, DefCfaOffset(16)),
+            (1, Offset(RBX, -16)),
+            (5, DefCfaOffset(32)),
+            (24, DefCfaOffset(16)),
+            (25, Restore(RBX)),
+            (25, DefCfaOffset(8)),
+        ]
+    );
+
+    // the code behind an early return still runs in the frame
+    let mut asm = assembler();
+    let exit = asm.create_label();
+    emit_pushq_reg(&mut asm, RBX);
+    asm.jump_if(CondCode::Zero, exit);
+    asm.cfi(RememberState);
+    emit_popq_reg(&mut asm, RBX);
+    emit_retq(&mut asm);
+    asm.cfi(RestoreState);
+    asm.bind_label(exit);
+    emit_pushq_reg(&mut asm, RAX);
+    assert_eq!(
+        asm.cfi_ops[5..],
+        [
+            (9, RestoreState),
+            (10, DefCfaOffset(24)),
+            (10, Offset(RAX, -24)),
+        ]
+    );
+}
+
+#[test]
+fn panics_unwind_through_code() {
+    let cache = CodeCache::new(1 << 20);
+
+    for &frame_pointer in [true, false].iter() {
+        let code = caller(frame_pointer).finalize().unwrap();
+        let memory = cache.emit(&code).unwrap();
+        let copy = cache.copy(&memory);
+
+        for memory in vec![memory, copy] {
+            let fun: JitFunction<extern "C-unwind" fn(i64) -> i64> =
+                unsafe { JitFunction::new(memory) };
+
+            assert_eq!(fun.call(7), 7);
+            assert!(catch_unwind(|| fun.call(42)).is_err());
+        }
+    }
+}
+
+#[test]
+fn panics_unwind_through_calls() {
+    let cache = CodeCache::new(1 << 20);
+
+    for &frame_pointer in [true, false].iter() {
+        let asm = builder_caller(frame_pointer);
+        let ops: Vec<_> = asm.cfi_ops.iter().map(|&(_, op)| op).collect();
+        // the frame is back where it was once the call returned
+        assert!(ops.contains(&Restore(RBX)));
+        assert_eq!(ops.last(), Some(&DefCfaOffset(8)));
+
+        let memory = cache.emit(&asm.finalize().unwrap()).unwrap();
+        let fun: JitFunction<extern "C-unwind" fn(i64) -> i64> =
+            unsafe { JitFunction::new(memory) };
+
+        assert_eq!(fun.call(7), 7);
+        assert!(catch_unwind(|| fun.call(42)).is_err());
+    }
+}
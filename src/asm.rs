@@ -4,86 +4,13 @@ use jazz_jit::assembler::*;
 use jazz_jit::assembler_x64::*;
 use jazz_jit::constants_x64::*;
 use jazz_jit::*;
-#[derive(Copy, Clone, PartialEq, Eq, Debug, Hash)]
-pub struct Spill {
-    pub src: Register,
-    pub index: i32,
-}
-
-#[derive(Copy, Clone, PartialEq, Eq, Debug, Hash)]
-pub enum RelocSize {
-    Byte,
-    Word,
-    Long,
-    Quad,
-}
-
-#[derive(Copy, Clone, PartialEq, Eq, Debug, Hash)]
-pub enum RelocType {
-    Absoulte,
-    Value,
-    Relative,
-}
-
-#[derive(Copy, Clone, PartialEq, Eq, Debug, Hash)]
-pub struct RelocationInfo {
-    pub offset: u32,
-    pub target: u32,
-    pub ty: RelocType,
-    pub size: RelocSize,
-    pub notify_gc: bool,
-}
-
-impl RelocationInfo {
-    pub const fn new(ty: RelocType, size: RelocSize, offset: u32) -> Self {
-        Self {
-            offset,
-            ty,
-            size,
-            target: 0,
-            notify_gc: false,
-        }
-    }
-
-    pub unsafe fn relocate(&self, _: &mut Heap, buffer: &mut [u8]) {
-        let mut addr: u64;
-        match self.ty {
-            RelocType::Absoulte => addr = buffer.as_ptr().offset(self.target as _) as u64,
-            RelocType::Value => addr = self.target as _,
-            _ => addr = self.target as u64 - self.offset as u64,
-        };
-        match self.size {
-            RelocSize::Byte => addr -= 1,
-            RelocSize::Word => addr -= 2,
-            RelocSize::Long => addr -= 4,
-            RelocSize::Quad => addr -= 8,
-        }
-
-        match self.size {
-            RelocSize::Byte => {
-                *(buffer.as_mut_ptr().offset(self.offset as _)) = addr as u8;
-            }
-            RelocSize::Word => {
-                *(buffer.as_mut_ptr().offset(self.offset as _) as *mut u16) = addr as u16;
-            }
-            RelocSize::Long => {
-                *(buffer.as_mut_ptr().offset(self.offset as _) as *mut u32) = addr as u32;
-            }
-            RelocSize::Quad => {
-                *(buffer.as_mut_ptr().offset(self.offset as _) as *mut u64) = addr;
-            }
-        }
-    }
-}
+pub use jazz_jit::regalloc::Spill;
 
 pub const CONTEXT_REG: Register = RSI;
 pub const ROOT_REG: Register = RDI;
 pub const SCRATCH: Register = R14;
 
-use std::cell::RefCell;
-use std::rc::Rc;
 pub struct Masm {
-    relocation_info: Vec<Rc<RefCell<RelocationInfo>>>,
     asm: Assembler,
     stubs: std::collections::HashMap<&'static str, *const u8>,
 }
@@ -91,11 +18,14 @@ pub struct Masm {
 impl Masm {
     pub fn new() -> Self {
         Self {
-            relocation_info: vec![],
-            asm: Assembler::new(),
+            asm: Assembler::for_host(),
             stubs: std::collections::HashMap::new(),
         }
     }
+    pub fn finalize(self) -> Result<Code, AsmError> {
+        self.asm.finalize()
+    }
+
     pub unsafe fn prologue(&mut self) {
         self.push(RBP);
         (**self).mov(true, RSP, RBP);
@@ -106,11 +36,6 @@ impl Masm {
         self.pop(RBP);
         emit_retq_imm(&mut self.asm, args * 8);
     }
-    pub unsafe fn relocate(&mut self, heap: &mut Heap) {
-        for item in self.relocation_info.iter().cloned() {
-            item.borrow().relocate(heap, &mut self.asm.data);
-        }
-    }
     pub unsafe fn push(&mut self, r: Register) {
         emit_pushq_reg(&mut self.asm, r);
     }
@@ -37,23 +37,9 @@ fn main() {
 
     roundsd(&mut asm, XMM0, XMM0, RoundMode::Nearest);
     asm.ret();
-    let cs = Capstone::new()
-        .x86()
-        .mode(arch::x86::ArchMode::Mode64)
-        .syntax(arch::x86::ArchSyntax::Att)
-        .detail(true)
-        .build()
-        .unwrap();
-    for op in asm.data().iter() {
-        print!("0x{:x} ", op);
-    }
-    println!();
-    let ins = cs.disasm_all(asm.data(), 0x0);
-    for ins in ins.unwrap().iter() {
-        println!("{}", ins);
-    }
+    print!("{}", asm.disassemble(disasm::Syntax::Att));
 
-    let mem = jazz_jit::get_executable_memory(&asm);
-    let fun: fn(f64) -> f64 = unsafe { std::mem::transmute(mem.ptr()) };
-    println!("{}", fun(2.6));*/
+    let fun: JitFunction<extern "C" fn(f64) -> f64> =
+        unsafe { JitFunction::new(jazz_jit::get_executable_memory(&asm.finalize().unwrap()).unwrap()) };
+    println!("{}", fun.call(2.6));*/
 }
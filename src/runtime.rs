@@ -1,6 +1,6 @@
 use crate::heap::*;
 
-pub unsafe extern "C" fn rt_lookup_property(
+pub unsafe extern "C-unwind" fn rt_lookup_property(
     heap: *mut Heap,
     obj: *mut u8,
     key: *mut u8,
@@ -80,7 +80,7 @@ pub unsafe extern "C" fn rt_lookup_property(
     }
 }
 
-pub unsafe extern "C" fn rt_grow_object(heap: *mut Heap, obj: *mut u8, min_size: usize) -> isize {
+pub unsafe extern "C-unwind" fn rt_grow_object(heap: *mut Heap, obj: *mut u8, min_size: usize) -> isize {
     let map_addr = HObject::map_slot_s(obj);
     let map = (*map_addr) as *mut HMap;
     let mut size = (*map).size() << 1;
@@ -121,7 +121,7 @@ pub unsafe extern "C" fn rt_grow_object(heap: *mut Heap, obj: *mut u8, min_size:
     return 0;
 }
 
-pub unsafe extern "C" fn rt_to_number(heap: *mut Heap, value: *mut u8) -> *mut u8 {
+pub unsafe extern "C-unwind" fn rt_to_number(heap: *mut Heap, value: *mut u8) -> *mut u8 {
     let tag = HValue::get_tag(value);
     match tag {
         HeapTag::String => {
@@ -144,7 +144,7 @@ pub unsafe extern "C" fn rt_to_number(heap: *mut Heap, value: *mut u8) -> *mut u
     }
 }
 
-pub unsafe extern "C" fn rt_strict_cmp(heap: *mut Heap, lhs: *mut u8, rhs: *mut u8) -> i32 {
+pub unsafe extern "C-unwind" fn rt_strict_cmp(heap: *mut Heap, lhs: *mut u8, rhs: *mut u8) -> i32 {
     // Fast case - pointers are equal
     if lhs == rhs {
         return 0;
@@ -176,7 +176,7 @@ pub unsafe extern "C" fn rt_strict_cmp(heap: *mut Heap, lhs: *mut u8, rhs: *mut
     }
 }
 
-pub unsafe extern "C" fn rt_strcmp(heap: *mut Heap, lhs: *mut u8, rhs: *mut u8) -> i32 {
+pub unsafe extern "C-unwind" fn rt_strcmp(heap: *mut Heap, lhs: *mut u8, rhs: *mut u8) -> i32 {
     let lhs_len = HString::static_length(lhs);
     let rhs_len = HString::static_length(rhs);
 
@@ -6,8 +6,12 @@ use constants_x64::*;
 use generic::*;
 use jazz_jit::*;
 
-unsafe fn entry_stub() -> *const u8 {
+/// Entry into generated code: `(root, tagged argc, argv)`.
+pub type EntryStub = extern "C-unwind" fn(*mut u8, usize, *const *mut HValue) -> *mut HValue;
+
+unsafe fn entry_stub() -> JitFunction<'static, EntryStub> {
     let mut asm = Masm::new();
+    (*asm).set_name("entry_stub");
     asm.prologue();
 
     (*asm).mov(true, ROOT_REG, RDI);
@@ -85,6 +89,5 @@ unsafe fn entry_stub() -> *const u8 {
     asm.pop(RBX);
     asm.pop(RBP);
     asm.epilogue(0);
-    let mem = get_executable_memory(&*asm);
-    mem.ptr()
+    JitFunction::new(get_executable_memory(&asm.finalize().unwrap()).unwrap())
 }